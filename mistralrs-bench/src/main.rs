@@ -58,6 +58,7 @@ fn run_bench(
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        top_a: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
@@ -66,6 +67,9 @@ fn run_bench(
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        length_bias: None,
+        stop_on_balanced_brackets: false,
+        max_output_bytes: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -84,6 +88,9 @@ fn run_bench(
         tool_choice: None,
         logits_processors: None,
         return_raw_logits: false,
+        continue_final_message: false,
+        reasoning_markers: None,
+        assistant_prefix: None,
     });
 
     let mut usages = Vec::new();
@@ -225,6 +232,7 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        top_a: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
@@ -233,6 +241,9 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        length_bias: None,
+        stop_on_balanced_brackets: false,
+        max_output_bytes: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -255,6 +266,9 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         tool_choice: None,
         logits_processors: None,
         return_raw_logits: false,
+        continue_final_message: false,
+        reasoning_markers: None,
+        assistant_prefix: None,
     });
 
     sender
@@ -1,20 +1,96 @@
 #![allow(clippy::cast_precision_loss)]
 
+use std::cell::RefCell;
 #[cfg(feature = "metal")]
 use std::sync::atomic::AtomicUsize;
 
 use crate::{
     cublaslt::CUBLASLT_HANDLE,
     layers::{get_use_matmul_via_f16, MatMul},
-    pipeline::text_models_inputs_processor::FlashParams,
+    layers_masker::CausalMasker,
+    pipeline::{text_models_inputs_processor::FlashParams, Pipeline},
 };
 
-use candle_core::{Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor};
 
 #[cfg(feature = "metal")]
 /// Initial, sentinel value is usize::MAX
 static METAL_VERSION_CACHE: AtomicUsize = AtomicUsize::new(usize::MAX);
 
+/// The longest key sequence for which attention weights can be recorded. The probabilities of one
+/// layer take `heads * q_len * k_len` elements, so this is only practical for short prompts.
+pub const MAX_ATTENTION_WEIGHTS_SEQ_LEN: usize = 1024;
+
+thread_local! {
+    /// The attention probabilities recorded on this thread by `record_attention_weights`.
+    static ATTENTION_WEIGHTS: RefCell<Option<Vec<Tensor>>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, typically an eager model forward of a single sequence, and record the attention
+/// probabilities of every attention layer it calls. While recording, attention always uses the
+/// dense implementation, as the fused kernels do not expose the softmax output.
+///
+/// Returns the output of `f` and the probabilities, of shape `(layers, heads, q_len, k_len)`.
+pub(crate) fn record_attention_weights<T>(f: impl FnOnce() -> Result<T>) -> Result<(T, Tensor)> {
+    let previous = ATTENTION_WEIGHTS.with(|w| w.replace(Some(Vec::new())));
+    let out = f();
+    let weights = ATTENTION_WEIGHTS
+        .with(|w| w.replace(previous))
+        .unwrap_or_default();
+    let out = out?;
+    if weights.is_empty() {
+        candle_core::bail!("No attention layers were run while recording attention weights.");
+    }
+    let weights = weights
+        .iter()
+        .map(|w| w.squeeze(0))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((out, Tensor::stack(&weights, 0)?))
+}
+
+fn is_recording_attention_weights() -> bool {
+    ATTENTION_WEIGHTS.with(|w| w.borrow().is_some())
+}
+
+/// Dense attention which records the attention probabilities. `k` and `v` have the KV heads
+/// already repeated.
+fn recorded_sdpa(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    sdpa_params: &SdpaParams,
+) -> Result<Tensor> {
+    let k_len = k.dim(2)?;
+    if q.dim(0)? != 1 {
+        candle_core::bail!("Attention weights can only be recorded for a single sequence.");
+    }
+    if k_len > MAX_ATTENTION_WEIGHTS_SEQ_LEN {
+        candle_core::bail!(
+            "Attention weights can only be recorded for at most {MAX_ATTENTION_WEIGHTS_SEQ_LEN} tokens, got {k_len}."
+        );
+    }
+    let dtype = q.dtype();
+    let mut att = (q
+        .to_dtype(DType::F32)?
+        .matmul(&k.to_dtype(DType::F32)?.t()?)?
+        * sdpa_params.softmax_scale as f64)?;
+    if let Some(softcap) = sdpa_params.softcap {
+        att = ((att / softcap as f64)?.tanh()? * softcap as f64)?;
+    }
+    att = match mask {
+        Some(m) => att.broadcast_add(&m.to_dtype(DType::F32)?)?,
+        None => att,
+    };
+    let att = candle_nn::ops::softmax_last_dim(&att)?;
+    ATTENTION_WEIGHTS.with(|w| {
+        if let Some(weights) = w.borrow_mut().as_mut() {
+            weights.push(att.clone());
+        }
+    });
+    MatMul.matmul(&att.to_dtype(dtype)?, v)
+}
+
 #[cfg(feature = "flash-attn")]
 fn flash_attn(
     q: &Tensor,
@@ -90,15 +166,46 @@ fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
     }
 }
 
-/// Computes softmax(QK^T*sqrt(d_k))V
+/// Computes softmax(QK^T*softmax_scale)V, accumulating QK^T and the softmax in f32.
+/// The attention probabilities are cast back to the input dtype before being applied to V.
+fn naive_sdpa_f32_softmax(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    sdpa_params: &SdpaParams,
+) -> Result<Tensor> {
+    let dtype = q.dtype();
+    let mut att = (q
+        .to_dtype(DType::F32)?
+        .matmul(&k.to_dtype(DType::F32)?.t()?)?
+        * sdpa_params.softmax_scale as f64)?;
+    if let Some(softcap) = sdpa_params.softcap {
+        att = (att / softcap as f64)?;
+        att = att.tanh()?;
+        att = (att * softcap as f64)?;
+    }
+
+    att = match mask {
+        Some(m) => att.broadcast_add(&m.to_dtype(DType::F32)?)?,
+        None => att,
+    };
+    att = candle_nn::ops::softmax_last_dim(&att)?.to_dtype(dtype)?;
+    MatMul.matmul(&att, v)
+}
+
+/// Computes softmax(QK^T*softmax_scale)V
 fn naive_sdpa(
     q: &Tensor,
     k: &Tensor,
     v: &Tensor,
     mask: Option<&Tensor>,
-    head_dim: usize,
     sdpa_params: &SdpaParams,
 ) -> Result<Tensor> {
+    if sdpa_params.attention_softmax_f32 && q.dtype() != DType::F32 {
+        return naive_sdpa_f32_softmax(q, k, v, mask, sdpa_params);
+    }
+
     #[cfg(feature = "metal")]
     let supports_attn_softmax = {
         use std::sync::atomic::Ordering;
@@ -166,14 +273,11 @@ fn naive_sdpa(
             att = (att * softcap as f64)?;
         }
 
-        att = candle_nn::ops::attn_softmax_last_dim(
-            &att,
-            mask.unwrap(),
-            1. / (head_dim as f32).sqrt(),
-        )?;
+        att =
+            candle_nn::ops::attn_softmax_last_dim(&att, mask.unwrap(), sdpa_params.softmax_scale)?;
         MatMul.matmul(&att, v)
     } else {
-        let mut att = MatMul.matmul_affine_div(q, &k.t()?, (head_dim as f64).sqrt())?;
+        let mut att = (MatMul.matmul(q, &k.t()?)? * sdpa_params.softmax_scale as f64)?;
         if let Some(softcap) = sdpa_params.softcap {
             att = (att / softcap as f64)?;
             att = att.tanh()?;
@@ -195,6 +299,9 @@ pub struct SdpaParams {
     pub softcap: Option<f32>,
     pub softmax_scale: f32,
     pub sliding_window: Option<usize>,
+    /// Run QK^T and the softmax in f32 for half precision inputs. This is ignored by flash attention,
+    /// which already accumulates in f32.
+    pub attention_softmax_f32: bool,
 }
 
 pub struct Sdpa;
@@ -211,6 +318,11 @@ impl Sdpa {
     /// 1) If `use_flash_attn == true`, use a flash attention V2 kernel
     /// 2) If using CUDA and the cuBLASLt kernel is initialized, then it will use an optimized version.
     /// 3) Otherwise, use the "naive" SDPA implementation.
+    ///
+    /// If `attention_softmax_f32 == true` and the inputs are f16/bf16, steps 2 and 3 are replaced
+    /// by the naive implementation with an f32 QK^T and softmax.
+    ///
+    /// Within [`record_attention_weights`], the dense implementation is always used.
     #[allow(unused_variables, clippy::too_many_arguments)]
     pub fn run_attention(
         &self,
@@ -222,6 +334,11 @@ impl Sdpa {
         sdpa_params: &SdpaParams,
     ) -> Result<Tensor> {
         let (b_sz, n_attn_heads, seq_len, head_dim) = q.dims4()?;
+        if is_recording_attention_weights() {
+            let k = repeat_kv(k.clone(), sdpa_params.n_kv_groups)?;
+            let v = repeat_kv(v.clone(), sdpa_params.n_kv_groups)?;
+            return recorded_sdpa(q, &k, &v, mask, sdpa_params);
+        }
         if sdpa_params.use_flash_attn {
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
             let q = q.transpose(1, 2)?;
@@ -230,7 +347,9 @@ impl Sdpa {
             return flash_attn(&q, &k, &v, flash_params, sdpa_params)?.transpose(1, 2);
         }
 
-        if q.device().is_metal() && seq_len == 1 {
+        let softmax_f32 = sdpa_params.attention_softmax_f32 && q.dtype() != DType::F32;
+
+        if q.device().is_metal() && seq_len == 1 && !softmax_f32 {
             return candle_nn::ops::sdpa(
                 q,
                 k,
@@ -242,6 +361,9 @@ impl Sdpa {
 
         let k = repeat_kv(k.clone(), sdpa_params.n_kv_groups)?;
         let v = repeat_kv(v.clone(), sdpa_params.n_kv_groups)?;
+        if softmax_f32 {
+            return naive_sdpa(q, &k, &v, mask, sdpa_params);
+        }
         if let (Device::Cuda(_), Some(cublaslt)) = (q.device(), *CUBLASLT_HANDLE.lock().unwrap()) {
             if !get_use_matmul_via_f16() {
                 #[cfg(feature = "cuda")]
@@ -303,10 +425,330 @@ impl Sdpa {
                 }
             } else {
                 // Use the f16 kernels here if quantized (ISQ or GGML), and a large enough prompt
-                naive_sdpa(q, &k, &v, mask, head_dim, sdpa_params)
+                naive_sdpa(q, &k, &v, mask, sdpa_params)
             }
         } else {
-            naive_sdpa(q, &k, &v, mask, head_dim, sdpa_params)
+            naive_sdpa(q, &k, &v, mask, sdpa_params)
+        }
+    }
+}
+
+/// The largest absolute difference between the flash attention and dense attention outputs which is
+/// accepted by [`verify_flash_attention`].
+pub const FLASH_ATTENTION_TOLERANCE: f32 = 2e-2;
+
+/// The prompt length of the attention self-test.
+const VERIFY_ATTENTION_SEQ_LEN: usize = 256;
+
+/// Run flash attention and the dense implementation on the same random causal prompt and return the
+/// largest absolute difference of their outputs. A sliding window longer than the prompt is
+/// shortened, so that the windowed kernel is exercised.
+fn flash_attention_max_diff(
+    n_attn_heads: usize,
+    n_kv_heads: usize,
+    head_dim: usize,
+    sliding_window: Option<usize>,
+    device: &Device,
+    dtype: DType,
+) -> Result<f32> {
+    let seq_len = VERIFY_ATTENTION_SEQ_LEN;
+    let sliding_window = sliding_window.map(|w| w.min(seq_len / 2));
+    // The flash attention kernels only support half precision
+    let dtype = if dtype == DType::F32 {
+        DType::F16
+    } else {
+        dtype
+    };
+
+    let q = Tensor::randn(0f32, 1., (1, n_attn_heads, seq_len, head_dim), device)?;
+    let k = Tensor::randn(0f32, 1., (1, n_kv_heads, seq_len, head_dim), device)?;
+    let v = Tensor::randn(0f32, 1., (1, n_kv_heads, seq_len, head_dim), device)?;
+    let (q, k, v) = (q.to_dtype(dtype)?, k.to_dtype(dtype)?, v.to_dtype(dtype)?);
+    let params = |use_flash_attn| SdpaParams {
+        n_kv_groups: n_attn_heads / n_kv_heads,
+        use_flash_attn,
+        softcap: None,
+        softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+        sliding_window,
+        attention_softmax_f32: false,
+    };
+
+    let cumulative_seqlens = Tensor::new(&[0u32, seq_len as u32], device)?;
+    let flash_params = FlashParams {
+        max_q: seq_len as u32,
+        max_k: seq_len as u32,
+        cumulative_seqlens_q: cumulative_seqlens.clone(),
+        cumulative_seqlens_k: cumulative_seqlens,
+    };
+    let flash = Sdpa.run_attention(&q, &k, &v, None, Some(&flash_params), &params(true))?;
+
+    let input_ids = Tensor::zeros((1, seq_len), DType::U32, device)?;
+    let past: &[usize] = &[0];
+    let mask = CausalMasker.make_sliding_window_causal_mask_matrix(
+        &input_ids,
+        &past,
+        sliding_window,
+        dtype,
+        n_attn_heads,
+    )?;
+    let dense = Sdpa.run_attention(&q, &k, &v, mask.as_ref(), None, &params(false))?;
+
+    (flash.to_dtype(DType::F32)? - dense.to_dtype(DType::F32)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()
+}
+
+/// Check that the flash attention kernel agrees with the dense implementation for the head layout
+/// and sliding window of the loaded model, on random inputs. Returns the largest absolute
+/// difference of the outputs, or an error if it is over [`FLASH_ATTENTION_TOLERANCE`].
+pub fn verify_flash_attention(pipeline: &dyn Pipeline) -> anyhow::Result<f32> {
+    let metadata = pipeline.get_metadata();
+    let Some(model_config) = &metadata.model_metadata else {
+        anyhow::bail!("The model does not expose its attention configuration.");
+    };
+    let device = pipeline.device();
+    if !device.is_cuda() {
+        anyhow::bail!("Flash attention requires a CUDA device, the model is on {device:?}.");
+    }
+    let max_diff = flash_attention_max_diff(
+        model_config.num_attn_heads(),
+        model_config.num_kv_heads(),
+        model_config.head_dim(),
+        metadata.sliding_window,
+        &device,
+        metadata.activation_dtype,
+    )?;
+    if max_diff > FLASH_ATTENTION_TOLERANCE {
+        anyhow::bail!(
+            "Flash attention and dense attention disagree by up to {max_diff}, over the tolerance of {FLASH_ATTENTION_TOLERANCE}. The flash attention kernel may be broken or not support this model."
+        );
+    }
+    Ok(max_diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use super::{record_attention_weights, Sdpa, SdpaParams};
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a.to_dtype(DType::F32)? - b.to_dtype(DType::F32)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()
+    }
+
+    #[test]
+    fn test_attention_softmax_f32() -> Result<()> {
+        // Gemma-like head layout: large head dim, MQA.
+        let (n_attn_heads, n_kv_heads, seq_len, head_dim) = (8, 1, 256, 256);
+        let dev = Device::Cpu;
+
+        // Scale q up so the logits are large enough for f16 accumulation to lose precision.
+        let q = (Tensor::randn(0f32, 1., (1, n_attn_heads, seq_len, head_dim), &dev)? * 4.)?;
+        let k = Tensor::randn(0f32, 1., (1, n_kv_heads, seq_len, head_dim), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, n_kv_heads, seq_len, head_dim), &dev)?;
+
+        let params = |attention_softmax_f32| SdpaParams {
+            n_kv_groups: n_attn_heads / n_kv_heads,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+            sliding_window: None,
+            attention_softmax_f32,
+        };
+
+        let expected = Sdpa.run_attention(&q, &k, &v, None, None, &params(false))?;
+
+        let (q, k, v) = (
+            q.to_dtype(DType::F16)?,
+            k.to_dtype(DType::F16)?,
+            v.to_dtype(DType::F16)?,
+        );
+        let f16_out = Sdpa.run_attention(&q, &k, &v, None, None, &params(false))?;
+        let f32_out = Sdpa.run_attention(&q, &k, &v, None, None, &params(true))?;
+        assert_eq!(f32_out.dtype(), DType::F16);
+
+        let f16_err = max_abs_diff(&f16_out, &expected)?;
+        let f32_err = max_abs_diff(&f32_out, &expected)?;
+        assert!(
+            f32_err <= f16_err,
+            "f32 softmax error {f32_err} is larger than f16 softmax error {f16_err}"
+        );
+        assert!(f32_err < 2e-2, "f32 softmax error {f32_err} is too large");
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunked_prefill_matches_single_shot() -> Result<()> {
+        use crate::{layers_masker::CausalMasker, pipeline::KvCache};
+
+        let (n_attn_heads, n_kv_heads, seq_len, head_dim) = (4, 2, 100, 16);
+        let chunk_size = 32;
+        let dev = Device::Cpu;
+
+        let q = Tensor::randn(0f32, 1., (1, n_attn_heads, seq_len, head_dim), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, n_kv_heads, seq_len, head_dim), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, n_kv_heads, seq_len, head_dim), &dev)?;
+        let params = SdpaParams {
+            n_kv_groups: n_attn_heads / n_kv_heads,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+            sliding_window: None,
+            attention_softmax_f32: false,
+        };
+        let mask_for = |tgt_len: usize, past_kv_len: usize| {
+            let input_ids = Tensor::zeros((1, tgt_len), DType::U32, &dev)?;
+            let past: &[usize] = &[past_kv_len];
+            CausalMasker.make_causal_mask_matrix(&input_ids, &past, DType::F32, n_attn_heads)
+        };
+
+        let mut cache = KvCache::new(2, 4096, 512);
+        let (full_k, full_v) = cache.append(&k, &v)?;
+        let mask = mask_for(seq_len, 0)?;
+        let expected = Sdpa.run_attention(&q, &full_k, &full_v, mask.as_ref(), None, &params)?;
+
+        let mut cache = KvCache::new(2, 4096, 512);
+        let mut outputs = Vec::new();
+        for start in (0..seq_len).step_by(chunk_size) {
+            let len = chunk_size.min(seq_len - start);
+            let mask = mask_for(len, cache.current_seq_len())?;
+            let (k, v) = cache.append(&k.narrow(2, start, len)?, &v.narrow(2, start, len)?)?;
+            outputs.push(Sdpa.run_attention(
+                &q.narrow(2, start, len)?,
+                &k,
+                &v,
+                mask.as_ref(),
+                None,
+                &params,
+            )?);
+        }
+        let chunked = Tensor::cat(&outputs, 2)?;
+
+        assert_eq!(max_abs_diff(&cache.k()?.unwrap(), &k)?, 0.);
+        assert_eq!(max_abs_diff(&cache.v()?.unwrap(), &v)?, 0.);
+        let err = max_abs_diff(&chunked, &expected)?;
+        assert!(
+            err < 1e-5,
+            "chunked prefill differs from single-shot by {err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_softmax_scale() -> Result<()> {
+        use crate::layers_masker::CausalMasker;
+
+        let (n_attn_heads, seq_len, head_dim) = (2, 6, 8);
+        let dev = Device::Cpu;
+        let q = Tensor::randn(0f32, 1., (1, n_attn_heads, seq_len, head_dim), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, n_attn_heads, seq_len, head_dim), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, n_attn_heads, seq_len, head_dim), &dev)?;
+        // A scale other than 1/sqrt(head_dim), as with the Granite attention multiplier
+        let softmax_scale = 0.5;
+        let input_ids = Tensor::zeros((1, seq_len), DType::U32, &dev)?;
+        let past: &[usize] = &[0];
+        let mask = CausalMasker
+            .make_causal_mask_matrix(&input_ids, &past, DType::F32, n_attn_heads)?
+            .expect("Expected a mask for the prompt");
+
+        let att = (q.matmul(&k.t()?)? * softmax_scale as f64)?.broadcast_add(&mask)?;
+        let expected = candle_nn::ops::softmax_last_dim(&att)?.matmul(&v)?;
+        for attention_softmax_f32 in [false, true] {
+            let params = SdpaParams {
+                n_kv_groups: 1,
+                use_flash_attn: false,
+                softcap: None,
+                softmax_scale,
+                sliding_window: None,
+                attention_softmax_f32,
+            };
+            for dtype in [DType::F32, DType::F16] {
+                let (q, k, v) = (q.to_dtype(dtype)?, k.to_dtype(dtype)?, v.to_dtype(dtype)?);
+                let mask = mask.to_dtype(dtype)?;
+                let out = Sdpa.run_attention(&q, &k, &v, Some(&mask), None, &params)?;
+                let err = max_abs_diff(&out, &expected)?;
+                assert!(
+                    err < 1e-2,
+                    "{dtype:?}, f32 softmax {attention_softmax_f32}: {err}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bidirectional_mask() -> Result<()> {
+        use crate::layers_masker::CausalMasker;
+
+        let (n_attn_heads, seq_len, head_dim) = (2, 6, 8);
+        let dev = Device::Cpu;
+        let q = Tensor::randn(0f32, 1., (1, n_attn_heads, seq_len, head_dim), &dev)?;
+        let k = Tensor::randn(0f32, 1., (1, n_attn_heads, seq_len, head_dim), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, n_attn_heads, seq_len, head_dim), &dev)?;
+        let params = SdpaParams {
+            n_kv_groups: 1,
+            use_flash_attn: false,
+            softcap: None,
+            softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+            sliding_window: None,
+            attention_softmax_f32: false,
+        };
+        let input_ids = Tensor::zeros((1, seq_len), DType::U32, &dev)?;
+        let past: &[usize] = &[0];
+        // The attention probabilities of position 0, and whether each of them is nonzero
+        let first_row = |bidirectional| -> Result<Tensor> {
+            let mask = CausalMasker.make_attention_mask_matrix(
+                &input_ids,
+                &past,
+                DType::F32,
+                n_attn_heads,
+                bidirectional,
+            )?;
+            let (_, probs) = record_attention_weights(|| {
+                Sdpa.run_attention(&q, &k, &v, mask.as_ref(), None, &params)
+            })?;
+            probs.narrow(2, 0, 1)?.gt(0f64)?.flatten_all()
+        };
+
+        // With the causal mask, position 0 only attends to itself
+        let causal = first_row(false)?.to_vec1::<u8>()?;
+        assert_eq!(
+            causal.iter().map(|x| *x as usize).sum::<usize>(),
+            n_attn_heads
+        );
+
+        // Bidirectionally, nothing is masked and position 0 attends to later positions
+        let bidirectional = first_row(true)?.to_vec1::<u8>()?;
+        assert!(bidirectional.iter().all(|x| *x == 1));
+        Ok(())
+    }
+
+    #[cfg(feature = "flash-attn")]
+    #[test]
+    fn test_flash_attention_matches_dense() -> Result<()> {
+        use super::{flash_attention_max_diff, FLASH_ATTENTION_TOLERANCE};
+
+        let dev = Device::new_cuda(0)?;
+        // GQA with a sliding window, and MHA without one
+        for (n_attn_heads, n_kv_heads, head_dim, sliding_window) in
+            [(8, 2, 128, Some(4096)), (4, 4, 64, None)]
+        {
+            let max_diff = flash_attention_max_diff(
+                n_attn_heads,
+                n_kv_heads,
+                head_dim,
+                sliding_window,
+                &dev,
+                DType::BF16,
+            )?;
+            assert!(max_diff <= FLASH_ATTENTION_TOLERANCE, "{max_diff}");
         }
+        Ok(())
     }
 }
@@ -0,0 +1,91 @@
+//! Classification heads, applied to the last-layer hidden state of a prompt.
+
+use std::path::Path;
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::Linear;
+
+/// A linear classification head, as the `score` layer of the `-ForSequenceClassification` models
+/// of Hugging Face Transformers. This lets reward models and classifiers which share a base
+/// architecture with a supported model be served by loading their head separately.
+#[derive(Debug, Clone)]
+pub struct ClassificationHead {
+    score: Linear,
+    num_labels: usize,
+}
+
+impl ClassificationHead {
+    /// Load the head from the `score.weight`, `(num_labels, hidden_size)`, and the optional
+    /// `score.bias` of a safetensors file.
+    pub fn load(path: impl AsRef<Path>, device: &Device) -> Result<Self> {
+        let mut tensors = candle_core::safetensors::load(path, device)?;
+        let Some(weight) = tensors.remove("score.weight") else {
+            candle_core::bail!("The classification head must contain a `score.weight` tensor.");
+        };
+        let bias = tensors
+            .remove("score.bias")
+            .map(|bias| bias.to_dtype(DType::F32))
+            .transpose()?;
+        let (num_labels, _) = weight.dims2()?;
+        Ok(Self {
+            score: Linear::new(weight.to_dtype(DType::F32)?, bias),
+            num_labels,
+        })
+    }
+
+    pub fn num_labels(&self) -> usize {
+        self.num_labels
+    }
+
+    /// The class logits of the pooled hidden states, `(batch, hidden_size)`. The head is applied
+    /// in F32.
+    pub fn forward(&self, hidden: &Tensor) -> Result<Tensor> {
+        let hidden = hidden
+            .to_device(self.score.weight().device())?
+            .to_dtype(DType::F32)?;
+        self.score.forward(&hidden)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{Device, Result, Tensor};
+
+    use super::ClassificationHead;
+
+    #[test]
+    fn test_classification_head() -> Result<()> {
+        let dev = Device::Cpu;
+        let path = std::env::temp_dir().join(format!(
+            "mistralrs_classification_head_{}.safetensors",
+            std::process::id()
+        ));
+        let weight = Tensor::randn(0f32, 1., (3, 8), &dev)?;
+        let tensors = HashMap::from([
+            ("score.weight".to_string(), weight.clone()),
+            (
+                "score.bias".to_string(),
+                Tensor::new(&[1f32, 2., 3.], &dev)?,
+            ),
+        ]);
+        candle_core::safetensors::save(&tensors, &path)?;
+        let head = ClassificationHead::load(&path, &dev);
+        std::fs::remove_file(&path)?;
+        let head = head?;
+        assert_eq!(head.num_labels(), 3);
+
+        let hidden = Tensor::randn(0f32, 1., (1, 8), &dev)?;
+        let logits = head.forward(&hidden)?;
+        assert_eq!(logits.dims(), &[1, 3]);
+        let expected = (hidden.matmul(&weight.t()?)? + Tensor::new(&[[1f32, 2., 3.]], &dev)?)?;
+        let diff = (logits - expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5);
+        Ok(())
+    }
+}
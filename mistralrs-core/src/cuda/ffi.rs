@@ -1,5 +1,7 @@
 use std::ffi::c_void;
 
+use candle_core::cuda::cudarc::driver::sys::CUstream;
+
 #[allow(dead_code)]
 extern "C" {
     pub(crate) fn count_nonzero_bf16(d_in: *const c_void, N: u32) -> u32;
@@ -161,4 +163,35 @@ extern "C" {
     pub(crate) fn leftshift_u32(d_in1: *const c_void, d_out: *mut c_void, N: u32, k: i32);
     pub(crate) fn leftshift_i64(d_in1: *const c_void, d_out: *mut c_void, N: u32, k: i32);
     pub(crate) fn leftshift_i32(d_in1: *const c_void, d_out: *mut c_void, N: u32, k: i32);
+
+    pub(crate) fn fused_add_rms_norm_f32(
+        x: *const c_void,
+        residual: *const c_void,
+        weight: *const c_void,
+        out: *mut c_void,
+        rows: u32,
+        cols: u32,
+        eps: f32,
+        stream: CUstream,
+    );
+    pub(crate) fn fused_add_rms_norm_f16(
+        x: *const c_void,
+        residual: *const c_void,
+        weight: *const c_void,
+        out: *mut c_void,
+        rows: u32,
+        cols: u32,
+        eps: f32,
+        stream: CUstream,
+    );
+    pub(crate) fn fused_add_rms_norm_bf16(
+        x: *const c_void,
+        residual: *const c_void,
+        weight: *const c_void,
+        out: *mut c_void,
+        rows: u32,
+        cols: u32,
+        eps: f32,
+        stream: CUstream,
+    );
 }
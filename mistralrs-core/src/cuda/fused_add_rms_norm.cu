@@ -0,0 +1,67 @@
+// Residual add followed by RMSNorm in a single pass over the hidden states.
+// The output holds the sum in its first half and the normalized sum in its second half.
+#include <cuda_bf16.h>
+#include <cuda_fp16.h>
+#include <stdint.h>
+
+template <typename T>
+__global__ void fused_add_rms_norm_kernel(const T *x, const T *residual,
+                                          const T *weight, T *out,
+                                          const uint32_t rows,
+                                          const uint32_t cols,
+                                          const float eps) {
+  extern __shared__ float shared[];
+  const size_t offset = (size_t)blockIdx.x * cols;
+  T *sum = out + offset;
+  T *normed = out + (size_t)rows * cols + offset;
+
+  float sum_sq = 0.f;
+  for (uint32_t i = threadIdx.x; i < cols; i += blockDim.x) {
+    // Round the sum to T like the unfused add does
+    const T s = T(static_cast<float>(x[offset + i]) +
+                  static_cast<float>(residual[offset + i]));
+    sum[i] = s;
+    const float sf = static_cast<float>(s);
+    sum_sq += sf * sf;
+  }
+  shared[threadIdx.x] = sum_sq;
+  __syncthreads();
+  for (uint32_t stride = blockDim.x / 2; stride > 0; stride >>= 1) {
+    if (threadIdx.x < stride) {
+      shared[threadIdx.x] += shared[threadIdx.x + stride];
+    }
+    __syncthreads();
+  }
+  const float scale = rsqrtf(shared[0] / (float)cols + eps);
+
+  for (uint32_t i = threadIdx.x; i < cols; i += blockDim.x) {
+    normed[i] = T(static_cast<float>(sum[i]) * scale *
+                  static_cast<float>(weight[i]));
+  }
+}
+
+template <typename T>
+void fused_add_rms_norm(const T *x, const T *residual, const T *weight, T *out,
+                        const uint32_t rows, const uint32_t cols,
+                        const float eps, cudaStream_t stream) {
+  // One block per row, the block size must be a power of 2 for the reduction
+  int nthreads = 32;
+  while (nthreads < cols && nthreads < 1024) {
+    nthreads <<= 1;
+  }
+  fused_add_rms_norm_kernel<<<rows, nthreads, nthreads * sizeof(float),
+                              stream>>>(x, residual, weight, out, rows, cols,
+                                        eps);
+}
+
+#define FUSED_ADD_RMS_NORM_OP(TYPENAME, RUST_NAME)                             \
+  extern "C" void fused_add_rms_norm_##RUST_NAME(                              \
+      const TYPENAME *x, const TYPENAME *residual, const TYPENAME *weight,     \
+      TYPENAME *out, uint32_t rows, uint32_t cols, float eps,                  \
+      cudaStream_t stream) {                                                   \
+    fused_add_rms_norm(x, residual, weight, out, rows, cols, eps, stream);     \
+  }
+
+FUSED_ADD_RMS_NORM_OP(float, f32)
+FUSED_ADD_RMS_NORM_OP(__half, f16)
+FUSED_ADD_RMS_NORM_OP(__nv_bfloat16, bf16)
@@ -101,11 +101,13 @@ fn get_t5_model(
             .map(|f| repo.get(f))
             .collect::<std::result::Result<Vec<_>, ApiError>>()
             .map_err(candle_core::Error::msg)?,
+        false,
         vec![],
         Some(dtype),
         device,
         silent,
         None,
+        None,
         |_| true,
     )?;
     let config_filename = repo.get("config.json").map_err(candle_core::Error::msg)?;
@@ -125,9 +127,17 @@ fn get_clip_model_and_tokenizer(
     ));
 
     let model_file = repo.get("model.safetensors")?;
-    let vb = from_mmaped_safetensors(vec![model_file], vec![], None, device, silent, None, |_| {
-        true
-    })?;
+    let vb = from_mmaped_safetensors(
+        vec![model_file],
+        vec![],
+        false,
+        None,
+        device,
+        silent,
+        None,
+        None,
+        |_| true,
+    )?;
     let config_file = repo.get("config.json")?;
     let config: ClipConfig = serde_json::from_reader(File::open(config_file)?)?;
     let config = config.text_config;
@@ -45,6 +45,7 @@ pub struct PagedAttentionScheduler {
     config: PagedAttentionSchedulerConfig,
     pub block_engine: BlockEngine,
     block_size: usize,
+    cancel_all: bool,
 }
 
 impl PagedAttentionScheduler {
@@ -60,6 +61,7 @@ impl PagedAttentionScheduler {
                 cache_config.num_cpu_blocks,
             ),
             block_size: cache_config.block_size,
+            cancel_all: false,
         }
     }
 
@@ -339,10 +341,17 @@ impl Scheduler for PagedAttentionScheduler {
     fn add_seq(&mut self, seq: Sequence) {
         self.waiting.push_back(Arc::new(Mutex::new(seq)));
     }
+    fn cancel_all(&mut self) {
+        self.cancel_all = true;
+    }
     fn schedule(&mut self) -> SchedulerOutput<'_> {
-        SchedulerOutput::PagedAttention {
-            output: self.schedule(),
+        let output = self.schedule();
+        if self.cancel_all {
+            output.scheduled.iter().for_each(|seq| {
+                get_mut_arcmutex!(seq).set_state(SequenceState::Done(StopReason::Canceled))
+            });
         }
+        SchedulerOutput::PagedAttention { output }
     }
     fn waiting_len(&self) -> usize {
         self.waiting.len() + self.swapped_out.len()
@@ -1,12 +1,14 @@
-use candle_core::Tensor;
+use candle_core::{IndexOp, Tensor};
 use either::Either;
 use llguidance::toktrie::TokEnv;
 use once_cell::sync::Lazy;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    num::NonZeroUsize,
+    path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, RwLock, Weak,
     },
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
@@ -14,16 +16,22 @@ use tokio::sync::{mpsc::Receiver, Mutex};
 
 use crate::{
     pipeline::{
+        chat_template::with_json_object_hint,
         llg::{constraint_from_llg_grammar, llg_grammar_from_constraint},
         text_models_inputs_processor::PagedAttentionMeta,
-        AdapterInstruction, CacheBackendMetadata, CacheInstruction, EitherCache, NormalCache,
+        AdapterInstruction, CacheBackendMetadata, CacheInstruction, EitherCache, KvCache,
+        NormalCache,
+    },
+    request::{
+        AttentionWeightsRequest, ClassificationRequest, DetokenizationRequest, NormalRequest,
+        TokenizationRequest,
     },
-    request::{DetokenizationRequest, NormalRequest, TokenizationRequest},
     response::CompletionChoice,
     scheduler::{Scheduler, SchedulerOutput},
     sequence::{SeqStepType, StopReason},
     tools::{ToolCallingMatcher, ToolChoice},
-    CompletionResponse, RequestMessage, Response, SchedulerConfig, DEBUG,
+    utils::{brackets::BracketTracker, logits_dump::LogitsDump, reasoning::ReasoningParser},
+    CompletionResponse, MistralRs, RequestMessage, Response, SchedulerConfig, DEBUG,
 };
 use rand::SeedableRng;
 use rand_isaac::Isaac64Rng;
@@ -35,7 +43,7 @@ use crate::{
     prefix_cacher::PrefixCacheManager,
     request::Request,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
-    sampler::Sampler,
+    sampler::{EosLengthBias, Sampler, SamplingUpdate},
     sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
     Constraint, StopTokens,
 };
@@ -44,6 +52,146 @@ pub enum EngineInstruction {
     Terminate,
 }
 
+/// What to do with a prompt which has more tokens than the `max_prompt_tokens` admission limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PromptLimitPolicy {
+    /// Reject the request with a validation error.
+    #[default]
+    Reject,
+    /// Keep only the last `max_prompt_tokens` tokens of the prompt.
+    Truncate,
+}
+
+impl std::str::FromStr for PromptLimitPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "truncate" => Ok(Self::Truncate),
+            a => Err(format!(
+                "Unknown prompt limit policy `{a}`. Possible policies: `reject`, `truncate`."
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for PromptLimitPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reject => write!(f, "reject"),
+            Self::Truncate => write!(f, "truncate"),
+        }
+    }
+}
+
+/// Apply the `max_prompt_tokens` admission limit to a prompt. This is independent of the model's
+/// maximum sequence length.
+fn apply_prompt_limit(
+    prompt_tokens: Vec<u32>,
+    max_prompt_tokens: usize,
+    policy: PromptLimitPolicy,
+) -> Result<Vec<u32>, String> {
+    if prompt_tokens.len() <= max_prompt_tokens {
+        return Ok(prompt_tokens);
+    }
+    match policy {
+        PromptLimitPolicy::Reject => Err(format!(
+            "Prompt has {} tokens, which is more than the allowed `max_prompt_tokens` of {max_prompt_tokens}.",
+            prompt_tokens.len()
+        )),
+        PromptLimitPolicy::Truncate => {
+            Ok(prompt_tokens[prompt_tokens.len() - max_prompt_tokens..].to_vec())
+        }
+    }
+}
+
+/// The number of KV cache tokens a request may need once admitted: each choice can grow to the
+/// prompt plus `max_len` tokens, capped at the model's maximum sequence length.
+fn admission_tokens(
+    prompt_len: usize,
+    max_len: Option<usize>,
+    n_choices: usize,
+    max_seq_len: usize,
+) -> usize {
+    let seq_len = max_len.map_or(max_seq_len, |max_len| prompt_len + max_len);
+    seq_len.min(max_seq_len.max(prompt_len)) * n_choices
+}
+
+/// What a draining engine does on its next step.
+#[derive(Debug, PartialEq, Eq)]
+enum DrainAction {
+    /// Keep running the in-flight requests.
+    Continue,
+    /// The drain timed out: cancel the requests which are still in flight.
+    Cancel,
+    /// All requests finished, so the engine can stop.
+    Stop,
+}
+
+fn drain_action(deadline: Instant, now: Instant, in_flight: usize) -> DrainAction {
+    if in_flight == 0 {
+        DrainAction::Stop
+    } else if now >= deadline {
+        DrainAction::Cancel
+    } else {
+        DrainAction::Continue
+    }
+}
+
+/// Holds sequences back from the scheduler so that at most `max_concurrent` run at once.
+struct SequenceQueue<T> {
+    max_concurrent: Option<NonZeroUsize>,
+    queued: VecDeque<T>,
+}
+
+impl<T> SequenceQueue<T> {
+    fn new(max_concurrent: Option<NonZeroUsize>) -> Self {
+        Self {
+            max_concurrent,
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// The position among the waiting items of the queued item at `index`, starting at 1, or
+    /// `None` if it may run alongside the `in_flight` items.
+    fn waiting_position(&self, index: usize, in_flight: usize) -> Option<usize> {
+        let free = usize::from(self.max_concurrent?).saturating_sub(in_flight);
+        (index >= free).then(|| index - free + 1)
+    }
+
+    /// Queue `item`. If it has to wait for one of the `in_flight` items to finish, returns its
+    /// position among the waiting items.
+    fn push(&mut self, item: T, in_flight: usize) -> Option<usize> {
+        self.queued.push_back(item);
+        self.waiting_position(self.queued.len() - 1, in_flight)
+    }
+
+    /// The position among the waiting items of the first queued item matching `f`.
+    fn position(&self, in_flight: usize, f: impl Fn(&T) -> bool) -> Option<usize> {
+        let index = self.queued.iter().position(f)?;
+        self.waiting_position(index, in_flight)
+    }
+
+    /// Take the queued items which may run alongside the `in_flight` ones, in order.
+    fn admit(&mut self, in_flight: usize) -> Vec<T> {
+        let n = match self.max_concurrent {
+            Some(max) => usize::from(max).saturating_sub(in_flight),
+            None => self.queued.len(),
+        };
+        let n = n.min(self.queued.len());
+        self.queued.drain(..n).collect()
+    }
+
+    /// Take all queued items regardless of the limit.
+    fn drain(&mut self) -> Vec<T> {
+        self.queued.drain(..).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.queued.len()
+    }
+}
+
 const SEED: u64 = 0;
 /// Terminate all sequences on the next scheduling step. Be sure to reset this.
 pub static TERMINATE_ALL_NEXT_STEP: AtomicBool = AtomicBool::new(false);
@@ -58,6 +206,14 @@ pub struct Engine {
     scheduler: Box<dyn Scheduler>,
     id: usize,
     truncate_sequence: bool,
+    max_prompt_tokens: Option<(usize, PromptLimitPolicy)>,
+    // The queued sequences with the id of their request
+    queue: SequenceQueue<(usize, Sequence)>,
+    // The samplers of the sequences of each request, by request id
+    sampler_handles: HashMap<usize, Vec<Weak<RwLock<Arc<Sampler>>>>>,
+    fallback: Option<(Arc<MistralRs>, usize)>,
+    drain_deadline: Option<Instant>,
+    logits_dump_dir: Option<PathBuf>,
     no_kv_cache: bool,
     prefix_cacher: PrefixCacheManager,
     is_debug: bool,
@@ -72,6 +228,10 @@ impl Engine {
         pipeline: Arc<Mutex<dyn Pipeline>>,
         config: SchedulerConfig,
         truncate_sequence: bool,
+        max_prompt_tokens: Option<(usize, PromptLimitPolicy)>,
+        max_concurrent_sequences: Option<NonZeroUsize>,
+        fallback: Option<(Arc<MistralRs>, usize)>,
+        logits_dump_dir: Option<PathBuf>,
         no_kv_cache: bool,
         no_prefix_cache: bool,
         prefix_cache_n: usize,
@@ -81,6 +241,7 @@ impl Engine {
         let device = get_mut_arcmutex!(pipeline).device().clone();
         let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
         let has_no_kv_cache = get_mut_arcmutex!(pipeline).get_metadata().has_no_kv_cache;
+        let is_recurrent = get_mut_arcmutex!(pipeline).get_metadata().is_recurrent;
         if no_kv_cache {
             // Diffusion models...
             assert_eq!(has_no_kv_cache, no_kv_cache);
@@ -89,13 +250,21 @@ impl Engine {
         // TODO
         let no_prefix_cache = matches!(config, SchedulerConfig::PagedAttentionMeta { .. })
             || no_prefix_cache
-            || has_no_kv_cache;
+            || no_kv_cache
+            || has_no_kv_cache
+            || is_recurrent;
         Self {
             rx,
             pipeline,
             scheduler: config.into_scheduler(),
             id: 0,
             truncate_sequence,
+            max_prompt_tokens,
+            queue: SequenceQueue::new(max_concurrent_sequences),
+            sampler_handles: HashMap::new(),
+            fallback,
+            drain_deadline: None,
+            logits_dump_dir,
             no_kv_cache: no_kv_cache & !has_no_kv_cache,
             prefix_cacher: PrefixCacheManager::new(
                 device,
@@ -129,6 +298,27 @@ impl Engine {
                 }
                 self.handle_request(request).await;
             }
+            if let Some(deadline) = self.drain_deadline {
+                let in_flight =
+                    self.scheduler.waiting_len() + self.scheduler.running_len() + self.queue.len();
+                match drain_action(deadline, Instant::now(), in_flight) {
+                    DrainAction::Continue => (),
+                    DrainAction::Cancel => {
+                        for (_, seq) in self.queue.drain() {
+                            self.scheduler.add_seq(seq);
+                        }
+                        self.scheduler.cancel_all()
+                    }
+                    DrainAction::Stop => {
+                        info!("All in-flight requests finished, stopping the engine.");
+                        break 'lp;
+                    }
+                }
+            }
+            let in_flight = self.scheduler.waiting_len() + self.scheduler.running_len();
+            for (_, seq) in self.queue.admit(in_flight) {
+                self.scheduler.add_seq(seq);
+            }
             let run_start = Instant::now();
             let scheduled = self.scheduler.schedule();
 
@@ -244,8 +434,32 @@ impl Engine {
                                 "All sequences must either return raw logits, or not."
                             );
 
-                            // Reset non granular state because the old sequence must be dead.
-                            // Technically we don't need to do this but it is better to be safe.
+                            // The prefix caches can only be loaded together if they all cover
+                            // the same number of tokens, otherwise run the whole prompts.
+                            let prefix_cache_len = scheduled.prompt[0].prefix_cache_len();
+                            let use_prefix_cache = prefix_cache_len > 0
+                                && scheduled
+                                    .prompt
+                                    .iter()
+                                    .all(|seq| seq.prefix_cache_len() == prefix_cache_len);
+                            if !use_prefix_cache {
+                                scheduled
+                                    .prompt
+                                    .iter_mut()
+                                    .for_each(|seq| seq.reset_prefix_cache());
+                            }
+                            let pre_op = if use_prefix_cache {
+                                CacheInstruction::In(adapter_inst)
+                            } else {
+                                // Reset non granular state because the old sequence must be dead.
+                                // Technically we don't need to do this but it is better to be safe.
+                                CacheInstruction::Reset {
+                                    load_preallocated_cache: true,
+                                    reset_non_granular: false,
+                                    adapter_inst,
+                                }
+                            };
+
                             pipeline
                                 .step(
                                     &mut scheduled.prompt,
@@ -254,14 +468,7 @@ impl Engine {
                                     &mut self.prefix_cacher,
                                     self.disable_eos_stop,
                                     rng.clone(),
-                                    CacheBackendMetadata::DefaultInstructions {
-                                        pre_op: CacheInstruction::Reset {
-                                            load_preallocated_cache: true,
-                                            reset_non_granular: false,
-                                            adapter_inst,
-                                        },
-                                        post_op,
-                                    },
+                                    CacheBackendMetadata::DefaultInstructions { pre_op, post_op },
                                 )
                                 .await
                         };
@@ -354,7 +561,12 @@ impl Engine {
                     if scheduled.prompt.len() == 0
                         && scheduled.completion.len() == 0
                         && self.scheduler.waiting_len() == 0
+                        && self.queue.len() == 0
                     {
+                        if self.drain_deadline.is_some() {
+                            // Nothing is left to drain, stop on the next iteration
+                            continue 'lp;
+                        }
                         // If there is nothing to do, sleep until a request comes in
                         if let Some(request) = self.rx.recv().await {
                             if matches!(request, Request::Terminate) {
@@ -517,11 +729,72 @@ impl Engine {
             }
             Request::Tokenize(req) => self.tokenize_text(req).await,
             Request::Detokenize(req) => self.detokenize_text(req).await,
+            Request::Classify(req) => self.classify(req).await,
+            Request::AttentionWeights(req) => self.attention_weights(req).await,
+            Request::SaveSafetensors(req) => {
+                let result = get_mut_arcmutex!(self.pipeline).save_safetensors(&req.path);
+                req.response
+                    .send(result)
+                    .await
+                    .expect("Expected receiver.");
+            }
+            Request::UpdateSampling(id, update) => self.update_sampling(id, &update),
+            Request::Drain(deadline) => {
+                info!(
+                    "Draining: finishing {} in-flight requests, new requests are rejected.",
+                    self.scheduler.waiting_len() + self.scheduler.running_len() + self.queue.len()
+                );
+                self.drain_deadline = Some(deadline);
+            }
+            Request::PrefixCacheStats(response) => response
+                .send(self.prefix_cacher.stats())
+                .await
+                .expect("Expected receiver."),
+            Request::QueuePosition(id, response) => {
+                let in_flight = self.scheduler.waiting_len() + self.scheduler.running_len();
+                let position = self
+                    .queue
+                    .position(in_flight, |(request_id, _)| *request_id == id);
+                response.send(position).await.expect("Expected receiver.")
+            }
             Request::Terminate => panic!("This is unreachable in `handle_request`. Termination is handled in the `run` loop."),
         }
     }
 
+    fn update_sampling(&mut self, id: usize, update: &SamplingUpdate) {
+        let handles = self
+            .sampler_handles
+            .get(&id)
+            .map(|handles| handles.iter().filter_map(Weak::upgrade).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if handles.is_empty() {
+            warn!("Cannot update the sampling of request {id}, it is not running.");
+            return;
+        }
+        for handle in handles {
+            let mut sampler = handle.write().expect("Sampler lock was poisoned.");
+            *sampler = Arc::new(sampler.with_update(update));
+        }
+    }
+
     async fn add_request(&mut self, request: NormalRequest) {
+        if self.drain_deadline.is_some() {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "The engine is shutting down and no longer accepts requests.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        // Forget the samplers of the sequences which finished
+        self.sampler_handles.retain(|_, handles| {
+            handles.retain(|handle| handle.strong_count() > 0);
+            !handles.is_empty()
+        });
+        // The fallback model tokenizes the request itself, so it is given the original messages.
+        let fallback_request = self.fallback.as_ref().map(|_| request.clone());
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat(_) | RequestMessage::VisionChat { .. }
@@ -596,14 +869,46 @@ impl Engine {
                 messages,
             } => {
                 let pipeline = &*get_mut_arcmutex!(self.pipeline);
-                let template = pipeline.get_processor().process(
-                    pipeline,
-                    messages,
-                    true,
-                    true,
-                    request.tools.unwrap_or_default(),
-                );
-                handle_seq_error!(template, request.response)
+                let tools = request.tools.unwrap_or_default();
+                let process = |messages| {
+                    pipeline.get_processor().process(
+                        pipeline,
+                        messages,
+                        !request.continue_final_message,
+                        request.continue_final_message,
+                        true,
+                        tools.clone(),
+                    )
+                };
+                let hinted = matches!(request.constraint, Constraint::JsonObject)
+                    .then(|| with_json_object_hint(&messages))
+                    .flatten();
+                // Fall back to the original messages if the template rejects the system message
+                let template = match hinted {
+                    Some(hinted) => process(hinted).or_else(|_| process(messages)),
+                    None => process(messages),
+                };
+                let (mut prompt_tokens, mut prompt_text) =
+                    handle_seq_error!(template, request.response);
+                if let Some(prefix) = &request.assistant_prefix {
+                    // Prefill the start of the response, it is continued instead of generated
+                    let prefix_tokens = pipeline
+                        .tokenizer()
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "`assistant_prefix` requires the model to have a tokenizer."
+                            )
+                        })
+                        .and_then(|tokenizer| {
+                            tokenizer
+                                .encode(prefix.as_str(), false)
+                                .map_err(anyhow::Error::msg)
+                        });
+                    prompt_tokens
+                        .extend(handle_seq_error!(prefix_tokens, request.response).get_ids());
+                    prompt_text.push_str(prefix);
+                }
+                (prompt_tokens, prompt_text)
             }
             RequestMessage::Completion { text, .. } => {
                 let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
@@ -655,6 +960,57 @@ impl Engine {
             return;
         }
 
+        if let Some((max_prompt_tokens, policy)) = self.max_prompt_tokens {
+            let prompt_len = prompt_tokens.len();
+            prompt_tokens = match apply_prompt_limit(prompt_tokens, max_prompt_tokens, policy) {
+                Ok(prompt_tokens) => prompt_tokens,
+                Err(e) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(e.into()))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            };
+            if prompt_tokens.len() < prompt_len {
+                warn!("Prompt for request {} was {prompt_len} tokens, which is over `max_prompt_tokens`. The first {} tokens were truncated.", request.id, prompt_len - prompt_tokens.len());
+            }
+        }
+
+        if let (Some((fallback, max_admission_tokens)), Some(fallback_request)) =
+            (&self.fallback, fallback_request)
+        {
+            let max_seq_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
+            let needed = admission_tokens(
+                prompt_tokens.len(),
+                request.sampling_params.max_len,
+                request.sampling_params.n_choices,
+                max_seq_len,
+            );
+            // Routed before the length guard, as the fallback may serve prompts this model cannot
+            if needed > *max_admission_tokens || prompt_tokens.len() > max_seq_len {
+                info!("Request {} needs {needed} KV cache tokens for a {} token prompt, which is over the admission budget of {max_admission_tokens} or the maximum sequence length of {max_seq_len}. It is served by the fallback model `{}`.", request.id, prompt_tokens.len(), fallback.get_id());
+                let sent = match fallback.get_sender() {
+                    Ok(sender) => sender
+                        .send(Request::Normal(fallback_request))
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(format!("{e:?}")),
+                };
+                if let Err(e) = sent {
+                    request
+                        .response
+                        .send(Response::InternalError(
+                            format!("Failed to send the request to the fallback model: {e}").into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                }
+                return;
+            }
+        }
+
         if prompt_tokens.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
             if !self.truncate_sequence {
                 request
@@ -680,10 +1036,15 @@ impl Engine {
                 warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt_tokens.len());
             }
         }
-        let prefill_cache = handle_seq_error!(
-            self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
-            request.response
-        );
+        // The image inputs are tied to the whole prompt, so those prompts are always run in full
+        let prefill_cache = if images.is_some() {
+            None
+        } else {
+            handle_seq_error!(
+                self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
+                request.response
+            )
+        };
 
         let topk = request
             .sampling_params
@@ -692,6 +1053,7 @@ impl Engine {
             .unwrap_or(-1);
         let topp = request.sampling_params.top_p.unwrap_or(1.0);
         let minp = request.sampling_params.min_p.unwrap_or(0.0);
+        let topa = request.sampling_params.top_a.unwrap_or(0.0);
         let num_hidden_layers = get_mut_arcmutex!(self.pipeline)
             .get_metadata()
             .num_hidden_layers;
@@ -774,6 +1136,25 @@ impl Engine {
             best_of,
         )));
 
+        let mut logits_processors = request.logits_processors.unwrap_or_default();
+        if let Some(length_bias) = request.sampling_params.length_bias {
+            let eos_toks = get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .eos_tok
+                .clone();
+            match EosLengthBias::new(eos_toks, prompt_tokens.len(), length_bias) {
+                Ok(processor) => logits_processors.push(Arc::new(processor)),
+                Err(e) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(e.to_string().into()))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            }
+        }
+
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
 
         let sampler = Sampler::new(
@@ -786,7 +1167,8 @@ impl Engine {
             topk,
             topp,
             minp,
-            request.logits_processors.unwrap_or_default(),
+            topa,
+            logits_processors,
         );
         let sampler = handle_seq_error!(sampler, request.response);
 
@@ -828,6 +1210,12 @@ impl Engine {
                 .map(|conf| conf.block_size);
 
             let cache = get_mut_arcmutex!(self.pipeline).cache().clone();
+            let normal_cache_template = match &cache {
+                EitherCache::Normal(normal) => {
+                    get_mut_arcmutex!(normal).0.first().map(KvCache::empty_like)
+                }
+                EitherCache::Full(_) => None,
+            };
             let seq_preallocated_cache = if let EitherCache::Normal(_cache) = cache {
                 let metadata = get_mut_arcmutex!(self.pipeline).get_metadata();
                 let model_metadata = metadata
@@ -871,7 +1259,7 @@ impl Engine {
             let now = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time travel has occurred!");
-            let seq = Sequence::new_waiting(
+            let mut seq = Sequence::new_waiting(
                 prompt_tokens.clone(),
                 prompt_text.clone(),
                 self.id,
@@ -904,17 +1292,57 @@ impl Engine {
                 seq_preallocated_cache,
                 request.return_raw_logits,
             );
-            let seq = if let Some(prefill_cache) = prefill_cache.clone() {
-                seq.prefill(
+            if let (Some(markers), true) = (&request.reasoning_markers, is_chat) {
+                seq.set_reasoning_parser(ReasoningParser::new(markers.clone()));
+            }
+            if let (Some(prefix), true) = (&request.assistant_prefix, is_chat) {
+                seq.set_assistant_prefix(prefix.clone());
+            }
+            if request.sampling_params.stop_on_balanced_brackets {
+                seq.set_bracket_tracker(BracketTracker::new(&prompt_text));
+            }
+            if let Some(max_output_bytes) = request.sampling_params.max_output_bytes {
+                seq.set_max_output_bytes(max_output_bytes);
+            }
+            self.sampler_handles
+                .entry(request.id)
+                .or_default()
+                .push(seq.sampler_handle());
+            if let Some(logits_dump_dir) = &self.logits_dump_dir {
+                match LogitsDump::create_in(logits_dump_dir, request.id, response_index) {
+                    Ok(logits_dump) => seq.set_logits_dump(logits_dump),
+                    Err(e) => warn!(
+                        "Failed to create logits dump in `{}`: {e}",
+                        logits_dump_dir.display()
+                    ),
+                }
+            }
+            let seq = match (prefill_cache.clone(), &normal_cache_template) {
+                (Some(prefill_cache), Some(template)) => handle_seq_error!(
+                    seq.prefill_normal(
+                        prefill_cache.normal,
+                        prefill_cache.toks,
+                        prefill_cache.prefix_len,
+                        template,
+                    ),
+                    request.response
+                ),
+                (Some(prefill_cache), None) => seq.prefill(
                     prefill_cache.normal,
                     prefill_cache.xlora,
                     prefill_cache.toks,
-                )
-            } else {
-                seq
+                    prefill_cache.prefix_len,
+                ),
+                (None, _) => seq,
             };
             self.id += 1;
-            self.scheduler.add_seq(seq);
+            let in_flight = self.scheduler.waiting_len() + self.scheduler.running_len();
+            if let Some(position) = self.queue.push((request.id, seq), in_flight) {
+                info!(
+                    "Request {} is waiting at position {position} for the concurrent sequence limit.",
+                    request.id
+                );
+            }
         }
     }
 
@@ -926,6 +1354,7 @@ impl Engine {
                     pipeline,
                     messages,
                     request.add_generation_prompt,
+                    false,
                     request.add_special_tokens,
                     request.tools.unwrap_or_default(),
                 );
@@ -1017,4 +1446,625 @@ impl Engine {
             .await
             .expect("Sender disconnected unexpectedly!");
     }
+
+    async fn classify(&self, request: ClassificationRequest) {
+        let result = self.classify_prompt(&request);
+        request
+            .response
+            .send(result)
+            .await
+            .expect("Expected receiver.");
+    }
+
+    /// Pool the last-layer hidden state of the last prompt token, applying the head if provided.
+    fn classify_prompt(&self, request: &ClassificationRequest) -> anyhow::Result<Vec<f32>> {
+        let pipeline = &mut *get_mut_arcmutex!(self.pipeline);
+        let tokens = prompt_tokens(pipeline, &request.prompt)?;
+        if tokens.is_empty() {
+            anyhow::bail!("Cannot classify an empty prompt.");
+        }
+        let hidden = pipeline.forward_hidden(tokens)?;
+        let (_, seq_len, _) = hidden.dims3()?;
+        let pooled = hidden.i((.., seq_len - 1))?;
+        let logits = match &request.head {
+            Some(head) => head.forward(&pooled)?,
+            None => pooled.to_dtype(candle_core::DType::F32)?,
+        };
+        Ok(logits.flatten_all()?.to_vec1::<f32>()?)
+    }
+
+    async fn attention_weights(&self, request: AttentionWeightsRequest) {
+        let result = self.prompt_attention_weights(&request);
+        request
+            .response
+            .send(result)
+            .await
+            .expect("Expected receiver.");
+    }
+
+    fn prompt_attention_weights(
+        &self,
+        request: &AttentionWeightsRequest,
+    ) -> anyhow::Result<Tensor> {
+        let pipeline = &mut *get_mut_arcmutex!(self.pipeline);
+        let tokens = prompt_tokens(pipeline, &request.prompt)?;
+        if tokens.is_empty() {
+            anyhow::bail!("Cannot return the attention weights of an empty prompt.");
+        }
+        Ok(pipeline.attention_weights(tokens)?)
+    }
+}
+
+/// The tokens of a prompt, tokenizing it with the special tokens if it is given as text.
+fn prompt_tokens(
+    pipeline: &dyn Pipeline,
+    prompt: &Either<Vec<u32>, String>,
+) -> anyhow::Result<Vec<u32>> {
+    match prompt {
+        Either::Left(tokens) => Ok(tokens.clone()),
+        Either::Right(text) => {
+            let tokenizer = pipeline
+                .tokenizer()
+                .ok_or_else(|| anyhow::Error::msg("Pipeline does not include a tokenizer."))?;
+            Ok(tokenizer
+                .encode(text.clone(), true)
+                .map_err(anyhow::Error::msg)?
+                .get_ids()
+                .to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use std::num::NonZeroUsize;
+
+    use anyhow::Context;
+    use either::Either;
+    use indexmap::IndexMap;
+    use tokio::sync::mpsc::{channel, Sender};
+
+    use super::{
+        admission_tokens, apply_prompt_limit, drain_action, DrainAction, PromptLimitPolicy,
+        SequenceQueue,
+    };
+    use crate::{
+        pipeline::{EitherCache, KvCache},
+        testing::{
+            load_tiny_llama, load_tiny_llama_with_kv_cache_backend, write_tiny_llama, TempDir,
+            TINY_LLAMA_LAYERS,
+        },
+        AttentionWeightsRequest, DefaultSchedulerMethod, KvCacheBackendType, MistralRs,
+        MistralRsBuilder, NormalRequest, ReasoningMarkers, Request, RequestMessage, Response,
+        SamplingParams, SchedulerConfig,
+    };
+
+    fn scheduler_config() -> SchedulerConfig {
+        SchedulerConfig::DefaultScheduler {
+            method: DefaultSchedulerMethod::Fixed(NonZeroUsize::new(16).unwrap()),
+        }
+    }
+
+    /// A streamed chat request generating up to `max_len` tokens.
+    fn chat_request(id: usize, max_len: usize, response: Sender<Response>) -> Request {
+        conversation_request(id, &[("user", "Hello!")], max_len, response)
+    }
+
+    /// A streamed chat request continuing the `(role, content)` turns.
+    fn conversation_request(
+        id: usize,
+        turns: &[(&str, &str)],
+        max_len: usize,
+        response: Sender<Response>,
+    ) -> Request {
+        let messages = RequestMessage::Chat(
+            turns
+                .iter()
+                .map(|(role, content)| {
+                    IndexMap::from([
+                        ("role".to_string(), Either::Left(role.to_string())),
+                        ("content".to_string(), Either::Left(content.to_string())),
+                    ])
+                })
+                .collect(),
+        );
+        let sampling_params = SamplingParams {
+            max_len: Some(max_len),
+            ..SamplingParams::deterministic()
+        };
+        let mut request =
+            NormalRequest::new_simple(messages, sampling_params, response, id, None, None);
+        request.is_streaming = true;
+        Request::Normal(request)
+    }
+
+    #[test]
+    fn test_prompt_limit_reject() {
+        let prompt = (0..10).collect::<Vec<u32>>();
+        assert_eq!(
+            apply_prompt_limit(prompt.clone(), 10, PromptLimitPolicy::Reject),
+            Ok(prompt.clone())
+        );
+        let err = apply_prompt_limit(prompt, 8, PromptLimitPolicy::Reject).unwrap_err();
+        assert!(err.contains("10 tokens"));
+        assert!(err.contains("`max_prompt_tokens` of 8"));
+    }
+
+    #[test]
+    fn test_prompt_limit_truncate() {
+        let prompt = (0..10).collect::<Vec<u32>>();
+        assert_eq!(
+            apply_prompt_limit(prompt.clone(), 4, PromptLimitPolicy::Truncate),
+            Ok(vec![6, 7, 8, 9])
+        );
+        assert_eq!(
+            apply_prompt_limit(prompt.clone(), 16, PromptLimitPolicy::Truncate),
+            Ok(prompt)
+        );
+    }
+
+    #[test]
+    fn test_fallback_admission() {
+        let budget = 1024;
+        // A small request is admitted on the primary model
+        assert!(admission_tokens(100, Some(256), 1, 4096) <= budget);
+        // An oversized prompt, or a request without `max_len` which may grow to the maximum
+        // sequence length, is routed to the fallback
+        assert!(admission_tokens(1000, Some(256), 1, 4096) > budget);
+        assert!(admission_tokens(100, None, 1, 4096) > budget);
+        // As is an oversized batch of small sequences
+        assert!(admission_tokens(100, Some(156), 8, 4096) > budget);
+        // The maximum sequence length caps the footprint of each choice
+        assert_eq!(admission_tokens(100, Some(10_000), 2, 512), 1024);
+    }
+
+    #[test]
+    fn test_drain_window() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(30);
+        // A request submitted just before the drain keeps running within the window
+        assert_eq!(drain_action(deadline, start, 1), DrainAction::Continue);
+        assert_eq!(
+            drain_action(deadline, start + Duration::from_secs(10), 1),
+            DrainAction::Continue
+        );
+        // Once it completes, the engine stops without waiting for the deadline
+        assert_eq!(
+            drain_action(deadline, start + Duration::from_secs(10), 0),
+            DrainAction::Stop
+        );
+        // Requests still in flight at the deadline are canceled
+        assert_eq!(drain_action(deadline, deadline, 2), DrainAction::Cancel);
+    }
+
+    #[test]
+    fn test_sequence_queue_waits_for_limit() {
+        let mut queue = SequenceQueue::new(NonZeroUsize::new(1));
+        assert_eq!(queue.push("first", 0), None);
+        assert_eq!(queue.admit(0), vec!["first"]);
+
+        // The second request waits while the first runs
+        assert_eq!(queue.push("second", 1), Some(1));
+        assert_eq!(queue.push("third", 1), Some(2));
+        assert!(queue.admit(1).is_empty());
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.position(1, |item| *item == "third"), Some(2));
+        assert_eq!(queue.position(1, |item| *item == "first"), None);
+
+        // And is admitted once the first finished
+        assert_eq!(queue.admit(0), vec!["second"]);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.position(1, |item| *item == "third"), Some(1));
+    }
+
+    #[test]
+    fn test_sequence_queue_without_limit() {
+        let mut queue = SequenceQueue::new(None);
+        for i in 0..4 {
+            assert_eq!(queue.push(i, 8), None);
+        }
+        assert_eq!(queue.admit(8), vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrency_limit_queues_second_request() -> anyhow::Result<()> {
+        let dir = TempDir::new("engine_concurrency_limit");
+        write_tiny_llama(dir.path(), &[])?;
+        let runner = MistralRsBuilder::new(load_tiny_llama(dir.path())?, scheduler_config())
+            .with_max_concurrent_sequences(NonZeroUsize::new(1).unwrap())
+            .build();
+
+        // Both requests stream to the same channel, their chunks are told apart by sequence id
+        let (tx, mut rx) = channel(10_000);
+        let sender = runner.get_sender()?;
+        sender.send(chat_request(0, 256, tx.clone())).await?;
+        sender.send(chat_request(1, 8, tx)).await?;
+        assert_eq!(runner.queue_position(1).await?, Some(1));
+
+        let mut seq_ids = Vec::new();
+        let mut finished = 0;
+        while finished < 2 {
+            match rx.recv().await {
+                Some(Response::Chunk(chunk)) => {
+                    seq_ids.push(chunk.id.clone());
+                    if chunk.choices.iter().all(|c| c.finish_reason.is_some()) {
+                        finished += 1;
+                    }
+                }
+                Some(_) => anyhow::bail!("Expected only streamed chunks"),
+                None => anyhow::bail!("The engine stopped before both requests finished"),
+            }
+        }
+        // The second sequence only starts once the first finished
+        let first = seq_ids[0].clone();
+        let switch = seq_ids.iter().position(|id| *id != first).unwrap();
+        assert!(switch > 1);
+        assert!(seq_ids[switch..].iter().all(|id| *id != first));
+        assert_eq!(runner.queue_position(1).await?, None);
+        Ok(())
+    }
+
+    /// The model named by the chunks of a streamed request, once it finished.
+    async fn serving_model(
+        runner: &MistralRs,
+        request: impl FnOnce(Sender<Response>) -> Request,
+    ) -> anyhow::Result<String> {
+        let (tx, mut rx) = channel(10_000);
+        runner.get_sender()?.send(request(tx)).await?;
+        let mut model = None;
+        while let Some(response) = rx.recv().await {
+            let Response::Chunk(chunk) = response else {
+                anyhow::bail!("Expected only streamed chunks");
+            };
+            model = Some(chunk.model.clone());
+            if chunk.choices.iter().all(|c| c.finish_reason.is_some()) {
+                break;
+            }
+        }
+        model.ok_or_else(|| anyhow::anyhow!("The request produced no chunks"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_oversized_request_is_served_by_fallback() -> anyhow::Result<()> {
+        let primary_dir = TempDir::new("engine_fallback_primary");
+        let fallback_dir = TempDir::new("engine_fallback_fallback");
+        write_tiny_llama(primary_dir.path(), &[])?;
+        write_tiny_llama(fallback_dir.path(), &[])?;
+        let fallback =
+            MistralRsBuilder::new(load_tiny_llama(fallback_dir.path())?, scheduler_config())
+                .build();
+        let runner =
+            MistralRsBuilder::new(load_tiny_llama(primary_dir.path())?, scheduler_config())
+                .with_fallback(fallback, 64)
+                .build();
+
+        // The chat prompt is about 25 tokens, so only the longer generation is over the budget
+        assert_eq!(
+            serving_model(&runner, |tx| chat_request(0, 8, tx)).await?,
+            primary_dir.path().display().to_string()
+        );
+        assert_eq!(
+            serving_model(&runner, |tx| chat_request(1, 128, tx)).await?,
+            fallback_dir.path().display().to_string()
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_prompt_over_max_seq_len_is_served_by_fallback() -> anyhow::Result<()> {
+        let primary_dir = TempDir::new("engine_fallback_long_primary");
+        let fallback_dir = TempDir::new("engine_fallback_long_fallback");
+        write_tiny_llama(primary_dir.path(), &[])?;
+        write_tiny_llama(fallback_dir.path(), &[])?;
+        // The chat prompt is about 25 tokens, over the context of the primary model
+        let config_path = primary_dir.path().join("config.json");
+        let mut config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&config_path)?)?;
+        config["max_position_embeddings"] = serde_json::json!(16);
+        std::fs::write(&config_path, config.to_string())?;
+
+        let primary = load_tiny_llama(primary_dir.path())?;
+        assert_eq!(primary.lock().await.get_metadata().max_seq_len, 16);
+        let fallback =
+            MistralRsBuilder::new(load_tiny_llama(fallback_dir.path())?, scheduler_config())
+                .build();
+        // The budget is large enough for the request, only its prompt length routes it
+        let runner = MistralRsBuilder::new(primary, scheduler_config())
+            .with_fallback(fallback, 1024)
+            .build();
+        assert_eq!(
+            serving_model(&runner, |tx| chat_request(0, 4, tx)).await?,
+            fallback_dir.path().display().to_string()
+        );
+        Ok(())
+    }
+
+    /// The finish reason of the last chunk left in `rx`, once the engine stopped.
+    async fn last_finish_reason(
+        rx: &mut tokio::sync::mpsc::Receiver<Response>,
+    ) -> anyhow::Result<Option<String>> {
+        let mut finish_reason = None;
+        while let Some(response) = rx.recv().await {
+            let Response::Chunk(chunk) = response else {
+                anyhow::bail!("Expected only streamed chunks");
+            };
+            finish_reason = chunk.choices[0].finish_reason.clone();
+        }
+        Ok(finish_reason)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drain_finishes_in_flight_request() -> anyhow::Result<()> {
+        let dir = TempDir::new("engine_drain_finish");
+        write_tiny_llama(dir.path(), &[])?;
+        let runner =
+            MistralRsBuilder::new(load_tiny_llama(dir.path())?, scheduler_config()).build();
+
+        let (tx, mut rx) = channel(10_000);
+        runner.get_sender()?.send(chat_request(0, 8, tx)).await?;
+        runner.drain(Duration::from_secs(60)).await?;
+
+        // The request ran to completion before the engine stopped
+        assert_eq!(
+            last_finish_reason(&mut rx).await?.as_deref(),
+            Some("length")
+        );
+        assert!(runner.engine_dead()?);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drain_cancels_at_deadline() -> anyhow::Result<()> {
+        let dir = TempDir::new("engine_drain_cancel");
+        write_tiny_llama(dir.path(), &[])?;
+        let runner =
+            MistralRsBuilder::new(load_tiny_llama(dir.path())?, scheduler_config()).build();
+
+        let (tx, mut rx) = channel(10_000);
+        runner.get_sender()?.send(chat_request(0, 512, tx)).await?;
+        // Wait for the request to be running
+        assert!(matches!(rx.recv().await, Some(Response::Chunk(_))));
+        runner.drain(Duration::ZERO).await?;
+
+        // The request was canceled rather than generating all of its tokens
+        assert_eq!(
+            last_finish_reason(&mut rx).await?.as_deref(),
+            Some("canceled")
+        );
+        assert!(runner.engine_dead()?);
+
+        // And the stopped engine is not rebooted for new requests
+        let (tx, _rx) = channel(1);
+        assert!(runner
+            .get_sender()?
+            .send(chat_request(1, 8, tx))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_prefix_cache_stats_count_follow_up_hit() -> anyhow::Result<()> {
+        let dir = TempDir::new("engine_prefix_cache_stats");
+        write_tiny_llama(dir.path(), &["a"])?;
+        let runner =
+            MistralRsBuilder::new(load_tiny_llama(dir.path())?, scheduler_config()).build();
+
+        // The dummy run at startup is the first miss
+        let stats = runner.prefix_cache_stats().await?;
+        assert_eq!((stats.hits, stats.misses), (0, 1));
+
+        // The first generation is cached once it finished. The model always generates "a", so
+        // the follow up which quotes the answer extends the cached tokens.
+        serving_model(&runner, |tx| chat_request(0, 8, tx)).await?;
+        let stats = runner.prefix_cache_stats().await?;
+        assert_eq!((stats.hits, stats.misses), (0, 2));
+
+        serving_model(&runner, |tx| {
+            conversation_request(
+                1,
+                &[
+                    ("user", "Hello!"),
+                    ("assistant", "aaaaaaaa"),
+                    ("user", "Again!"),
+                ],
+                8,
+                tx,
+            )
+        })
+        .await?;
+        let stats = runner.prefix_cache_stats().await?;
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        // At least the first prompt was reused
+        assert!(stats.reused_toks > 8);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_warmup_prefix_is_hit_by_chat_request() -> anyhow::Result<()> {
+        let dir = TempDir::new("engine_warmup_prefix");
+        write_tiny_llama(dir.path(), &["a"])?;
+        let system = "Answer briefly.";
+        let runner = MistralRsBuilder::new(load_tiny_llama(dir.path())?, scheduler_config())
+            .with_warmup_prefixes(vec![system.to_string()])
+            .build();
+        // The dummy run and the warmup
+        let stats = runner.prefix_cache_stats().await?;
+        assert_eq!((stats.hits, stats.misses), (0, 2));
+
+        serving_model(&runner, |tx| {
+            conversation_request(0, &[("system", system), ("user", "Hello!")], 8, tx)
+        })
+        .await?;
+        let stats = runner.prefix_cache_stats().await?;
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        // The `<|im_start|>` token and the bytes of the system turn up to the end of its text
+        let warmed = 1 + "system\n".len() + system.len();
+        assert_eq!(stats.reused_toks, warmed);
+        Ok(())
+    }
+
+    /// The text generated for a streamed request.
+    async fn generated_text(
+        runner: &MistralRs,
+        request: impl FnOnce(Sender<Response>) -> Request,
+    ) -> anyhow::Result<String> {
+        let (tx, mut rx) = channel(10_000);
+        runner.get_sender()?.send(request(tx)).await?;
+        let mut text = String::new();
+        while let Some(response) = rx.recv().await {
+            let Response::Chunk(chunk) = response else {
+                anyhow::bail!("Expected only streamed chunks");
+            };
+            text.push_str(&chunk.choices[0].delta.content);
+            if chunk.choices.iter().all(|c| c.finish_reason.is_some()) {
+                break;
+            }
+        }
+        Ok(text)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_offloaded_kv_cache_matches_contiguous() -> anyhow::Result<()> {
+        let dir = TempDir::new("engine_offloaded_kv_cache");
+        write_tiny_llama(dir.path(), &[])?;
+
+        let mut outputs = Vec::new();
+        for backend in [
+            KvCacheBackendType::Contiguous,
+            KvCacheBackendType::Offloaded,
+        ] {
+            let pipeline = load_tiny_llama_with_kv_cache_backend(dir.path(), backend)?;
+            match pipeline.lock().await.cache() {
+                EitherCache::Normal(normal) => {
+                    assert!(normal.lock().unwrap().0.iter().all(|layer| matches!(
+                        layer,
+                        KvCache::Offloaded(_)
+                    ) == (backend
+                        == KvCacheBackendType::Offloaded)))
+                }
+                EitherCache::Full(_) => anyhow::bail!("Expected a normal cache"),
+            }
+            let runner = MistralRsBuilder::new(pipeline, scheduler_config()).build();
+            // The follow up starts from the prefix cache of the first request
+            let first = generated_text(&runner, |tx| chat_request(0, 16, tx)).await?;
+            let follow_up = generated_text(&runner, |tx| {
+                conversation_request(
+                    1,
+                    &[
+                        ("user", "Hello!"),
+                        ("assistant", &first),
+                        ("user", "Again!"),
+                    ],
+                    16,
+                    tx,
+                )
+            })
+            .await?;
+            outputs.push((first, follow_up));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reasoning_is_streamed_in_deltas() -> anyhow::Result<()> {
+        let dir = TempDir::new("engine_streamed_reasoning");
+        write_tiny_llama(dir.path(), &["a"])?;
+        let runner =
+            MistralRsBuilder::new(load_tiny_llama(dir.path())?, scheduler_config()).build();
+
+        // The assistant prefix opens the reasoning block, which the model never closes
+        let (tx, mut rx) = channel(10_000);
+        let Request::Normal(mut request) = chat_request(0, 8, tx) else {
+            unreachable!()
+        };
+        request.reasoning_markers = Some(ReasoningMarkers::default());
+        request.assistant_prefix = Some("<think>".to_string());
+        runner.get_sender()?.send(Request::Normal(request)).await?;
+
+        let mut reasoning = Vec::new();
+        loop {
+            let Some(Response::Chunk(chunk)) = rx.recv().await else {
+                anyhow::bail!("Expected only streamed chunks");
+            };
+            let choice = &chunk.choices[0];
+            assert_eq!(choice.delta.content, "");
+            reasoning.extend(choice.delta.reasoning_content.clone());
+            if choice.finish_reason.is_some() {
+                break;
+            }
+        }
+        assert_eq!(reasoning, vec!["a"; 8]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_assistant_prefix_is_prefilled() -> anyhow::Result<()> {
+        let dir = TempDir::new("engine_assistant_prefix");
+        write_tiny_llama(dir.path(), &["a"])?;
+        let runner =
+            MistralRsBuilder::new(load_tiny_llama(dir.path())?, scheduler_config()).build();
+
+        let prefix = "Sure, ";
+        let mut usages = Vec::new();
+        for assistant_prefix in [None, Some(prefix)] {
+            let (tx, mut rx) = channel(1);
+            let Request::Normal(mut request) = chat_request(0, 4, tx) else {
+                unreachable!()
+            };
+            request.is_streaming = false;
+            request.assistant_prefix = assistant_prefix.map(str::to_string);
+            runner.get_sender()?.send(Request::Normal(request)).await?;
+
+            let Some(Response::Done(response)) = rx.recv().await else {
+                anyhow::bail!("Expected a chat response");
+            };
+            let expected = format!("{}aaaa", assistant_prefix.unwrap_or_default());
+            assert_eq!(
+                response.choices[0].message.content.as_deref(),
+                Some(expected.as_str())
+            );
+            usages.push(response.usage);
+        }
+        // The prefix is prefilled with one token per byte, it is not generated
+        assert_eq!(
+            usages[1].prompt_tokens,
+            usages[0].prompt_tokens + prefix.len()
+        );
+        assert_eq!(usages[1].completion_tokens, 4);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_attention_weights_request() -> anyhow::Result<()> {
+        let dir = TempDir::new("engine_attention_weights");
+        write_tiny_llama(dir.path(), &[])?;
+        let runner =
+            MistralRsBuilder::new(load_tiny_llama(dir.path())?, scheduler_config()).build();
+
+        let (tx, mut rx) = channel(1);
+        runner
+            .get_sender()?
+            .send(Request::AttentionWeights(AttentionWeightsRequest {
+                prompt: Either::Left(vec![1, 2, 3, 4, 5]),
+                response: tx,
+            }))
+            .await?;
+        let weights = rx.recv().await.context("no response")??;
+        assert_eq!(weights.dims4()?, (TINY_LLAMA_LAYERS, 4, 5, 5));
+
+        // Every query attends causally to the prompt, with its probabilities summing to 1
+        let weights = weights.flatten_to(1)?.to_vec3::<f32>()?;
+        for row in weights.iter().flatten() {
+            let total: f32 = row.iter().sum();
+            assert!((total - 1.).abs() < 1e-4, "{row:?}");
+        }
+        for (i, row) in weights.iter().flat_map(|head| head.iter().enumerate()) {
+            assert!(row[i + 1..].iter().all(|p| *p < 1e-6), "{row:?}");
+        }
+        Ok(())
+    }
 }
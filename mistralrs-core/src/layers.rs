@@ -1,12 +1,13 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use std::{
+    collections::HashMap,
     f32::consts::PI,
     ops::Mul,
     str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
 };
 
@@ -15,7 +16,8 @@ use candle_core::{
     Context, DType, Device, IndexOp, Result, Tensor, D,
 };
 use candle_nn::{Conv2d, Conv2dConfig, Linear, Module, VarBuilder};
-use mistralrs_quant::QuantMethod;
+use mistralrs_quant::{QuantMethod, QuantizedConfig};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
 pub use crate::attention::Sdpa;
@@ -61,9 +63,28 @@ impl RmsNorm {
         Ok(Self { eps, weight: w })
     }
 
+    /// For SmoothQuant, fold the smoothing scales of the `layers` reading the output of this norm
+    /// into its weight. The layers must be loaded with `QuantizedConfig::with_smooth_scales_folded`.
+    pub fn fold_smooth_scales(
+        self,
+        config: &Option<QuantizedConfig>,
+        layers: &[VarBuilder],
+    ) -> Result<Self> {
+        Ok(Self {
+            eps: self.eps,
+            weight: mistralrs_quant::fold_smooth_scales(&self.weight, config, layers)?,
+        })
+    }
+
     pub fn weight(&self) -> &Tensor {
         &self.weight
     }
+
+    /// Add `residual` to `xs` and normalize the sum, returning `(xs + residual, normed)`. This is
+    /// a single fused pass where the device supports it, see [`crate::ops::fused_add_rms_norm`].
+    pub fn add_forward(&self, xs: &Tensor, residual: &Tensor) -> Result<(Tensor, Tensor)> {
+        crate::ops::fused_add_rms_norm(xs, residual, &self.weight, self.eps as f32)
+    }
 }
 
 impl Module for RmsNorm {
@@ -134,9 +155,10 @@ pub struct PhiRotaryEmbedding {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ScaledRopeType {
+    /// LongRoPE, with the short and long factors selected by the sequence length relative to
+    /// `original_max_position_embeddings`. This was previously named `su`.
     #[serde(alias = "su")]
-    #[serde(alias = "longrope")]
-    Su,
+    LongRope,
     #[serde(alias = "yarn")]
     Yarn,
 }
@@ -145,10 +167,10 @@ impl FromStr for ScaledRopeType {
     type Err = candle_core::Error;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
-            "su" | "longrope" => Ok(Self::Su),
+            "su" | "longrope" => Ok(Self::LongRope),
             "yarn" => Ok(Self::Yarn),
             _ => Err(candle_core::Error::Msg(
-                "Expected either `su` or `yarn` scaled RoPE type.".to_string(),
+                "Expected either `longrope` or `yarn` scaled RoPE type.".to_string(),
             )),
         }
     }
@@ -160,13 +182,16 @@ pub enum PhiRopeScalingConfig {
     Classic {
         short_factor: Vec<f64>,
         long_factor: Vec<f64>,
-        #[serde(rename = "type")]
+        #[serde(rename = "type", alias = "rope_type")]
         scaling_type: ScaledRopeType,
+        /// Overrides the scale of the cos and sin tables computed from the context extension.
+        #[serde(default)]
+        attention_factor: Option<f64>,
     },
     Scaled {
         short_factor: Vec<f64>,
         long_factor: Vec<f64>,
-        #[serde(rename = "type")]
+        #[serde(rename = "type", alias = "rope_type")]
         scaling_type: ScaledRopeType,
         long_mscale: f64,
         short_mscale: f64,
@@ -182,25 +207,42 @@ pub struct PhiRopeConfig {
 }
 
 impl PhiRotaryEmbedding {
+    fn check_factor_lens(short_factor: &[f64], long_factor: &[f64], dim: usize) -> Result<()> {
+        for (name, factor) in [("short", short_factor), ("long", long_factor)] {
+            if factor.len() != dim / 2 {
+                candle_core::bail!(
+                    "Misaligned length {}, expected {} for `su`/`longrope` {name} rescale factors",
+                    factor.len(),
+                    dim / 2
+                );
+            }
+        }
+        Ok(())
+    }
+
     fn new_classic_scaled(
         short_factor: &[f64],
         long_factor: &[f64],
         scaling_type: &ScaledRopeType,
+        attention_factor: Option<f64>,
         cfg: &PhiRopeConfig,
         dtype: DType,
         dev: &Device,
     ) -> Result<Self> {
         let max_seq_len = cfg.max_position_embeddings;
         let dim = cfg.head_dim;
+        Self::check_factor_lens(short_factor, long_factor, dim)?;
 
         // Calculate scale
         let scale =
             cfg.max_position_embeddings as f64 / cfg.original_max_position_embeddings as f64;
-        let scaling_factor = if scale <= 1.0 {
+        let scaling_factor = if let Some(attention_factor) = attention_factor {
+            attention_factor
+        } else if scale <= 1.0 {
             1.0
         } else {
             match scaling_type {
-                ScaledRopeType::Su => {
+                ScaledRopeType::LongRope => {
                     (1.0 + scale.ln() / (cfg.original_max_position_embeddings as f64).ln()).sqrt()
                 }
                 ScaledRopeType::Yarn => 0.1 * scale.ln() + 1.0,
@@ -289,24 +331,10 @@ impl PhiRotaryEmbedding {
         let max_seq_len = cfg.max_position_embeddings;
         let dim = cfg.head_dim;
 
-        if !matches!(scaling_type, ScaledRopeType::Su) {
+        if !matches!(scaling_type, ScaledRopeType::LongRope) {
             candle_core::bail!("Scaled Phi3 RoPE (non-classic scaled, with mscales) must have type `su`/`longrope`.");
         }
-
-        if short_factor.len() != dim / 2 {
-            candle_core::bail!(
-                "Misaligned length {}, expected {} for `su`/`longrope` short rescale factors",
-                short_factor.len(),
-                dim / 2
-            );
-        }
-        if long_factor.len() != dim / 2 {
-            candle_core::bail!(
-                "Misaligned length {}, expected {} for `su`/`longrope` long rescale factors",
-                long_factor.len(),
-                dim / 2
-            );
-        }
+        Self::check_factor_lens(short_factor, long_factor, dim)?;
 
         // Short cos/sin
         let inv_freq_short: Vec<_> = (0..dim)
@@ -358,9 +386,16 @@ impl PhiRotaryEmbedding {
                 short_factor,
                 long_factor,
                 scaling_type,
-            }) => {
-                Self::new_classic_scaled(short_factor, long_factor, scaling_type, &cfg, dtype, dev)
-            }
+                attention_factor,
+            }) => Self::new_classic_scaled(
+                short_factor,
+                long_factor,
+                scaling_type,
+                *attention_factor,
+                &cfg,
+                dtype,
+                dev,
+            ),
 
             Some(PhiRopeScalingConfig::Scaled {
                 short_factor,
@@ -439,6 +474,10 @@ pub enum Llama3RotaryEmbedding {
 pub enum Llama3RopeType {
     #[serde(rename = "llama3")]
     Llama3,
+    /// Positions are divided by `factor`, extending the context to
+    /// `original_max_position_embeddings * factor`.
+    #[serde(rename = "linear")]
+    Linear,
     #[default]
     #[serde(rename = "default")]
     Default,
@@ -447,10 +486,18 @@ pub enum Llama3RopeType {
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct Llama3RopeConfig {
     pub factor: f32,
+    #[serde(default)]
     pub low_freq_factor: f32,
+    #[serde(default)]
     pub high_freq_factor: f32,
+    #[serde(default)]
     pub original_max_position_embeddings: usize,
+    #[serde(alias = "type")]
     pub rope_type: Llama3RopeType,
+    /// Scale applied to the cos and sin tables, and so to q and k. This is 1 for Llama 3 RoPE,
+    /// but some checkpoints specify it directly.
+    #[serde(default)]
+    pub attention_factor: Option<f32>,
 }
 
 fn calculate_default_inv_freq(cfg: &llama::Config) -> Vec<f32> {
@@ -461,6 +508,51 @@ fn calculate_default_inv_freq(cfg: &llama::Config) -> Vec<f32> {
         .collect()
 }
 
+/// The sin and cos tables for the positions up to `max_seq_len`, scaled by `attention_factor`.
+/// These are shared through the rotary table cache if it is enabled.
+fn rope_sin_cos(
+    inv_freq: Vec<f32>,
+    max_seq_len: usize,
+    attention_factor: f32,
+    dtype: DType,
+    dev: &Device,
+) -> Result<(Tensor, Tensor)> {
+    // The frequencies include the theta, head dim and scaling of the RoPE
+    let key = RopeTableKey::SinCos {
+        inv_freq: inv_freq.iter().map(|f| f.to_bits()).collect(),
+        max_seq_len,
+        attention_factor: attention_factor.to_bits(),
+        dtype,
+    };
+    let tables = cached_rope_tables(key, dev, || {
+        compute_rope_sin_cos(inv_freq, max_seq_len, attention_factor, dtype, dev)
+            .map(|(sin, cos)| RopeTables::SinCos { sin, cos })
+    })?;
+    match tables {
+        RopeTables::SinCos { sin, cos } => Ok((sin, cos)),
+        RopeTables::Rotary(_) => unreachable!("Cached rotary embedding for sin and cos tables."),
+    }
+}
+
+fn compute_rope_sin_cos(
+    inv_freq: Vec<f32>,
+    max_seq_len: usize,
+    attention_factor: f32,
+    dtype: DType,
+    dev: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let inv_freq_len = inv_freq.len();
+    let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+    let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+        .to_dtype(DType::F32)?
+        .reshape((max_seq_len, 1))?;
+    let freqs = t.matmul(&inv_freq)?;
+    let attention_factor = f64::from(attention_factor);
+    let sin = (freqs.sin()? * attention_factor)?.to_dtype(dtype)?;
+    let cos = (freqs.cos()? * attention_factor)?.to_dtype(dtype)?;
+    Ok((sin, cos))
+}
+
 // https://github.com/huggingface/transformers/blob/1392a6867f40a55dfabaf306745c67627598b1af/src/transformers/modeling_rope_utils.py#L298
 impl Llama3RotaryEmbedding {
     pub fn new_llama3(
@@ -470,6 +562,26 @@ impl Llama3RotaryEmbedding {
         is_gpt_neox: bool,
     ) -> Result<Self> {
         match &cfg.rope_scaling {
+            Some(Llama3RopeConfig {
+                rope_type: Llama3RopeType::Default,
+                attention_factor: Some(attention_factor),
+                ..
+            }) => {
+                // The default frequencies, with the tables scaled
+                let inv_freq = calculate_default_inv_freq(cfg);
+                let (sin, cos) = rope_sin_cos(
+                    inv_freq,
+                    cfg.max_position_embeddings,
+                    *attention_factor,
+                    dtype,
+                    dev,
+                )?;
+                Ok(Self::Llama3 {
+                    sin,
+                    cos,
+                    is_gptx: is_gpt_neox,
+                })
+            }
             None
             | Some(Llama3RopeConfig {
                 rope_type: Llama3RopeType::Default,
@@ -482,6 +594,29 @@ impl Llama3RotaryEmbedding {
                 is_gpt_neox,
                 dtype,
             )?)),
+            Some(
+                rope_scaling @ Llama3RopeConfig {
+                    rope_type: Llama3RopeType::Linear,
+                    ..
+                },
+            ) => {
+                let inv_freq = calculate_default_inv_freq(cfg)
+                    .into_iter()
+                    .map(|freq| freq / rope_scaling.factor)
+                    .collect::<Vec<_>>();
+                let (sin, cos) = rope_sin_cos(
+                    inv_freq,
+                    cfg.max_position_embeddings,
+                    rope_scaling.attention_factor.unwrap_or(1.),
+                    dtype,
+                    dev,
+                )?;
+                Ok(Self::Llama3 {
+                    sin,
+                    cos,
+                    is_gptx: is_gpt_neox,
+                })
+            }
             Some(rope_scaling) => {
                 let low_freq_wavelen = rope_scaling.original_max_position_embeddings as f32
                     / rope_scaling.low_freq_factor;
@@ -505,15 +640,13 @@ impl Llama3RotaryEmbedding {
                         }
                     })
                     .collect::<Vec<_>>();
-                let inv_freq_len = inv_freq.len();
-                let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
-
-                let t = Tensor::arange(0u32, cfg.max_position_embeddings as u32, dev)?
-                    .to_dtype(DType::F32)?
-                    .reshape((cfg.max_position_embeddings, 1))?;
-                let freqs = t.matmul(&inv_freq)?;
-                let sin = freqs.sin()?.to_dtype(dtype)?;
-                let cos = freqs.cos()?.to_dtype(dtype)?;
+                let (sin, cos) = rope_sin_cos(
+                    inv_freq,
+                    cfg.max_position_embeddings,
+                    rope_scaling.attention_factor.unwrap_or(1.),
+                    dtype,
+                    dev,
+                )?;
                 Ok(Self::Llama3 {
                     sin,
                     cos,
@@ -546,7 +679,7 @@ impl Llama3RotaryEmbedding {
                 rope_type: MLlamaRopeType::Llama3,
                 original_max_position_embeddings,
                 factor,
-                attention_factor: _,
+                attention_factor,
                 beta_fast: _,
                 beta_slow: _,
                 short_factor: _,
@@ -582,15 +715,13 @@ impl Llama3RotaryEmbedding {
                         }
                     })
                     .collect::<Vec<_>>();
-                let inv_freq_len = inv_freq.len();
-                let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
-
-                let t = Tensor::arange(0u32, cfg.max_position_embeddings as u32, dev)?
-                    .to_dtype(DType::F32)?
-                    .reshape((cfg.max_position_embeddings, 1))?;
-                let freqs = t.matmul(&inv_freq)?;
-                let sin = freqs.sin()?.to_dtype(dtype)?;
-                let cos = freqs.cos()?.to_dtype(dtype)?;
+                let (sin, cos) = rope_sin_cos(
+                    inv_freq,
+                    cfg.max_position_embeddings,
+                    attention_factor.unwrap_or(1.),
+                    dtype,
+                    dev,
+                )?;
                 Ok(Self::Llama3 {
                     sin,
                     cos,
@@ -885,6 +1016,83 @@ impl Module for QLinear {
     }
 }
 
+/// Cache the rotary sin and cos tables process wide, so that models with the same RoPE
+/// parameters share them instead of each computing their own.
+static USE_ROPE_TABLE_CACHE: AtomicBool = AtomicBool::new(false);
+
+/// The tables of each key, on each of their devices. CUDA and Metal devices are only the same if
+/// they are the same instance, which is not determined by their location.
+static ROPE_TABLE_CACHE: Lazy<Mutex<HashMap<RopeTableKey, Vec<(Device, RopeTables)>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The parameters which determine the rotary tables, apart from the device. Floats are compared by
+/// their bits.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RopeTableKey {
+    Rotary {
+        base: u32,
+        head_dim: usize,
+        rot_dim: usize,
+        max_position_embeddings: usize,
+        is_gpt_neox: bool,
+        dtype: DType,
+    },
+    SinCos {
+        inv_freq: Vec<u32>,
+        max_seq_len: usize,
+        attention_factor: u32,
+        dtype: DType,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum RopeTables {
+    Rotary(candle_nn::RotaryEmbedding),
+    SinCos { sin: Tensor, cos: Tensor },
+}
+
+/// Enable or disable sharing the rotary sin and cos tables between models with the same RoPE
+/// parameters, such as several models loaded together. Disabling the cache drops the cached
+/// tables, as does unloading a model.
+pub fn set_rope_table_cache(enabled: bool) {
+    USE_ROPE_TABLE_CACHE.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        clear_rope_table_cache();
+    }
+}
+
+/// Drop the cached rotary tables, such as when a model is unloaded. The models which are still
+/// loaded keep their tables.
+pub(crate) fn clear_rope_table_cache() {
+    ROPE_TABLE_CACHE
+        .lock()
+        .expect("Rotary table cache was poisoned")
+        .clear();
+}
+
+/// The tables for `key` on `dev`, from the cache if enabled. Otherwise, or if they are not cached
+/// yet, they are computed with `compute`.
+fn cached_rope_tables(
+    key: RopeTableKey,
+    dev: &Device,
+    compute: impl FnOnce() -> Result<RopeTables>,
+) -> Result<RopeTables> {
+    if !USE_ROPE_TABLE_CACHE.load(Ordering::Relaxed) {
+        return compute();
+    }
+    // Hold the lock while computing, so that concurrent loads compute the tables once
+    let mut cache = ROPE_TABLE_CACHE
+        .lock()
+        .expect("Rotary table cache was poisoned");
+    let entries = cache.entry(key).or_default();
+    if let Some((_, tables)) = entries.iter().find(|(device, _)| device.same_device(dev)) {
+        return Ok(tables.clone());
+    }
+    let tables = compute()?;
+    entries.push((dev.clone(), tables.clone()));
+    Ok(tables)
+}
+
 #[derive(Debug, Clone)]
 pub struct RotaryEmbedding(candle_nn::RotaryEmbedding);
 
@@ -897,14 +1105,24 @@ impl RotaryEmbedding {
         is_gpt_neox: bool,
         dtype: DType,
     ) -> Result<Self> {
-        Ok(Self(candle_nn::RotaryEmbedding::new(
-            base,
+        let key = RopeTableKey::Rotary {
+            base: base.to_bits(),
             head_dim,
+            rot_dim: head_dim,
             max_position_embeddings,
-            device,
             is_gpt_neox,
             dtype,
-        )?))
+        };
+        Self::cached(key, device, || {
+            candle_nn::RotaryEmbedding::new(
+                base,
+                head_dim,
+                max_position_embeddings,
+                device,
+                is_gpt_neox,
+                dtype,
+            )
+        })
     }
 
     pub fn new_partial(
@@ -916,15 +1134,38 @@ impl RotaryEmbedding {
         is_gpt_neox: bool,
         dtype: DType,
     ) -> Result<Self> {
-        Ok(Self(candle_nn::RotaryEmbedding::new_partial(
-            base,
+        let key = RopeTableKey::Rotary {
+            base: base.to_bits(),
             head_dim,
             rot_dim,
             max_position_embeddings,
-            device,
             is_gpt_neox,
             dtype,
-        )?))
+        };
+        Self::cached(key, device, || {
+            candle_nn::RotaryEmbedding::new_partial(
+                base,
+                head_dim,
+                rot_dim,
+                max_position_embeddings,
+                device,
+                is_gpt_neox,
+                dtype,
+            )
+        })
+    }
+
+    fn cached(
+        key: RopeTableKey,
+        device: &Device,
+        compute: impl FnOnce() -> Result<candle_nn::RotaryEmbedding>,
+    ) -> Result<Self> {
+        match cached_rope_tables(key, device, || compute().map(RopeTables::Rotary))? {
+            RopeTables::Rotary(rope) => Ok(Self(rope)),
+            RopeTables::SinCos { .. } => {
+                unreachable!("Cached sin and cos tables for a rotary embedding.")
+            }
+        }
     }
 
     pub fn forward(
@@ -1057,3 +1298,210 @@ impl Module for Conv3dNoBias {
         (self.conv2d_1.forward(&xs1)? + self.conv2d_2.forward(&xs2)?)?.unsqueeze(2)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{
+        clear_rope_table_cache, set_rope_table_cache, Llama3RopeConfig, Llama3RopeType,
+        Llama3RotaryEmbedding, PhiRopeConfig, PhiRopeScalingConfig, PhiRotaryEmbedding,
+    };
+    use crate::models::llama;
+
+    #[test]
+    fn test_rope_attention_factor() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let rope = |rope_type, attention_factor| {
+            let cfg = llama::Config {
+                hidden_size: 8,
+                num_attention_heads: 2,
+                rope_theta: 10_000.,
+                max_position_embeddings: 16,
+                rope_scaling: Some(Llama3RopeConfig {
+                    factor: 8.,
+                    low_freq_factor: 1.,
+                    high_freq_factor: 4.,
+                    original_max_position_embeddings: 8,
+                    rope_type,
+                    attention_factor,
+                }),
+                ..Default::default()
+            };
+            Llama3RotaryEmbedding::new_llama3(DType::F32, &cfg, &dev, true)
+        };
+        // (b * seq_len, heads, head_dim)
+        let xs = Tensor::arange(0f32, 24., &dev)?.reshape((3, 2, 4))?;
+        let scores = |rope: Llama3RotaryEmbedding| -> candle_core::Result<Vec<f32>> {
+            let (mut q, mut k) = (xs.clone(), xs.clone());
+            let positions_kernel = Tensor::zeros(1, DType::I64, &dev)?;
+            rope.forward(&[0], &positions_kernel, &mut q, &mut k, 1)?;
+            q.matmul(&k.t()?)?.flatten_all()?.to_vec1::<f32>()
+        };
+
+        // A factor of 1 for the default RoPE type builds the same scaled tables
+        for (rope_type, base_factor) in [
+            (Llama3RopeType::Llama3, None),
+            (Llama3RopeType::Default, Some(1.)),
+        ] {
+            let base = scores(rope(rope_type.clone(), base_factor)?)?;
+            let scaled = scores(rope(rope_type, Some(2.))?)?;
+            // q and k are both scaled, so the scores are scaled by the square of the factor
+            for (base, scaled) in base.iter().zip(scaled) {
+                assert!((base * 4. - scaled).abs() < 1e-2 * base.abs().max(1.));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_linear_rope_divides_positions() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let rope = |rope_scaling| {
+            let cfg = llama::Config {
+                hidden_size: 8,
+                num_attention_heads: 2,
+                rope_theta: 10_000.,
+                max_position_embeddings: 64,
+                rope_scaling,
+                ..Default::default()
+            };
+            Llama3RotaryEmbedding::new_llama3(DType::F32, &cfg, &dev, true)
+        };
+        let linear: Llama3RopeConfig = serde_json::from_str(r#"{"type": "linear", "factor": 4.0}"#)
+            .map_err(candle_core::Error::msg)?;
+        let (linear, unscaled) = (rope(Some(linear))?, rope(None)?);
+
+        // (b * seq_len, heads, head_dim)
+        let xs = Tensor::arange(0f32, 8., &dev)?.reshape((1, 2, 4))?;
+        let rotated = |rope: &Llama3RotaryEmbedding, pos: usize| -> candle_core::Result<Vec<f32>> {
+            let (mut q, mut k) = (xs.clone(), xs.clone());
+            let positions_kernel = Tensor::new(&[pos as i64], &dev)?;
+            rope.forward(&[pos], &positions_kernel, &mut q, &mut k, 1)?;
+            q.flatten_all()?.to_vec1::<f32>()
+        };
+        // Position 4p of the linear scaling is rotated as position p without scaling
+        for pos in [1, 5, 12] {
+            let expected = rotated(&unscaled, pos)?;
+            for (linear, expected) in rotated(&linear, 4 * pos)?.iter().zip(expected) {
+                assert!((linear - expected).abs() < 1e-4, "{linear} {expected}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rope_table_cache() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let sin = |factor| -> candle_core::Result<Tensor> {
+            let cfg = llama::Config {
+                hidden_size: 8,
+                num_attention_heads: 2,
+                rope_theta: 10_000.,
+                max_position_embeddings: 16,
+                rope_scaling: Some(Llama3RopeConfig {
+                    factor,
+                    low_freq_factor: 1.,
+                    high_freq_factor: 4.,
+                    original_max_position_embeddings: 8,
+                    rope_type: Llama3RopeType::Llama3,
+                    attention_factor: None,
+                }),
+                ..Default::default()
+            };
+            match Llama3RotaryEmbedding::new_llama3(DType::F32, &cfg, &dev, true)? {
+                Llama3RotaryEmbedding::Llama3 { sin, .. } => Ok(sin),
+                Llama3RotaryEmbedding::Default(_) => unreachable!(),
+            }
+        };
+
+        set_rope_table_cache(true);
+        // Two models with the same RoPE parameters share the table
+        let (first, second) = (sin(8.)?, sin(8.)?);
+        assert_eq!(first.id(), second.id());
+        // A different scaling builds its own table
+        let scaled = sin(4.)?;
+        assert_ne!(first.id(), scaled.id());
+        assert!(
+            (&first - &scaled)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?
+                > 0.
+        );
+
+        // Unloading a model drops the cached tables, while the loaded models keep theirs
+        clear_rope_table_cache();
+        let reloaded = sin(8.)?;
+        assert_ne!(first.id(), reloaded.id());
+        assert_eq!(reloaded.id(), sin(8.)?.id());
+
+        set_rope_table_cache(false);
+        assert_ne!(first.id(), sin(8.)?.id());
+        Ok(())
+    }
+
+    #[test]
+    fn test_longrope_factor_selection() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let rope_scaling: PhiRopeScalingConfig = serde_json::from_str(
+            r#"{"type": "longrope", "short_factor": [1.0, 1.0], "long_factor": [4.0, 4.0]}"#,
+        )
+        .map_err(candle_core::Error::msg)?;
+        let rope = PhiRotaryEmbedding::new(
+            DType::F32,
+            PhiRopeConfig {
+                rope_scaling: Some(rope_scaling),
+                max_position_embeddings: 32,
+                original_max_position_embeddings: 8,
+                rope_theta: 10_000.,
+                head_dim: 4,
+            },
+            &dev,
+        )?;
+        let long_sin = rope.long_sin.as_ref().unwrap();
+
+        // Up to the original length the short factors are used, past it the long ones
+        let (sin, _) = rope.get_long_or_short_sin_cos(&[7]);
+        assert!(
+            sin.eq(&rope.short_sin)?
+                .flatten_all()?
+                .min(0)?
+                .to_scalar::<u8>()?
+                == 1
+        );
+        let (sin, _) = rope.get_long_or_short_sin_cos(&[3, 8]);
+        assert!(sin.eq(long_sin)?.flatten_all()?.min(0)?.to_scalar::<u8>()? == 1);
+
+        // Both tables are scaled by the attention factor for the context extension
+        let scale = (1. + 4f64.ln() / 8f64.ln()).sqrt() as f32;
+        let (_, cos) = rope.get_long_or_short_sin_cos(&[0]);
+        assert!((cos.get(0)?.to_vec1::<f32>()?[0] - scale).abs() < 1e-5);
+        // The long factors slow down the rotation
+        let short = rope.short_sin.get(1)?.to_vec1::<f32>()?[0];
+        let long = long_sin.get(1)?.to_vec1::<f32>()?[0];
+        assert!((short - 1f32.sin() * scale).abs() < 1e-5);
+        assert!((long - 0.25f32.sin() * scale).abs() < 1e-5);
+
+        // `rope_type` is accepted as well
+        let rope_scaling: PhiRopeScalingConfig = serde_json::from_str(
+            r#"{"rope_type": "longrope", "short_factor": [1.0], "long_factor": [4.0]}"#,
+        )
+        .map_err(candle_core::Error::msg)?;
+        let res = PhiRotaryEmbedding::new(
+            DType::F32,
+            PhiRopeConfig {
+                rope_scaling: Some(rope_scaling),
+                max_position_embeddings: 32,
+                original_max_position_embeddings: 8,
+                rope_theta: 10_000.,
+                head_dim: 4,
+            },
+            &dev,
+        );
+        // But the factors must match the head dimension
+        assert!(res.unwrap_err().to_string().contains("Misaligned length 1"));
+        Ok(())
+    }
+}
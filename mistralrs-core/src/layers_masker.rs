@@ -171,6 +171,23 @@ impl CausalMasker {
         Ok(Some(causal_mask))
     }
 
+    /// With `bidirectional`, every position attends to all positions, so there is nothing to mask.
+    /// This is meant for computing embeddings with bidirectional attention, not for generation.
+    /// Otherwise, this is the causal mask.
+    pub fn make_attention_mask_matrix(
+        &self,
+        input_ids: &Tensor,
+        cache: &dyn PastKvLenCache,
+        dtype: DType,
+        n_attn_heads: usize,
+        bidirectional: bool,
+    ) -> Result<Option<Tensor>> {
+        if bidirectional {
+            return Ok(None);
+        }
+        self.make_causal_mask_matrix(input_ids, cache, dtype, n_attn_heads)
+    }
+
     pub fn make_sliding_window_causal_mask_matrix(
         &self,
         input_ids: &Tensor,
@@ -2,8 +2,12 @@
 
 use candle_core::Device;
 use cublaslt::setup_cublas_lt_wrapper;
+use either::Either;
 use engine::Engine;
-pub use engine::{EngineInstruction, ENGINE_INSTRUCTIONS, TERMINATE_ALL_NEXT_STEP};
+pub use engine::{
+    EngineInstruction, PromptLimitPolicy, ENGINE_INSTRUCTIONS, TERMINATE_ALL_NEXT_STEP,
+};
+use indexmap::IndexMap;
 pub use lora::Ordering;
 pub use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
@@ -15,12 +19,14 @@ use std::{
     error::Error,
     fs::OpenOptions,
     io::Write,
+    num::NonZeroUsize,
+    path::PathBuf,
     sync::{
         atomic::{self, AtomicBool, AtomicUsize},
         Arc, Mutex, RwLock,
     },
     thread::{self, JoinHandle},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::{channel, Sender};
 use tracing::info;
@@ -52,6 +58,7 @@ mod paged_attention;
 #[cfg(not(all(feature = "cuda", target_family = "unix")))]
 use dummy_paged_attention as paged_attention;
 mod attention;
+mod classification;
 mod diffusion_models;
 mod pipeline;
 mod prefix_cacher;
@@ -60,6 +67,8 @@ mod response;
 mod sampler;
 mod scheduler;
 mod sequence;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod toml_selector;
 mod tools;
 mod topology;
@@ -68,41 +77,55 @@ mod vision_models;
 mod xlora_models;
 
 pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
+pub use attention::{verify_flash_attention, FLASH_ATTENTION_TOLERANCE};
+pub use classification::ClassificationHead;
 pub use device_map::{DeviceLayerMapMetadata, DeviceMapMetadata, LayerDeviceMapper};
 pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
+pub use layers::set_rope_table_cache;
 pub use mistralrs_quant::IsqType;
 pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
 pub use pipeline::{
-    chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline,
+    chat_template::{ChatTemplate, SPECIAL_TOKEN_DUPLICATION_TARGET},
+    parse_isq_value, AnyMoeLoader, AnyMoePipeline, ArcticLoader, CohereLoader,
     DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder, DiffusionLoaderType,
     DiffusionSpecificConfig, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader,
-    GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, Idefics2Loader, IsqOrganization,
-    LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MistralLoader,
-    MixtralLoader, ModelKind, ModelPaths, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
-    NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig,
-    SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource, VisionLoader,
-    VisionLoaderBuilder, VisionLoaderType, VisionPromptPrefixer, VisionSpecificConfig,
+    GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, GraniteLoader, Idefics2Loader,
+    IsqOrganization, KvCacheBackendType, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
+    LocalModelPaths, MambaLoader, MistralLoader, MixtralLoader, ModelKind, ModelPaths,
+    NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader,
+    Phi3Loader, Phi3VLoader, Qwen2Loader, Qwen2MoeLoader, SelfSpeculativeConfig,
+    SelfSpeculativeLoader, SpeculativeConfig, SpeculativeLoader, SpeculativePipeline,
+    Starcoder2Loader, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
+    VisionPromptPrefixer, VisionSpecificConfig,
 };
+pub use prefix_cacher::PrefixCacheStats;
 pub use request::{
-    Constraint, DetokenizationRequest, ImageGenerationResponseFormat, LlguidanceGrammar,
-    MessageContent, NormalRequest, Request, RequestMessage, TokenizationRequest,
+    AttentionWeightsRequest, ClassificationRequest, Constraint, DetokenizationRequest,
+    ImageGenerationResponseFormat, LlguidanceGrammar, MessageContent, NormalRequest, Request,
+    RequestMessage, SaveSafetensorsRequest, TokenizationRequest,
 };
 pub use response::*;
 pub use sampler::{
-    CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    next_token_candidates, CustomLogitsProcessor, DrySamplingParams, LengthBiasParams,
+    NextTokenCandidate, SamplingParams, SamplingUpdate, StopTokens, TopLogprob,
 };
 pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
 use serde::Serialize;
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
 pub use tools::{
-    CalledFunction, Function, Tool, ToolCallResponse, ToolCallType, ToolChoice, ToolType,
+    CalledFunction, CalledFunctionDelta, Function, Tool, ToolCallDelta, ToolCallResponse,
+    ToolCallType, ToolChoice, ToolType,
 };
 pub use topology::{LayerTopology, Topology};
+pub use utils::config_overrides::ConfigOverrides;
 pub use utils::debug::initialize_logging;
+pub use utils::device::{load_on_device, DeviceSpec};
 pub use utils::memory_usage::MemoryUsage;
 pub use utils::normal::{ModelDType, TryIntoDType};
 pub use utils::paged_attn_supported;
+pub use utils::reasoning::ReasoningMarkers;
+pub use utils::tensor_name_map::TensorNameMap;
 
 // re-export llguidance for easier LlguidanceGrammar construction
 pub use llguidance;
@@ -115,6 +138,8 @@ pub struct MistralRsConfig {
     pub kind: ModelKind,
     pub device: Device,
     pub category: ModelCategory,
+    /// The maximum sequence length of the model, including a `rope_scaling` context extension.
+    pub max_seq_len: usize,
 }
 
 /// The MistralRs struct handles sending requests to the engine.
@@ -130,6 +155,7 @@ pub struct MistralRs {
     reboot_state: RebootState,
     engine_handler: RwLock<JoinHandle<()>>,
     engine_id: usize,
+    draining: AtomicBool,
     category: ModelCategory,
     config: MistralRsConfig,
 }
@@ -139,6 +165,10 @@ struct RebootState {
     pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>>,
     method: SchedulerConfig,
     truncate_sequence: bool,
+    max_prompt_tokens: Option<(usize, PromptLimitPolicy)>,
+    max_concurrent_sequences: Option<NonZeroUsize>,
+    fallback: Option<(Arc<MistralRs>, usize)>,
+    logits_dump_dir: Option<PathBuf>,
     no_kv_cache: bool,
     no_prefix_cache: bool,
     prefix_cache_n: usize,
@@ -175,12 +205,17 @@ pub struct MistralRsBuilder {
     method: SchedulerConfig,
     log: Option<String>,
     truncate_sequence: Option<bool>,
+    max_prompt_tokens: Option<(usize, PromptLimitPolicy)>,
+    max_concurrent_sequences: Option<NonZeroUsize>,
+    fallback: Option<(Arc<MistralRs>, usize)>,
+    logits_dump_dir: Option<PathBuf>,
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
     prefix_cache_n: Option<usize>,
     disable_eos_stop: Option<bool>,
     gemm_full_precision_f16: Option<bool>,
     throughput_logging_enabled: Option<()>,
+    warmup_prefixes: Vec<String>,
 }
 
 impl MistralRsBuilder {
@@ -190,12 +225,17 @@ impl MistralRsBuilder {
             method,
             log: None,
             truncate_sequence: None,
+            max_prompt_tokens: None,
+            max_concurrent_sequences: None,
+            fallback: None,
+            logits_dump_dir: None,
             no_kv_cache: None,
             no_prefix_cache: None,
             prefix_cache_n: None,
             disable_eos_stop: None,
             gemm_full_precision_f16: None,
             throughput_logging_enabled: None,
+            warmup_prefixes: Vec::new(),
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -210,6 +250,39 @@ impl MistralRsBuilder {
         self.truncate_sequence = Some(truncate_sequence);
         self
     }
+    /// Limit the number of prompt tokens admitted for a single request, independent of the
+    /// model's maximum sequence length. Prompts over the limit are handled per `policy`.
+    pub fn with_max_prompt_tokens(
+        mut self,
+        max_prompt_tokens: usize,
+        policy: PromptLimitPolicy,
+    ) -> Self {
+        self.max_prompt_tokens = Some((max_prompt_tokens, policy));
+        self
+    }
+    /// Serve requests with `fallback`, typically a smaller model, when their admission would need
+    /// more than `max_admission_tokens` KV cache tokens on this model. The footprint of a request
+    /// is the number of choices times its maximum sequence length, so both oversized prompts and
+    /// oversized batches are routed. Prompts longer than the maximum sequence length of this model
+    /// are routed as well, instead of being rejected or truncated. The `model` field of the
+    /// response names the serving model.
+    pub fn with_fallback(mut self, fallback: Arc<MistralRs>, max_admission_tokens: usize) -> Self {
+        self.fallback = Some((fallback, max_admission_tokens));
+        self
+    }
+    /// Run at most `max_concurrent_sequences` sequences at once. Further sequences wait in a queue
+    /// until a running one finishes, see [`MistralRs::queue_position`].
+    pub fn with_max_concurrent_sequences(mut self, max_concurrent_sequences: NonZeroUsize) -> Self {
+        self.max_concurrent_sequences = Some(max_concurrent_sequences);
+        self
+    }
+    /// Write the logits of every generation step to `<dir>/<request id>_<choice index>_<n>.npy`,
+    /// as a `[steps, vocab]` f32 array, where `n` makes the file name unique. The logits are
+    /// streamed to disk as they are produced.
+    pub fn with_logits_dump_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.logits_dump_dir = Some(dir.into());
+        self
+    }
     pub fn with_no_kv_cache(mut self, no_kv_cache: bool) -> Self {
         self.no_kv_cache = Some(no_kv_cache);
         self
@@ -234,6 +307,15 @@ impl MistralRsBuilder {
         self.throughput_logging_enabled = Some(());
         self
     }
+    /// Run each of these system prompts once at startup so that their KV caches are in the
+    /// prefix cache. Each one is rendered with the chat template as the only message and cut
+    /// right after its text, so chat requests starting with the same system message only
+    /// prefill the remaining tokens. Like the other prefix caches, they are dropped once they
+    /// are the least recently used beyond the capacity.
+    pub fn with_warmup_prefixes(mut self, warmup_prefixes: Vec<String>) -> Self {
+        self.warmup_prefixes = warmup_prefixes;
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -283,6 +365,8 @@ impl Drop for MistralRs {
             .lock()
             .expect("`ENGINE_INSTRUCTIONS` was poisioned")
             .insert(self.engine_id, Some(EngineInstruction::Terminate));
+        // The model is unloaded, so its rotary tables no longer need to be shared
+        layers::clear_rope_table_cache();
     }
 }
 
@@ -293,12 +377,17 @@ impl MistralRs {
             method,
             log,
             truncate_sequence,
+            max_prompt_tokens,
+            max_concurrent_sequences,
+            fallback,
+            logits_dump_dir,
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
             disable_eos_stop,
             gemm_full_precision_f16,
             throughput_logging_enabled,
+            warmup_prefixes,
         } = config;
 
         let category = pipeline.try_lock().unwrap().category();
@@ -323,6 +412,10 @@ impl MistralRs {
             pipeline: pipeline.clone(),
             method: method.clone(),
             truncate_sequence,
+            max_prompt_tokens,
+            max_concurrent_sequences,
+            fallback: fallback.clone(),
+            logits_dump_dir: logits_dump_dir.clone(),
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
@@ -337,10 +430,12 @@ impl MistralRs {
 
         let kind = pipeline.try_lock().unwrap().get_metadata().kind.clone();
         let device = pipeline.try_lock().unwrap().device();
+        let max_seq_len = pipeline.try_lock().unwrap().get_metadata().max_seq_len;
         let config = MistralRsConfig {
             kind,
             device,
             category: category.clone(),
+            max_seq_len,
         };
 
         let engine_handler = thread::spawn(move || {
@@ -351,6 +446,10 @@ impl MistralRs {
                     pipeline,
                     method,
                     truncate_sequence,
+                    max_prompt_tokens,
+                    max_concurrent_sequences,
+                    fallback,
+                    logits_dump_dir,
                     no_kv_cache,
                     no_prefix_cache,
                     prefix_cache_n,
@@ -389,6 +488,9 @@ impl MistralRs {
                     tools: None,
                     logits_processors: None,
                     return_raw_logits: false,
+                    continue_final_message: false,
+                    reasoning_markers: None,
+                    assistant_prefix: None,
                 });
                 info!("Beginning dummy run.");
                 let start = Instant::now();
@@ -406,6 +508,66 @@ impl MistralRs {
             });
         }
 
+        let prefix_cache_enabled = !no_prefix_cache
+            && !no_kv_cache
+            && !matches!(method, SchedulerConfig::PagedAttentionMeta { .. });
+        if !warmup_prefixes.is_empty() && !prefix_cache_enabled {
+            warn!("Skipping the warmup prefixes as the prefix cache is disabled.");
+        } else if !warmup_prefixes.is_empty() {
+            let clone_sender = sender.read().unwrap().clone();
+            tokio::task::block_in_place(|| {
+                info!(
+                    "Warming the prefix cache with {} prefixes.",
+                    warmup_prefixes.len()
+                );
+                let start = Instant::now();
+                for prefix in warmup_prefixes {
+                    let (tx, mut rx) = channel(1);
+                    let req = Request::Normal(NormalRequest {
+                        id: 0,
+                        messages: RequestMessage::Chat(vec![IndexMap::from([
+                            ("role".to_string(), Either::Left("system".to_string())),
+                            ("content".to_string(), Either::Left(prefix)),
+                        ])]),
+                        sampling_params: SamplingParams {
+                            max_len: Some(1),
+                            ..SamplingParams::deterministic()
+                        },
+                        response: tx,
+                        return_logprobs: false,
+                        is_streaming: false,
+                        constraint: Constraint::None,
+                        suffix: None,
+                        adapters: None,
+                        tool_choice: None,
+                        tools: None,
+                        logits_processors: None,
+                        return_raw_logits: false,
+                        // Leave out the end of the system turn, which is followed by
+                        // different tokens for a completion or the next message
+                        continue_final_message: true,
+                        reasoning_markers: None,
+                        assistant_prefix: None,
+                    });
+                    clone_sender.blocking_send(req).unwrap();
+                    match rx.blocking_recv() {
+                        Some(Response::InternalError(e) | Response::ValidationError(e)) => {
+                            warn!("Warmup prefix failed: {e}")
+                        }
+                        Some(Response::CompletionModelError(e, _)) => {
+                            warn!("Warmup prefix failed: {e}")
+                        }
+                        Some(_) => (),
+                        None => warn!("Warmup prefix failed!"),
+                    }
+                }
+                info!(
+                    "Prefix cache warmup completed in {}s.",
+                    Instant::now().duration_since(start).as_secs_f64()
+                );
+            });
+        }
+
         Arc::new(Self {
             engine_id,
             sender,
@@ -418,6 +580,7 @@ impl MistralRs {
             next_request_id: Mutex::new(RefCell::new(0)),
             reboot_state,
             engine_handler: RwLock::new(engine_handler),
+            draining: AtomicBool::new(false),
             category,
             config,
         })
@@ -450,6 +613,10 @@ impl MistralRs {
                         reboot_state.pipeline.clone(),
                         reboot_state.method,
                         reboot_state.truncate_sequence,
+                        reboot_state.max_prompt_tokens,
+                        reboot_state.max_concurrent_sequences,
+                        reboot_state.fallback,
+                        reboot_state.logits_dump_dir,
                         reboot_state.no_kv_cache,
                         reboot_state.no_prefix_cache,
                         reboot_state.prefix_cache_n,
@@ -477,7 +644,8 @@ impl MistralRs {
     }
 
     pub fn get_sender(&self) -> Result<Sender<Request>, MistralRsError> {
-        if self.engine_dead()? {
+        // A drained engine is not rebooted, so requests sent after a shutdown fail
+        if self.engine_dead()? && !self.draining.load(atomic::Ordering::SeqCst) {
             tracing::warn!("Engine is dead, rebooting");
             self.reboot_engine()?
         }
@@ -487,6 +655,64 @@ impl MistralRs {
         }
     }
 
+    /// The counters of the prefix cache lookups since the engine started.
+    pub async fn prefix_cache_stats(&self) -> Result<PrefixCacheStats, MistralRsError> {
+        let (tx, mut rx) = channel(1);
+        self.get_sender()?
+            .send(Request::PrefixCacheStats(tx))
+            .await
+            .map_err(|_| MistralRsError::EnginePoisoned)?;
+        rx.recv().await.ok_or(MistralRsError::EnginePoisoned)
+    }
+
+    /// The position of the request `request_id` among the sequences waiting for the limit set by
+    /// [`MistralRsBuilder::with_max_concurrent_sequences`], starting at 1. Returns `None` once
+    /// the request is running or finished, or if it is unknown.
+    pub async fn queue_position(&self, request_id: usize) -> Result<Option<usize>, MistralRsError> {
+        let (tx, mut rx) = channel(1);
+        self.get_sender()?
+            .send(Request::QueuePosition(request_id, tx))
+            .await
+            .map_err(|_| MistralRsError::EnginePoisoned)?;
+        rx.recv().await.ok_or(MistralRsError::EnginePoisoned)
+    }
+
+    /// Gracefully shut down the engine: new requests are rejected while the in-flight ones
+    /// finish. Requests which are still running after `timeout` are canceled with the output
+    /// generated so far. Returns once the engine has stopped.
+    pub async fn drain(&self, timeout: Duration) -> Result<(), MistralRsError> {
+        let sender = self.get_sender()?;
+        self.draining.store(true, atomic::Ordering::SeqCst);
+        if sender
+            .send(Request::Drain(Instant::now() + timeout))
+            .await
+            .is_err()
+        {
+            // The engine already stopped
+            return Ok(());
+        }
+        while !self.engine_dead()? {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Ok(())
+    }
+
+    /// Change the sampling parameters of the running request `request_id`, which take effect from
+    /// its next decode step on. The request must have been sent with a unique id, such as one from
+    /// [`MistralRs::next_request_id`]. Requests which are not running are not affected.
+    pub async fn update_sampling(
+        &self,
+        request_id: usize,
+        update: SamplingUpdate,
+    ) -> Result<(), MistralRsError> {
+        // Nothing is running if the engine stopped
+        let _ = self
+            .get_sender()?
+            .send(Request::UpdateSampling(request_id, update))
+            .await;
+        Ok(())
+    }
+
     pub fn get_id(&self) -> String {
         self.id.clone()
     }
@@ -28,6 +28,9 @@ pub struct Ordering {
     pub layers: Option<HashMap<String, usize>>,
     pub base_model_id: String,
     pub preload_adapters: Option<Vec<PreloadAdapter>>,
+    /// A safetensors file, in each adapter model repo, which bundles several adapters. Its tensor
+    /// names are prefixed by the adapter name: `<adapter name>.<tensor name>`.
+    pub combined_adapters: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -6,8 +6,8 @@ use std::{
 use crate::{
     get_toml_selected_model_dtype,
     pipeline::{GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, NormalSpecificConfig},
-    DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFSpecificConfig, Loader, ModelDType,
-    ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology,
+    DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFSpecificConfig, KvCacheBackendType,
+    Loader, ModelDType, ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology,
     VisionLoaderBuilder, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
 };
 
@@ -18,6 +18,7 @@ pub struct LoaderBuilder {
     chat_template: Option<String>,
     use_flash_attn: bool,
     prompt_batchsize: Option<NonZeroUsize>,
+    kv_cache_backend: KvCacheBackendType,
 }
 
 impl LoaderBuilder {
@@ -28,6 +29,7 @@ impl LoaderBuilder {
             chat_template: None,
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_backend: KvCacheBackendType::default(),
         }
     }
 
@@ -47,6 +49,11 @@ impl LoaderBuilder {
         self.prompt_batchsize = prompt_batchsize;
         self
     }
+    /// The KV cache backend of plain models, see [`NormalLoaderBuilder::with_kv_cache_backend`].
+    pub fn with_kv_cache_backend(mut self, kv_cache_backend: KvCacheBackendType) -> Self {
+        self.kv_cache_backend = kv_cache_backend;
+        self
+    }
 
     pub fn build(self) -> anyhow::Result<Box<dyn Loader>> {
         loader_from_model_selected(self)
@@ -129,6 +136,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             from_uqff,
             imatrix,
             calibration_file,
+            added_tokens,
         } => NormalLoaderBuilder::new(
             NormalSpecificConfig {
                 use_flash_attn,
@@ -145,6 +153,8 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             Some(model_id),
         )
         .with_no_kv_cache(args.no_kv_cache)
+        .with_kv_cache_backend(args.kv_cache_backend)
+        .with_added_tokens(added_tokens)
         .build(arch)?,
         ModelSelected::XLora {
             model_id,
@@ -23,6 +23,16 @@ fn parse_model_dtype(x: &str) -> Result<ModelDType, String> {
     x.parse()
 }
 
+fn parse_added_token(x: &str) -> Result<(String, u32), String> {
+    let (content, id) = x
+        .rsplit_once('=')
+        .ok_or_else(|| format!("Expected an added token as `<CONTENT>=<ID>`, got `{x}`."))?;
+    let id = id
+        .parse()
+        .map_err(|e| format!("Invalid id of the added token `{content}`: {e}"))?;
+    Ok((content.to_string(), id))
+}
+
 #[derive(Debug, Subcommand)]
 pub enum ModelSelected {
     /// Select the model from a toml file
@@ -76,6 +86,11 @@ pub enum ModelSelected {
         /// Incompatible with `--imatrix/-i`
         #[arg(short, long)]
         calibration_file: Option<PathBuf>,
+
+        /// Extra special token to register in the tokenizer, with its id, as `<CONTENT>=<ID>` (for example `<|tool|>=32011`).
+        /// The id must be below the vocab size of the model. This may be specified multiple times.
+        #[arg(long = "added-token", value_parser = parse_added_token)]
+        added_tokens: Vec<(String, u32)>,
     },
 
     /// Select an X-LoRA architecture
@@ -0,0 +1,883 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+/// Snowflake Arctic Model
+/// https://huggingface.co/Snowflake/snowflake-arctic-instruct/blob/main/modeling_arctic.py
+/// https://www.snowflake.com/blog/arctic-open-efficient-foundation-language-models-snowflake/
+use candle_core::{Device, Module, Result, Tensor};
+use candle_nn::{RotaryEmbedding, VarBuilder};
+use mistralrs_quant::{QuantMethod, QuantMethodConfig, QuantizedConfig, UnquantLinear};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    amoe::AnyMoeBaseModelMixin,
+    attention::SdpaParams,
+    device_map::DeviceMapper,
+    layers::{Activation, CausalMasker, MatMul, RmsNorm, Sdpa},
+    layers_masker::PastKvLenCache,
+    models::mixtral::{self, BlockSparseTop2MLP, SparseMoeBlock},
+    paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
+    pipeline::{
+        extract_logits,
+        text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
+        EitherCache, IsqModel, KvCache, NormalCache, NormalLoadingMetadata, NormalModel,
+    },
+    utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
+};
+
+/// https://huggingface.co/Snowflake/snowflake-arctic-instruct/blob/main/configuration_arctic.py
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) num_key_value_heads: usize,
+    pub(crate) hidden_act: Activation,
+    pub(crate) max_position_embeddings: usize,
+    pub(crate) rms_norm_eps: f64,
+    pub(crate) rope_theta: f64,
+    pub(crate) sliding_window: Option<usize>,
+    pub(crate) num_experts_per_tok: usize,
+    pub(crate) num_local_experts: usize,
+    pub(crate) moe_layer_frequency: usize,
+    pub(crate) parallel_attn_mlp_res: bool,
+    pub(crate) use_residual: bool,
+    pub(crate) use_flash_attn: bool,
+    pub(crate) quantization_config: Option<QuantizedConfig>,
+    pub(crate) tie_word_embeddings: bool,
+}
+
+impl Config {
+    fn is_moe_layer(&self, layer_idx: usize) -> bool {
+        (layer_idx + 1) % self.moe_layer_frequency == 0
+    }
+
+    /// As in the reference implementation, only the MoE layers have the parallel residual MLP.
+    fn has_residual_mlp(&self, layer_idx: usize) -> bool {
+        self.parallel_attn_mlp_res && self.use_residual && self.is_moe_layer(layer_idx)
+    }
+
+    /// The experts and dense MLPs are Mixtral blocks, this is the config used to build them.
+    fn mixtral_config(&self, intermediate_size: usize) -> mixtral::Config {
+        mixtral::Config {
+            vocab_size: self.vocab_size,
+            hidden_size: self.hidden_size,
+            intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads,
+            hidden_act: self.hidden_act,
+            max_position_embeddings: self.max_position_embeddings,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            sliding_window: self.sliding_window,
+            num_experts_per_tok: self.num_experts_per_tok,
+            num_local_experts: self.num_local_experts,
+            use_flash_attn: self.use_flash_attn,
+            quantization_config: self.quantization_config.clone(),
+            tie_word_embeddings: self.tie_word_embeddings,
+        }
+    }
+}
+
+struct Attention {
+    q_proj: Arc<dyn QuantMethod>,
+    k_proj: Arc<dyn QuantMethod>,
+    v_proj: Arc<dyn QuantMethod>,
+    o_proj: Arc<dyn QuantMethod>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    sliding_window: Option<usize>,
+    paged_attn: Option<PagedAttention>,
+    sdpa_params: SdpaParams,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        paged_attn: Option<PagedAttention>,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let head_dim = hidden_sz / num_heads;
+        let q_proj = mistralrs_quant::linear_no_bias(
+            hidden_sz,
+            num_heads * head_dim,
+            &cfg.quantization_config,
+            vb.pp("q_proj"),
+        )?;
+        let k_proj = mistralrs_quant::linear_no_bias(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            &cfg.quantization_config,
+            vb.pp("k_proj"),
+        )?;
+        let v_proj = mistralrs_quant::linear_no_bias(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            &cfg.quantization_config,
+            vb.pp("v_proj"),
+        )?;
+        let o_proj = mistralrs_quant::linear_no_bias(
+            num_heads * head_dim,
+            hidden_sz,
+            &cfg.quantization_config,
+            vb.pp("o_proj"),
+        )?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            rotary_emb,
+            sliding_window: cfg.sliding_window,
+            paged_attn,
+            sdpa_params: SdpaParams {
+                n_kv_groups: num_heads / num_kv_heads,
+                use_flash_attn: cfg.use_flash_attn,
+                softcap: None,
+                softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                sliding_window: cfg.sliding_window,
+                attention_softmax_f32: false,
+            },
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut KvCache,
+        metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let mut q = MatMul.qmethod_matmul(&xs, &*self.q_proj)?;
+        let mut k = MatMul.qmethod_matmul(&xs, &*self.k_proj)?;
+        let mut v = MatMul.qmethod_matmul(&xs, &*self.v_proj)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            q = q.to_dtype(original_dtype)?;
+            k = k.to_dtype(original_dtype)?;
+            v = v.to_dtype(original_dtype)?;
+        }
+
+        let mut q = q.reshape((b_sz * q_len, self.num_heads, self.head_dim))?;
+        let mut k = k.reshape((b_sz * q_len, self.num_kv_heads, self.head_dim))?;
+        let v = if q_len != 1 {
+            v.reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?
+        } else {
+            // Optimization for seqlen = 1, avoid transpose and just modify reshape dims
+            v.reshape((b_sz, self.num_kv_heads, q_len, self.head_dim))?
+        };
+
+        self.rotary_emb
+            .forward(seqlen_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+
+        if q.rank() == 3 && q_len != 1 {
+            q = q
+                .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+        } else if q.rank() == 3 {
+            // Optimization for seqlen = 1, avoid transpose and just modify reshape dims
+            q = q
+                .reshape((b_sz, self.num_heads, q_len, self.head_dim))?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, self.num_kv_heads, q_len, self.head_dim))?
+                .contiguous()?;
+        }
+
+        let mut attn_output = match &self.paged_attn {
+            Some(paged_attn) => match metadata {
+                Some(((key_cache, value_cache), input_metadata)) => paged_attn.forward(
+                    &q,
+                    &k,
+                    &v,
+                    attention_mask,
+                    Some(key_cache),
+                    Some(value_cache),
+                    input_metadata,
+                    None,
+                )?,
+                None => {
+                    let mut input_metadata = PagedAttentionInputMetadata {
+                        block_tables: None,
+                        context_lens: None,
+                        max_context_len: None,
+                        slot_mappings: Tensor::new(&[0f32], q.device())?,
+                    };
+                    paged_attn.forward(
+                        &q,
+                        &k,
+                        &v,
+                        attention_mask,
+                        None,
+                        None,
+                        &mut input_metadata,
+                        None,
+                    )?
+                }
+            },
+            None => {
+                let (k, v, attn_mask) =
+                    kv_cache.append_sliding_window(&k, &v, attention_mask, self.sliding_window)?;
+
+                Sdpa.run_attention(
+                    &q,
+                    &k,
+                    &v,
+                    attn_mask.as_ref(),
+                    Some(flash_params),
+                    &self.sdpa_params,
+                )?
+            }
+        };
+
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            attn_output = attn_output.to_dtype(t)?;
+        }
+        attn_output = if attention_mask.is_some() {
+            attn_output.transpose(1, 2)?.reshape((b_sz, q_len, ()))?
+        } else {
+            attn_output.reshape((b_sz, q_len, ()))?
+        };
+        let mut res = MatMul.qmethod_matmul(&attn_output, &*self.o_proj)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+/// Every `moe_layer_frequency`-th layer routes to the experts, the others use a single dense MLP.
+enum ArcticMoe {
+    Moe(SparseMoeBlock),
+    Dense(BlockSparseTop2MLP),
+}
+
+impl Module for ArcticMoe {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Moe(moe) => moe.forward(xs),
+            Self::Dense(mlp) => mlp.forward(xs),
+        }
+    }
+}
+
+/// The dense MLP computed in parallel to the MoE of the MoE layers when `parallel_attn_mlp_res`
+/// is set.
+struct ResidualMlp {
+    residual_layernorm: RmsNorm,
+    residual_mlp: BlockSparseTop2MLP,
+}
+
+struct FeedForward {
+    post_attention_layernorm: RmsNorm,
+    block_sparse_moe: ArcticMoe,
+    residual: Option<ResidualMlp>,
+}
+
+impl FeedForward {
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let moe_vb = mapper.set_device(layer_idx, vb.pp("block_sparse_moe"), loading_isq);
+        let block_sparse_moe = if cfg.is_moe_layer(layer_idx) {
+            ArcticMoe::Moe(SparseMoeBlock::new(
+                &cfg.mixtral_config(cfg.intermediate_size),
+                moe_vb,
+            )?)
+        } else {
+            ArcticMoe::Dense(BlockSparseTop2MLP::new(
+                &cfg.mixtral_config(cfg.intermediate_size),
+                moe_vb.pp("mlp"),
+            )?)
+        };
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("post_attention_layernorm"), false),
+        )?;
+        let residual = if cfg.has_residual_mlp(layer_idx) {
+            // The residual MLP keeps the hidden size as its intermediate size
+            Some(ResidualMlp {
+                residual_layernorm: RmsNorm::new(
+                    cfg.hidden_size,
+                    cfg.rms_norm_eps,
+                    mapper.set_device(layer_idx, vb.pp("residual_layernorm"), false),
+                )?,
+                residual_mlp: BlockSparseTop2MLP::new(
+                    &cfg.mixtral_config(cfg.hidden_size),
+                    mapper.set_device(layer_idx, vb.pp("residual_mlp"), loading_isq),
+                )?,
+            })
+        } else {
+            None
+        };
+        Ok(Self {
+            post_attention_layernorm,
+            block_sparse_moe,
+            residual,
+        })
+    }
+
+    /// `residual_input` is the input of the layer, `residual_attn` is the attention output with
+    /// the residual connection applied.
+    fn forward(&self, residual_input: &Tensor, residual_attn: &Tensor) -> Result<Tensor> {
+        match &self.residual {
+            Some(ResidualMlp {
+                residual_layernorm,
+                residual_mlp,
+            }) => {
+                // The dense MLP reads the attention output while the MoE reads the layer input,
+                // so the two branches are independent and their outputs are summed.
+                let dense = residual_attn
+                    .apply(residual_layernorm)?
+                    .apply(residual_mlp)?
+                    .to_dtype(residual_attn.dtype())?;
+                let moe = residual_input
+                    .apply(&self.post_attention_layernorm)?
+                    .apply(&self.block_sparse_moe)?
+                    .to_dtype(residual_attn.dtype())?;
+                (residual_attn + dense)? + moe
+            }
+            None => {
+                let xs = residual_attn
+                    .apply(&self.post_attention_layernorm)?
+                    .apply(&self.block_sparse_moe)?
+                    .to_dtype(residual_attn.dtype())?;
+                residual_attn + xs
+            }
+        }
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    input_layernorm: RmsNorm,
+    ffn: FeedForward,
+}
+
+impl DecoderLayer {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+        paged_attn: Option<PagedAttention>,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(
+            rotary_emb,
+            cfg,
+            mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq),
+            paged_attn,
+        )?;
+        let input_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("input_layernorm"), false),
+        )?;
+        let ffn = FeedForward::new(cfg, vb, mapper, layer_idx, loading_isq)?;
+        Ok(Self {
+            self_attn,
+            input_layernorm,
+            ffn,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut KvCache,
+        metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let attn = self.self_attn.forward(
+            &xs.apply(&self.input_layernorm)?,
+            attention_mask,
+            seqlen_offsets,
+            start_offsets_kernel,
+            kv_cache,
+            metadata,
+            flash_params,
+        )?;
+        let residual_attn = (attn + residual)?;
+        self.ffn.forward(residual, &residual_attn)
+    }
+}
+
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Arc<dyn QuantMethod>,
+    sliding_window: Option<usize>,
+    device: Device,
+    cache: EitherCache,
+    max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    cfg: ModelConfigMetadata,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        is_gptx: bool,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Self> {
+        if let Some(ref quant_cfg) = &cfg.quantization_config {
+            tracing::info!(
+                "Using {} quantization: {}.",
+                quant_cfg.quant_method.to_string(),
+                quant_cfg.get_bits_name(&vb)
+            );
+        }
+        let mapper = normal_loading_metadata.mapper;
+        let vb_m = vb.pp("model");
+
+        let embed_tokens = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_m.pp("embed_tokens"), false),
+        )?;
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let mut ropes = HashMap::new();
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let device = mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&normal_loading_metadata.real_device);
+            ropes.insert(
+                device.location(),
+                Arc::new(RotaryEmbedding::new(
+                    cfg.rope_theta as f32,
+                    head_dim,
+                    cfg.max_position_embeddings,
+                    device,
+                    is_gptx,
+                    vb_m.dtype(),
+                )?),
+            );
+        }
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in
+            NiceProgressBar::<_, 'b'>(0..cfg.num_hidden_layers, "Loading repeating layers")
+        {
+            let device = mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&normal_loading_metadata.real_device);
+            let rotary_emb = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+            let paged_attn = match &attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => Some(PagedAttention::new(
+                    cfg.num_attention_heads,
+                    head_dim,
+                    (1.0 / (head_dim as f64).sqrt()) as f32,
+                    Some(cfg.num_key_value_heads),
+                    cfg.sliding_window,
+                    device,
+                    None,
+                )?),
+            };
+            let layer = DecoderLayer::new(
+                rotary_emb.clone(),
+                cfg,
+                vb_l.pp(layer_idx),
+                &*mapper,
+                layer_idx,
+                normal_loading_metadata.loading_isq,
+                paged_attn,
+            )?;
+            layers.push(layer)
+        }
+        let norm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_nm_device(vb_m.pp("norm"), false),
+        )?;
+        let lm_head = if !cfg.tie_word_embeddings {
+            mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                &None,
+                mapper.set_nm_device(vb.pp("lm_head"), normal_loading_metadata.loading_isq),
+            )?
+        } else {
+            Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                candle_nn::Linear::new(
+                    mapper.cast_nm_device(
+                        embed_tokens.embeddings(),
+                        normal_loading_metadata.loading_isq,
+                    )?,
+                    None,
+                ),
+            ))?)
+        };
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            sliding_window: cfg.sliding_window,
+            device: normal_loading_metadata.real_device,
+            cache: EitherCache::Normal(NormalCache::new(
+                cfg.num_hidden_layers,
+                cfg.max_position_embeddings,
+            )),
+            max_seq_len: cfg.max_position_embeddings,
+            mapper,
+            cfg: ModelConfigMetadata {
+                num_layers: cfg.num_hidden_layers,
+                hidden_size: cfg.hidden_size,
+                num_kv_heads: cfg.num_key_value_heads,
+                num_attn_heads: cfg.num_attention_heads,
+                sliding_window: cfg.sliding_window,
+                head_dim: None,
+            },
+        })
+    }
+
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        let cache = &mut self.cache.normal().0;
+        let attention_mask = CausalMasker.make_sliding_window_causal_mask_matrix(
+            input_ids,
+            metadata
+                .as_ref()
+                .map(|(_, _)| &seqlen_offsets as &dyn PastKvLenCache)
+                .unwrap_or(cache as &dyn PastKvLenCache),
+            self.sliding_window,
+            xs.dtype(),
+            self.cfg.num_attn_heads,
+        )?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(
+                &xs,
+                attention_mask
+                    .as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                seqlen_offsets,
+                start_offsets_kernel.clone(),
+                &mut cache[i],
+                metadata
+                    .as_mut()
+                    .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
+                flash_params,
+            )?;
+        }
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.norm)?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+    }
+}
+
+impl IsqModel for Model {
+    fn get_layers(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((&mut layer.self_attn.q_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.k_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.v_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
+            match &mut layer.ffn.block_sparse_moe {
+                ArcticMoe::Moe(moe) => {
+                    tensors.push((&mut moe.gate, Some(i)));
+                    for expert in &mut moe.experts {
+                        tensors.push((&mut expert.w1, Some(i)));
+                        tensors.push((&mut expert.w2, Some(i)));
+                        tensors.push((&mut expert.w3, Some(i)));
+                    }
+                }
+                ArcticMoe::Dense(mlp) => {
+                    tensors.push((&mut mlp.w1, Some(i)));
+                    tensors.push((&mut mlp.w2, Some(i)));
+                    tensors.push((&mut mlp.w3, Some(i)));
+                }
+            }
+            if let Some(residual) = &mut layer.ffn.residual {
+                tensors.push((&mut residual.residual_mlp.w1, Some(i)));
+                tensors.push((&mut residual.residual_mlp.w2, Some(i)));
+                tensors.push((&mut residual.residual_mlp.w3, Some(i)));
+            }
+        }
+        (tensors, &*self.mapper)
+    }
+
+    fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+        let uvb = UnVarBuilder::new();
+
+        let uvb_m = uvb.pp("model");
+        uvb_m.pp("embed_tokens").add(&self.embed_tokens);
+        uvb_m.pp("norm").add(&self.norm);
+
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let uvb_l = uvb_m.pp("layers").pp(layer_idx);
+            uvb_l.pp("input_layernorm").add(&layer.input_layernorm);
+            uvb_l
+                .pp("post_attention_layernorm")
+                .add(&layer.ffn.post_attention_layernorm);
+            if let Some(residual) = &layer.ffn.residual {
+                uvb_l
+                    .pp("residual_layernorm")
+                    .add(&residual.residual_layernorm);
+            }
+        }
+
+        uvb.to_safetensors()
+    }
+}
+
+impl NormalModel for Model {
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            context_lens,
+            metadata,
+            flash_params,
+        )
+    }
+    fn xlora_forward(
+        &self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _start_offsets_kernel: Tensor,
+        _start_offsets_kernel_full: Tensor,
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _flash_params: &FlashParams,
+        _flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        unimplemented!()
+    }
+    fn cache(&self) -> &EitherCache {
+        &self.cache
+    }
+    fn cache_mut(&mut self) -> &mut EitherCache {
+        &mut self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn config(&self) -> &ModelConfigMetadata {
+        &self.cfg
+    }
+}
+
+impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Module, Result, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{ArcticMoe, Config, FeedForward};
+    use crate::{layers::Activation, DeviceMapMetadata};
+
+    const HIDDEN: usize = 8;
+    const INTERMEDIATE: usize = 12;
+    const EXPERTS: usize = 4;
+
+    fn cfg() -> Config {
+        Config {
+            vocab_size: 16,
+            hidden_size: HIDDEN,
+            intermediate_size: INTERMEDIATE,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            num_key_value_heads: 2,
+            hidden_act: Activation::Silu,
+            max_position_embeddings: 32,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            sliding_window: None,
+            num_experts_per_tok: 2,
+            num_local_experts: EXPERTS,
+            moe_layer_frequency: 2,
+            parallel_attn_mlp_res: true,
+            use_residual: true,
+            use_flash_attn: false,
+            quantization_config: None,
+            tie_word_embeddings: false,
+        }
+    }
+
+    fn mlp_tensors(ws: &mut HashMap<String, Tensor>, prefix: &str, inter: usize) -> Result<()> {
+        let dev = Device::Cpu;
+        for (name, shape) in [
+            ("w1", (inter, HIDDEN)),
+            ("w2", (HIDDEN, inter)),
+            ("w3", (inter, HIDDEN)),
+        ] {
+            ws.insert(
+                format!("{prefix}.{name}.weight"),
+                Tensor::randn(0f32, 0.5, shape, &dev)?,
+            );
+        }
+        Ok(())
+    }
+
+    fn ffn_weights() -> Result<HashMap<String, Tensor>> {
+        let dev = Device::Cpu;
+        let mut ws = HashMap::new();
+        for norm in ["post_attention_layernorm", "residual_layernorm"] {
+            ws.insert(
+                format!("{norm}.weight"),
+                Tensor::randn(1f32, 0.1, HIDDEN, &dev)?,
+            );
+        }
+        ws.insert(
+            "block_sparse_moe.gate.weight".to_string(),
+            Tensor::randn(0f32, 1., (EXPERTS, HIDDEN), &dev)?,
+        );
+        for i in 0..EXPERTS {
+            mlp_tensors(
+                &mut ws,
+                &format!("block_sparse_moe.experts.{i}"),
+                INTERMEDIATE,
+            )?;
+        }
+        mlp_tensors(&mut ws, "block_sparse_moe.mlp", INTERMEDIATE)?;
+        mlp_tensors(&mut ws, "residual_mlp", HIDDEN)?;
+        Ok(ws)
+    }
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    #[test]
+    fn test_parallel_branches() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let cfg = cfg();
+        let mapper = DeviceMapMetadata::dummy()
+            .into_mapper(cfg.num_hidden_layers, &dev, None)
+            .unwrap();
+        let input = Tensor::randn(0f32, 1., (2, 3, HIDDEN), &dev)?;
+        let attn = Tensor::randn(0f32, 1., (2, 3, HIDDEN), &dev)?;
+
+        // Layer 0 is dense and has no residual MLP, so its checkpoint does not have the weights
+        let mut dense_weights = ffn_weights()?;
+        dense_weights.retain(|name, _| !name.starts_with("residual_"));
+        let vb = VarBuilder::from_tensors(dense_weights, DType::F32, &dev);
+        let ffn = FeedForward::new(&cfg, vb, &*mapper, 0, false)?;
+        assert!(matches!(ffn.block_sparse_moe, ArcticMoe::Dense(_)));
+        assert!(ffn.residual.is_none());
+        let mlp = ffn
+            .block_sparse_moe
+            .forward(&ffn.post_attention_layernorm.forward(&attn)?)?;
+        let out = ffn.forward(&input, &attn)?;
+        assert!(max_diff(&out, &(&attn + mlp)?)? < 1e-5);
+
+        // Layer 1 routes to the experts, in parallel to the residual MLP
+        let vb = VarBuilder::from_tensors(ffn_weights()?, DType::F32, &dev);
+        let ffn = FeedForward::new(&cfg, vb, &*mapper, 1, false)?;
+        assert!(matches!(ffn.block_sparse_moe, ArcticMoe::Moe(_)));
+        let residual = ffn.residual.as_ref().expect("Expected a residual MLP");
+        let dense = residual
+            .residual_mlp
+            .forward(&residual.residual_layernorm.forward(&attn)?)?;
+        let moe = ffn
+            .block_sparse_moe
+            .forward(&ffn.post_attention_layernorm.forward(&input)?)?;
+        assert_eq!(dense.dims(), attn.dims());
+        assert_eq!(moe.dims(), input.dims());
+
+        let out = ffn.forward(&input, &attn)?;
+        assert_eq!(out.dims(), &[2, 3, HIDDEN]);
+        assert!(max_diff(&out, &((&attn + dense)? + moe)?)? < 1e-5);
+
+        // Without `use_residual`, the MoE layers do not have it either
+        let cfg = Config {
+            use_residual: false,
+            ..cfg
+        };
+        let vb = VarBuilder::from_tensors(ffn_weights()?, DType::F32, &dev);
+        let ffn = FeedForward::new(&cfg, vb, &*mapper, 1, false)?;
+        assert!(ffn.residual.is_none());
+        Ok(())
+    }
+}
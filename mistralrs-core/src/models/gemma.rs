@@ -31,6 +31,7 @@ fn default_max_position_embeddings() -> usize {
 }
 
 serde_default_fn!(bool, word_emb_default, false);
+serde_default_fn!(bool, attention_softmax_f32_default, true);
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
 pub struct Config {
@@ -55,6 +56,9 @@ pub struct Config {
     #[serde(default = "word_emb_default")]
     #[allow(dead_code)]
     pub tie_word_embeddings: bool,
+    /// Run the attention QK^T and softmax in f32 when the model is in f16/bf16.
+    #[serde(default = "attention_softmax_f32_default")]
+    pub attention_softmax_f32: bool,
 }
 
 impl Config {
@@ -243,6 +247,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: None,
+                attention_softmax_f32: cfg.attention_softmax_f32,
             },
         })
     }
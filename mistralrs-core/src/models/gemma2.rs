@@ -48,6 +48,8 @@ pub struct Config {
     pub use_flash_attn: bool,
     #[allow(dead_code)]
     pub tie_word_embeddings: bool,
+    /// Run the attention QK^T and softmax in f32 when the model is in f16/bf16.
+    pub attention_softmax_f32: bool,
 }
 
 impl Config {
@@ -250,6 +252,7 @@ impl Attention {
                 softcap: cfg.attn_logit_softcapping.map(|x| x as f32),
                 softmax_scale: 1.0 / (cfg.query_pre_attn_scalar as f32).sqrt(),
                 sliding_window,
+                attention_softmax_f32: cfg.attention_softmax_f32,
             },
         })
     }
@@ -20,7 +20,7 @@ use crate::{
     pipeline::{
         extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        EitherCache, IsqModel, KvCache, NormalCache, NormalLoadingMetadata, NormalModel,
+        EitherCache, IsqModel, KvCacheBackend, NormalCache, NormalLoadingMetadata, NormalModel,
     },
     serde_default_fn,
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
@@ -46,6 +46,10 @@ pub struct Config {
     pub quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     pub tie_word_embeddings: bool,
+    /// Drop the causal mask so that every position attends to all others, for embedding
+    /// fine-tunes with bidirectional attention. This is not suitable for generation.
+    #[serde(default)]
+    pub bidirectional: bool,
 }
 
 struct CausalSelfAttention {
@@ -70,7 +74,7 @@ impl CausalSelfAttention {
         attention_mask: &Option<Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        kv_cache: &mut KvCache,
+        kv_cache: &mut impl KvCacheBackend,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -170,7 +174,9 @@ impl CausalSelfAttention {
         if let Some(t) = self.q_proj.quantized_act_type() {
             y = y.to_dtype(t)?;
         }
-        y = if attention_mask.is_some() {
+        // The output is `(b_sz, heads, seq_len, head_dim)`, which only needs the transpose when
+        // there are several tokens. The mask is not a proxy for this: bidirectional prompts have none.
+        y = if seq_len != 1 {
             y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?
         } else {
             y.reshape((b_sz, seq_len, ()))?
@@ -191,24 +197,14 @@ impl CausalSelfAttention {
         let size_in = cfg.hidden_size;
         let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
         let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = mistralrs_quant::linear_no_bias(
-            size_in,
-            size_q,
-            &cfg.quantization_config,
-            vb.pp("q_proj"),
-        )?;
-        let k_proj = mistralrs_quant::linear_no_bias(
-            size_in,
-            size_kv,
-            &cfg.quantization_config,
-            vb.pp("k_proj"),
-        )?;
-        let v_proj = mistralrs_quant::linear_no_bias(
-            size_in,
-            size_kv,
-            &cfg.quantization_config,
-            vb.pp("v_proj"),
-        )?;
+        // The SmoothQuant scales of the layers reading the input norm are folded into it
+        let qkv_quant_cfg = QuantizedConfig::with_smooth_scales_folded(&cfg.quantization_config);
+        let q_proj =
+            mistralrs_quant::linear_no_bias(size_in, size_q, &qkv_quant_cfg, vb.pp("q_proj"))?;
+        let k_proj =
+            mistralrs_quant::linear_no_bias(size_in, size_kv, &qkv_quant_cfg, vb.pp("k_proj"))?;
+        let v_proj =
+            mistralrs_quant::linear_no_bias(size_in, size_kv, &qkv_quant_cfg, vb.pp("v_proj"))?;
         let o_proj = mistralrs_quant::linear_no_bias(
             size_q,
             size_in,
@@ -228,10 +224,12 @@ impl CausalSelfAttention {
             paged_attn,
             sdpa_params: SdpaParams {
                 n_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
-                use_flash_attn: cfg.use_flash_attn,
+                // The flash attention kernels are always causal for prompts
+                use_flash_attn: cfg.use_flash_attn && !cfg.bidirectional,
                 softcap: None,
                 softmax_scale: 1.0 / ((cfg.hidden_size / cfg.num_attention_heads) as f32).sqrt(),
                 sliding_window: None,
+                attention_softmax_f32: false,
             },
         })
     }
@@ -249,18 +247,12 @@ impl Mlp {
     fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
-        let c_fc1 = mistralrs_quant::linear_no_bias(
-            h_size,
-            i_size,
-            &cfg.quantization_config,
-            vb.pp("gate_proj"),
-        )?;
-        let c_fc2 = mistralrs_quant::linear_no_bias(
-            h_size,
-            i_size,
-            &cfg.quantization_config,
-            vb.pp("up_proj"),
-        )?;
+        // The SmoothQuant scales of the layers reading the post attention norm are folded into it
+        let fc_quant_cfg = QuantizedConfig::with_smooth_scales_folded(&cfg.quantization_config);
+        let c_fc1 =
+            mistralrs_quant::linear_no_bias(h_size, i_size, &fc_quant_cfg, vb.pp("gate_proj"))?;
+        let c_fc2 =
+            mistralrs_quant::linear_no_bias(h_size, i_size, &fc_quant_cfg, vb.pp("up_proj"))?;
         let c_proj = mistralrs_quant::linear_no_bias(
             i_size,
             h_size,
@@ -348,13 +340,13 @@ impl Block {
         attention_mask: &Option<Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        kv_cache: &mut KvCache,
+        kv_cache: &mut impl KvCacheBackend,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let residual = x;
         let x = self.rms_1.forward(x)?;
-        let x = (self.attn.forward(
+        let x = self.attn.forward(
             &x,
             attention_mask,
             seqlen_offsets,
@@ -362,9 +354,9 @@ impl Block {
             kv_cache,
             metadata,
             flash_params,
-        )? + residual)?;
-        let residual = &x;
-        let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
+        )?;
+        let (residual, x) = self.rms_2.add_forward(&x, residual)?;
+        let x = (self.mlp.forward(&x)? + residual)?;
         Ok(x)
     }
 
@@ -388,11 +380,19 @@ impl Block {
             cfg.hidden_size,
             cfg.rms_norm_eps,
             mapper.set_device(layer_idx, vb.pp("input_layernorm"), false),
+        )?
+        .fold_smooth_scales(
+            &cfg.quantization_config,
+            &["q_proj", "k_proj", "v_proj"].map(|l| vb.pp("self_attn").pp(l)),
         )?;
         let rms_2 = RmsNorm::new(
             cfg.hidden_size,
             cfg.rms_norm_eps,
             mapper.set_device(layer_idx, vb.pp("post_attention_layernorm"), false),
+        )?
+        .fold_smooth_scales(
+            &cfg.quantization_config,
+            &["gate_proj", "up_proj"].map(|l| vb.pp("mlp").pp(l)),
         )?;
         Ok(Self {
             rms_1,
@@ -412,6 +412,7 @@ pub struct Llama {
     device: Device,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
     cfg: ModelConfigMetadata,
+    bidirectional: bool,
 }
 
 impl Llama {
@@ -550,6 +551,7 @@ impl Llama {
                 sliding_window: None,
                 head_dim: None,
             },
+            bidirectional: cfg.bidirectional,
         })
     }
 
@@ -585,12 +587,44 @@ impl Llama {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let x = self.forward_hidden_embeds(
+            input_ids,
+            input_embeds,
+            seqlen_offsets,
+            start_offsets_kernel,
+            metadata,
+            flash_params,
+            self.blocks.len(),
+        )?;
+        self.logits(x, context_lens)
+    }
+
+    fn logits(&self, mut x: Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            x = x.to_dtype(t)?;
+        }
+        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
+        extract_logits(&xs, context_lens)
+    }
+
+    /// The hidden states after the first `num_layers` layers, after the final norm.
+    #[allow(clippy::too_many_arguments)]
+    fn forward_hidden_embeds(
+        &self,
+        input_ids: &Tensor,
+        input_embeds: Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
+        num_layers: usize,
     ) -> Result<Tensor> {
         let mut x = input_embeds;
         let cache = &mut self.kv_cache.normal().0;
-        let mask = CausalMasker.make_causal_mask_matrix(
+        let mask = CausalMasker.make_attention_mask_matrix(
             input_ids,
             metadata
                 .as_ref()
@@ -598,8 +632,9 @@ impl Llama {
                 .unwrap_or(cache as &dyn PastKvLenCache),
             x.dtype(),
             self.blocks[0].attn.num_attention_heads,
+            self.bidirectional,
         )?;
-        for (block_idx, block) in self.blocks.iter().enumerate() {
+        for (block_idx, block) in self.blocks.iter().take(num_layers).enumerate() {
             x = self.mapper.map(x, block_idx)?;
             x = block.forward(
                 &x,
@@ -614,12 +649,7 @@ impl Llama {
             )?;
         }
         let x = x.to_device(&self.device)?;
-        let mut x = self.ln_f.forward(&x)?;
-        if let Some(t) = self.lm_head.quantized_act_type() {
-            x = x.to_dtype(t)?;
-        }
-        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
-        extract_logits(&xs, context_lens)
+        self.ln_f.forward(&x)
     }
 }
 
@@ -665,6 +695,21 @@ impl IsqModel for Llama {
         uvb.to_safetensors()
     }
 
+    fn layer_names(&self) -> candle_core::Result<Vec<String>> {
+        // NOTE: dependant on the exact implementation in get_layers!
+        let mut names = vec!["lm_head".to_string()];
+        for i in 0..self.blocks.len() {
+            let p = format!("model.layers.{i}");
+            for proj in ["q_proj", "k_proj", "v_proj", "o_proj"] {
+                names.push(format!("{p}.self_attn.{proj}"));
+            }
+            for proj in ["gate_proj", "up_proj", "down_proj"] {
+                names.push(format!("{p}.mlp.{proj}"));
+            }
+        }
+        Ok(names)
+    }
+
     fn imatrix_names(&self) -> candle_core::Result<Vec<Option<String>>> {
         // NOTE: dependant on the exact implementation in get_layers!
         let mut names = Vec::new();
@@ -703,6 +748,44 @@ impl NormalModel for Llama {
             flash_params,
         )
     }
+    fn forward_hidden(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward_hidden_embeds(
+            input_ids,
+            self.wte.forward(input_ids)?,
+            seqlen_offsets,
+            start_offsets_kernel,
+            None,
+            flash_params,
+            self.blocks.len(),
+        )
+    }
+    fn forward_early_exit(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+        num_layers: usize,
+    ) -> Result<Tensor> {
+        let x = self.forward_hidden_embeds(
+            input_ids,
+            self.wte.forward(input_ids)?,
+            seqlen_offsets,
+            start_offsets_kernel,
+            metadata,
+            flash_params,
+            num_layers,
+        )?;
+        self.logits(x, context_lens)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -861,3 +944,252 @@ impl AnyMoeBaseModelMixin for Llama {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{CausalSelfAttention, Config, Llama};
+    use crate::{
+        attention::record_attention_weights,
+        layers::{Llama3RotaryEmbedding, MatMul},
+        paged_attention::AttentionImplementation,
+        pipeline::{
+            text_models_inputs_processor::FlashParams, KvCache, NormalLoadingMetadata, NormalModel,
+        },
+        DeviceMapMetadata,
+    };
+
+    fn cfg() -> Config {
+        Config {
+            hidden_size: 16,
+            intermediate_size: 32,
+            vocab_size: 32,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            use_flash_attn: false,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            max_position_embeddings: 64,
+            rope_scaling: None,
+            quantization_config: None,
+            tie_word_embeddings: false,
+            bidirectional: false,
+        }
+    }
+
+    fn weights(cfg: &Config) -> Result<HashMap<String, Tensor>> {
+        let dev = Device::Cpu;
+        let (h, i, v) = (cfg.hidden_size, cfg.intermediate_size, cfg.vocab_size);
+        let kv = h / cfg.num_attention_heads * cfg.num_key_value_heads;
+        let mut shapes = vec![
+            ("model.embed_tokens.weight".to_string(), vec![v, h]),
+            ("model.norm.weight".to_string(), vec![h]),
+            ("lm_head.weight".to_string(), vec![v, h]),
+        ];
+        for layer in 0..cfg.num_hidden_layers {
+            let p = format!("model.layers.{layer}");
+            shapes.extend([
+                (format!("{p}.self_attn.q_proj.weight"), vec![h, h]),
+                (format!("{p}.self_attn.k_proj.weight"), vec![kv, h]),
+                (format!("{p}.self_attn.v_proj.weight"), vec![kv, h]),
+                (format!("{p}.self_attn.o_proj.weight"), vec![h, h]),
+                (format!("{p}.mlp.gate_proj.weight"), vec![i, h]),
+                (format!("{p}.mlp.up_proj.weight"), vec![i, h]),
+                (format!("{p}.mlp.down_proj.weight"), vec![h, i]),
+                (format!("{p}.input_layernorm.weight"), vec![h]),
+                (format!("{p}.post_attention_layernorm.weight"), vec![h]),
+            ]);
+        }
+        shapes
+            .into_iter()
+            .map(|(name, shape)| Ok((name, Tensor::randn(0f32, 0.3, shape, &dev)?)))
+            .collect()
+    }
+
+    fn model(cfg: &Config, weights: HashMap<String, Tensor>) -> Result<Llama> {
+        let dev = Device::Cpu;
+        Llama::new(
+            cfg,
+            VarBuilder::from_tensors(weights, DType::F32, &dev),
+            true,
+            NormalLoadingMetadata {
+                mapper: DeviceMapMetadata::dummy().into_mapper(
+                    cfg.num_hidden_layers,
+                    &dev,
+                    None,
+                )?,
+                loading_isq: false,
+                real_device: dev.clone(),
+            },
+            AttentionImplementation::Eager,
+        )
+    }
+
+    fn logits(cfg: &Config, weights: HashMap<String, Tensor>) -> Result<Tensor> {
+        let dev = Device::Cpu;
+        let model = model(cfg, weights)?;
+        let input_ids = Tensor::new(&[[1u32, 5, 7, 2, 9]], &dev)?;
+        let flash_params = FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: Tensor::zeros(1, DType::U32, &dev)?,
+            cumulative_seqlens_k: Tensor::zeros(1, DType::U32, &dev)?,
+        };
+        model.forward(
+            &input_ids,
+            &[0],
+            Tensor::new(&[0i64], &dev)?,
+            vec![(0, 5)],
+            None,
+            &flash_params,
+        )
+    }
+
+    fn flash_params(dev: &Device) -> Result<FlashParams> {
+        Ok(FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: Tensor::zeros(1, DType::U32, dev)?,
+            cumulative_seqlens_k: Tensor::zeros(1, DType::U32, dev)?,
+        })
+    }
+
+    #[test]
+    fn test_bidirectional_attention_matches_unmasked_reference() -> Result<()> {
+        let dev = Device::Cpu;
+        let cfg = Config {
+            num_key_value_heads: 4,
+            bidirectional: true,
+            ..cfg()
+        };
+        let (b_sz, seq_len, heads) = (1, 5, cfg.num_attention_heads);
+        let head_dim = cfg.hidden_size / heads;
+        let vb = VarBuilder::from_tensors(weights(&cfg)?, DType::F32, &dev);
+        let rope = Arc::new(Llama3RotaryEmbedding::new_llama3(
+            DType::F32,
+            &cfg,
+            &dev,
+            true,
+        )?);
+        let attn =
+            CausalSelfAttention::load(vb.pp("model.layers.0.self_attn"), &cfg, rope.clone(), None)?;
+
+        let x = Tensor::randn(0f32, 1., (b_sz, seq_len, cfg.hidden_size), &dev)?;
+        // Bidirectional prompts have no mask
+        let out = attn.forward(
+            &x,
+            &None,
+            &[0],
+            Tensor::new(&[0i64], &dev)?,
+            &mut KvCache::new(2, cfg.max_position_embeddings, 16),
+            None,
+            &flash_params(&dev)?,
+        )?;
+
+        // Unmasked attention over all positions, computed explicitly
+        let proj = |layer: &Arc<dyn mistralrs_quant::QuantMethod>| -> Result<Tensor> {
+            MatMul
+                .qmethod_matmul(&x, &**layer)?
+                .reshape((b_sz * seq_len, heads, head_dim))
+        };
+        let (mut q, mut k) = (proj(&attn.q_proj)?, proj(&attn.k_proj)?);
+        rope.forward(&[0], &Tensor::new(&[0i64], &dev)?, &mut q, &mut k, b_sz)?;
+        let to_heads = |t: Tensor| -> Result<Tensor> {
+            if t.rank() == 3 {
+                t.reshape((b_sz, seq_len, heads, head_dim))?.transpose(1, 2)
+            } else {
+                Ok(t)
+            }
+        };
+        let q = to_heads(q)?.contiguous()?;
+        let k = to_heads(k)?.contiguous()?;
+        let v = to_heads(proj(&attn.v_proj)?)?.contiguous()?;
+        let scores = (q.matmul(&k.t()?)? / (head_dim as f64).sqrt())?;
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let y = probs
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, cfg.hidden_size))?;
+        let expected = MatMul.qmethod_matmul(&y, &*attn.o_proj)?;
+
+        assert_eq!(out.dims(), expected.dims());
+        let diff = (out - expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "{diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_attention_weights_shape() -> Result<()> {
+        let cfg = cfg();
+        let weights = weights(&cfg)?;
+        let expected = logits(&cfg, weights.clone())?;
+
+        let (out, attn) = record_attention_weights(|| logits(&cfg, weights))?;
+        // Recording falls back to the dense path, which matches the regular forward
+        let diff = (out - &expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "{diff}");
+
+        assert_eq!(
+            attn.dims(),
+            &[cfg.num_hidden_layers, cfg.num_attention_heads, 5, 5]
+        );
+        // Each row is a causal probability distribution
+        let sums = attn.sum(3)?.flatten_all()?.to_vec1::<f32>()?;
+        assert!(sums.iter().all(|s| (s - 1.).abs() < 1e-5));
+        let future = attn
+            .narrow(2, 0, 1)?
+            .narrow(3, 1, 4)?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert_eq!(future, 0.);
+        Ok(())
+    }
+
+    #[test]
+    fn test_early_exit_matches_truncated_model() -> Result<()> {
+        let dev = Device::Cpu;
+        let cfg = cfg();
+        let weights = weights(&cfg)?;
+        let input_ids = Tensor::new(&[[1u32, 5, 7, 2, 9]], &dev)?;
+        let early = model(&cfg, weights.clone())?.forward_early_exit(
+            &input_ids,
+            &[0],
+            Tensor::new(&[0i64], &dev)?,
+            vec![(0, 5)],
+            None,
+            &flash_params(&dev)?,
+            1,
+        )?;
+
+        // The first layer with the final norm and LM head of the full model
+        let truncated = Config {
+            num_hidden_layers: 1,
+            ..cfg()
+        };
+        let expected = logits(&truncated, weights.clone())?;
+        let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+            (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+        };
+        let diff = max_diff(&early, &expected)?;
+        assert!(diff < 1e-4, "{diff}");
+
+        // The skipped layer changes the logits
+        let full = logits(&cfg, weights)?;
+        assert!(max_diff(&full, &early)? > 1e-3);
+        Ok(())
+    }
+}
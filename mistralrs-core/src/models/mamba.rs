@@ -0,0 +1,755 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+/// Mamba Model
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/mamba/modeling_mamba.py
+///
+/// Mamba has no attention. Each layer keeps a recurrent state instead of a KV cache: the last
+/// `conv_kernel - 1` inputs of its causal convolution and the state of its selective scan. The
+/// states have a constant size, so decoding uses constant memory however long the context is.
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::VarBuilder;
+use mistralrs_quant::{QuantMethod, QuantMethodConfig, QuantizedConfig, UnquantLinear};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::{
+    amoe::AnyMoeBaseModelMixin,
+    device_map::DeviceMapper,
+    layers::{MatMul, RmsNorm},
+    paged_attention::ModelConfigMetadata,
+    pipeline::{
+        extract_logits,
+        text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
+        Cache, EitherCache, IsqModel, NormalLoadingMetadata, NormalModel,
+    },
+    utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
+};
+
+/// Mamba has no position embeddings, so the context length is only bounded by memory.
+const MAX_SEQ_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) state_size: usize,
+    pub(crate) conv_kernel: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) time_step_rank: usize,
+    pub(crate) layer_norm_epsilon: f64,
+    pub(crate) use_bias: bool,
+    pub(crate) use_conv_bias: bool,
+    pub(crate) quantization_config: Option<QuantizedConfig>,
+    pub(crate) tie_word_embeddings: bool,
+}
+
+fn project(xs: &Tensor, layer: &dyn QuantMethod) -> Result<Tensor> {
+    let original_dtype = xs.dtype();
+    let mut xs = xs.clone();
+    if let Some(t) = layer.quantized_act_type() {
+        xs = xs.to_dtype(t)?;
+    }
+    let mut res = MatMul.qmethod_matmul(&xs, layer)?;
+    if layer.quantized_act_type().is_some() {
+        res = res.to_dtype(original_dtype)?;
+    }
+    Ok(res)
+}
+
+fn softplus(xs: &Tensor) -> Result<Tensor> {
+    // Linear above the threshold, as in PyTorch, where `exp` would overflow
+    let sp = (xs.exp()? + 1.)?.log()?;
+    xs.ge(20f64)?.where_cond(xs, &sp)
+}
+
+struct MambaMixer {
+    in_proj: Arc<dyn QuantMethod>,
+    conv1d_weight: Tensor,
+    conv1d_bias: Option<Tensor>,
+    x_proj: Arc<dyn QuantMethod>,
+    dt_proj: Arc<dyn QuantMethod>,
+    a_log: Tensor,
+    d: Tensor,
+    out_proj: Arc<dyn QuantMethod>,
+    intermediate_size: usize,
+    state_size: usize,
+    conv_kernel: usize,
+    time_step_rank: usize,
+}
+
+impl MambaMixer {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let (h, i) = (cfg.hidden_size, cfg.intermediate_size);
+        let in_proj = mistralrs_quant::linear_b(
+            h,
+            2 * i,
+            cfg.use_bias,
+            &cfg.quantization_config,
+            vb.pp("in_proj"),
+        )?;
+        let vb_conv = vb.pp("conv1d");
+        let conv1d_weight = vb_conv.get((i, 1, cfg.conv_kernel), "weight")?.squeeze(1)?;
+        let conv1d_bias = if cfg.use_conv_bias {
+            Some(vb_conv.get(i, "bias")?.unsqueeze(1)?)
+        } else {
+            None
+        };
+        let x_proj = mistralrs_quant::linear_no_bias(
+            i,
+            cfg.time_step_rank + 2 * cfg.state_size,
+            &cfg.quantization_config,
+            vb.pp("x_proj"),
+        )?;
+        let dt_proj = mistralrs_quant::linear(
+            cfg.time_step_rank,
+            i,
+            &cfg.quantization_config,
+            vb.pp("dt_proj"),
+        )?;
+        let a_log = vb.get((i, cfg.state_size), "A_log")?;
+        let d = vb.get(i, "D")?;
+        let out_proj = mistralrs_quant::linear_b(
+            i,
+            h,
+            cfg.use_bias,
+            &cfg.quantization_config,
+            vb.pp("out_proj"),
+        )?;
+        Ok(Self {
+            in_proj,
+            conv1d_weight,
+            conv1d_bias,
+            x_proj,
+            dt_proj,
+            a_log,
+            d,
+            out_proj,
+            intermediate_size: i,
+            state_size: cfg.state_size,
+            conv_kernel: cfg.conv_kernel,
+            time_step_rank: cfg.time_step_rank,
+        })
+    }
+
+    /// The depthwise causal convolution of `xs`, `(batch, intermediate_size, conv_kernel - 1 +
+    /// seq_len)`, which is prefixed by the convolution state.
+    fn conv(&self, xs: &Tensor, seq_len: usize) -> Result<Tensor> {
+        let weight = self.conv1d_weight.to_dtype(xs.dtype())?;
+        let mut ys = xs
+            .narrow(2, 0, seq_len)?
+            .broadcast_mul(&weight.narrow(1, 0, 1)?)?;
+        for k in 1..self.conv_kernel {
+            ys = (ys
+                + xs.narrow(2, k, seq_len)?
+                    .broadcast_mul(&weight.narrow(1, k, 1)?)?)?;
+        }
+        match &self.conv1d_bias {
+            Some(bias) => ys.broadcast_add(&bias.to_dtype(ys.dtype())?),
+            None => Ok(ys),
+        }
+    }
+
+    /// The selective scan of `u`, `(batch, seq_len, intermediate_size)`, starting from `state`.
+    /// Computed in F32, returns the outputs and the state after the last token.
+    fn selective_scan(&self, u: &Tensor, state: Option<Tensor>) -> Result<(Tensor, Tensor)> {
+        let (b_sz, seq_len, _) = u.dims3()?;
+        let (n, r) = (self.state_size, self.time_step_rank);
+        let x_dbl = project(u, &*self.x_proj)?;
+        let delta = project(&x_dbl.narrow(D::Minus1, 0, r)?, &*self.dt_proj)?;
+        let delta = softplus(&delta.to_dtype(DType::F32)?)?;
+        let b = x_dbl.narrow(D::Minus1, r, n)?.to_dtype(DType::F32)?;
+        let c = x_dbl.narrow(D::Minus1, r + n, n)?.to_dtype(DType::F32)?;
+        let u_f32 = u.to_dtype(DType::F32)?;
+
+        let a = self.a_log.to_dtype(DType::F32)?.exp()?.neg()?;
+        // (batch, seq_len, intermediate_size, state_size)
+        let delta_a = delta.unsqueeze(D::Minus1)?.broadcast_mul(&a)?.exp()?;
+        let delta_b_u = (&delta * &u_f32)?
+            .unsqueeze(D::Minus1)?
+            .broadcast_mul(&b.unsqueeze(2)?)?;
+
+        let mut state = match state {
+            Some(state) => state,
+            None => Tensor::zeros((b_sz, self.intermediate_size, n), DType::F32, u.device())?,
+        };
+        let mut ys = Vec::with_capacity(seq_len);
+        for t in 0..seq_len {
+            state = ((delta_a.i((.., t))? * &state)? + delta_b_u.i((.., t))?)?;
+            let c_t = c.i((.., t))?.contiguous()?.unsqueeze(D::Minus1)?;
+            ys.push(state.matmul(&c_t)?.squeeze(D::Minus1)?);
+        }
+        let ys = (Tensor::stack(&ys, 1)? + u_f32.broadcast_mul(&self.d.to_dtype(DType::F32)?)?)?;
+        Ok((ys.to_dtype(u.dtype())?, state))
+    }
+
+    /// Returns the output and the new `(conv_state, ssm_state)`.
+    fn forward(
+        &self,
+        xs: &Tensor,
+        state: Option<(Tensor, Tensor)>,
+    ) -> Result<(Tensor, (Tensor, Tensor))> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+        let i = self.intermediate_size;
+        let xs_and_res = project(xs, &*self.in_proj)?;
+        let xs = xs_and_res.narrow(D::Minus1, 0, i)?;
+        let res = xs_and_res.narrow(D::Minus1, i, i)?;
+
+        let (conv_state, ssm_state) = match state {
+            Some((conv_state, ssm_state)) => (conv_state, Some(ssm_state)),
+            None => (
+                Tensor::zeros((b_sz, i, self.conv_kernel - 1), xs.dtype(), xs.device())?,
+                None,
+            ),
+        };
+        let xs = Tensor::cat(&[&conv_state, &xs.transpose(1, 2)?], 2)?;
+        let conv_state = xs.narrow(2, seq_len, self.conv_kernel - 1)?.contiguous()?;
+        let xs = self.conv(&xs, seq_len)?.transpose(1, 2)?.silu()?;
+
+        let (ys, ssm_state) = self.selective_scan(&xs, ssm_state)?;
+        let ys = (ys * res.silu()?)?;
+        Ok((project(&ys, &*self.out_proj)?, (conv_state, ssm_state)))
+    }
+}
+
+struct MambaBlock {
+    norm: RmsNorm,
+    mixer: MambaMixer,
+}
+
+impl MambaBlock {
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let norm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_device(layer_idx, vb.pp("norm"), false),
+        )?;
+        let mixer = MambaMixer::new(
+            cfg,
+            mapper.set_device(layer_idx, vb.pp("mixer"), loading_isq),
+        )?;
+        Ok(Self { norm, mixer })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        state: Option<(Tensor, Tensor)>,
+    ) -> Result<(Tensor, (Tensor, Tensor))> {
+        let (ys, state) = self.mixer.forward(&xs.apply(&self.norm)?, state)?;
+        Ok(((xs + ys)?, state))
+    }
+}
+
+pub struct Model {
+    embeddings: candle_nn::Embedding,
+    embeddings_name: &'static str,
+    layers: Vec<MambaBlock>,
+    norm_f: RmsNorm,
+    lm_head: Arc<dyn QuantMethod>,
+    device: Device,
+    cache: EitherCache,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    cfg: ModelConfigMetadata,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+    ) -> Result<Self> {
+        let vb_m = vb.pp("backbone");
+        let vb_lm_head = vb.pp("lm_head");
+        if let Some(ref quant_cfg) = &cfg.quantization_config {
+            tracing::info!(
+                "Using {} quantization: {}.",
+                quant_cfg.quant_method.to_string(),
+                quant_cfg.get_bits_name(&vb_m)
+            );
+        }
+        let mapper = normal_loading_metadata.mapper;
+
+        // The original checkpoints name the embeddings `embedding`
+        let embeddings_name = if vb_m.contains_tensor("embedding.weight") {
+            "embedding"
+        } else {
+            "embeddings"
+        };
+        let embeddings = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_m.pp(embeddings_name), false),
+        )?;
+
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in
+            NiceProgressBar::<_, 'b'>(0..cfg.num_hidden_layers, "Loading repeating layers")
+        {
+            layers.push(MambaBlock::new(
+                cfg,
+                vb_l.pp(layer_idx),
+                &*mapper,
+                layer_idx,
+                normal_loading_metadata.loading_isq,
+            )?);
+        }
+        let norm_f = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_nm_device(vb_m.pp("norm_f"), false),
+        )?;
+        let lm_head = if !cfg.tie_word_embeddings {
+            mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                &None,
+                mapper.set_nm_device(vb_lm_head, normal_loading_metadata.loading_isq),
+            )?
+        } else {
+            Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                candle_nn::Linear::new(
+                    mapper.cast_nm_device(
+                        embeddings.embeddings(),
+                        normal_loading_metadata.loading_isq,
+                    )?,
+                    None,
+                ),
+            ))?)
+        };
+        Ok(Self {
+            embeddings,
+            embeddings_name,
+            layers,
+            norm_f,
+            lm_head,
+            device: normal_loading_metadata.real_device,
+            cache: EitherCache::Full(Cache::new(cfg.num_hidden_layers, false)),
+            mapper,
+            // There is no KV cache, only recurrent states of constant size
+            cfg: ModelConfigMetadata {
+                num_layers: cfg.num_hidden_layers,
+                hidden_size: cfg.hidden_size,
+                num_kv_heads: 1,
+                num_attn_heads: 1,
+                sliding_window: None,
+                head_dim: Some(1),
+            },
+        })
+    }
+
+    /// Run the tokens of `input_ids` from the recurrent states in the cache, or from zero states
+    /// if they are unset, and store the states after the last token in the cache. `seq_lens` are
+    /// the numbers of tokens of each sequence, which are right padded to the longest.
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        seq_lens: &[usize],
+        context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        if seq_lens.iter().all(|&len| len == seq_len) {
+            self.forward_batch(input_ids, context_lens)
+        } else {
+            self.forward_padded(input_ids, seq_lens, context_lens)
+        }
+    }
+
+    fn forward_batch(
+        &self,
+        input_ids: &Tensor,
+        context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mut xs = self.embeddings.forward(input_ids)?;
+        let mut cache = self.cache.full().lock();
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            let (ys, state) = layer.forward(&xs, cache[i].take())?;
+            xs = ys;
+            cache[i] = Some(state);
+        }
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.norm_f)?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+    }
+
+    /// The scan would run the padding into the states of the shorter sequences, so these are run
+    /// one at a time without their padding. The logits taken at the end of the padded sequences
+    /// are taken at the end of each sequence instead.
+    fn forward_padded(
+        &self,
+        input_ids: &Tensor,
+        seq_lens: &[usize],
+        context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let states = self
+            .cache
+            .full()
+            .lock()
+            .iter_mut()
+            .map(Option::take)
+            .collect::<Vec<_>>();
+        let mut logits = Vec::with_capacity(seq_lens.len());
+        let mut seq_states = vec![Vec::with_capacity(seq_lens.len()); states.len()];
+        for (b, (&len, (start, n))) in seq_lens.iter().zip(context_lens).enumerate() {
+            if n > len {
+                candle_core::bail!(
+                    "Cannot return {n} logits for a sequence of {len} tokens in a padded Mamba batch."
+                );
+            }
+            for (layer, state) in self.cache.full().lock().iter_mut().zip(&states) {
+                *layer = match state {
+                    Some((conv_state, ssm_state)) => {
+                        Some((conv_state.narrow(0, b, 1)?, ssm_state.narrow(0, b, 1)?))
+                    }
+                    None => None,
+                };
+            }
+            let input_ids = input_ids.narrow(0, b, 1)?.narrow(1, 0, len)?;
+            let start = start.saturating_sub(seq_len - len).min(len - n);
+            logits.push(self.forward_batch(&input_ids, vec![(start, n)])?);
+            for (layer, states) in self.cache.full().lock().iter_mut().zip(&mut seq_states) {
+                states.push(layer.take().expect("Expected the state of the sequence."));
+            }
+        }
+        for (layer, states) in self.cache.full().lock().iter_mut().zip(seq_states) {
+            let (conv_states, ssm_states): (Vec<_>, Vec<_>) = states.into_iter().unzip();
+            *layer = Some((Tensor::cat(&conv_states, 0)?, Tensor::cat(&ssm_states, 0)?));
+        }
+        Tensor::cat(&logits, 0)
+    }
+}
+
+impl IsqModel for Model {
+    fn get_layers(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((&mut layer.mixer.in_proj, Some(i)));
+            tensors.push((&mut layer.mixer.out_proj, Some(i)));
+        }
+        (tensors, &*self.mapper)
+    }
+
+    fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+        let uvb = UnVarBuilder::new();
+
+        let uvb_m = uvb.pp("backbone");
+        uvb_m.pp(self.embeddings_name).add(&self.embeddings);
+        uvb_m.pp("norm_f").add(&self.norm_f);
+
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let uvb_l = uvb_m.pp("layers").pp(layer_idx);
+            uvb_l.pp("norm").add(&layer.norm);
+
+            let mixer = &layer.mixer;
+            let uvb_mixer = uvb_l.pp("mixer");
+            uvb_mixer
+                .pp("conv1d")
+                .add_tensor("weight", mixer.conv1d_weight.unsqueeze(1).unwrap());
+            if let Some(bias) = &mixer.conv1d_bias {
+                uvb_mixer
+                    .pp("conv1d")
+                    .add_tensor("bias", bias.squeeze(1).unwrap());
+            }
+            uvb_mixer.pp("x_proj").add(&mixer.x_proj);
+            uvb_mixer.pp("dt_proj").add(&mixer.dt_proj);
+            uvb_mixer.add_tensor("A_log", mixer.a_log.clone());
+            uvb_mixer.add_tensor("D", mixer.d.clone());
+        }
+
+        uvb.to_safetensors()
+    }
+}
+
+impl NormalModel for Model {
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        // The position after the last token of each sequence, less the tokens already run
+        let seq_len = input_ids.dim(1)?;
+        let seq_lens = position_ids
+            .iter()
+            .zip(seqlen_offsets)
+            .map(|(&end, &offset)| end.saturating_sub(offset).clamp(1, seq_len))
+            .collect::<Vec<_>>();
+        self.forward(input_ids, &seq_lens, context_lens)
+    }
+    fn xlora_forward(
+        &self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _start_offsets_kernel: Tensor,
+        _start_offsets_kernel_full: Tensor,
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _flash_params: &FlashParams,
+        _flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        unimplemented!()
+    }
+    fn cache(&self) -> &EitherCache {
+        &self.cache
+    }
+    fn cache_mut(&mut self) -> &mut EitherCache {
+        &mut self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn is_recurrent(&self) -> bool {
+        true
+    }
+    fn max_seq_len(&self) -> usize {
+        MAX_SEQ_LEN
+    }
+    fn config(&self) -> &ModelConfigMetadata {
+        &self.cfg
+    }
+}
+
+impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, IndexOp, Result, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{Config, MambaMixer, Model};
+    use crate::{pipeline::NormalLoadingMetadata, DeviceMapMetadata};
+
+    fn cfg() -> Config {
+        Config {
+            vocab_size: 32,
+            hidden_size: 16,
+            num_hidden_layers: 2,
+            state_size: 4,
+            conv_kernel: 4,
+            intermediate_size: 32,
+            time_step_rank: 2,
+            layer_norm_epsilon: 1e-5,
+            use_bias: false,
+            use_conv_bias: true,
+            quantization_config: None,
+            tie_word_embeddings: false,
+        }
+    }
+
+    fn model(cfg: &Config) -> Result<Model> {
+        let dev = Device::Cpu;
+        let (h, i, v) = (cfg.hidden_size, cfg.intermediate_size, cfg.vocab_size);
+        let (n, r, k) = (cfg.state_size, cfg.time_step_rank, cfg.conv_kernel);
+        let mut shapes = vec![
+            ("backbone.embeddings.weight".to_string(), vec![v, h]),
+            ("backbone.norm_f.weight".to_string(), vec![h]),
+            ("lm_head.weight".to_string(), vec![v, h]),
+        ];
+        for layer in 0..cfg.num_hidden_layers {
+            let p = format!("backbone.layers.{layer}");
+            shapes.extend([
+                (format!("{p}.norm.weight"), vec![h]),
+                (format!("{p}.mixer.in_proj.weight"), vec![2 * i, h]),
+                (format!("{p}.mixer.conv1d.weight"), vec![i, 1, k]),
+                (format!("{p}.mixer.conv1d.bias"), vec![i]),
+                (format!("{p}.mixer.x_proj.weight"), vec![r + 2 * n, i]),
+                (format!("{p}.mixer.dt_proj.weight"), vec![i, r]),
+                (format!("{p}.mixer.dt_proj.bias"), vec![i]),
+                (format!("{p}.mixer.A_log"), vec![i, n]),
+                (format!("{p}.mixer.D"), vec![i]),
+                (format!("{p}.mixer.out_proj.weight"), vec![h, i]),
+            ]);
+        }
+        let weights = shapes
+            .into_iter()
+            .map(|(name, shape)| Ok((name, Tensor::randn(0f32, 0.3, shape, &dev)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        Model::new(
+            cfg,
+            VarBuilder::from_tensors(weights, DType::F32, &dev),
+            NormalLoadingMetadata {
+                mapper: DeviceMapMetadata::dummy().into_mapper(
+                    cfg.num_hidden_layers,
+                    &dev,
+                    None,
+                )?,
+                loading_isq: false,
+                real_device: dev.clone(),
+            },
+        )
+    }
+
+    fn reset(model: &Model) {
+        for layer in &mut *model.cache.full().lock() {
+            *layer = None;
+        }
+    }
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    #[test]
+    fn test_recurrent_decode() -> Result<()> {
+        let cfg = cfg();
+        let model = model(&cfg)?;
+        let tokens = [1u32, 5, 7, 2, 9, 3, 11];
+        let prompt_len = 3;
+
+        // The reference runs the whole sequence through the scan at once
+        let input_ids = Tensor::new(&tokens[..], &Device::Cpu)?.unsqueeze(0)?;
+        let reference = model.forward(&input_ids, &[tokens.len()], vec![(0, tokens.len())])?;
+        reset(&model);
+
+        // Run the prompt, then decode one token at a time from the recurrent states
+        let prompt = Tensor::new(&tokens[..prompt_len], &Device::Cpu)?.unsqueeze(0)?;
+        let logits = model.forward(&prompt, &[prompt_len], vec![(prompt_len - 1, 1)])?;
+        assert!(max_diff(&logits.i((.., 0))?, &reference.i((.., prompt_len - 1))?)? < 1e-4);
+        for (pos, &token) in tokens.iter().enumerate().skip(prompt_len) {
+            let input_ids = Tensor::new(&[[token]], &Device::Cpu)?;
+            let logits = model.forward(&input_ids, &[1], vec![(0, 1)])?;
+            assert!(max_diff(&logits.i((.., 0))?, &reference.i((.., pos))?)? < 1e-4);
+
+            // The states do not grow with the context
+            for state in &*model.cache.full().lock() {
+                let (conv_state, ssm_state) = state.as_ref().unwrap();
+                assert_eq!(
+                    conv_state.dims(),
+                    &[1, cfg.intermediate_size, cfg.conv_kernel - 1]
+                );
+                assert_eq!(
+                    ssm_state.dims(),
+                    &[1, cfg.intermediate_size, cfg.state_size]
+                );
+            }
+        }
+        Ok(())
+    }
+    #[test]
+    fn test_selective_scan_values() -> Result<()> {
+        let dev = Device::Cpu;
+        let cfg = Config {
+            hidden_size: 1,
+            state_size: 1,
+            conv_kernel: 2,
+            intermediate_size: 1,
+            time_step_rank: 1,
+            ..cfg()
+        };
+        // The time step is softplus(ln(e - 1)) = 1 and B = C = u, so with A = -1 the scan is
+        // h_t = h_{t-1} / e + u_t^2 and y_t = h_t u_t + D u_t
+        let weights = HashMap::from([
+            (
+                "in_proj.weight".to_string(),
+                Tensor::ones((2, 1), DType::F32, &dev)?,
+            ),
+            (
+                "conv1d.weight".to_string(),
+                Tensor::ones((1, 1, 2), DType::F32, &dev)?,
+            ),
+            (
+                "conv1d.bias".to_string(),
+                Tensor::zeros(1, DType::F32, &dev)?,
+            ),
+            (
+                "x_proj.weight".to_string(),
+                Tensor::new(&[[0f32], [1.], [1.]], &dev)?,
+            ),
+            (
+                "dt_proj.weight".to_string(),
+                Tensor::ones((1, 1), DType::F32, &dev)?,
+            ),
+            (
+                "dt_proj.bias".to_string(),
+                Tensor::new(&[(std::f32::consts::E - 1.).ln()], &dev)?,
+            ),
+            (
+                "A_log".to_string(),
+                Tensor::zeros((1, 1), DType::F32, &dev)?,
+            ),
+            ("D".to_string(), Tensor::new(&[0.5f32], &dev)?),
+            (
+                "out_proj.weight".to_string(),
+                Tensor::ones((1, 1), DType::F32, &dev)?,
+            ),
+        ]);
+        let mixer = MambaMixer::new(&cfg, VarBuilder::from_tensors(weights, DType::F32, &dev))?;
+
+        let u = Tensor::new(&[1f32, 2., 3.], &dev)?.reshape((1, 3, 1))?;
+        let (ys, state) = mixer.selective_scan(&u, None)?;
+        let expected = [1.5f32, 9.735759, 33.320559];
+        for (y, expected) in ys.flatten_all()?.to_vec1::<f32>()?.iter().zip(expected) {
+            assert!((y - expected).abs() < 1e-4, "{y} != {expected}");
+        }
+        let state = state.flatten_all()?.to_vec1::<f32>()?[0];
+        assert!((state - 10.606853).abs() < 1e-4, "{state}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_padded_batch_matches_sequences() -> Result<()> {
+        let dev = Device::Cpu;
+        let cfg = cfg();
+        let model = model(&cfg)?;
+        let prompts = [vec![1u32, 5, 7], vec![4, 2, 9, 3, 11]];
+        let next = [6u32, 8];
+
+        // Each sequence run alone, for its prompt and then one decoded token
+        let mut expected = Vec::new();
+        for (prompt, &token) in prompts.iter().zip(&next) {
+            reset(&model);
+            let input_ids = Tensor::new(prompt.as_slice(), &dev)?.unsqueeze(0)?;
+            let prompt_logits =
+                model.forward(&input_ids, &[prompt.len()], vec![(prompt.len() - 1, 1)])?;
+            let decode_logits =
+                model.forward(&Tensor::new(&[[token]], &dev)?, &[1], vec![(0, 1)])?;
+            expected.push((prompt_logits, decode_logits));
+        }
+
+        // The shorter prompt is right padded, and the logits are taken at the padded end
+        reset(&model);
+        let input_ids = Tensor::new(&[[1u32, 5, 7, 0, 0], [4, 2, 9, 3, 11]], &dev)?;
+        let prompt_logits = model.forward(&input_ids, &[3, 5], vec![(4, 1), (4, 1)])?;
+        let decode_logits = model.forward(
+            &Tensor::new(&[[next[0]], [next[1]]], &dev)?,
+            &[1, 1],
+            vec![(0, 1), (0, 1)],
+        )?;
+        for (b, (prompt, decode)) in expected.iter().enumerate() {
+            assert!(max_diff(&prompt_logits.i(b)?, &prompt.i(0)?)? < 1e-4);
+            assert!(max_diff(&decode_logits.i(b)?, &decode.i(0)?)? < 1e-4);
+        }
+        Ok(())
+    }
+}
@@ -66,16 +66,19 @@ impl MLP {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let intermediate_sz = cfg.intermediate_size;
+        // The SmoothQuant scales of the layers reading the post attention norm are folded into it
+        let gate_up_quant_cfg =
+            QuantizedConfig::with_smooth_scales_folded(&cfg.quantization_config);
         let gate_proj = mistralrs_quant::linear_no_bias(
             hidden_sz,
             intermediate_sz,
-            &cfg.quantization_config,
+            &gate_up_quant_cfg,
             vb.pp("gate_proj"),
         )?;
         let up_proj = mistralrs_quant::linear_no_bias(
             hidden_sz,
             intermediate_sz,
-            &cfg.quantization_config,
+            &gate_up_quant_cfg,
             vb.pp("up_proj"),
         )?;
         let down_proj = mistralrs_quant::linear_no_bias(
@@ -179,22 +182,24 @@ impl Attention {
         let num_heads = cfg.num_attention_heads;
         let num_kv_heads = cfg.num_key_value_heads;
         let head_dim = cfg.head_dim();
+        // The SmoothQuant scales of the layers reading the input norm are folded into it
+        let qkv_quant_cfg = QuantizedConfig::with_smooth_scales_folded(&cfg.quantization_config);
         let q_proj = mistralrs_quant::linear_no_bias(
             hidden_sz,
             num_heads * head_dim,
-            &cfg.quantization_config,
+            &qkv_quant_cfg,
             vb.pp("q_proj"),
         )?;
         let k_proj = mistralrs_quant::linear_no_bias(
             hidden_sz,
             num_kv_heads * head_dim,
-            &cfg.quantization_config,
+            &qkv_quant_cfg,
             vb.pp("k_proj"),
         )?;
         let v_proj = mistralrs_quant::linear_no_bias(
             hidden_sz,
             num_kv_heads * head_dim,
-            &cfg.quantization_config,
+            &qkv_quant_cfg,
             vb.pp("v_proj"),
         )?;
         let o_proj = mistralrs_quant::linear_no_bias(
@@ -220,6 +225,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: cfg.sliding_window,
+                attention_softmax_f32: false,
             },
         })
     }
@@ -373,11 +379,19 @@ impl DecoderLayer {
             cfg.hidden_size,
             cfg.rms_norm_eps,
             mapper.set_device(layer_idx, vb.pp("input_layernorm"), false),
+        )?
+        .fold_smooth_scales(
+            &cfg.quantization_config,
+            &["q_proj", "k_proj", "v_proj"].map(|l| vb.pp("self_attn").pp(l)),
         )?;
         let post_attention_layernorm = RmsNorm::new(
             cfg.hidden_size,
             cfg.rms_norm_eps,
             mapper.set_device(layer_idx, vb.pp("post_attention_layernorm"), false),
+        )?
+        .fold_smooth_scales(
+            &cfg.quantization_config,
+            &["gate_proj", "up_proj"].map(|l| vb.pp("mlp").pp(l)),
         )?;
         Ok(Self {
             self_attn,
@@ -409,11 +423,8 @@ impl DecoderLayer {
             metadata,
             flash_params,
         )?;
-        let xs = (xs + residual)?;
-        let residual = &xs;
-        let xs = self
-            .mlp
-            .forward(&xs.apply(&self.post_attention_layernorm)?)?;
+        let (residual, xs) = self.post_attention_layernorm.add_forward(&xs, residual)?;
+        let xs = self.mlp.forward(&xs)?;
         residual + xs
     }
 }
@@ -115,6 +115,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: cfg.sliding_window,
+                attention_softmax_f32: false,
             },
         })
     }
@@ -241,15 +242,15 @@ impl Attention {
 }
 
 #[derive(Clone)]
-struct BlockSparseTop2MLP {
-    w1: Arc<dyn QuantMethod>,
-    w2: Arc<dyn QuantMethod>,
-    w3: Arc<dyn QuantMethod>,
+pub(crate) struct BlockSparseTop2MLP {
+    pub(crate) w1: Arc<dyn QuantMethod>,
+    pub(crate) w2: Arc<dyn QuantMethod>,
+    pub(crate) w3: Arc<dyn QuantMethod>,
     act_fn: Activation,
 }
 
 impl BlockSparseTop2MLP {
-    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    pub(crate) fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let intermediate_sz = cfg.intermediate_size;
         let w1 = mistralrs_quant::linear_no_bias(
@@ -297,14 +298,14 @@ impl Module for BlockSparseTop2MLP {
 }
 
 #[derive(Clone)]
-struct SparseMoeBlock {
-    gate: Arc<dyn QuantMethod>,
-    experts: Vec<BlockSparseTop2MLP>,
+pub(crate) struct SparseMoeBlock {
+    pub(crate) gate: Arc<dyn QuantMethod>,
+    pub(crate) experts: Vec<BlockSparseTop2MLP>,
     num_experts_per_tok: usize,
 }
 
 impl SparseMoeBlock {
-    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    pub(crate) fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let gate = mistralrs_quant::linear_no_bias(
             cfg.hidden_size,
             cfg.num_local_experts,
@@ -461,10 +462,8 @@ impl DecoderLayer {
             metadata,
             flash_params,
         )?;
-        let xs = (xs + residual)?;
-        let residual = &xs;
+        let (residual, xs) = self.post_attention_layernorm.add_forward(&xs, residual)?;
         let xs = xs
-            .apply(&self.post_attention_layernorm)?
             .apply(&self.block_sparse_moe)?
             .to_dtype(residual.dtype())?;
         residual + xs
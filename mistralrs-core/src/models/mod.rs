@@ -1,6 +1,10 @@
+pub(crate) mod arctic;
+pub(crate) mod cohere;
 pub(crate) mod gemma;
 pub(crate) mod gemma2;
+pub(crate) mod granite;
 pub(crate) mod llama;
+pub(crate) mod mamba;
 pub(crate) mod mistral;
 pub(crate) mod mixtral;
 pub(crate) mod phi2;
@@ -12,4 +16,5 @@ pub(crate) mod quantized_phi3;
 pub(crate) mod quantized_qwen2;
 pub(crate) mod quantized_starcoder2;
 pub(crate) mod qwen2;
+pub(crate) mod qwen2_moe;
 pub(crate) mod starcoder2;
@@ -144,6 +144,7 @@ impl Attention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: cfg.sliding_window,
+                attention_softmax_f32: false,
             },
         })
     }
@@ -306,6 +306,7 @@ impl ModelConfig::FromGGML for ModelWeights {
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                     sliding_window: None,
+                    attention_softmax_f32: false,
                 },
             })
         }
@@ -631,6 +632,7 @@ impl ModelConfig::FromGGUF for ModelWeights {
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                     sliding_window: None,
+                    attention_softmax_f32: false,
                 },
             })
         }
@@ -0,0 +1,1011 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+/// Qwen2-MoE Model
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/qwen2_moe/modeling_qwen2_moe.py
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{RotaryEmbedding, VarBuilder};
+use mistralrs_quant::{QuantMethod, QuantMethodConfig, QuantizedConfig, UnquantLinear};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    amoe::AnyMoeBaseModelMixin,
+    attention::SdpaParams,
+    device_map::DeviceMapper,
+    layers::{Activation, CausalMasker, MatMul, RmsNorm, Sdpa},
+    layers_masker::PastKvLenCache,
+    paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
+    pipeline::{
+        extract_logits,
+        text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
+        EitherCache, IsqModel, KvCache, NormalCache, NormalLoadingMetadata, NormalModel,
+    },
+    utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
+};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) num_key_value_heads: usize,
+    pub(crate) max_position_embeddings: usize,
+    pub(crate) sliding_window: Option<usize>,
+    pub(crate) rope_theta: f64,
+    pub(crate) rms_norm_eps: f64,
+    pub(crate) hidden_act: Activation,
+    pub(crate) decoder_sparse_step: usize,
+    pub(crate) moe_intermediate_size: usize,
+    pub(crate) shared_expert_intermediate_size: usize,
+    pub(crate) num_experts_per_tok: usize,
+    pub(crate) num_experts: usize,
+    pub(crate) norm_topk_prob: bool,
+    pub(crate) mlp_only_layers: Vec<usize>,
+    pub(crate) use_flash_attn: bool,
+    pub(crate) quantization_config: Option<QuantizedConfig>,
+    pub(crate) tie_word_embeddings: bool,
+}
+
+impl Config {
+    /// Layers in `mlp_only_layers` are dense, and otherwise every `decoder_sparse_step` layer is
+    /// a MoE layer.
+    fn is_moe_layer(&self, layer_idx: usize) -> bool {
+        !self.mlp_only_layers.contains(&layer_idx)
+            && self.num_experts > 0
+            && (layer_idx + 1) % self.decoder_sparse_step == 0
+    }
+}
+
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: Arc<dyn QuantMethod>,
+    up_proj: Arc<dyn QuantMethod>,
+    down_proj: Arc<dyn QuantMethod>,
+    act_fn: Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, intermediate_sz: usize, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let gate_proj = mistralrs_quant::linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            &cfg.quantization_config,
+            vb.pp("gate_proj"),
+        )?;
+        let up_proj = mistralrs_quant::linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            &cfg.quantization_config,
+            vb.pp("up_proj"),
+        )?;
+        let down_proj = mistralrs_quant::linear_no_bias(
+            intermediate_sz,
+            hidden_sz,
+            &cfg.quantization_config,
+            vb.pp("down_proj"),
+        )?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act,
+        })
+    }
+
+    fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        vec![&mut self.gate_proj, &mut self.up_proj, &mut self.down_proj]
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.gate_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let lhs = MatMul
+            .qmethod_matmul(&xs, &*self.gate_proj)?
+            .apply(&self.act_fn)?;
+        let rhs = MatMul.qmethod_matmul(&xs, &*self.up_proj)?;
+        let mut res = MatMul.qmethod_matmul(&(lhs * rhs)?, &*self.down_proj)?;
+        if self.gate_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+/// Routed experts plus a shared expert which every token goes through, weighted by a sigmoid
+/// gate.
+struct SparseMoeBlock {
+    gate: Arc<dyn QuantMethod>,
+    experts: Vec<MLP>,
+    shared_expert: MLP,
+    shared_expert_gate: Arc<dyn QuantMethod>,
+    num_experts_per_tok: usize,
+    norm_topk_prob: bool,
+}
+
+impl SparseMoeBlock {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        // The routers are not quantized in pre-quantized checkpoints
+        let gate = mistralrs_quant::linear_no_bias(
+            cfg.hidden_size,
+            cfg.num_experts,
+            &None,
+            vb.pp("gate"),
+        )?;
+        let mut experts = Vec::with_capacity(cfg.num_experts);
+        let vb_e = vb.pp("experts");
+        for idx in 0..cfg.num_experts {
+            experts.push(MLP::new(cfg, cfg.moe_intermediate_size, vb_e.pp(idx))?);
+        }
+        let shared_expert = MLP::new(
+            cfg,
+            cfg.shared_expert_intermediate_size,
+            vb.pp("shared_expert"),
+        )?;
+        let shared_expert_gate = mistralrs_quant::linear_no_bias(
+            cfg.hidden_size,
+            1,
+            &None,
+            vb.pp("shared_expert_gate"),
+        )?;
+        Ok(Self {
+            gate,
+            experts,
+            shared_expert,
+            shared_expert_gate,
+            num_experts_per_tok: cfg.num_experts_per_tok,
+            norm_topk_prob: cfg.norm_topk_prob,
+        })
+    }
+
+    /// The weighted sum of the top-k routed experts, for `xs` of shape `(tokens, hidden)`.
+    fn routed_forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let hidden_dim = xs.dim(1)?;
+        let original_dtype = xs.dtype();
+        let mut router_xs = xs.clone();
+        if let Some(t) = self.gate.quantized_act_type() {
+            router_xs = router_xs.to_dtype(t)?;
+        }
+        let router_logits = MatMul
+            .qmethod_matmul(&router_xs, &*self.gate)?
+            .to_dtype(DType::F32)?;
+        let routing_weights = candle_nn::ops::softmax_last_dim(&router_logits)?.to_vec2::<f32>()?;
+
+        // top_x contains the row indexes to evaluate for each expert.
+        let mut top_x = vec![vec![]; self.experts.len()];
+        let mut selected_rws = vec![vec![]; self.experts.len()];
+        for (row_idx, rw) in routing_weights.iter().enumerate() {
+            let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
+            dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
+            let selected = &dst[..self.num_experts_per_tok.min(dst.len())];
+            let norm = if self.norm_topk_prob {
+                selected.iter().map(|&i| rw[i as usize]).sum::<f32>()
+            } else {
+                1.
+            };
+            for &expert_idx in selected {
+                let expert_idx = expert_idx as usize;
+                top_x[expert_idx].push(row_idx as u32);
+                selected_rws[expert_idx].push(rw[expert_idx] / norm);
+            }
+        }
+
+        let mut ys = xs.zeros_like()?;
+        for (expert_idx, expert_layer) in self.experts.iter().enumerate() {
+            let top_x = &top_x[expert_idx];
+            if top_x.is_empty() {
+                continue;
+            }
+            let top_x = Tensor::new(top_x.as_slice(), xs.device())?;
+            let selected_rws = Tensor::new(selected_rws[expert_idx].as_slice(), xs.device())?
+                .reshape(((), 1))?
+                .to_dtype(original_dtype)?;
+            let current_state = xs.index_select(&top_x, 0)?.reshape(((), hidden_dim))?;
+            let current_hidden_states = expert_layer
+                .forward(&current_state)?
+                .broadcast_mul(&selected_rws)?;
+            ys = ys.index_add(&top_x, &current_hidden_states, 0)?;
+        }
+        Ok(ys)
+    }
+
+    /// The output of the shared expert scaled by its gate, for `xs` of shape `(tokens, hidden)`.
+    fn shared_forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut gate_xs = xs.clone();
+        if let Some(t) = self.shared_expert_gate.quantized_act_type() {
+            gate_xs = gate_xs.to_dtype(t)?;
+        }
+        let gate = candle_nn::ops::sigmoid(
+            &MatMul
+                .qmethod_matmul(&gate_xs, &*self.shared_expert_gate)?
+                .to_dtype(xs.dtype())?,
+        )?;
+        self.shared_expert.forward(xs)?.broadcast_mul(&gate)
+    }
+}
+
+impl Module for SparseMoeBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, seq_len, hidden_dim) = xs.dims3()?;
+        let xs = xs.reshape(((), hidden_dim))?;
+        let ys = (self.routed_forward(&xs)? + self.shared_forward(&xs)?)?;
+        ys.reshape((b_size, seq_len, hidden_dim))
+    }
+}
+
+enum MoeOrMlp {
+    Moe(SparseMoeBlock),
+    Mlp(MLP),
+}
+
+impl MoeOrMlp {
+    fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        match self {
+            Self::Moe(moe) => {
+                let mut layers = vec![&mut moe.gate];
+                for expert in &mut moe.experts {
+                    layers.extend(expert.get_isq_layers());
+                }
+                layers.extend(moe.shared_expert.get_isq_layers());
+                layers.push(&mut moe.shared_expert_gate);
+                layers
+            }
+            Self::Mlp(mlp) => mlp.get_isq_layers(),
+        }
+    }
+}
+
+impl Module for MoeOrMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Moe(moe) => moe.forward(xs),
+            Self::Mlp(mlp) => mlp.forward(xs),
+        }
+    }
+}
+
+struct Attention {
+    q_proj: Arc<dyn QuantMethod>,
+    k_proj: Arc<dyn QuantMethod>,
+    v_proj: Arc<dyn QuantMethod>,
+    o_proj: Arc<dyn QuantMethod>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    paged_attn: Option<PagedAttention>,
+    sdpa_params: SdpaParams,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        paged_attn: Option<PagedAttention>,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let head_dim = hidden_sz / num_heads;
+        let q_proj = mistralrs_quant::linear(
+            hidden_sz,
+            num_heads * head_dim,
+            &cfg.quantization_config,
+            vb.pp("q_proj"),
+        )?;
+        let k_proj = mistralrs_quant::linear(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            &cfg.quantization_config,
+            vb.pp("k_proj"),
+        )?;
+        let v_proj = mistralrs_quant::linear(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            &cfg.quantization_config,
+            vb.pp("v_proj"),
+        )?;
+        let o_proj = mistralrs_quant::linear_no_bias(
+            num_heads * head_dim,
+            hidden_sz,
+            &cfg.quantization_config,
+            vb.pp("o_proj"),
+        )?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            rotary_emb,
+            paged_attn,
+            sdpa_params: SdpaParams {
+                n_kv_groups: num_heads / num_kv_heads,
+                use_flash_attn: cfg.use_flash_attn,
+                softcap: None,
+                softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                sliding_window: None,
+                attention_softmax_f32: false,
+            },
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut KvCache,
+        metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let mut q = MatMul.qmethod_matmul(&xs, &*self.q_proj)?;
+        let mut k = MatMul.qmethod_matmul(&xs, &*self.k_proj)?;
+        let mut v = MatMul.qmethod_matmul(&xs, &*self.v_proj)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            q = q.to_dtype(original_dtype)?;
+            k = k.to_dtype(original_dtype)?;
+            v = v.to_dtype(original_dtype)?;
+        }
+
+        let mut q = q.reshape((b_sz * q_len, self.num_heads, self.head_dim))?;
+        let mut k = k.reshape((b_sz * q_len, self.num_kv_heads, self.head_dim))?;
+        let v = if q_len != 1 {
+            v.reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?
+        } else {
+            // Optimization for seqlen = 1, avoid transpose and just modify reshape dims
+            v.reshape((b_sz, self.num_kv_heads, q_len, self.head_dim))?
+        };
+
+        self.rotary_emb
+            .forward(seqlen_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+
+        if q.rank() == 3 && q_len != 1 {
+            q = q
+                .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+        } else if q.rank() == 3 {
+            // Optimization for seqlen = 1, avoid transpose and just modify reshape dims
+            q = q
+                .reshape((b_sz, self.num_heads, q_len, self.head_dim))?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, self.num_kv_heads, q_len, self.head_dim))?
+                .contiguous()?;
+        }
+
+        let mut attn_output = match &self.paged_attn {
+            Some(paged_attn) => match metadata {
+                Some(((key_cache, value_cache), input_metadata)) => paged_attn.forward(
+                    &q,
+                    &k,
+                    &v,
+                    attention_mask,
+                    Some(key_cache),
+                    Some(value_cache),
+                    input_metadata,
+                    None,
+                )?,
+                None => {
+                    let mut input_metadata = PagedAttentionInputMetadata {
+                        block_tables: None,
+                        context_lens: None,
+                        max_context_len: None,
+                        slot_mappings: Tensor::new(&[0f32], q.device())?,
+                    };
+                    paged_attn.forward(
+                        &q,
+                        &k,
+                        &v,
+                        attention_mask,
+                        None,
+                        None,
+                        &mut input_metadata,
+                        None,
+                    )?
+                }
+            },
+            None => {
+                let (k, v) = kv_cache.append(&k, &v)?;
+
+                Sdpa.run_attention(
+                    &q,
+                    &k,
+                    &v,
+                    attention_mask,
+                    Some(flash_params),
+                    &self.sdpa_params,
+                )?
+            }
+        };
+
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            attn_output = attn_output.to_dtype(t)?;
+        }
+        attn_output = if attention_mask.is_some() {
+            attn_output.transpose(1, 2)?.reshape((b_sz, q_len, ()))?
+        } else {
+            attn_output.reshape((b_sz, q_len, ()))?
+        };
+        let mut res = MatMul.qmethod_matmul(&attn_output, &*self.o_proj)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MoeOrMlp,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+        paged_attn: Option<PagedAttention>,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(
+            rotary_emb,
+            cfg,
+            mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq),
+            paged_attn,
+        )?;
+        let mlp_vb = mapper.set_device(layer_idx, vb.pp("mlp"), loading_isq);
+        let mlp = if cfg.is_moe_layer(layer_idx) {
+            MoeOrMlp::Moe(SparseMoeBlock::new(cfg, mlp_vb)?)
+        } else {
+            MoeOrMlp::Mlp(MLP::new(cfg, cfg.intermediate_size, mlp_vb)?)
+        };
+        let input_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("input_layernorm"), false),
+        )?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("post_attention_layernorm"), false),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut KvCache,
+        metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(
+            &xs,
+            attention_mask,
+            seqlen_offsets,
+            start_offsets_kernel,
+            kv_cache,
+            metadata,
+            flash_params,
+        )?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = self
+            .mlp
+            .forward(&xs.apply(&self.post_attention_layernorm)?)?;
+        residual + xs
+    }
+}
+
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Arc<dyn QuantMethod>,
+    sliding_window: Option<usize>,
+    device: Device,
+    cache: EitherCache,
+    max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    cfg: ModelConfigMetadata,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        is_gptx: bool,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Self> {
+        if let Some(ref quant_cfg) = &cfg.quantization_config {
+            tracing::info!(
+                "Using {} quantization: {}.",
+                quant_cfg.quant_method.to_string(),
+                quant_cfg.get_bits_name(&vb)
+            );
+        }
+        let mapper = normal_loading_metadata.mapper;
+        let vb_m = vb.pp("model");
+
+        let embed_tokens = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_m.pp("embed_tokens"), false),
+        )?;
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+
+        let mut ropes = HashMap::new();
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let device = mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&normal_loading_metadata.real_device);
+            ropes.insert(
+                device.location(),
+                Arc::new(RotaryEmbedding::new(
+                    cfg.rope_theta as f32,
+                    head_dim,
+                    cfg.max_position_embeddings,
+                    device,
+                    is_gptx,
+                    vb_m.dtype(),
+                )?),
+            );
+        }
+
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in
+            NiceProgressBar::<_, 'b'>(0..cfg.num_hidden_layers, "Loading repeating layers")
+        {
+            let device = mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&normal_loading_metadata.real_device);
+            let rotary_emb = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+            let paged_attn = match &attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => Some(PagedAttention::new(
+                    cfg.num_attention_heads,
+                    head_dim,
+                    (1.0 / (head_dim as f64).sqrt()) as f32,
+                    Some(cfg.num_key_value_heads),
+                    cfg.sliding_window,
+                    device,
+                    None,
+                )?),
+            };
+            let layer = DecoderLayer::new(
+                rotary_emb.clone(),
+                cfg,
+                vb_l.pp(layer_idx),
+                &*mapper,
+                layer_idx,
+                normal_loading_metadata.loading_isq,
+                paged_attn,
+            )?;
+            layers.push(layer)
+        }
+        let norm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_nm_device(vb_m.pp("norm"), false),
+        )?;
+        let lm_head = if !cfg.tie_word_embeddings {
+            mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                &None,
+                mapper.set_nm_device(vb.pp("lm_head"), normal_loading_metadata.loading_isq),
+            )?
+        } else {
+            Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                candle_nn::Linear::new(
+                    mapper.cast_nm_device(
+                        embed_tokens.embeddings(),
+                        normal_loading_metadata.loading_isq,
+                    )?,
+                    None,
+                ),
+            ))?)
+        };
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            sliding_window: cfg.sliding_window,
+            device: normal_loading_metadata.real_device,
+            cache: EitherCache::Normal(NormalCache::new(
+                cfg.num_hidden_layers,
+                cfg.max_position_embeddings,
+            )),
+            max_seq_len: cfg.max_position_embeddings,
+            mapper,
+            cfg: ModelConfigMetadata {
+                num_layers: cfg.num_hidden_layers,
+                hidden_size: cfg.hidden_size,
+                num_kv_heads: cfg.num_key_value_heads,
+                num_attn_heads: cfg.num_attention_heads,
+                sliding_window: cfg.sliding_window,
+                head_dim: None,
+            },
+        })
+    }
+
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        let cache = &mut self.cache.normal().0;
+        let attention_mask = CausalMasker.make_sliding_window_causal_mask_matrix(
+            input_ids,
+            metadata
+                .as_ref()
+                .map(|(_, _)| &seqlen_offsets as &dyn PastKvLenCache)
+                .unwrap_or(cache as &dyn PastKvLenCache),
+            self.sliding_window,
+            xs.dtype(),
+            self.cfg.num_attn_heads,
+        )?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(
+                &xs,
+                attention_mask
+                    .as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                seqlen_offsets,
+                start_offsets_kernel.clone(),
+                &mut cache[i],
+                metadata
+                    .as_mut()
+                    .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
+                flash_params,
+            )?
+        }
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.norm)?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+    }
+}
+
+impl IsqModel for Model {
+    fn get_layers(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((&mut layer.self_attn.q_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.k_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.v_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
+            tensors.extend(
+                layer
+                    .mlp
+                    .get_isq_layers()
+                    .into_iter()
+                    .map(|m| (m, Some(i)))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        (tensors, &*self.mapper)
+    }
+
+    fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+        let uvb = UnVarBuilder::new();
+
+        let uvb_m = uvb.pp("model");
+        uvb_m.pp("embed_tokens").add(&self.embed_tokens);
+        uvb_m.pp("norm").add(&self.norm);
+
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let uvb_l = uvb_m.pp("layers").pp(layer_idx);
+            uvb_l.pp("input_layernorm").add(&layer.input_layernorm);
+            uvb_l
+                .pp("post_attention_layernorm")
+                .add(&layer.post_attention_layernorm);
+        }
+
+        uvb.to_safetensors()
+    }
+}
+
+impl NormalModel for Model {
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            context_lens,
+            metadata,
+            flash_params,
+        )
+    }
+    fn xlora_forward(
+        &self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _start_offsets_kernel: Tensor,
+        _start_offsets_kernel_full: Tensor,
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _flash_params: &FlashParams,
+        _flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        unimplemented!()
+    }
+    fn cache(&self) -> &EitherCache {
+        &self.cache
+    }
+    fn cache_mut(&mut self) -> &mut EitherCache {
+        &mut self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn config(&self) -> &ModelConfigMetadata {
+        &self.cfg
+    }
+}
+
+impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Module, Result, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{Config, SparseMoeBlock};
+    use crate::layers::Activation;
+
+    fn cfg(norm_topk_prob: bool) -> Config {
+        Config {
+            hidden_size: 2,
+            hidden_act: Activation::Silu,
+            moe_intermediate_size: 2,
+            shared_expert_intermediate_size: 3,
+            num_experts_per_tok: 2,
+            num_experts: 3,
+            norm_topk_prob,
+            ..Default::default()
+        }
+    }
+
+    /// A `(rows, cols)` weight of small distinct values, which depend on `seed`.
+    #[allow(clippy::cast_precision_loss)]
+    fn weight(rows: usize, cols: usize, seed: usize) -> Vec<Vec<f32>> {
+        (0..rows)
+            .map(|r| {
+                (0..cols)
+                    .map(|c| ((seed + r * cols + c) * 7 % 11) as f32 / 10. - 0.5)
+                    .collect()
+            })
+            .collect()
+    }
+
+    struct MlpWeights {
+        gate: Vec<Vec<f32>>,
+        up: Vec<Vec<f32>>,
+        down: Vec<Vec<f32>>,
+    }
+
+    impl MlpWeights {
+        fn new(hidden: usize, intermediate: usize, seed: usize) -> Self {
+            Self {
+                gate: weight(intermediate, hidden, seed),
+                up: weight(intermediate, hidden, seed + 1),
+                down: weight(hidden, intermediate, seed + 2),
+            }
+        }
+
+        fn forward(&self, x: &[f64]) -> Vec<f64> {
+            let h = matvec(&self.gate, x)
+                .into_iter()
+                .zip(matvec(&self.up, x))
+                .map(|(g, u)| g / (1. + (-g).exp()) * u)
+                .collect::<Vec<_>>();
+            matvec(&self.down, &h)
+        }
+    }
+
+    fn matvec(w: &[Vec<f32>], x: &[f64]) -> Vec<f64> {
+        w.iter()
+            .map(|row| row.iter().zip(x).map(|(w, x)| f64::from(*w) * x).sum())
+            .collect()
+    }
+
+    /// The weights of a MoE block, used both by the model and by a reference computed per token.
+    struct MoeWeights {
+        router: Vec<Vec<f32>>,
+        experts: Vec<MlpWeights>,
+        shared_expert: MlpWeights,
+        shared_expert_gate: Vec<Vec<f32>>,
+    }
+
+    impl MoeWeights {
+        fn new(cfg: &Config) -> Self {
+            let (h, i) = (cfg.hidden_size, cfg.moe_intermediate_size);
+            Self {
+                router: vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![-1.0, -0.5]],
+                experts: (0..cfg.num_experts)
+                    .map(|e| MlpWeights::new(h, i, 3 * e))
+                    .collect(),
+                shared_expert: MlpWeights::new(h, cfg.shared_expert_intermediate_size, 5),
+                shared_expert_gate: vec![vec![0.8, -0.3]],
+            }
+        }
+
+        fn var_builder(&self, dev: &Device) -> Result<VarBuilder<'static>> {
+            let mut weights = vec![
+                ("gate.weight".to_string(), &self.router),
+                (
+                    "shared_expert_gate.weight".to_string(),
+                    &self.shared_expert_gate,
+                ),
+            ];
+            let mlps = std::iter::once(("shared_expert".to_string(), &self.shared_expert)).chain(
+                self.experts
+                    .iter()
+                    .enumerate()
+                    .map(|(e, mlp)| (format!("experts.{e}"), mlp)),
+            );
+            for (prefix, mlp) in mlps {
+                weights.extend([
+                    (format!("{prefix}.gate_proj.weight"), &mlp.gate),
+                    (format!("{prefix}.up_proj.weight"), &mlp.up),
+                    (format!("{prefix}.down_proj.weight"), &mlp.down),
+                ]);
+            }
+            let weights = weights
+                .into_iter()
+                .map(|(name, w)| {
+                    Ok((
+                        name,
+                        Tensor::from_vec(w.concat(), (w.len(), w[0].len()), dev)?,
+                    ))
+                })
+                .collect::<Result<HashMap<_, _>>>()?;
+            Ok(VarBuilder::from_tensors(weights, DType::F32, dev))
+        }
+
+        /// The output of the block for one token, computed in f64.
+        fn reference(&self, x: &[f32], top_k: usize, norm_topk_prob: bool) -> Vec<f64> {
+            let x = x.iter().map(|x| f64::from(*x)).collect::<Vec<_>>();
+            let logits = matvec(&self.router, &x);
+            let z = logits.iter().map(|l| l.exp()).sum::<f64>();
+            let probs = logits.iter().map(|l| l.exp() / z).collect::<Vec<_>>();
+            let mut ranked = (0..probs.len()).collect::<Vec<_>>();
+            ranked.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+            let selected = &ranked[..top_k];
+            let norm = if norm_topk_prob {
+                selected.iter().map(|&e| probs[e]).sum()
+            } else {
+                1.
+            };
+
+            let gate = matvec(&self.shared_expert_gate, &x)[0];
+            let gate = 1. / (1. + (-gate).exp());
+            let mut out = self
+                .shared_expert
+                .forward(&x)
+                .into_iter()
+                .map(|y| y * gate)
+                .collect::<Vec<_>>();
+            for &e in selected {
+                for (out, y) in out.iter_mut().zip(self.experts[e].forward(&x)) {
+                    *out += probs[e] / norm * y;
+                }
+            }
+            out
+        }
+    }
+
+    #[test]
+    fn test_moe_block_matches_reference() -> Result<()> {
+        let dev = Device::Cpu;
+        // The tokens are routed to different pairs of experts
+        let tokens = [[1.0f32, 0.5], [-0.4, 2.0], [-1.5, -0.2]];
+        let xs = Tensor::new(&tokens, &dev)?.reshape((1, tokens.len(), 2))?;
+
+        let mut outputs = Vec::new();
+        for norm_topk_prob in [false, true] {
+            let cfg = cfg(norm_topk_prob);
+            let weights = MoeWeights::new(&cfg);
+            let block = SparseMoeBlock::new(&cfg, weights.var_builder(&dev)?)?;
+            let out = block.forward(&xs)?.squeeze(0)?.to_vec2::<f32>()?;
+            for (token, out) in tokens.iter().zip(&out) {
+                let expected = weights.reference(token, cfg.num_experts_per_tok, norm_topk_prob);
+                for (out, expected) in out.iter().zip(expected) {
+                    assert!(
+                        (f64::from(*out) - expected).abs() < 1e-5,
+                        "{out} != {expected} for {token:?} with norm_topk_prob {norm_topk_prob}"
+                    );
+                }
+            }
+            outputs.push(out);
+        }
+        // Renormalizing the top-k probabilities scales up the routed experts
+        assert_ne!(outputs[0], outputs[1]);
+        Ok(())
+    }
+}
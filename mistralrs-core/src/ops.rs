@@ -13,6 +13,8 @@ use crate::cuda::ffi;
 #[cfg(feature = "cuda")]
 use candle_core::cuda::{cudarc::driver::DevicePtr, CudaStorage, WrapErr};
 #[cfg(feature = "cuda")]
+use candle_core::CustomOp3;
+#[cfg(feature = "cuda")]
 use half::{bf16, f16};
 #[cfg(feature = "cuda")]
 use std::ffi::c_void;
@@ -630,6 +632,127 @@ impl SplitOp for Tensor {
     }
 }
 
+#[cfg(feature = "cuda")]
+struct FusedAddRmsNorm {
+    eps: f32,
+}
+
+#[cfg(feature = "cuda")]
+impl FusedAddRmsNorm {
+    /// Check the layouts, returning the number of rows and the hidden size.
+    fn rows_cols(&self, x_l: &Layout, r_l: &Layout, w_l: &Layout) -> Result<(usize, usize)> {
+        if x_l.shape() != r_l.shape() {
+            return Err(Error::ShapeMismatchBinaryOp {
+                lhs: x_l.shape().clone(),
+                rhs: r_l.shape().clone(),
+                op: "fused-add-rms-norm",
+            });
+        }
+        let cols = x_l.dims().last().copied().unwrap_or(1);
+        if w_l.dims() != [cols] {
+            candle_core::bail!(
+                "fused-add-rms-norm expects a weight of shape [{cols}], got {:?}",
+                w_l.dims()
+            );
+        }
+        Ok((x_l.shape().elem_count() / cols, cols))
+    }
+
+    fn out_shape(x_l: &Layout) -> Shape {
+        Shape::from_dims(&[&[2], x_l.dims()].concat())
+    }
+}
+
+#[cfg(feature = "cuda")]
+impl CustomOp3 for FusedAddRmsNorm {
+    fn name(&self) -> &'static str {
+        "fused-add-rms-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _s1: &CpuStorage,
+        _l1: &Layout,
+        _s2: &CpuStorage,
+        _l2: &Layout,
+        _s3: &CpuStorage,
+        _l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle_core::bail!("fused-add-rms-norm is only implemented for CUDA.")
+    }
+
+    fn cuda_fwd(
+        &self,
+        s1: &CudaStorage,
+        l1: &Layout,
+        s2: &CudaStorage,
+        l2: &Layout,
+        s3: &CudaStorage,
+        l3: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        let (rows, cols) = self.rows_cols(l1, l2, l3)?;
+        let dev = s1.device().clone();
+        macro_rules! launch {
+            ($ty:ty, $kernel:ident) => {{
+                let ptr = |s: &CudaStorage, l: &Layout| -> Result<*const c_void> {
+                    let (start, end) = l.contiguous_offsets().ok_or(Error::RequiresContiguous {
+                        op: "fused-add-rms-norm",
+                    })?;
+                    Ok(*s.as_cuda_slice::<$ty>()?.slice(start..end).device_ptr() as *const c_void)
+                };
+                let out = unsafe { dev.alloc::<$ty>(2 * rows * cols) }.w()?;
+                unsafe {
+                    ffi::$kernel(
+                        ptr(s1, l1)?,
+                        ptr(s2, l2)?,
+                        ptr(s3, l3)?,
+                        *out.device_ptr() as *mut c_void,
+                        u32::try_from(rows)?,
+                        u32::try_from(cols)?,
+                        self.eps,
+                        *dev.cu_stream(),
+                    )
+                };
+                CudaStorage::wrap_cuda_slice(out, dev.clone())
+            }};
+        }
+        let dst = match s1.dtype() {
+            DType::F32 => launch!(f32, fused_add_rms_norm_f32),
+            DType::F16 => launch!(f16, fused_add_rms_norm_f16),
+            DType::BF16 => launch!(bf16, fused_add_rms_norm_bf16),
+            other => return Err(Error::UnsupportedDTypeForOp(other, "fused-add-rms-norm")),
+        };
+        Ok((dst, Self::out_shape(l1)))
+    }
+}
+
+/// Compute `xs + residual` and the RMSNorm of the sum, returning `(sum, normed)`.
+///
+/// On CUDA (with the `cuda` feature), this is a single fused pass which writes both outputs.
+/// Other devices and dtypes use the separate add and norm ops, whose CPU kernels are parallel.
+pub fn fused_add_rms_norm(
+    xs: &Tensor,
+    residual: &Tensor,
+    weight: &Tensor,
+    eps: f32,
+) -> Result<(Tensor, Tensor)> {
+    #[cfg(feature = "cuda")]
+    if xs.device().is_cuda()
+        && matches!(xs.dtype(), DType::F32 | DType::F16 | DType::BF16)
+        && xs.dtype() == residual.dtype()
+    {
+        let out = xs.contiguous()?.apply_op3_no_bwd(
+            &residual.contiguous()?,
+            &weight.contiguous()?,
+            &FusedAddRmsNorm { eps },
+        )?;
+        return Ok((out.get(0)?, out.get(1)?));
+    }
+    let sum = (xs + residual)?;
+    let normed = candle_nn::ops::rms_norm(&sum.contiguous()?, weight, eps)?;
+    Ok((sum, normed))
+}
+
 mod tests {
     #[test]
     fn test_topk() {
@@ -888,4 +1011,130 @@ mod tests {
 
         Ok(())
     }
+
+    /// `(xs + residual, rms_norm(xs + residual) * weight)` of `(rows, cols)` values, computed
+    /// in f64.
+    #[cfg(test)]
+    #[allow(clippy::cast_precision_loss)]
+    fn reference_add_rms_norm(
+        xs: &[Vec<f32>],
+        residual: &[Vec<f32>],
+        weight: &[f32],
+        eps: f64,
+    ) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+        let sum: Vec<Vec<f64>> = xs
+            .iter()
+            .zip(residual)
+            .map(|(x, r)| {
+                x.iter()
+                    .zip(r)
+                    .map(|(x, r)| *x as f64 + *r as f64)
+                    .collect()
+            })
+            .collect();
+        let normed = sum
+            .iter()
+            .map(|row| {
+                let mean_sq = row.iter().map(|v| v * v).sum::<f64>() / row.len() as f64;
+                let scale = (mean_sq + eps).sqrt().recip();
+                row.iter()
+                    .zip(weight)
+                    .map(|(v, w)| v * scale * *w as f64)
+                    .collect()
+            })
+            .collect();
+        (sum, normed)
+    }
+
+    #[cfg(test)]
+    fn check_fused_add_rms_norm(device: &candle_core::Device) -> candle_core::Result<()> {
+        use crate::ops::fused_add_rms_norm;
+        use candle_core::{DType, Tensor};
+
+        let xs = Tensor::randn(0f32, 1., (6, 48), device)?;
+        let residual = Tensor::randn(0f32, 1., (6, 48), device)?;
+        let weight = Tensor::randn(1f32, 0.1, 48, device)?;
+        for (dtype, tol) in [(DType::F32, 1e-4), (DType::F16, 2e-2), (DType::BF16, 1e-1)] {
+            let (xs, residual, weight) = (
+                xs.to_dtype(dtype)?,
+                residual.to_dtype(dtype)?,
+                weight.to_dtype(dtype)?,
+            );
+            // The reference starts from the rounded inputs
+            let (expected_sum, expected_normed) = reference_add_rms_norm(
+                &xs.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+                &residual.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+                &weight.to_dtype(DType::F32)?.to_vec1::<f32>()?,
+                1e-6,
+            );
+            let (sum, normed) =
+                fused_add_rms_norm(&xs.unsqueeze(0)?, &residual.unsqueeze(0)?, &weight, 1e-6)?;
+            assert_eq!(sum.dims(), &[1, 6, 48]);
+            assert_eq!(normed.dims(), &[1, 6, 48]);
+            for (out, expected) in [(sum, expected_sum), (normed, expected_normed)] {
+                let out = out.squeeze(0)?.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+                for (out, expected) in out.iter().flatten().zip(expected.iter().flatten()) {
+                    let diff = (*out as f64 - expected).abs();
+                    assert!(
+                        diff < tol * expected.abs().max(1.),
+                        "{dtype:?}: {out} {expected}"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fused_add_rms_norm_cpu() -> candle_core::Result<()> {
+        check_fused_add_rms_norm(&candle_core::Device::Cpu)
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn test_fused_add_rms_norm_cuda() -> candle_core::Result<()> {
+        check_fused_add_rms_norm(&candle_core::Device::new_cuda(0)?)
+    }
+
+    /// Run with `cargo test --release -p mistralrs-core --features cuda bench_fused_add_rms_norm
+    /// -- --ignored --nocapture`.
+    #[cfg(feature = "cuda")]
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_fused_add_rms_norm() -> candle_core::Result<()> {
+        use crate::ops::fused_add_rms_norm;
+        use candle_core::{DType, Device, Tensor};
+        use std::time::Instant;
+
+        let device = Device::new_cuda(0)?;
+        const ITERS: u32 = 100;
+        // Decode step of a 7B model with a batch of 8
+        let xs = Tensor::randn(0f32, 1., (8, 1, 4096), &device)?.to_dtype(DType::BF16)?;
+        let residual = Tensor::randn(0f32, 1., (8, 1, 4096), &device)?.to_dtype(DType::BF16)?;
+        let weight = Tensor::ones(4096, DType::BF16, &device)?;
+
+        let unfused = || -> candle_core::Result<(Tensor, Tensor)> {
+            let sum = (&xs + &residual)?;
+            let normed = candle_nn::ops::rms_norm(&sum, &weight, 1e-6)?;
+            Ok((sum, normed))
+        };
+        let fused = || fused_add_rms_norm(&xs, &residual, &weight, 1e-6);
+        for (name, f) in [
+            (
+                "unfused",
+                &unfused as &dyn Fn() -> candle_core::Result<(Tensor, Tensor)>,
+            ),
+            ("fused", &fused),
+        ] {
+            f()?;
+            device.synchronize()?;
+            let start = Instant::now();
+            for _ in 0..ITERS {
+                f()?;
+            }
+            device.synchronize()?;
+            println!("{name}: {:?} per call", start.elapsed() / ITERS);
+        }
+        Ok(())
+    }
 }
@@ -88,6 +88,7 @@ impl PagedAttention {
                     softcap: softcapping.map(|x| x as f32),
                     softmax_scale: self.scale,
                     sliding_window: self.sliding_window,
+                    attention_softmax_f32: false,
                 },
             )?),
         };
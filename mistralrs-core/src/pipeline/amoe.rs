@@ -376,6 +376,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             -1,
             0.0,
             0.0,
+            0.0,
             vec![],
         )
         .map_err(candle_core::Error::msg)?;
@@ -408,6 +409,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
                                 ("content".to_string(), Either::Left(prompt.clone())),
                             ])],
                             true,
+                            false,
                             true,
                             Vec::new(),
                         )
@@ -1,10 +1,13 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use candle_core::{Result, Tensor, D};
+use candle_core::{Device, Result, Tensor, D};
 
 use crate::{get_mut_arcmutex, sequence::Sequence};
 
-use super::{CacheManagerMixin, MetadataMixin};
+use super::{
+    kv_cache::{KvCacheBackendType, OffloadedKvCache},
+    CacheManagerMixin, KvCacheBackend, MetadataMixin,
+};
 
 pub trait CacheManager<T: CacheManagerMixin + MetadataMixin + ?Sized> {
     fn clone_in_cache(
@@ -140,41 +143,165 @@ impl SingleCache {
     }
 }
 
+/// Keys and values kept contiguous on the device, the default [`KvCacheBackend`].
 #[derive(Debug, Clone)]
-pub struct KvCache {
+pub struct ContiguousKvCache {
     pub k: SingleCache,
     pub v: SingleCache,
 }
 
-impl KvCache {
+impl ContiguousKvCache {
     pub fn new(dim: usize, max_seq_len: usize, capacity_seq_len: usize) -> Self {
         let k = SingleCache::new(dim, max_seq_len, capacity_seq_len);
         let v = SingleCache::new(dim, max_seq_len, capacity_seq_len);
         Self { k, v }
     }
 
+    pub fn k(&self) -> Result<Option<Tensor>> {
+        self.k.current_data()
+    }
+
+    pub fn v(&self) -> Result<Option<Tensor>> {
+        self.v.current_data()
+    }
+
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let k = k.contiguous()?;
+        let v = v.contiguous()?;
+        self.k.append(&k)?;
+        self.v.append(&v)?;
+        let out_k = self.k.current_data()?;
+        let out_v = self.v.current_data()?;
+        let k = match out_k {
+            None => {
+                let mut shape = k.dims().to_vec();
+                shape[self.k.dim] = 0;
+                Tensor::zeros(shape, k.dtype(), k.device())?
+            }
+            Some(k) => k,
+        };
+        let v = match out_v {
+            None => {
+                let mut shape = v.dims().to_vec();
+                shape[self.k.dim] = 0;
+                Tensor::zeros(shape, v.dtype(), v.device())?
+            }
+            Some(v) => v,
+        };
+        Ok((k, v))
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.k.current_seq_len()
+    }
+
+    pub fn reset(&mut self) {
+        self.k.reset();
+        self.v.reset();
+    }
+
+    pub fn set_len(&mut self, len: usize) {
+        self.k.set_len(len);
+        self.v.set_len(len);
+    }
+}
+
+/// The KV cache of one attention layer, in the [`KvCacheBackend`] selected at load. The caches of
+/// the model and of the sequences keep the backend they were created with.
+#[derive(Debug, Clone)]
+pub enum KvCache {
+    Contiguous(ContiguousKvCache),
+    Offloaded(OffloadedKvCache),
+}
+
+impl KvCache {
+    /// A cache with the default, contiguous backend.
+    pub fn new(dim: usize, max_seq_len: usize, capacity_seq_len: usize) -> Self {
+        Self::Contiguous(ContiguousKvCache::new(dim, max_seq_len, capacity_seq_len))
+    }
+
+    /// A cache with the `backend`, which returns the keys and values on `device`.
+    pub fn with_backend(
+        backend: KvCacheBackendType,
+        dim: usize,
+        max_seq_len: usize,
+        capacity_seq_len: usize,
+        device: &Device,
+    ) -> Self {
+        match backend {
+            KvCacheBackendType::Contiguous => Self::new(dim, max_seq_len, capacity_seq_len),
+            KvCacheBackendType::Offloaded => Self::Offloaded(OffloadedKvCache::new(
+                dim,
+                max_seq_len,
+                capacity_seq_len,
+                device.clone(),
+            )),
+        }
+    }
+
+    /// An empty cache with the backend, dimension and maximum length of this one.
+    pub fn empty_like(&self) -> Self {
+        let (dim, max_seq_len) = (self.k_cache().dim, self.k_cache().max_seq_len);
+        match self {
+            Self::Contiguous(_) => Self::new(dim, max_seq_len, NormalCache::CACHE_GROW_SIZE),
+            Self::Offloaded(cache) => Self::Offloaded(OffloadedKvCache::new(
+                dim,
+                max_seq_len,
+                NormalCache::CACHE_GROW_SIZE,
+                cache.device().clone(),
+            )),
+        }
+    }
+
+    /// A cache with the backend of this one, holding `k` and `v`.
+    pub fn with_caches(&self, k: SingleCache, v: SingleCache) -> Result<Self> {
+        Ok(match self {
+            Self::Contiguous(_) => Self::Contiguous(ContiguousKvCache { k, v }),
+            Self::Offloaded(cache) => {
+                Self::Offloaded(OffloadedKvCache::from_caches(k, v, cache.device().clone())?)
+            }
+        })
+    }
+
+    /// The backend storage. For the offloaded backend, its tensors are in CPU memory.
+    fn storage(&self) -> &ContiguousKvCache {
+        match self {
+            Self::Contiguous(cache) => cache,
+            Self::Offloaded(cache) => cache.storage(),
+        }
+    }
+
+    fn storage_mut(&mut self) -> &mut ContiguousKvCache {
+        match self {
+            Self::Contiguous(cache) => cache,
+            Self::Offloaded(cache) => cache.storage_mut(),
+        }
+    }
+
     pub fn k_cache(&self) -> &SingleCache {
-        &self.k
+        &self.storage().k
     }
 
     pub fn v_cache(&self) -> &SingleCache {
-        &self.v
+        &self.storage().v
     }
 
     pub fn k_cache_mut(&mut self) -> &mut SingleCache {
-        &mut self.k
+        &mut self.storage_mut().k
     }
 
     pub fn v_cache_mut(&mut self) -> &mut SingleCache {
-        &mut self.v
+        &mut self.storage_mut().v
     }
 
+    /// The cached keys, where the backend stores them.
     pub fn k(&self) -> Result<Option<Tensor>> {
-        self.k.current_data()
+        self.storage().k()
     }
 
+    /// The cached values, where the backend stores them.
     pub fn v(&self) -> Result<Option<Tensor>> {
-        self.v.current_data()
+        self.storage().v()
     }
 
     pub fn append_sliding_window(
@@ -187,7 +314,7 @@ impl KvCache {
         let (mut k, mut v) = self.append(k, v)?;
 
         if let Some(sliding_window) = sliding_window {
-            assert_eq!(self.k.dim, 2);
+            assert_eq!(self.k_cache().dim, 2);
             let kv_seq_len = k.dim(2)?;
             if kv_seq_len > sliding_window {
                 k = k.narrow(2, kv_seq_len - (sliding_window - 1), sliding_window - 1)?;
@@ -206,44 +333,22 @@ impl KvCache {
         Ok((k, v, mask.cloned()))
     }
 
+    /// Append the keys and values of the new tokens, returning those of all cached tokens on the
+    /// device of `k` and `v`.
     pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
-        let k = k.contiguous()?;
-        let v = v.contiguous()?;
-        self.k.append(&k)?;
-        self.v.append(&v)?;
-        let out_k = self.k.current_data()?;
-        let out_v = self.v.current_data()?;
-        let k = match out_k {
-            None => {
-                let mut shape = k.dims().to_vec();
-                shape[self.k.dim] = 0;
-                Tensor::zeros(shape, k.dtype(), k.device())?
-            }
-            Some(k) => k,
-        };
-        let v = match out_v {
-            None => {
-                let mut shape = v.dims().to_vec();
-                shape[self.k.dim] = 0;
-                Tensor::zeros(shape, v.dtype(), v.device())?
-            }
-            Some(v) => v,
-        };
-        Ok((k, v))
+        KvCacheBackend::append(self, k, v)
     }
 
     pub fn current_seq_len(&self) -> usize {
-        self.k.current_seq_len()
+        self.storage().current_seq_len()
     }
 
     pub fn reset(&mut self) {
-        self.k.reset();
-        self.v.reset();
+        self.storage_mut().reset()
     }
 
     pub fn set_len(&mut self, len: usize) {
-        self.k.set_len(len);
-        self.v.set_len(len);
+        self.storage_mut().set_len(len)
     }
 }
 
@@ -264,6 +369,16 @@ impl NormalCache {
             len
         ])))
     }
+
+    /// Store the keys and values of every layer in `backend`, returning them on `device`. The
+    /// cached tokens are dropped.
+    pub fn set_backend(&mut self, backend: KvCacheBackendType, device: &Device) {
+        for layer in &mut self.0 {
+            let (dim, max_seq_len) = (layer.k_cache().dim, layer.k_cache().max_seq_len);
+            *layer =
+                KvCache::with_backend(backend, dim, max_seq_len, Self::CACHE_GROW_SIZE, device);
+        }
+    }
 }
 
 pub struct NormalCacheManager;
@@ -278,18 +393,24 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
         let mut new_k_cache = Vec::new();
         let mut new_v_cache = Vec::new();
         // Use this for the various parameters. Assumes all seqs are from one model.
-        let template_cache_dim = seqs[0].normal_cache()[0].as_ref().unwrap().k.dim;
+        let template_cache_dim = seqs[0].normal_cache()[0].as_ref().unwrap().k_cache().dim;
         let template_cache_csl = seqs[0].normal_cache()[0]
             .as_ref()
             .unwrap()
-            .k
+            .k_cache()
             .current_seq_len;
-        let template_cache_msl = seqs[0].normal_cache()[0].as_ref().unwrap().k.max_seq_len;
+        let template_cache_msl = seqs[0].normal_cache()[0]
+            .as_ref()
+            .unwrap()
+            .k_cache()
+            .max_seq_len;
         let template_cache_capsl = seqs[0].normal_cache()[0]
             .as_ref()
             .unwrap()
-            .k
+            .k_cache()
             .capacity_seq_len;
+        // The backend selected at load
+        let template_cache = pipeline.cache().normal().0[0].empty_like();
 
         'outer: for layer in 0..pipeline.get_metadata().num_hidden_layers {
             let mut k_vec = Vec::new();
@@ -306,8 +427,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                 let cache = cache
                     .as_ref()
                     .expect("Not handling completions in `clone_in_cache`.");
-                k_vec.push(cache.k.all_data.clone().unwrap());
-                v_vec.push(cache.v.all_data.clone().unwrap());
+                k_vec.push(cache.k_cache().all_data.clone().unwrap());
+                v_vec.push(cache.v_cache().all_data.clone().unwrap());
             }
             new_k_cache.push(Some(if k_vec.len() > 1 {
                 Tensor::cat(&k_vec, 0).unwrap()
@@ -322,22 +443,26 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
         }
         let mut caches = Vec::new();
         for (k_cache, v_cache) in new_k_cache.into_iter().zip(new_v_cache) {
-            caches.push(KvCache {
-                k: SingleCache {
-                    all_data: k_cache.map(|x| x.contiguous().unwrap()),
-                    dim: template_cache_dim,
-                    current_seq_len: template_cache_csl,
-                    max_seq_len: template_cache_msl,
-                    capacity_seq_len: template_cache_capsl,
-                },
-                v: SingleCache {
-                    all_data: v_cache.map(|x| x.contiguous().unwrap()),
-                    dim: template_cache_dim,
-                    current_seq_len: template_cache_csl,
-                    max_seq_len: template_cache_msl,
-                    capacity_seq_len: template_cache_capsl,
-                },
-            });
+            caches.push(
+                template_cache
+                    .with_caches(
+                        SingleCache {
+                            all_data: k_cache.map(|x| x.contiguous().unwrap()),
+                            dim: template_cache_dim,
+                            current_seq_len: template_cache_csl,
+                            max_seq_len: template_cache_msl,
+                            capacity_seq_len: template_cache_capsl,
+                        },
+                        SingleCache {
+                            all_data: v_cache.map(|x| x.contiguous().unwrap()),
+                            dim: template_cache_dim,
+                            current_seq_len: template_cache_csl,
+                            max_seq_len: template_cache_msl,
+                            capacity_seq_len: template_cache_capsl,
+                        },
+                    )
+                    .unwrap(),
+            );
         }
         *pipeline.cache().normal() = NormalCache(caches);
     }
@@ -350,8 +475,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                 continue;
             }
 
-            let k_cache = cache.k.all_data.clone().unwrap();
-            let v_cache = cache.v.all_data.clone().unwrap();
+            let k_cache = cache.k_cache().all_data.clone().unwrap();
+            let v_cache = cache.v_cache().all_data.clone().unwrap();
 
             let k_caches = k_cache.chunk(seqs.len(), 0).unwrap();
             debug_assert_eq!(k_caches.len(), seqs.len());
@@ -363,22 +488,27 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                 let seq_cache = &mut output_cache[layer];
                 let k = k_caches.get(seq_i).unwrap().clone();
                 let v = v_caches.get(seq_i).unwrap().clone();
-                *seq_cache = Some(KvCache {
-                    k: SingleCache {
-                        all_data: Some(k),
-                        dim: cache.k.dim,
-                        current_seq_len: cache.k.current_seq_len,
-                        max_seq_len: cache.k.max_seq_len,
-                        capacity_seq_len: cache.k.capacity_seq_len,
-                    },
-                    v: SingleCache {
-                        all_data: Some(v),
-                        dim: cache.v.dim,
-                        current_seq_len: cache.v.current_seq_len,
-                        max_seq_len: cache.v.max_seq_len,
-                        capacity_seq_len: cache.v.capacity_seq_len,
-                    },
-                });
+                let (k_cache, v_cache) = (cache.k_cache(), cache.v_cache());
+                *seq_cache = Some(
+                    cache
+                        .with_caches(
+                            SingleCache {
+                                all_data: Some(k),
+                                dim: k_cache.dim,
+                                current_seq_len: k_cache.current_seq_len,
+                                max_seq_len: k_cache.max_seq_len,
+                                capacity_seq_len: k_cache.capacity_seq_len,
+                            },
+                            SingleCache {
+                                all_data: Some(v),
+                                dim: v_cache.dim,
+                                current_seq_len: v_cache.current_seq_len,
+                                max_seq_len: v_cache.max_seq_len,
+                                capacity_seq_len: v_cache.capacity_seq_len,
+                            },
+                        )
+                        .unwrap(),
+                );
             }
         }
     }
@@ -390,11 +520,12 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
         load_preallocated_cache: bool,
     ) {
         // Use this for the various parameters. Assumes all seqs are from one model.
-        let template_cache_dim = pipeline.cache().normal().0[0].k.dim;
-        let template_cache_msl = pipeline.cache().normal().0[0].k.max_seq_len;
+        let template_cache_dim = pipeline.cache().normal().0[0].k_cache().dim;
+        let template_cache_msl = pipeline.cache().normal().0[0].k_cache().max_seq_len;
 
         for layer in pipeline.cache().normal().0.iter_mut() {
-            if !load_preallocated_cache {
+            // The offloaded backend grows in CPU memory instead
+            if !load_preallocated_cache || matches!(layer, KvCache::Offloaded(_)) {
                 layer.reset();
                 continue;
             }
@@ -415,7 +546,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
             } else {
                 v_caches[0].clone()
             };
-            let cache = KvCache {
+            let cache = KvCache::Contiguous(ContiguousKvCache {
                 k: SingleCache {
                     all_data: Some(k_cache.zeros_like().unwrap()),
                     dim: template_cache_dim,
@@ -430,7 +561,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     max_seq_len: template_cache_msl,
                     capacity_seq_len: k_cache.dims()[template_cache_dim],
                 },
-            };
+            });
             *layer = cache;
         }
     }
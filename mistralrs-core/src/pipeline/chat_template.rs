@@ -7,7 +7,7 @@ use itertools::Itertools;
 use minijinja::{context, value::Kwargs, Environment, Error, ErrorKind, Value};
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{MessageContent, Tool};
 
@@ -16,6 +16,23 @@ const SUPPORTED_ALTERNATE_EOS: &[&str] = &[
     "<end_of_turn>", // Handle Gemma2 chat case
 ];
 
+const CHATML_IM_START: &str = "<|im_start|>";
+const CHATML_IM_END: &str = "<|im_end|>";
+
+/// The ChatML chat template, used if a model has no chat template but has ChatML special tokens.
+pub(crate) const CHATML_CHAT_TEMPLATE: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>' + '\\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}";
+
+/// Whether both ChatML markers (`<|im_start|>` and `<|im_end|>`) are special tokens of the tokenizer.
+/// A marker which is only a regular token in the vocab does not count.
+pub(crate) fn has_chatml_special_tokens(tokenizer: &Tokenizer) -> bool {
+    let added = tokenizer.get_added_tokens_decoder();
+    [CHATML_IM_START, CHATML_IM_END].iter().all(|marker| {
+        added
+            .values()
+            .any(|tok| tok.special && tok.content == *marker)
+    })
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct AddedTokensDecoder {
@@ -95,6 +112,64 @@ impl ChatTemplate {
             Either::Right(ref added) => Some(added.content.clone()),
         }
     }
+
+    /// The sources of all chat templates, including the named ones.
+    fn template_sources(&self) -> Vec<&str> {
+        match self.chat_template.as_ref().map(|template| &template.0) {
+            Some(Either::Left(template)) => vec![template.as_str()],
+            Some(Either::Right(templates)) => templates
+                .iter()
+                .filter_map(|template| template.get("template").map(String::as_str))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// The tracing target of the BOS/EOS duplication warnings, so that their level can be configured
+/// separately, e.g. `RUST_LOG=mistralrs::special_tokens=off` silences them.
+pub const SPECIAL_TOKEN_DUPLICATION_TARGET: &str = "mistralrs::special_tokens";
+
+/// The special tokens which would be duplicated in the prompt, because both the chat template
+/// renders them and the tokenizer adds them when encoding the rendered prompt. Only the
+/// post-processor of `tokenizer.json` adds tokens when encoding: the `add_bos_token` and
+/// `add_eos_token` flags of the tokenizer config are not used by the tokenizer.
+fn special_token_duplications(chat_template: &ChatTemplate, tokenizer: &Tokenizer) -> Vec<String> {
+    let sources = chat_template.template_sources();
+    let added = tokenizer
+        .encode("", true)
+        .map(|encoding| encoding.get_ids().to_vec())
+        .unwrap_or_default();
+
+    let mut duplications = Vec::new();
+    for (name, variable, tok, position) in [
+        ("BOS", "bos_token", chat_template.bos_tok(), added.first()),
+        ("EOS", "eos_token", chat_template.eos_tok(), added.last()),
+    ] {
+        let Some(tok) = tok.filter(|tok| !tok.is_empty()) else {
+            continue;
+        };
+        let template_adds = sources
+            .iter()
+            .any(|source| source.contains(variable) || source.contains(&tok));
+        let tokenizer_adds = tokenizer
+            .token_to_id(&tok)
+            .is_some_and(|id| position == Some(&id));
+        if template_adds && tokenizer_adds {
+            duplications.push(format!(
+                "The chat template renders the {name} token `{tok}` and the post-processor of `tokenizer.json` also adds it, so prompts will contain it twice. Remove it from the chat template or from the `post_processor` of the tokenizer."
+            ));
+        }
+    }
+    duplications
+}
+
+/// Warn once, at load, about special tokens which the chat template and the tokenizer would
+/// both add to the prompt.
+pub(crate) fn warn_special_token_duplication(chat_template: &ChatTemplate, tokenizer: &Tokenizer) {
+    for duplication in special_token_duplications(chat_template, tokenizer) {
+        warn!(target: SPECIAL_TOKEN_DUPLICATION_TARGET, "{duplication}");
+    }
 }
 
 pub fn calculate_eos_tokens(
@@ -157,6 +232,7 @@ pub fn calculate_eos_tokens(
         "bos_toks = {bos_render}, eos_toks = {eos_render}, unk_tok = {}",
         chat_template.unk_tok().unwrap_or("`None`".to_string()),
     );
+    warn_special_token_duplication(chat_template, tokenizer);
 
     let mut eos_toks = Vec::new();
     for eos_tok in eos_tok_ids {
@@ -214,15 +290,108 @@ fn tojson(value: Value, kwargs: Kwargs) -> Result<Value, Error> {
     })
 }
 
+const JSON_OBJECT_HINT: &str = "Respond with a JSON object.";
+
+/// Ask for a JSON object in the system message, unless the messages already mention JSON. The
+/// hint is appended to a leading system message, or added as a new one.
+pub(crate) fn with_json_object_hint(
+    messages: &[IndexMap<String, MessageContent>],
+) -> Option<Vec<IndexMap<String, MessageContent>>> {
+    let mentions_json = messages.iter().any(|message| match message.get("content") {
+        Some(Either::Left(text)) => text.to_lowercase().contains("json"),
+        Some(Either::Right(parts)) => parts.iter().any(|part| {
+            part.get("text")
+                .and_then(|text| text.as_str())
+                .is_some_and(|text| text.to_lowercase().contains("json"))
+        }),
+        None => false,
+    });
+    if mentions_json {
+        return None;
+    }
+
+    let mut messages = messages.to_vec();
+    let leading_system = messages.first_mut().filter(
+        |message| matches!(message.get("role"), Some(Either::Left(role)) if role == "system"),
+    );
+    match leading_system.and_then(|message| message.get_mut("content")) {
+        Some(Either::Left(text)) => {
+            text.push_str("\n\n");
+            text.push_str(JSON_OBJECT_HINT);
+        }
+        _ => {
+            let mut system = IndexMap::new();
+            system.insert("role".to_string(), Either::Left("system".to_string()));
+            system.insert(
+                "content".to_string(),
+                Either::Left(JSON_OBJECT_HINT.to_string()),
+            );
+            messages.insert(0, system);
+        }
+    }
+    Some(messages)
+}
+
+/// The text of the final message, which is left open by `continue_final_message`.
+fn final_message_text(messages: &[IndexMap<String, MessageContent>]) -> Result<String> {
+    let Some(content) = messages.last().and_then(|message| message.get("content")) else {
+        anyhow::bail!("`continue_final_message` requires a final message with content.");
+    };
+    match content {
+        Either::Left(text) => Ok(text.clone()),
+        Either::Right(parts) => parts
+            .iter()
+            .rev()
+            .find_map(|part| part.get("text").and_then(|text| text.as_str()))
+            .map(ToString::to_string)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "`continue_final_message` requires the final message to contain text."
+                )
+            }),
+    }
+}
+
+/// Cut the rendered prompt right after the final message, dropping the end of turn tokens the
+/// template appends to it. Templates commonly strip the message, so that is tried as well.
+fn truncate_after_final_message(rendered: &mut String, final_message: &str) -> Result<()> {
+    for candidate in [final_message, final_message.trim()] {
+        if candidate.is_empty() {
+            continue;
+        }
+        if let Some(pos) = rendered.rfind(candidate) {
+            rendered.truncate(pos + candidate.len());
+            return Ok(());
+        }
+    }
+    anyhow::bail!("The final message could not be found in the rendered chat template, so it cannot be continued.")
+}
+
+/// Render the chat template. With `continue_final_message`, the final message is left open so
+/// that the model continues it instead of starting a new turn. This is incompatible with
+/// `add_generation_prompt`.
+#[allow(clippy::too_many_arguments)]
 pub fn apply_chat_template_to(
     messages: Vec<IndexMap<String, MessageContent>>,
     add_generation_prompt: bool,
+    continue_final_message: bool,
     template: &ChatTemplateValue,
     bos_tok: Option<String>,
     eos_tok: Option<String>,
     unk_tok: Option<String>,
     tools: Vec<Tool>,
 ) -> Result<String> {
+    if continue_final_message && add_generation_prompt {
+        anyhow::bail!(
+            "`continue_final_message` and `add_generation_prompt` cannot be used together."
+        );
+    }
+    let final_message = if continue_final_message {
+        Some(final_message_text(&messages)?)
+    } else {
+        None
+    };
+
     let mut env = Environment::new();
 
     // enable python methods such as .strip()
@@ -271,17 +440,17 @@ pub fn apply_chat_template_to(
     let date = chrono::Utc::now();
     let date_string = date.format("%d, %B, %Y").to_string();
 
-    if tools.is_empty() {
-        Ok(tmpl.render(context! {
+    let mut rendered = if tools.is_empty() {
+        tmpl.render(context! {
             messages => new_messages,
             add_generation_prompt => add_generation_prompt,
             bos_token => bos_tok,
             eos_token => eos_tok,
             unk_token => unk_tok,
             date_string => date_string,
-        })?)
+        })?
     } else {
-        Ok(tmpl.render(context! {
+        tmpl.render(context! {
             messages => new_messages,
             add_generation_prompt => add_generation_prompt,
             bos_token => bos_tok,
@@ -289,6 +458,187 @@ pub fn apply_chat_template_to(
             unk_token => unk_tok,
             tools => tools,
             date_string => date_string,
-        })?)
+        })?
+    };
+    if let Some(final_message) = final_message {
+        truncate_after_final_message(&mut rendered, &final_message)?;
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use either::Either;
+    use indexmap::IndexMap;
+    use tokenizers::{
+        models::wordlevel::WordLevel, processors::template::TemplateProcessing, AddedToken,
+        Tokenizer,
+    };
+
+    use super::{
+        apply_chat_template_to, has_chatml_special_tokens, special_token_duplications,
+        with_json_object_hint, ChatTemplate, ChatTemplateValue,
+    };
+    use crate::MessageContent;
+
+    const CHATML_TEMPLATE: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>' + '\\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}";
+
+    fn tokenizer_fixture() -> Tokenizer {
+        let vocab = HashMap::from([("[UNK]".to_string(), 0), ("hello".to_string(), 1)]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
+
+    #[test]
+    fn test_chatml_detection() {
+        let mut tokenizer = tokenizer_fixture();
+        assert!(!has_chatml_special_tokens(&tokenizer));
+
+        tokenizer.add_special_tokens(&[AddedToken::from("<|im_start|>", true)]);
+        assert!(!has_chatml_special_tokens(&tokenizer));
+
+        tokenizer.add_special_tokens(&[AddedToken::from("<|im_end|>", true)]);
+        assert!(has_chatml_special_tokens(&tokenizer));
+    }
+
+    #[test]
+    fn test_chatml_detection_requires_special() {
+        let mut tokenizer = tokenizer_fixture();
+        tokenizer.add_tokens(&[
+            AddedToken::from("<|im_start|>", false),
+            AddedToken::from("<|im_end|>", false),
+        ]);
+        assert!(!has_chatml_special_tokens(&tokenizer));
+    }
+
+    #[test]
+    fn test_continue_final_message() -> anyhow::Result<()> {
+        let message = |role: &str, content: &str| -> IndexMap<String, MessageContent> {
+            IndexMap::from([
+                ("role".to_string(), Either::Left(role.to_string())),
+                ("content".to_string(), Either::Left(content.to_string())),
+            ])
+        };
+        let messages = vec![
+            message("user", "Name a color."),
+            message("assistant", "The color is"),
+        ];
+        let template = ChatTemplateValue(Either::Left(CHATML_TEMPLATE.to_string()));
+        let render = |add_generation_prompt, continue_final_message| {
+            apply_chat_template_to(
+                messages.clone(),
+                add_generation_prompt,
+                continue_final_message,
+                &template,
+                None,
+                None,
+                None,
+                Vec::new(),
+            )
+        };
+
+        let closed = render(false, false)?;
+        assert!(closed.ends_with("The color is<|im_end|>\n"), "{closed}");
+        let with_prompt = render(true, false)?;
+        assert!(
+            with_prompt.ends_with("<|im_start|>assistant\n"),
+            "{with_prompt}"
+        );
+
+        let open = render(false, true)?;
+        assert_eq!(
+            open,
+            "<|im_start|>user\nName a color.<|im_end|>\n<|im_start|>assistant\nThe color is"
+        );
+        assert!(closed.starts_with(&open));
+
+        assert!(render(true, true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_object_hint() {
+        let message = |role: &str, content: &str| -> IndexMap<String, MessageContent> {
+            IndexMap::from([
+                ("role".to_string(), Either::Left(role.to_string())),
+                ("content".to_string(), Either::Left(content.to_string())),
+            ])
+        };
+        let content = |message: &IndexMap<String, MessageContent>| match &message["content"] {
+            Either::Left(text) => text.clone(),
+            Either::Right(_) => panic!("Expected text content."),
+        };
+
+        // Added as a new system message
+        let hinted = with_json_object_hint(&[message("user", "List three colors.")]).unwrap();
+        assert_eq!(hinted.len(), 2);
+        assert_eq!(content(&hinted[0]), "Respond with a JSON object.");
+
+        // Appended to the leading system message
+        let hinted = with_json_object_hint(&[
+            message("system", "You are terse."),
+            message("user", "List three colors."),
+        ])
+        .unwrap();
+        assert_eq!(hinted.len(), 2);
+        assert_eq!(
+            content(&hinted[0]),
+            "You are terse.\n\nRespond with a JSON object."
+        );
+
+        // Not needed if the messages already ask for JSON
+        assert!(with_json_object_hint(&[message("user", "List three colors as Json.")]).is_none());
+    }
+
+    #[test]
+    fn test_special_token_duplication() -> anyhow::Result<()> {
+        let mut tokenizer = tokenizer_fixture();
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<s>", true),
+            AddedToken::from("</s>", true),
+        ]);
+        // A Llama 2 style template, which renders the BOS token itself. The `add_bos_token` flag
+        // is not used by the tokenizer, so it does not matter.
+        let template: ChatTemplate = serde_json::from_str(
+            r#"{
+                "add_bos_token": true,
+                "add_eos_token": false,
+                "bos_token": "<s>",
+                "eos_token": "</s>",
+                "chat_template": "{{ bos_token }}{% for message in messages %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% endfor %}"
+            }"#,
+        )?;
+        assert!(special_token_duplications(&template, &tokenizer).is_empty());
+
+        // A post-processor which adds the BOS token duplicates it
+        let bos_id = tokenizer.token_to_id("<s>").unwrap();
+        tokenizer.with_post_processor(Some(
+            TemplateProcessing::builder()
+                .try_single("<s> $A")
+                .map_err(anyhow::Error::msg)?
+                .special_tokens(vec![("<s>", bos_id)])
+                .build()?,
+        ));
+        assert_eq!(tokenizer.encode("", true).unwrap().get_ids(), &[bos_id]);
+        let duplications = special_token_duplications(&template, &tokenizer);
+        assert_eq!(duplications.len(), 1);
+        assert!(duplications[0].contains("BOS"), "{}", duplications[0]);
+        assert!(
+            duplications[0].contains("post_processor"),
+            "{}",
+            duplications[0]
+        );
+        assert!(
+            !duplications[0].contains("add_bos_token"),
+            "{}",
+            duplications[0]
+        );
+        Ok(())
     }
 }
@@ -185,10 +185,12 @@ impl Loader for DiffusionLoader {
                         from_mmaped_safetensors(
                             vec![path.clone()],
                             Vec::new(),
+                            false,
                             Some(dtype),
                             if force_cpu { &Device::Cpu } else { device },
                             silent,
                             None,
+                            None,
                             |_| true,
                         )
                     })
@@ -222,6 +224,7 @@ impl Loader for DiffusionLoader {
                 eos_tok: vec![],
                 kind: self.kind.clone(),
                 has_no_kv_cache: true, // NOTE(EricLBuehler): no cache for these.
+                is_recurrent: false,
                 activation_dtype: dtype,
                 sliding_window: None,
                 cache_config: None,
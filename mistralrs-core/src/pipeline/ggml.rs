@@ -345,7 +345,7 @@ impl Loader for GGMLLoader {
             _ => unreachable!(),
         };
 
-        let tokenizer = get_tokenizer(paths.get_tokenizer_filename(), None)?;
+        let tokenizer = get_tokenizer(paths.get_tokenizer_filename(), None, &[])?;
         let gen_conf: Option<GenerationConfig> = paths
             .get_gen_conf_filename()
             .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
@@ -358,6 +358,7 @@ impl Loader for GGMLLoader {
                 .clone(),
             &self.chat_template,
             None,
+            &tokenizer,
         );
 
         let max_seq_len = match model {
@@ -386,6 +387,7 @@ impl Loader for GGMLLoader {
                 max_seq_len,
                 tok_env: Some(tok_env),
                 has_no_kv_cache: self.no_kv_cache,
+                is_recurrent: false,
                 num_hidden_layers,
                 eos_tok: eos,
                 kind: self.kind.clone(),
@@ -377,7 +377,7 @@ impl Loader for GGUFLoader {
             convert_gguf_to_hf_tokenizer(&model)?
         } else {
             GgufTokenizerConversion {
-                tokenizer: get_tokenizer(paths.get_tokenizer_filename(), None)?,
+                tokenizer: get_tokenizer(paths.get_tokenizer_filename(), None, &[])?,
                 bos: None,
                 eos: None,
                 unk: None,
@@ -481,6 +481,7 @@ impl Loader for GGUFLoader {
                 .clone(),
             &self.chat_template,
             gguf_chat_template,
+            &tokenizer,
         );
 
         let max_seq_len = match model {
@@ -533,6 +534,7 @@ impl Loader for GGUFLoader {
                 max_seq_len,
                 tok_env: Some(tok_env),
                 has_no_kv_cache: self.no_kv_cache,
+                is_recurrent: false,
                 num_hidden_layers,
                 eos_tok: eos,
                 kind: self.kind.clone(),
@@ -481,6 +481,12 @@ pub mod text_models_inputs_processor {
         mut paged_attn_metadata: Option<&mut PagedAttentionMeta<'_>>,
         prompt_batchsize: Option<NonZeroUsize>,
     ) -> Box<dyn Iterator<Item = Result<InnerInputProcessorOutput>>> {
+        // The tokens covered by a prefix cache are not run again. The engine only batches prompts
+        // whose prefix caches cover the same number of tokens.
+        let prefix_offset = input_seqs
+            .first()
+            .map(|seq| seq.prefix_cache_len())
+            .unwrap_or(0);
         if let (Some(prompt_batchsize), true) = (prompt_batchsize, paged_attn_metadata.is_none()) {
             let mut seq_chunks = Vec::new();
             let mut n_chunks = Vec::new();
@@ -508,7 +514,7 @@ pub mod text_models_inputs_processor {
                 .map(|(i, chunk)| {
                     let (toks, seq_ns): (Vec<Vec<T>>, Vec<usize>) = chunk.into_iter().unzip();
                     make_prompt_chunk(
-                        i * prompt_batchsize,
+                        prefix_offset + i * prompt_batchsize,
                         toks,
                         &seq_ns
                             .iter()
@@ -535,7 +541,7 @@ pub mod text_models_inputs_processor {
             }
             Box::new(std::iter::once(
                 make_prompt_chunk(
-                    0,
+                    prefix_offset,
                     toks,
                     &input_seqs.iter().map(|s| *s.id()).collect::<Vec<_>>(),
                     device,
@@ -2,7 +2,7 @@ use std::{
     borrow::Cow,
     collections::{HashMap, HashSet},
     fs::File,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::{atomic::AtomicUsize, Arc},
     time::Instant,
@@ -125,6 +125,76 @@ pub struct UqffFullSer<'a> {
     pub preprocessor_filename: &'a Option<PathBuf>,
 }
 
+/// Write the configuration and tokenizer alongside the serialized weights in `parent`, so that
+/// the model can be loaded standalone.
+fn write_full_ser(parent: &Path, full_ser: UqffFullSer<'_>) -> candle_core::Result<()> {
+    let config_out = parent.join("config.json");
+    let tokenizer_out = parent.join("tokenizer.json");
+    let tokenizer_cfg_out = parent.join("tokenizer_config.json");
+    let gen_cfg_out = parent.join("generation_config.json");
+    let processor_out = parent.join("processor_config.json");
+    let preprocessor_out = parent.join("preprocessor_config.json");
+
+    let UqffFullSer {
+        tokenizer,
+        template_filename,
+        generation_config,
+        config,
+        processor_filename,
+        preprocessor_filename,
+    } = full_ser;
+
+    info!("Serializing configuration to `{}`.", config_out.display());
+
+    std::fs::write(config_out, config)?;
+
+    info!("Serializing tokenizer to `{}`.", tokenizer_out.display());
+
+    serde_json::to_writer_pretty(File::create(&tokenizer_out)?, tokenizer)
+        .map_err(candle_core::Error::msg)?;
+
+    if let Some(template_filename) = template_filename {
+        info!(
+            "Serializing tokenizer config to `{}`.",
+            tokenizer_cfg_out.display()
+        );
+
+        let template = std::fs::read(template_filename).map_err(candle_core::Error::msg)?;
+        std::fs::write(&tokenizer_cfg_out, template).map_err(candle_core::Error::msg)?;
+    }
+
+    if let Some(generation_config) = generation_config {
+        info!(
+            "Serializing generation config to `{}`.",
+            gen_cfg_out.display()
+        );
+
+        let cfg = std::fs::read(generation_config).map_err(candle_core::Error::msg)?;
+        std::fs::write(&gen_cfg_out, cfg).map_err(candle_core::Error::msg)?;
+    }
+
+    if let Some(processor_config) = processor_filename {
+        info!(
+            "Serializing processor config to `{}`.",
+            processor_out.display()
+        );
+
+        let cfg = std::fs::read(processor_config).map_err(candle_core::Error::msg)?;
+        std::fs::write(&processor_out, cfg).map_err(candle_core::Error::msg)?;
+    }
+
+    if let Some(preprocessor_config) = preprocessor_filename {
+        info!(
+            "Serializing preprocessor config to `{}`.",
+            preprocessor_out.display()
+        );
+
+        let cfg = std::fs::read(preprocessor_config).map_err(candle_core::Error::msg)?;
+        std::fs::write(&preprocessor_out, cfg).map_err(candle_core::Error::msg)?;
+    }
+    Ok(())
+}
+
 pub enum ImatrixDataSource<'a> {
     File(&'a PathBuf),
     Collected,
@@ -235,6 +305,57 @@ pub trait IsqModel {
         None
     }
 
+    /// Safetensors names of the layers of [`Self::get_layers`], in the same order and without the
+    /// `.weight`/`.bias` suffix. Used with [`Self::residual_tensors`] to save the model.
+    fn layer_names(&self) -> candle_core::Result<Vec<String>> {
+        candle_core::bail!("This model does not support saving to safetensors.");
+    }
+
+    /// Save the weights to the safetensors file `path` with their standard names, along with the
+    /// configuration and tokenizer, so that it can be loaded as a regular model. The model must
+    /// not be quantized.
+    fn save_safetensors(
+        &mut self,
+        path: &Path,
+        full_ser: UqffFullSer<'_>,
+    ) -> candle_core::Result<()> {
+        let names = self.layer_names()?;
+        let mut tensors = self.residual_tensors();
+        let (layers, _) = self.get_layers();
+        if layers.len() != names.len() {
+            candle_core::bail!(
+                "Expected {} layer names, got {}.",
+                layers.len(),
+                names.len()
+            );
+        }
+        for ((layer, _), name) in layers.into_iter().zip(names) {
+            let Some((weight, bias)) = layer.unquant_weight_bias() else {
+                candle_core::bail!(
+                    "`{name}` is quantized, only unquantized models can be saved to safetensors."
+                );
+            };
+            tensors.push((format!("{name}.weight"), weight));
+            if let Some(bias) = bias {
+                tensors.push((format!("{name}.bias"), bias));
+            }
+        }
+
+        let parent = path
+            .parent()
+            .context("Target safetensors path must have a filename!")?;
+        std::fs::create_dir_all(parent)?;
+
+        info!(
+            "Serializing {} tensors to `{}`.",
+            tensors.len(),
+            path.display()
+        );
+        safetensors::serialize_to_file(tensors, &None, path)?;
+
+        write_full_ser(parent, full_ser)
+    }
+
     /// Quantize the model in-situ.
     ///
     /// This function will also create a UQFF file, or, if the model supports it (residual tensors are returned),
@@ -524,13 +645,6 @@ pub trait IsqModel {
                 };
 
                 let residual_out = parent.join(UQFF_RESIDUAL_SAFETENSORS);
-                let config_out = parent.join("config.json");
-                let tokenizer_out = parent.join("tokenizer.json");
-                let tokenizer_cfg_out = parent.join("tokenizer_config.json");
-                let gen_cfg_out = parent.join("generation_config.json");
-                let processor_out = parent.join("processor_config.json");
-                let preprocessor_out = parent.join("preprocessor_config.json");
-
                 info!(
                     "Serializing {} residual tensors to `{}`.",
                     residual.len(),
@@ -539,66 +653,7 @@ pub trait IsqModel {
 
                 safetensors::serialize_to_file(residual, &None, &residual_out)?;
 
-                let UqffFullSer {
-                    tokenizer,
-                    template_filename,
-                    generation_config,
-                    config,
-                    processor_filename,
-                    preprocessor_filename,
-                } = full_ser;
-
-                info!("Serializing configuration to `{}`.", config_out.display());
-
-                std::fs::write(config_out, config)?;
-
-                info!("Serializing tokenizer to `{}`.", tokenizer_out.display());
-
-                serde_json::to_writer_pretty(File::create(&tokenizer_out)?, tokenizer)
-                    .map_err(candle_core::Error::msg)?;
-
-                if let Some(template_filename) = template_filename {
-                    info!(
-                        "Serializing tokenizer config to `{}`.",
-                        tokenizer_cfg_out.display()
-                    );
-
-                    let template =
-                        std::fs::read(template_filename).map_err(candle_core::Error::msg)?;
-                    std::fs::write(&tokenizer_cfg_out, template)
-                        .map_err(candle_core::Error::msg)?;
-                }
-
-                if let Some(generation_config) = generation_config {
-                    info!(
-                        "Serializing generation config to `{}`.",
-                        gen_cfg_out.display()
-                    );
-
-                    let cfg = std::fs::read(generation_config).map_err(candle_core::Error::msg)?;
-                    std::fs::write(&gen_cfg_out, cfg).map_err(candle_core::Error::msg)?;
-                }
-
-                if let Some(processor_config) = processor_filename {
-                    info!(
-                        "Serializing processor config to `{}`.",
-                        processor_out.display()
-                    );
-
-                    let cfg = std::fs::read(processor_config).map_err(candle_core::Error::msg)?;
-                    std::fs::write(&processor_out, cfg).map_err(candle_core::Error::msg)?;
-                }
-
-                if let Some(preprocessor_config) = preprocessor_filename {
-                    info!(
-                        "Serializing preprocessor config to `{}`.",
-                        preprocessor_out.display()
-                    );
-
-                    let cfg =
-                        std::fs::read(preprocessor_config).map_err(candle_core::Error::msg)?;
-                    std::fs::write(&preprocessor_out, cfg).map_err(candle_core::Error::msg)?;
-                }
+                write_full_ser(parent, full_ser)?;
             }
             let delta = Instant::now().duration_since(t_start).as_secs_f32();
             info!("Applied in-situ quantization into {dtype:?} to {n_quantized:?} tensors out of {total_tensors} total tensors. Took {delta:.2}s", );
@@ -0,0 +1,275 @@
+//! The KV cache backends which an attention layer can store its keys and values in.
+
+use std::str::FromStr;
+
+use candle_core::{Device, Result, Tensor};
+use serde::Deserialize;
+
+use super::cache_manager::{ContiguousKvCache, KvCache, SingleCache};
+
+/// Storage for the keys and values of one attention layer. The keys and values are laid out as
+/// `(batch, kv_heads, seq_len, head_dim)`.
+///
+/// [`ContiguousKvCache`], which keeps them contiguous on the device, is the default backend, and
+/// [`OffloadedKvCache`] keeps them in CPU memory. The backend of a model is selected at load with
+/// [`KvCacheBackendType`], and held by the [`KvCache`] of every layer.
+pub trait KvCacheBackend {
+    /// Append the keys and values of the new tokens, returning the keys and values of all cached
+    /// tokens, on the device of `k` and `v`.
+    fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)>;
+
+    /// The keys and values of all cached tokens, or `None` if nothing was cached yet.
+    fn gather(&self) -> Result<Option<(Tensor, Tensor)>>;
+
+    /// Drop all cached tokens.
+    fn reset(&mut self);
+
+    /// The number of cached tokens.
+    fn current_seq_len(&self) -> usize;
+
+    /// The number of bytes allocated for the keys and values.
+    fn memory_used(&self) -> usize;
+}
+
+/// The KV cache backend to load a model with.
+#[derive(Clone, Debug, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum KvCacheBackendType {
+    /// Keep the keys and values contiguous on the device.
+    #[default]
+    #[serde(rename = "contiguous")]
+    Contiguous,
+    /// Keep the keys and values in CPU memory, for contexts which do not fit on the device. They
+    /// are copied to the device for every attention computation, trading speed for device memory.
+    #[serde(rename = "offloaded")]
+    Offloaded,
+}
+
+impl FromStr for KvCacheBackendType {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "contiguous" => Ok(Self::Contiguous),
+            "offloaded" => Ok(Self::Offloaded),
+            other => Err(format!(
+                "Expected KV cache backend `contiguous` or `offloaded`, got `{other}`"
+            )),
+        }
+    }
+}
+
+fn allocated_bytes(cache: &SingleCache) -> usize {
+    cache
+        .all_data()
+        .as_ref()
+        .map_or(0, |data| data.elem_count() * data.dtype().size_in_bytes())
+}
+
+impl KvCacheBackend for ContiguousKvCache {
+    fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        ContiguousKvCache::append(self, k, v)
+    }
+
+    fn gather(&self) -> Result<Option<(Tensor, Tensor)>> {
+        Ok(self.k()?.zip(self.v()?))
+    }
+
+    fn reset(&mut self) {
+        ContiguousKvCache::reset(self)
+    }
+
+    fn current_seq_len(&self) -> usize {
+        ContiguousKvCache::current_seq_len(self)
+    }
+
+    fn memory_used(&self) -> usize {
+        allocated_bytes(&self.k) + allocated_bytes(&self.v)
+    }
+}
+
+/// A KV cache held in CPU memory, whose keys and values are returned on `device`.
+#[derive(Debug, Clone)]
+pub struct OffloadedKvCache {
+    cache: ContiguousKvCache,
+    device: Device,
+}
+
+impl OffloadedKvCache {
+    pub fn new(dim: usize, max_seq_len: usize, capacity_seq_len: usize, device: Device) -> Self {
+        Self {
+            cache: ContiguousKvCache::new(dim, max_seq_len, capacity_seq_len),
+            device,
+        }
+    }
+
+    /// Offload the caches `k` and `v`, which may be on any device.
+    pub fn from_caches(mut k: SingleCache, mut v: SingleCache, device: Device) -> Result<Self> {
+        for cache in [&mut k, &mut v] {
+            cache.all_data = match cache.all_data.take() {
+                Some(data) => Some(data.to_device(&Device::Cpu)?),
+                None => None,
+            };
+        }
+        Ok(Self {
+            cache: ContiguousKvCache { k, v },
+            device,
+        })
+    }
+
+    /// The device the keys and values are returned on.
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub(crate) fn storage(&self) -> &ContiguousKvCache {
+        &self.cache
+    }
+
+    pub(crate) fn storage_mut(&mut self) -> &mut ContiguousKvCache {
+        &mut self.cache
+    }
+}
+
+impl KvCacheBackend for OffloadedKvCache {
+    fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (all_k, all_v) = self
+            .cache
+            .append(&k.to_device(&Device::Cpu)?, &v.to_device(&Device::Cpu)?)?;
+        Ok((all_k.to_device(k.device())?, all_v.to_device(v.device())?))
+    }
+
+    fn gather(&self) -> Result<Option<(Tensor, Tensor)>> {
+        match KvCacheBackend::gather(&self.cache)? {
+            Some((k, v)) => Ok(Some((
+                k.to_device(&self.device)?,
+                v.to_device(&self.device)?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.cache.reset()
+    }
+
+    fn current_seq_len(&self) -> usize {
+        self.cache.current_seq_len()
+    }
+
+    fn memory_used(&self) -> usize {
+        KvCacheBackend::memory_used(&self.cache)
+    }
+}
+
+impl KvCacheBackend for KvCache {
+    fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        match self {
+            Self::Contiguous(cache) => KvCacheBackend::append(cache, k, v),
+            Self::Offloaded(cache) => KvCacheBackend::append(cache, k, v),
+        }
+    }
+
+    fn gather(&self) -> Result<Option<(Tensor, Tensor)>> {
+        match self {
+            Self::Contiguous(cache) => KvCacheBackend::gather(cache),
+            Self::Offloaded(cache) => KvCacheBackend::gather(cache),
+        }
+    }
+
+    fn reset(&mut self) {
+        KvCache::reset(self)
+    }
+
+    fn current_seq_len(&self) -> usize {
+        KvCache::current_seq_len(self)
+    }
+
+    fn memory_used(&self) -> usize {
+        match self {
+            Self::Contiguous(cache) => KvCacheBackend::memory_used(cache),
+            Self::Offloaded(cache) => KvCacheBackend::memory_used(cache),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use super::{KvCacheBackend, KvCacheBackendType, OffloadedKvCache};
+    use crate::pipeline::{KvCache, NormalCache};
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    /// Append a prompt and then a decoded token, checking that everything appended is returned.
+    fn exercise(cache: &mut impl KvCacheBackend) -> Result<()> {
+        let dev = Device::Cpu;
+        let k = Tensor::randn(0f32, 1., (1, 2, 10, 8), &dev)?;
+        let v = Tensor::randn(0f32, 1., (1, 2, 10, 8), &dev)?;
+        assert!(cache.gather()?.is_none());
+
+        cache.append(&k.narrow(2, 0, 9)?, &v.narrow(2, 0, 9)?)?;
+        let (all_k, all_v) = cache.append(&k.narrow(2, 9, 1)?, &v.narrow(2, 9, 1)?)?;
+        assert_eq!(cache.current_seq_len(), 10);
+        assert_eq!(max_abs_diff(&all_k, &k)?, 0.);
+        assert_eq!(max_abs_diff(&all_v, &v)?, 0.);
+
+        let (gathered_k, gathered_v) = cache.gather()?.expect("Expected cached tokens");
+        assert_eq!(max_abs_diff(&gathered_k, &k)?, 0.);
+        assert_eq!(max_abs_diff(&gathered_v, &v)?, 0.);
+        // The capacity of 16 tokens is allocated for the keys and for the values
+        assert_eq!(
+            cache.memory_used(),
+            2 * 2 * 16 * 8 * DType::F32.size_in_bytes()
+        );
+
+        cache.reset();
+        assert_eq!(cache.current_seq_len(), 0);
+        assert!(cache.gather()?.is_none());
+        assert_eq!(cache.memory_used(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_kv_cache_backend() -> Result<()> {
+        exercise(&mut KvCache::new(2, 4096, 16))
+    }
+
+    #[test]
+    fn test_offloaded_kv_cache_backend() -> Result<()> {
+        exercise(&mut OffloadedKvCache::new(2, 4096, 16, Device::Cpu))
+    }
+
+    #[test]
+    fn test_normal_cache_keeps_selected_backend() -> Result<()> {
+        let cache = NormalCache::new(2, 4096);
+        let mut cache = cache.lock().unwrap();
+        cache.set_backend(KvCacheBackendType::Offloaded, &Device::Cpu);
+        assert!(cache
+            .0
+            .iter()
+            .all(|layer| matches!(layer, KvCache::Offloaded(_))));
+
+        // The caches built for the sequences and a prefix cache hit keep it
+        let layer = &mut cache.0[0];
+        layer.append(
+            &Tensor::zeros((1, 2, 3, 8), DType::F32, &Device::Cpu)?,
+            &Tensor::zeros((1, 2, 3, 8), DType::F32, &Device::Cpu)?,
+        )?;
+        let seq_cache = layer.with_caches(layer.k_cache().clone(), layer.v_cache().clone())?;
+        assert!(matches!(seq_cache, KvCache::Offloaded(_)));
+        assert_eq!(seq_cache.current_seq_len(), 3);
+        assert!(matches!(layer.empty_like(), KvCache::Offloaded(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_kv_cache_backend() {
+        assert_eq!(
+            "offloaded".parse::<KvCacheBackendType>(),
+            Ok(KvCacheBackendType::Offloaded)
+        );
+        assert!("paged".parse::<KvCacheBackendType>().is_err());
+    }
+}
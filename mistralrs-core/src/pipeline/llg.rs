@@ -29,6 +29,8 @@ pub fn llg_grammar_from_constraint(constraint: &Constraint) -> Result<Option<Top
             JsonCompileOptions::default().json_to_llg_no_validate(value.clone())?
         }
         Constraint::Llguidance(value) => value.clone(),
+        Constraint::JsonObject => JsonCompileOptions::default()
+            .json_to_llg_no_validate(serde_json::json!({ "type": "object" }))?,
         Constraint::None => return Ok(None),
     };
     Ok(Some(grm))
@@ -50,3 +52,25 @@ pub fn constraint_from_llg_grammar(
     )?;
     Ok(llguidance::Constraint::new(parser))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{build_tok_env, constraint_from_llg_grammar, llg_grammar_from_constraint};
+    use crate::{testing::tiny_llama_tokenizer, Constraint};
+
+    #[test]
+    fn test_json_object_constraint_starts_an_object() -> anyhow::Result<()> {
+        let tok_env = build_tok_env(tiny_llama_tokenizer()?);
+        let grm =
+            llg_grammar_from_constraint(&Constraint::JsonObject)?.expect("Expected a grammar");
+        let mut llg = constraint_from_llg_grammar(tok_env, grm)?;
+        let step_res = llg.compute_mask()?;
+        let mask = step_res.sample_mask.as_ref().expect("Expected a mask");
+        // The byte tokens have the id of their byte
+        assert!(mask.is_allowed(u32::from(b'{')));
+        for not_an_object in [b'[', b'"', b'1', b'n'] {
+            assert!(!mask.is_allowed(u32::from(not_an_object)));
+        }
+        Ok(())
+    }
+}
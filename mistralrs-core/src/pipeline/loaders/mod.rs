@@ -17,9 +17,10 @@ use mistralrs_quant::IsqType;
 use tokio::sync::Mutex;
 
 pub use normal_loaders::{
-    AutoLoader, Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader,
-    NormalLoaderType, NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader,
-    Phi3Loader, Phi3_5MoELoader, Qwen2Loader, Starcoder2Loader,
+    ArcticLoader, AutoLoader, CohereLoader, Gemma2Loader, GemmaLoader, GraniteLoader, LlamaLoader,
+    MambaLoader, MistralLoader, MixtralLoader, NormalLoaderType, NormalLoadingMetadata,
+    NormalModel, NormalModelLoader, Phi2Loader, Phi3Loader, Phi3_5MoELoader, Qwen2Loader,
+    Qwen2MoeLoader, Starcoder2Loader,
 };
 
 pub use vision_loaders::{
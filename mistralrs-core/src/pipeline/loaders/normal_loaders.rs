@@ -16,7 +16,7 @@ use crate::{
         EitherCache, IsqModel,
     },
     serde_default_fn,
-    utils::log::once_log_info,
+    utils::{context_len::effective_context_len, log::once_log_info},
     xlora_models::NonGranularState,
 };
 use anyhow::Result;
@@ -47,6 +47,32 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> candle_core::Result<Tensor>;
+    /// The last-layer hidden states of `input_ids` after the final norm, `(batch, seq_len,
+    /// hidden_size)`, for classification heads. This does not use the PagedAttention KV cache.
+    fn forward_hidden(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Returning the hidden states is not supported for this model.")
+    }
+    /// The logits of an early exit after the first `num_layers` layers, with the final norm and
+    /// LM head of the model. This is the draft model of self-speculative decoding.
+    #[allow(clippy::too_many_arguments)]
+    fn forward_early_exit(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _context_lens: Vec<(usize, usize)>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+        _num_layers: usize,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Early exit is not supported for this model.")
+    }
     #[allow(clippy::too_many_arguments)]
     fn xlora_forward(
         &self,
@@ -68,6 +94,11 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
     fn cache(&self) -> &EitherCache;
     fn cache_mut(&mut self) -> &mut EitherCache;
     fn max_seq_len(&self) -> usize;
+    /// Whether the model keeps a recurrent state per layer in its cache instead of a KV cache.
+    /// The state can not be truncated to a prefix, so the prefix cache is disabled.
+    fn is_recurrent(&self) -> bool {
+        false
+    }
     fn activate_adapters(&mut self, _: Vec<String>) -> candle_core::Result<usize> {
         // NOTE: While X-LoRA shares a similar name, it is not equivalent. Its adapter set must remain the same.
         candle_core::bail!(
@@ -112,6 +143,12 @@ pub trait NormalModelLoader: IsqModelLoader {
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>>;
     /// Get total num_hidden_layers for the layers which will be device mapped.
     fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize>;
+    /// The `rope_scaling` types whose `factor` the RoPE of this architecture applies to extend the
+    /// context. For these, the loader sizes the rotary tables and the maximum sequence length of
+    /// the model to the extended context.
+    fn rope_scaling_types(&self) -> &'static [&'static str] {
+        &[]
+    }
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass(eq, eq_int))]
@@ -138,6 +175,16 @@ pub enum NormalLoaderType {
     Starcoder2,
     #[serde(rename = "phi3.5moe")]
     Phi3_5MoE,
+    #[serde(rename = "cohere")]
+    Cohere,
+    #[serde(rename = "arctic")]
+    Arctic,
+    #[serde(rename = "granite")]
+    Granite,
+    #[serde(rename = "qwen2moe")]
+    Qwen2Moe,
+    #[serde(rename = "mamba")]
+    Mamba,
 }
 
 // https://github.com/huggingface/transformers/blob/cff06aac6fad28019930be03f5d467055bf62177/src/transformers/models/auto/modeling_auto.py#L448
@@ -155,6 +202,11 @@ impl NormalLoaderType {
             "Qwen2ForCausalLM" => Ok(Self::Qwen2),
             "Starcoder2ForCausalLM" => Ok(Self::Starcoder2),
             "PhiMoEForCausalLM" => Ok(Self::Phi3_5MoE),
+            "CohereForCausalLM" => Ok(Self::Cohere),
+            "ArcticForCausalLM" => Ok(Self::Arctic),
+            "GraniteForCausalLM" => Ok(Self::Granite),
+            "Qwen2MoeForCausalLM" => Ok(Self::Qwen2Moe),
+            "MambaForCausalLM" => Ok(Self::Mamba),
             other => anyhow::bail!(
                 "Unsupported Huggging Face Transformers -CausalLM model class `{other}`. Please raise an issue."
             ),
@@ -176,7 +228,12 @@ impl FromStr for NormalLoaderType {
             "gemma2" => Ok(Self::Gemma2),
             "starcoder2" => Ok(Self::Starcoder2),
             "phi3.5moe" => Ok(Self::Phi3_5MoE),
-            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`, `phi3.5moe`.")),
+            "cohere" => Ok(Self::Cohere),
+            "arctic" => Ok(Self::Arctic),
+            "granite" => Ok(Self::Granite),
+            "qwen2moe" => Ok(Self::Qwen2Moe),
+            "mamba" => Ok(Self::Mamba),
+            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`, `phi3.5moe`, `cohere`, `arctic`, `granite`, `qwen2moe`, `mamba`.")),
         }
     }
 }
@@ -194,6 +251,11 @@ impl Display for NormalLoaderType {
             Self::Phi3_5MoE => write!(f, "phi3.5moe"),
             Self::Qwen2 => write!(f, "qwen2"),
             Self::Starcoder2 => write!(f, "starcoder2"),
+            Self::Cohere => write!(f, "cohere"),
+            Self::Arctic => write!(f, "arctic"),
+            Self::Granite => write!(f, "granite"),
+            Self::Qwen2Moe => write!(f, "qwen2moe"),
+            Self::Mamba => write!(f, "mamba"),
         }
     }
 }
@@ -230,6 +292,11 @@ impl AutoLoader {
             NormalLoaderType::Gemma2 => Ok(Box::new(Gemma2Loader)),
             NormalLoaderType::Starcoder2 => Ok(Box::new(Starcoder2Loader)),
             NormalLoaderType::Phi3_5MoE => Ok(Box::new(Phi3_5MoELoader)),
+            NormalLoaderType::Cohere => Ok(Box::new(CohereLoader)),
+            NormalLoaderType::Arctic => Ok(Box::new(ArcticLoader)),
+            NormalLoaderType::Granite => Ok(Box::new(GraniteLoader)),
+            NormalLoaderType::Qwen2Moe => Ok(Box::new(Qwen2MoeLoader)),
+            NormalLoaderType::Mamba => Ok(Box::new(MambaLoader)),
         }
     }
 }
@@ -291,6 +358,38 @@ impl IsqModelLoader for AutoLoader {
 }
 
 serde_default_fn!(bool, word_emb_default, false);
+serde_default_fn!(bool, attention_softmax_f32_default, true);
+
+/// How a loader resolves a config without `num_key_value_heads`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KvHeadsDefault {
+    /// One KV head per attention head (MHA), matching the reference implementation's default.
+    Mha,
+    /// The field must be present. Assuming MHA would silently run a GQA model with the wrong
+    /// number of KV heads if the field was stripped from the config.
+    Required,
+}
+
+fn num_key_value_heads(
+    arch: &str,
+    num_key_value_heads: Option<usize>,
+    num_attention_heads: usize,
+    default: KvHeadsDefault,
+) -> Result<usize> {
+    let num_key_value_heads = match (num_key_value_heads, default) {
+        (Some(n), _) => n,
+        (None, KvHeadsDefault::Mha) => num_attention_heads,
+        (None, KvHeadsDefault::Required) => {
+            anyhow::bail!("The {arch} config is missing the required `num_key_value_heads`.")
+        }
+    };
+    if num_key_value_heads == 0 || num_attention_heads % num_key_value_heads != 0 {
+        anyhow::bail!(
+            "The {arch} config has `num_key_value_heads` = {num_key_value_heads}, which does not divide `num_attention_heads` = {num_attention_heads}."
+        );
+    }
+    Ok(num_key_value_heads)
+}
 
 // ======================== Mistral loader
 
@@ -301,7 +400,7 @@ struct MistralBasicConfig {
     intermediate_size: usize,
     num_hidden_layers: usize,
     num_attention_heads: usize,
-    num_key_value_heads: usize,
+    num_key_value_heads: Option<usize>,
     hidden_act: Activation,
     max_position_embeddings: usize,
     rms_norm_eps: f64,
@@ -322,7 +421,12 @@ impl MistralBasicConfig {
             intermediate_size: basic_config.intermediate_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config.num_key_value_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Mistral",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Required,
+            )?,
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
             rms_norm_eps: basic_config.rms_norm_eps,
@@ -425,7 +529,7 @@ struct GemmaBasicConfig {
     intermediate_size: usize,
     num_attention_heads: usize,
     num_hidden_layers: usize,
-    num_key_value_heads: usize,
+    num_key_value_heads: Option<usize>,
     rms_norm_eps: f64,
     rope_theta: f64,
     vocab_size: usize,
@@ -435,6 +539,8 @@ struct GemmaBasicConfig {
     quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     tie_word_embeddings: bool,
+    #[serde(default = "attention_softmax_f32_default")]
+    attention_softmax_f32: bool,
 }
 
 impl GemmaBasicConfig {
@@ -446,7 +552,12 @@ impl GemmaBasicConfig {
             intermediate_size: basic_config.intermediate_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config.num_key_value_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Gemma",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Required,
+            )?,
             hidden_act: basic_config.hidden_act,
             hidden_activation: basic_config.hidden_activation,
             max_position_embeddings: basic_config.max_position_embeddings,
@@ -457,6 +568,7 @@ impl GemmaBasicConfig {
             use_flash_attn,
             quantization_config: basic_config.quantization_config,
             tie_word_embeddings: basic_config.tie_word_embeddings,
+            attention_softmax_f32: basic_config.attention_softmax_f32,
         })
     }
 }
@@ -554,31 +666,42 @@ struct LlamaBasicConfig {
     quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     tie_word_embeddings: bool,
+    #[serde(default)]
+    bidirectional: bool,
 }
 
 fn default_rope() -> f32 {
     10_000.0
 }
 
+/// The `rope_scaling` types extending the context of Llama models.
+const LLAMA_ROPE_SCALING_TYPES: &[&str] = &["linear"];
+
 impl LlamaBasicConfig {
     fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::llama::Config> {
         let basic_config: Self = serde_json::from_str(slice)?;
+        let max_position_embeddings = effective_context_len(slice, LLAMA_ROPE_SCALING_TYPES)
+            .unwrap_or(basic_config.max_position_embeddings);
         Ok(models::llama::Config {
             hidden_size: basic_config.hidden_size,
             intermediate_size: basic_config.intermediate_size,
             vocab_size: basic_config.vocab_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config
-                .num_key_value_heads
-                .unwrap_or(basic_config.num_attention_heads),
+            num_key_value_heads: num_key_value_heads(
+                "Llama",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Mha,
+            )?,
             rms_norm_eps: basic_config.rms_norm_eps,
             rope_theta: basic_config.rope_theta,
             use_flash_attn,
-            max_position_embeddings: basic_config.max_position_embeddings,
+            max_position_embeddings,
             rope_scaling: basic_config.rope_scaling,
             quantization_config: basic_config.quantization_config,
             tie_word_embeddings: basic_config.tie_word_embeddings,
+            bidirectional: basic_config.bidirectional,
         })
     }
 }
@@ -639,6 +762,9 @@ impl NormalModelLoader for LlamaLoader {
     fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
         Ok(LlamaBasicConfig::deserialize(config, false)?.num_hidden_layers)
     }
+    fn rope_scaling_types(&self) -> &'static [&'static str] {
+        LLAMA_ROPE_SCALING_TYPES
+    }
 }
 
 impl IsqModelLoader for LlamaLoader {
@@ -667,7 +793,7 @@ struct MixtralBasicConfig {
     intermediate_size: usize,
     num_hidden_layers: usize,
     num_attention_heads: usize,
-    num_key_value_heads: usize,
+    num_key_value_heads: Option<usize>,
     hidden_act: Activation,
     max_position_embeddings: usize,
     rms_norm_eps: f64,
@@ -689,7 +815,12 @@ impl MixtralBasicConfig {
             intermediate_size: basic_config.intermediate_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config.num_key_value_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Mixtral",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Required,
+            )?,
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
             rms_norm_eps: basic_config.rms_norm_eps,
@@ -807,7 +938,12 @@ impl Phi2BasicConfig {
             intermediate_size: basic_config.intermediate_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config.num_key_value_heads,
+            num_key_value_heads: Some(num_key_value_heads(
+                "Phi 2",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Mha,
+            )?),
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
             rope_theta: basic_config.rope_theta,
@@ -905,7 +1041,7 @@ struct Phi3BasicConfig {
     intermediate_size: usize,
     num_hidden_layers: usize,
     num_attention_heads: usize,
-    num_key_value_heads: usize,
+    num_key_value_heads: Option<usize>,
     rms_norm_eps: f64,
     rope_theta: f64,
     bos_token_id: Option<u32>,
@@ -928,7 +1064,12 @@ impl Phi3BasicConfig {
             intermediate_size: basic_config.intermediate_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config.num_key_value_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Phi 3",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Required,
+            )?,
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
             rope_theta: basic_config.rope_theta,
@@ -1027,7 +1168,7 @@ struct Qwen2BasicConfig {
     intermediate_size: usize,
     num_hidden_layers: usize,
     num_attention_heads: usize,
-    num_key_value_heads: usize,
+    num_key_value_heads: Option<usize>,
     max_position_embeddings: usize,
     sliding_window: usize,
     rope_theta: f64,
@@ -1046,7 +1187,12 @@ impl Qwen2BasicConfig {
             intermediate_size: basic_config.intermediate_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config.num_key_value_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Qwen2",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Required,
+            )?,
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
             rope_theta: basic_config.rope_theta,
@@ -1138,7 +1284,7 @@ struct Gemma2BasicConfig {
     intermediate_size: usize,
     num_attention_heads: usize,
     num_hidden_layers: usize,
-    num_key_value_heads: usize,
+    num_key_value_heads: Option<usize>,
     rms_norm_eps: f64,
     rope_theta: f64,
     vocab_size: usize,
@@ -1152,6 +1298,8 @@ struct Gemma2BasicConfig {
     quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     tie_word_embeddings: bool,
+    #[serde(default = "attention_softmax_f32_default")]
+    attention_softmax_f32: bool,
 }
 
 impl Gemma2BasicConfig {
@@ -1163,7 +1311,12 @@ impl Gemma2BasicConfig {
             intermediate_size: basic_config.intermediate_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config.num_key_value_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Gemma 2",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Required,
+            )?,
             hidden_act: basic_config.hidden_act,
             hidden_activation: basic_config.hidden_activation,
             max_position_embeddings: basic_config.max_position_embeddings,
@@ -1178,6 +1331,7 @@ impl Gemma2BasicConfig {
             final_logit_softcapping: basic_config.final_logit_softcapping,
             query_pre_attn_scalar: basic_config.query_pre_attn_scalar,
             tie_word_embeddings: basic_config.tie_word_embeddings,
+            attention_softmax_f32: basic_config.attention_softmax_f32,
         })
     }
 }
@@ -1267,7 +1421,7 @@ struct Starcoder2BasicConfig {
     intermediate_size: usize,
     num_hidden_layers: usize,
     num_attention_heads: usize,
-    num_key_value_heads: usize,
+    num_key_value_heads: Option<usize>,
     hidden_act: Activation,
     max_position_embeddings: usize,
     norm_epsilon: f64,
@@ -1288,7 +1442,12 @@ impl Starcoder2BasicConfig {
             intermediate_size: basic_config.intermediate_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config.num_key_value_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Starcoder2",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Required,
+            )?,
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
             rope_theta: basic_config.rope_theta,
@@ -1385,7 +1544,7 @@ struct Phi3_5MoEBasicConfig {
     intermediate_size: usize,
     num_hidden_layers: usize,
     num_attention_heads: usize,
-    num_key_value_heads: usize,
+    num_key_value_heads: Option<usize>,
     rms_norm_eps: f64,
     rope_theta: f64,
     rope_scaling: Option<PhiRopeScalingConfig>,
@@ -1410,7 +1569,12 @@ impl Phi3_5MoEBasicConfig {
             intermediate_size: basic_config.intermediate_size,
             num_hidden_layers: basic_config.num_hidden_layers,
             num_attention_heads: basic_config.num_attention_heads,
-            num_key_value_heads: basic_config.num_key_value_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Phi 3.5 MoE",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Required,
+            )?,
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
             rope_theta: basic_config.rope_theta,
@@ -1513,3 +1677,806 @@ impl IsqModelLoader for Phi3_5MoELoader {
         ])
     }
 }
+
+// ======================== Cohere loader
+
+serde_default_fn!(f64, cohere_logit_scale_default, 0.0625);
+serde_default_fn!(f64, cohere_layer_norm_eps_default, 1e-5);
+serde_default_fn!(bool, cohere_word_emb_default, true);
+
+#[derive(Deserialize)]
+struct CohereBasicConfig {
+    hidden_size: usize,
+    intermediate_size: usize,
+    vocab_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    hidden_act: Activation,
+    max_position_embeddings: usize,
+    #[serde(default = "cohere_layer_norm_eps_default")]
+    layer_norm_eps: f64,
+    #[serde(default = "default_rope")]
+    rope_theta: f32,
+    #[serde(default = "cohere_logit_scale_default")]
+    logit_scale: f64,
+    #[serde(default)]
+    use_qk_norm: bool,
+    #[serde(default)]
+    attention_bias: bool,
+    quantization_config: Option<QuantizedConfig>,
+    #[serde(default = "cohere_word_emb_default")]
+    tie_word_embeddings: bool,
+}
+
+impl CohereBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::cohere::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        Ok(models::cohere::Config {
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            vocab_size: basic_config.vocab_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Cohere",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Mha,
+            )?,
+            hidden_act: basic_config.hidden_act,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            layer_norm_eps: basic_config.layer_norm_eps,
+            rope_theta: basic_config.rope_theta as f64,
+            logit_scale: basic_config.logit_scale,
+            use_qk_norm: basic_config.use_qk_norm,
+            attention_bias: basic_config.attention_bias,
+            use_flash_attn,
+            quantization_config: basic_config.quantization_config,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Command-R or Command-R-plus model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct CohereLoader;
+
+impl NormalModelLoader for CohereLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::cohere::Model::new(
+            &CohereBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(config)?,
+            normal_loading_metadata,
+            attention_mechanism,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA is not supported for this architecture")
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        // Cohere uses the interleaved RoPE layout
+        Ok(false)
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(CohereBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(CohereBasicConfig::deserialize(config, false)?.num_hidden_layers)
+    }
+}
+
+impl IsqModelLoader for CohereLoader {
+    fn isq_layer_regexes(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            // Attention
+            Regex::new(r"layers\.(\d+)\.self_attn\.q_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.k_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.v_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.o_proj\.(weight|bias)$")?,
+            // MLP
+            Regex::new(r"layers\.(\d+)\.mlp\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+        ])
+    }
+}
+
+// ======================== Arctic loader
+
+serde_default_fn!(usize, arctic_moe_layer_frequency_default, 2);
+serde_default_fn!(f64, arctic_rope_theta_default, 1e6);
+serde_default_fn!(bool, arctic_use_residual_default, true);
+
+#[derive(Deserialize)]
+struct ArcticBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    hidden_act: Activation,
+    max_position_embeddings: usize,
+    rms_norm_eps: f64,
+    #[serde(default = "arctic_rope_theta_default")]
+    rope_theta: f64,
+    sliding_window: Option<usize>,
+    num_experts_per_tok: usize,
+    num_local_experts: usize,
+    #[serde(default = "arctic_moe_layer_frequency_default")]
+    moe_layer_frequency: usize,
+    #[serde(default)]
+    parallel_attn_mlp_res: bool,
+    #[serde(default = "arctic_use_residual_default")]
+    use_residual: bool,
+    quantization_config: Option<QuantizedConfig>,
+    #[serde(default = "word_emb_default")]
+    tie_word_embeddings: bool,
+}
+
+impl ArcticBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::arctic::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        if basic_config.moe_layer_frequency == 0 {
+            anyhow::bail!("Arctic `moe_layer_frequency` must be at least 1.");
+        }
+        Ok(models::arctic::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Arctic",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Mha,
+            )?,
+            hidden_act: basic_config.hidden_act,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            rms_norm_eps: basic_config.rms_norm_eps,
+            rope_theta: basic_config.rope_theta,
+            sliding_window: basic_config.sliding_window,
+            num_experts_per_tok: basic_config.num_experts_per_tok,
+            num_local_experts: basic_config.num_local_experts,
+            moe_layer_frequency: basic_config.moe_layer_frequency,
+            parallel_attn_mlp_res: basic_config.parallel_attn_mlp_res,
+            use_residual: basic_config.use_residual,
+            use_flash_attn,
+            quantization_config: basic_config.quantization_config,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Snowflake Arctic model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct ArcticLoader;
+
+impl NormalModelLoader for ArcticLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::arctic::Model::new(
+            &ArcticBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(config)?,
+            normal_loading_metadata,
+            attention_mechanism,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA is not supported for this architecture")
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        Ok(true)
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(ArcticBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(ArcticBasicConfig::deserialize(config, false)?.num_hidden_layers)
+    }
+}
+
+impl IsqModelLoader for ArcticLoader {
+    fn isq_layer_regexes(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            // Attention
+            Regex::new(r"layers\.(\d+)\.self_attn\.q_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.k_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.v_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.o_proj\.(weight|bias)$")?,
+            // Experts
+            Regex::new(r"layers\.(\d+)\.block_sparse_moe\.gate\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.block_sparse_moe\.experts\.(\d+)\.w1\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.block_sparse_moe\.experts\.(\d+)\.w2\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.block_sparse_moe\.experts\.(\d+)\.w3\.(weight|bias)$")?,
+            // Dense MLP of the non-MoE layers
+            Regex::new(r"layers\.(\d+)\.block_sparse_moe\.mlp\.w1\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.block_sparse_moe\.mlp\.w2\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.block_sparse_moe\.mlp\.w3\.(weight|bias)$")?,
+            // Residual MLP
+            Regex::new(r"layers\.(\d+)\.residual_mlp\.w1\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.residual_mlp\.w2\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.residual_mlp\.w3\.(weight|bias)$")?,
+        ])
+    }
+}
+
+// ======================== Granite loader
+
+serde_default_fn!(f64, granite_multiplier_default, 1.0);
+
+#[derive(Deserialize)]
+struct GraniteBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    hidden_act: Activation,
+    max_position_embeddings: usize,
+    rms_norm_eps: f64,
+    rope_theta: f64,
+    #[serde(default)]
+    attention_bias: bool,
+    #[serde(default)]
+    mlp_bias: bool,
+    #[serde(default = "granite_multiplier_default")]
+    embedding_multiplier: f64,
+    attention_multiplier: Option<f64>,
+    #[serde(default = "granite_multiplier_default")]
+    residual_multiplier: f64,
+    #[serde(default = "granite_multiplier_default")]
+    logits_scaling: f64,
+    quantization_config: Option<QuantizedConfig>,
+    #[serde(default = "word_emb_default")]
+    tie_word_embeddings: bool,
+}
+
+impl GraniteBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::granite::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        if basic_config.logits_scaling == 0. {
+            anyhow::bail!("Granite `logits_scaling` must be nonzero.");
+        }
+        let head_dim = basic_config.hidden_size / basic_config.num_attention_heads;
+        Ok(models::granite::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Granite",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Mha,
+            )?,
+            hidden_act: basic_config.hidden_act,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            rms_norm_eps: basic_config.rms_norm_eps,
+            rope_theta: basic_config.rope_theta,
+            attention_bias: basic_config.attention_bias,
+            mlp_bias: basic_config.mlp_bias,
+            embedding_multiplier: basic_config.embedding_multiplier,
+            attention_multiplier: basic_config
+                .attention_multiplier
+                .unwrap_or(1. / (head_dim as f64).sqrt()),
+            residual_multiplier: basic_config.residual_multiplier,
+            logits_scaling: basic_config.logits_scaling,
+            use_flash_attn,
+            quantization_config: basic_config.quantization_config,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+        })
+    }
+}
+
+/// [`NormalLoader`] for an IBM Granite model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct GraniteLoader;
+
+impl NormalModelLoader for GraniteLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::granite::Model::new(
+            &GraniteBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(config)?,
+            normal_loading_metadata,
+            attention_mechanism,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA is not supported for this architecture")
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        Ok(true)
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(GraniteBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(GraniteBasicConfig::deserialize(config, false)?.num_hidden_layers)
+    }
+}
+
+impl IsqModelLoader for GraniteLoader {
+    fn isq_layer_regexes(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            // Attention
+            Regex::new(r"layers\.(\d+)\.self_attn\.q_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.k_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.v_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.o_proj\.(weight|bias)$")?,
+            // MLP
+            Regex::new(r"layers\.(\d+)\.mlp\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+        ])
+    }
+}
+
+// ======================== Qwen2-MoE loader
+
+serde_default_fn!(usize, qwen2_moe_sparse_step_default, 1);
+
+#[derive(Deserialize)]
+struct Qwen2MoeBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    max_position_embeddings: usize,
+    #[serde(default)]
+    use_sliding_window: bool,
+    sliding_window: Option<usize>,
+    rope_theta: f64,
+    rms_norm_eps: f64,
+    hidden_act: Activation,
+    #[serde(default = "qwen2_moe_sparse_step_default")]
+    decoder_sparse_step: usize,
+    moe_intermediate_size: usize,
+    shared_expert_intermediate_size: usize,
+    num_experts_per_tok: usize,
+    num_experts: usize,
+    #[serde(default)]
+    norm_topk_prob: bool,
+    #[serde(default)]
+    mlp_only_layers: Vec<usize>,
+    quantization_config: Option<QuantizedConfig>,
+    #[serde(default = "word_emb_default")]
+    tie_word_embeddings: bool,
+}
+
+impl Qwen2MoeBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::qwen2_moe::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        if basic_config.decoder_sparse_step == 0 {
+            anyhow::bail!("Qwen2-MoE `decoder_sparse_step` must be nonzero.");
+        }
+        Ok(models::qwen2_moe::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: num_key_value_heads(
+                "Qwen2-MoE",
+                basic_config.num_key_value_heads,
+                basic_config.num_attention_heads,
+                KvHeadsDefault::Required,
+            )?,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            sliding_window: basic_config
+                .sliding_window
+                .filter(|_| basic_config.use_sliding_window),
+            rope_theta: basic_config.rope_theta,
+            rms_norm_eps: basic_config.rms_norm_eps,
+            hidden_act: basic_config.hidden_act,
+            decoder_sparse_step: basic_config.decoder_sparse_step,
+            moe_intermediate_size: basic_config.moe_intermediate_size,
+            shared_expert_intermediate_size: basic_config.shared_expert_intermediate_size,
+            num_experts_per_tok: basic_config.num_experts_per_tok,
+            num_experts: basic_config.num_experts,
+            norm_topk_prob: basic_config.norm_topk_prob,
+            mlp_only_layers: basic_config.mlp_only_layers,
+            use_flash_attn,
+            quantization_config: basic_config.quantization_config,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Qwen2-MoE model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct Qwen2MoeLoader;
+
+impl NormalModelLoader for Qwen2MoeLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::qwen2_moe::Model::new(
+            &Qwen2MoeBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(config)?,
+            normal_loading_metadata,
+            attention_mechanism,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA is not supported for this architecture")
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        Ok(true)
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(Qwen2MoeBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(Qwen2MoeBasicConfig::deserialize(config, false)?.num_hidden_layers)
+    }
+}
+
+impl IsqModelLoader for Qwen2MoeLoader {
+    fn isq_layer_regexes(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            // Attention
+            Regex::new(r"layers\.(\d+)\.self_attn\.q_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.k_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.v_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.o_proj\.(weight|bias)$")?,
+            // Dense MLP
+            Regex::new(r"layers\.(\d+)\.mlp\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+            // Experts
+            Regex::new(r"layers\.(\d+)\.mlp\.gate\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.experts\.(\d+)\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.experts\.(\d+)\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.experts\.(\d+)\.down_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.shared_expert\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.shared_expert\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.shared_expert\.down_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.shared_expert_gate\.(weight|bias)$")?,
+        ])
+    }
+}
+
+// ======================== Mamba loader
+
+serde_default_fn!(usize, mamba_d_state_default, 16);
+serde_default_fn!(usize, mamba_d_conv_default, 4);
+serde_default_fn!(usize, mamba_expand_default, 2);
+serde_default_fn!(usize, mamba_pad_vocab_default, 1);
+serde_default_fn!(f64, mamba_norm_eps_default, 1e-5);
+serde_default_fn!(bool, mamba_use_conv_bias_default, true);
+serde_default_fn!(bool, mamba_tie_word_embeddings_default, true);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MambaDtRank {
+    /// `"auto"`, which is `ceil(d_model / 16)`.
+    Auto(String),
+    Rank(usize),
+}
+
+/// Reads the original state-spaces config keys and their Hugging Face Transformers aliases.
+#[derive(Deserialize)]
+struct MambaBasicConfig {
+    #[serde(alias = "hidden_size")]
+    d_model: usize,
+    #[serde(alias = "num_hidden_layers")]
+    n_layer: usize,
+    #[serde(alias = "state_size", default = "mamba_d_state_default")]
+    d_state: usize,
+    #[serde(alias = "conv_kernel", default = "mamba_d_conv_default")]
+    d_conv: usize,
+    #[serde(default = "mamba_expand_default")]
+    expand: usize,
+    intermediate_size: Option<usize>,
+    #[serde(alias = "time_step_rank")]
+    dt_rank: Option<MambaDtRank>,
+    vocab_size: usize,
+    #[serde(default = "mamba_pad_vocab_default")]
+    pad_vocab_size_multiple: usize,
+    #[serde(default = "mamba_norm_eps_default")]
+    layer_norm_epsilon: f64,
+    #[serde(default)]
+    use_bias: bool,
+    #[serde(default = "mamba_use_conv_bias_default")]
+    use_conv_bias: bool,
+    quantization_config: Option<QuantizedConfig>,
+    #[serde(default = "mamba_tie_word_embeddings_default")]
+    tie_word_embeddings: bool,
+}
+
+impl MambaBasicConfig {
+    fn deserialize(slice: &str) -> Result<models::mamba::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        if basic_config.d_conv < 2 {
+            anyhow::bail!("Mamba `d_conv` must be at least 2.");
+        }
+        let time_step_rank = match basic_config.dt_rank {
+            None => basic_config.d_model.div_ceil(16),
+            Some(MambaDtRank::Auto(rank)) if rank == "auto" => basic_config.d_model.div_ceil(16),
+            Some(MambaDtRank::Auto(rank)) => {
+                anyhow::bail!("Mamba `dt_rank` must be an integer or `auto`, got `{rank}`.")
+            }
+            Some(MambaDtRank::Rank(rank)) => rank,
+        };
+        Ok(models::mamba::Config {
+            vocab_size: basic_config
+                .vocab_size
+                .next_multiple_of(basic_config.pad_vocab_size_multiple.max(1)),
+            hidden_size: basic_config.d_model,
+            num_hidden_layers: basic_config.n_layer,
+            state_size: basic_config.d_state,
+            conv_kernel: basic_config.d_conv,
+            intermediate_size: basic_config
+                .intermediate_size
+                .unwrap_or(basic_config.expand * basic_config.d_model),
+            time_step_rank,
+            layer_norm_epsilon: basic_config.layer_norm_epsilon,
+            use_bias: basic_config.use_bias,
+            use_conv_bias: basic_config.use_conv_bias,
+            quantization_config: basic_config.quantization_config,
+            tie_word_embeddings: basic_config.tie_word_embeddings,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Mamba state space model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct MambaLoader;
+
+impl NormalModelLoader for MambaLoader {
+    fn load(
+        &self,
+        config: &str,
+        _use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        _attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::mamba::Model::new(
+            &MambaBasicConfig::deserialize(config)?,
+            vb,
+            normal_loading_metadata,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA is not supported for this architecture")
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        Ok(true)
+    }
+    fn get_config_repr(&self, config: &str, _use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(MambaBasicConfig::deserialize(config)?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(MambaBasicConfig::deserialize(config)?.num_hidden_layers)
+    }
+}
+
+impl IsqModelLoader for MambaLoader {
+    fn isq_layer_regexes(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mixer\.in_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mixer\.out_proj\.(weight|bias)$")?,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{LlamaLoader, MambaLoader, MistralLoader, NormalModelLoader};
+    use crate::testing::{load_tiny_llama, load_tiny_llama_with, write_tiny_llama, TempDir};
+
+    const CONFIG: &str = r#"{
+        "vocab_size": 32,
+        "hidden_size": 8,
+        "intermediate_size": 16,
+        "num_hidden_layers": 1,
+        "num_attention_heads": 4,
+        "hidden_act": "silu",
+        "max_position_embeddings": 64,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0
+    }"#;
+
+    #[test]
+    fn test_missing_num_key_value_heads() {
+        // Llama defaults to MHA
+        let repr = format!("{:?}", LlamaLoader.get_config_repr(CONFIG, false).unwrap());
+        assert!(repr.contains("num_key_value_heads: 4"), "{repr}");
+
+        // Mistral requires the field
+        let err = MistralLoader.get_config_repr(CONFIG, false).unwrap_err();
+        assert!(err.to_string().contains("num_key_value_heads"), "{err}");
+    }
+
+    #[test]
+    fn test_invalid_num_key_value_heads() {
+        let config = CONFIG.replace(
+            "\"num_attention_heads\": 4,",
+            "\"num_attention_heads\": 4, \"num_key_value_heads\": 3,",
+        );
+        assert!(LlamaLoader.get_config_repr(&config, false).is_err());
+        assert!(MistralLoader.get_config_repr(&config, false).is_err());
+    }
+
+    #[test]
+    fn test_mamba_config() {
+        // The original state-spaces config, with the vocabulary padded for the embeddings
+        let config =
+            r#"{"d_model": 768, "n_layer": 24, "vocab_size": 50277, "pad_vocab_size_multiple": 8}"#;
+        let repr = format!("{:?}", MambaLoader.get_config_repr(config, false).unwrap());
+        for field in [
+            "vocab_size: 50280",
+            "state_size: 16",
+            "conv_kernel: 4",
+            "intermediate_size: 1536",
+            "time_step_rank: 48",
+        ] {
+            assert!(repr.contains(field), "{repr}");
+        }
+
+        // The Hugging Face Transformers names
+        let config = r#"{
+            "hidden_size": 768,
+            "num_hidden_layers": 24,
+            "state_size": 8,
+            "conv_kernel": 4,
+            "intermediate_size": 1024,
+            "time_step_rank": "auto",
+            "vocab_size": 50280
+        }"#;
+        let repr = format!("{:?}", MambaLoader.get_config_repr(config, false).unwrap());
+        for field in [
+            "state_size: 8",
+            "intermediate_size: 1024",
+            "time_step_rank: 48",
+        ] {
+            assert!(repr.contains(field), "{repr}");
+        }
+    }
+
+    #[test]
+    fn test_linear_rope_scaling_extends_context() -> anyhow::Result<()> {
+        let dir = TempDir::new("linear_rope_scaling");
+        write_tiny_llama(dir.path(), &[])?;
+        let config_path = dir.path().join("config.json");
+        let mut config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&config_path)?)?;
+        config["max_position_embeddings"] = json!(256);
+        config["rope_scaling"] = json!({
+            "type": "linear",
+            "factor": 4.0,
+            "original_max_position_embeddings": 256,
+        });
+        std::fs::write(&config_path, config.to_string())?;
+
+        // The length guard and the model both use the extended context
+        let pipeline = load_tiny_llama(dir.path())?;
+        assert_eq!(pipeline.blocking_lock().get_metadata().max_seq_len, 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn test_added_token_outside_of_vocab_is_rejected() -> anyhow::Result<()> {
+        let dir = TempDir::new("added_token_vocab_size");
+        write_tiny_llama(dir.path(), &[])?;
+        // The tiny model has a vocab size of 260, with all of its ids taken by the tokenizer
+        let err = load_tiny_llama_with(dir.path(), |builder| {
+            builder.with_added_tokens(vec![("<|tool|>".to_string(), 260)])
+        })
+        .err()
+        .expect("The added token is outside of the vocab");
+        assert!(err.to_string().contains("vocab size 260"), "{err}");
+        Ok(())
+    }
+}
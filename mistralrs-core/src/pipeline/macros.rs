@@ -389,7 +389,8 @@ macro_rules! normal_model_loader {
         $loading_uqff:expr,
         $real_device:expr,
         $attention_mechanism:expr,
-        $is_moqe:expr
+        $is_moqe:expr,
+        $tensor_name_map:expr
     ) => {{
         let regexes = if $loading_isq && $loading_uqff {
             // Dummy weights for the layers which will be overwritten...
@@ -405,10 +406,12 @@ macro_rules! normal_model_loader {
         let vb = from_mmaped_safetensors(
             $paths.get_weight_filenames().to_vec(),
             Vec::new(),
+            false,
             $dtype,
             $device,
             $silent,
             regexes,
+            $tensor_name_map,
             |_| true, // Will be overwritten...
         )?;
 
@@ -453,10 +456,12 @@ macro_rules! vision_normal_model_loader {
         let vb = from_mmaped_safetensors(
             $paths.get_weight_filenames().to_vec(),
             Vec::new(),
+            false,
             $dtype,
             $device,
             $silent,
             regexes,
+            None,
             |_| true,
         )?;
 
@@ -487,7 +492,8 @@ macro_rules! xlora_model_loader {
         $silent:expr,
         $mapper:expr,
         $loading_isq:expr,
-        $real_device:expr
+        $real_device:expr,
+        $tensor_name_map:expr
     ) => {{
         let mut safetensors_paths = $paths.get_weight_filenames().iter().collect::<Vec<_>>();
         safetensors_paths.push($paths.get_classifier_path().as_ref().unwrap());
@@ -496,17 +502,16 @@ macro_rules! xlora_model_loader {
                 .iter()
                 .map(|x| (*x).to_owned())
                 .collect::<Vec<_>>(),
+            $paths.get_adapter_filenames().as_ref().unwrap().clone(),
             $paths
-                .get_adapter_filenames()
+                .get_ordering()
                 .as_ref()
-                .unwrap()
-                .iter()
-                .map(|(_, x)| (*x).to_owned())
-                .collect::<Vec<_>>(),
+                .is_some_and(|ordering| ordering.combined_adapters.is_some()),
             $dtype,
             $device,
             $silent,
             None,
+            $tensor_name_map,
             |_| true,
         )?;
 
@@ -530,24 +535,23 @@ macro_rules! xlora_model_loader {
 #[doc(hidden)]
 #[macro_export]
 macro_rules! lora_model_loader {
-    ($paths:expr, $dtype:expr, $device:expr, $config:expr, $loader:expr, $use_flash_attn:expr, $silent:expr, $mapper:expr, $loading_isq:expr, $real_device:expr) => {{
+    ($paths:expr, $dtype:expr, $device:expr, $config:expr, $loader:expr, $use_flash_attn:expr, $silent:expr, $mapper:expr, $loading_isq:expr, $real_device:expr, $tensor_name_map:expr) => {{
         let safetensors_paths = $paths.get_weight_filenames().iter().collect::<Vec<_>>();
         let vb = from_mmaped_safetensors(
             safetensors_paths
                 .iter()
                 .map(|x| (*x).to_owned())
                 .collect::<Vec<_>>(),
+            $paths.get_adapter_filenames().as_ref().unwrap().clone(),
             $paths
-                .get_adapter_filenames()
+                .get_ordering()
                 .as_ref()
-                .unwrap()
-                .iter()
-                .map(|(_, x)| (*x).to_owned())
-                .collect::<Vec<_>>(),
+                .is_some_and(|ordering| ordering.combined_adapters.is_some()),
             Some($dtype),
             $device,
             $silent,
             None,
+            $tensor_name_map,
             |_| true,
         )?;
 
@@ -565,6 +569,10 @@ macro_rules! lora_model_loader {
             },
             &$crate::utils::varbuilder_utils::load_preload_adapters(
                 $paths.get_lora_preload_adapter_info(),
+                $paths
+                    .get_ordering()
+                    .as_ref()
+                    .is_some_and(|ordering| ordering.combined_adapters.is_some()),
                 $dtype,
                 $device,
                 $silent,
@@ -6,6 +6,7 @@ mod ggml;
 mod gguf;
 mod inputs_processor;
 mod isq;
+mod kv_cache;
 pub(crate) mod llg;
 mod loaders;
 mod macros;
@@ -19,6 +20,7 @@ mod vision;
 
 pub use super::diffusion_models::DiffusionGenerationParams;
 use crate::amoe::{AnyMoeConfig, AnyMoeExpertType, AnyMoeTrainingInputs, AnyMoeTrainingResult};
+use crate::attention::record_attention_weights;
 use crate::paged_attention::{CacheConfig, CacheEngine, ModelConfigLike};
 use crate::prefix_cacher::PrefixCacheManager;
 pub use amoe::{AnyMoeLoader, AnyMoePipeline};
@@ -30,13 +32,13 @@ use image::DynamicImage;
 pub use inputs_processor::InputProcessorOutput;
 pub use isq::{parse_isq_value, IsqModel, IsqOrganization};
 pub use loaders::{
-    AdapterKind, AutoLoader, DiffusionLoaderType, DiffusionModel, DiffusionModelLoader, FluxLoader,
-    Gemma2Loader, GemmaLoader, Idefics2Loader, Idefics3Loader, LLaVALoader, LLaVANextLoader,
-    LlamaLoader, Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths,
-    NormalLoaderType, NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader,
-    Phi3Loader, Phi3VLoader, Phi3_5MoELoader, PrettyName, QuantizationKind, Qwen2Loader,
-    Qwen2VLLoader, Starcoder2Loader, TokenSource, VLlamaLoader, VisionLoaderType, VisionModel,
-    VisionModelLoader,
+    AdapterKind, ArcticLoader, AutoLoader, CohereLoader, DiffusionLoaderType, DiffusionModel,
+    DiffusionModelLoader, FluxLoader, Gemma2Loader, GemmaLoader, GraniteLoader, Idefics2Loader,
+    Idefics3Loader, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths,
+    MambaLoader, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoaderType,
+    NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader, Phi3Loader, Phi3VLoader,
+    Phi3_5MoELoader, PrettyName, QuantizationKind, Qwen2Loader, Qwen2MoeLoader, Qwen2VLLoader,
+    Starcoder2Loader, TokenSource, VLlamaLoader, VisionLoaderType, VisionModel, VisionModelLoader,
 };
 use mistralrs_quant::IsqType;
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
@@ -45,10 +47,14 @@ pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
 use rand_isaac::Isaac64Rng;
-pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
+pub use speculative::{
+    SelfSpeculativeConfig, SelfSpeculativeLoader, SpeculativeConfig, SpeculativeLoader,
+    SpeculativePipeline,
+};
 use std::any::Any;
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokenizers::Tokenizer;
@@ -60,11 +66,12 @@ use candle_core::{DType, Device, IndexOp, Tensor, Var};
 use crate::sequence::Sequence;
 
 pub use self::cache_manager::{
-    Cache, CacheManager, EitherCache, KvCache, LayerCaches, NormalCache,
+    Cache, CacheManager, ContiguousKvCache, EitherCache, KvCache, LayerCaches, NormalCache,
 };
 pub use self::inputs_processor::{
     text_models_inputs_processor, InputsProcessor, InputsProcessorType,
 };
+pub use self::kv_cache::{KvCacheBackend, KvCacheBackendType, OffloadedKvCache};
 use self::text_models_inputs_processor::PagedAttentionMeta;
 
 pub struct GeneralMetadata {
@@ -72,6 +79,8 @@ pub struct GeneralMetadata {
     /// Only None if it doesnt make sense for the model
     pub tok_env: Option<llguidance::toktrie::TokEnv>,
     pub has_no_kv_cache: bool,
+    /// The cache holds recurrent states instead of KV caches, see [`NormalModel::is_recurrent`].
+    pub is_recurrent: bool,
     pub num_hidden_layers: usize,
     pub eos_tok: Vec<u32>,
     pub kind: ModelKind,
@@ -663,6 +672,33 @@ pub trait Pipeline:
     ) -> Result<(), candle_core::Error>;
 
     fn category(&self) -> ModelCategory;
+
+    /// Run only the first `num_layers` layers, with the final norm and LM head, in the following
+    /// forward passes. `None` runs the full model again.
+    fn set_early_exit(&mut self, _num_layers: Option<usize>) -> Result<(), candle_core::Error> {
+        candle_core::bail!("Early exit is not supported for this pipeline.")
+    }
+
+    /// The last-layer hidden states of a prompt, `(1, seq_len, hidden_size)`. The prompt is run
+    /// from an empty cache, and the cache of the running sequences is kept.
+    fn forward_hidden(&mut self, _tokens: Vec<u32>) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!("Returning the hidden states is not supported for this pipeline.")
+    }
+
+    /// The attention probabilities of every layer for a prompt, `(layers, heads, seq_len,
+    /// seq_len)`, with the prompt run as by [`Pipeline::forward_hidden`] and dense attention. The
+    /// prompt may be at most 1024 tokens long.
+    fn attention_weights(&mut self, tokens: Vec<u32>) -> Result<Tensor, candle_core::Error> {
+        let (_, weights) = record_attention_weights(|| self.forward_hidden(tokens))?;
+        Ok(weights)
+    }
+
+    /// Save the unquantized weights to the safetensors file `path` with their standard names. The
+    /// `config.json`, tokenizer and generation config are written alongside, so that the directory
+    /// can be loaded as a model.
+    fn save_safetensors(&mut self, _path: &Path) -> Result<()> {
+        anyhow::bail!("Saving to safetensors is not supported for this pipeline.")
+    }
 }
 
 pub(crate) fn extract_logits(
@@ -722,6 +758,7 @@ mod tests {
                     inputs.clone()
                 },
                 true,
+                false,
                 &ChatTemplateValue(Either::Left(template.to_string())),
                 Some(bos.to_string()),
                 Some(eos.to_string()),
@@ -8,12 +8,13 @@ use super::{
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, EitherCache,
-    ForwardInputsResult, IsqOrganization, IsqPipelineMixin, MetadataMixin, ModelCategory,
-    PreProcessingMixin,
+    ForwardInputsResult, IsqOrganization, IsqPipelineMixin, KvCache, KvCacheBackendType,
+    MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use super::{
-    AutoLoader, Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader,
-    NormalLoaderType, Phi2Loader, Phi3Loader, Phi3_5MoELoader, Qwen2Loader, Starcoder2Loader,
+    ArcticLoader, AutoLoader, CohereLoader, Gemma2Loader, GemmaLoader, GraniteLoader, LlamaLoader,
+    MambaLoader, MistralLoader, MixtralLoader, NormalLoaderType, Phi2Loader, Phi3Loader,
+    Phi3_5MoELoader, Qwen2Loader, Qwen2MoeLoader, Starcoder2Loader,
 };
 use crate::amoe::AnyMoeExpertType;
 use crate::lora::Ordering;
@@ -26,8 +27,11 @@ use crate::pipeline::text_models_inputs_processor::make_prompt_chunk;
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
 use crate::sequence::Sequence;
+use crate::utils::config_overrides::ConfigOverrides;
+use crate::utils::context_len::effective_context_len;
 use crate::utils::debug::DeviceRepr;
-use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::tensor_name_map::TensorNameMap;
+use crate::utils::tokenizer::{check_added_token_ids, get_tokenizer};
 use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
 use crate::xlora_models::NonGranularState;
 use crate::{
@@ -36,7 +40,8 @@ use crate::{
     Topology, TryIntoDType,
 };
 use anyhow::Result;
-use candle_core::{Device, Tensor, Var};
+use candle_core::{DType, Device, Tensor, Var};
+use either::Either;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
@@ -68,6 +73,8 @@ pub struct NormalPipeline {
     generation_config: Option<PathBuf>,
     config: String,
     imatrix: Option<PathBuf>,
+    // Number of layers to run for self-speculative drafts
+    early_exit: Option<usize>,
 }
 
 /// A loader for a "normal" (non-quantized) model.
@@ -85,6 +92,10 @@ pub struct NormalLoader {
     token_source: RwLock<Option<TokenSource>>,
     revision: RwLock<Option<String>>,
     from_uqff: RwLock<Option<PathBuf>>,
+    tensor_name_map: Option<TensorNameMap>,
+    config_overrides: Option<ConfigOverrides>,
+    added_tokens: Vec<(String, u32)>,
+    kv_cache_backend: KvCacheBackendType,
 }
 
 #[derive(Default)]
@@ -99,6 +110,10 @@ pub struct NormalLoaderBuilder {
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    tensor_name_map: Option<TensorNameMap>,
+    config_overrides: Option<ConfigOverrides>,
+    added_tokens: Vec<(String, u32)>,
+    kv_cache_backend: KvCacheBackendType,
 }
 
 #[derive(Clone, Default)]
@@ -138,6 +153,32 @@ impl NormalLoaderBuilder {
         self
     }
 
+    /// Rewrite the checkpoint tensor names with `tensor_name_map` before loading the model.
+    pub fn with_tensor_name_map(mut self, tensor_name_map: TensorNameMap) -> Self {
+        self.tensor_name_map = Some(tensor_name_map);
+        self
+    }
+
+    /// Apply `config_overrides` to the model config before the model is constructed.
+    pub fn with_config_overrides(mut self, config_overrides: ConfigOverrides) -> Self {
+        self.config_overrides = Some(config_overrides);
+        self
+    }
+
+    /// Register extra special tokens, with their ids, in the tokenizer. This is for fine-tunes which
+    /// add special tokens (for example `<|tool|>`) that are not in the base `tokenizer.json`. The
+    /// ids must be below the `vocab_size` of the model config, which is checked at load.
+    pub fn with_added_tokens(mut self, added_tokens: Vec<(String, u32)>) -> Self {
+        self.added_tokens = added_tokens;
+        self
+    }
+
+    /// Store the KV cache in `kv_cache_backend`, instead of contiguously on the device.
+    pub fn with_kv_cache_backend(mut self, kv_cache_backend: KvCacheBackendType) -> Self {
+        self.kv_cache_backend = kv_cache_backend;
+        self
+    }
+
     fn with_adapter(
         mut self,
         xlora_model_id: String,
@@ -200,6 +241,11 @@ impl NormalLoaderBuilder {
             Some(NormalLoaderType::Gemma2) => Box::new(Gemma2Loader),
             Some(NormalLoaderType::Starcoder2) => Box::new(Starcoder2Loader),
             Some(NormalLoaderType::Phi3_5MoE) => Box::new(Phi3_5MoELoader),
+            Some(NormalLoaderType::Cohere) => Box::new(CohereLoader),
+            Some(NormalLoaderType::Arctic) => Box::new(ArcticLoader),
+            Some(NormalLoaderType::Granite) => Box::new(GraniteLoader),
+            Some(NormalLoaderType::Qwen2Moe) => Box::new(Qwen2MoeLoader),
+            Some(NormalLoaderType::Mamba) => Box::new(MambaLoader),
             None => Box::new(AutoLoader),
         };
         Ok(Box::new(NormalLoader {
@@ -216,6 +262,10 @@ impl NormalLoaderBuilder {
             token_source: RwLock::new(None),
             revision: RwLock::new(None),
             from_uqff: RwLock::new(None),
+            tensor_name_map: self.tensor_name_map,
+            config_overrides: self.config_overrides,
+            added_tokens: self.added_tokens,
+            kv_cache_backend: self.kv_cache_backend,
         }))
     }
 }
@@ -273,7 +323,23 @@ impl Loader for NormalLoader {
         in_situ_quant: Option<IsqType>,
         mut paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let config = std::fs::read_to_string(paths.get_config_filename())?;
+        let mut config = std::fs::read_to_string(paths.get_config_filename())?;
+        if let Some(config_overrides) = &self.config_overrides {
+            config = config_overrides.apply(&config)?;
+        }
+        // Checked before the weights are loaded, as the tokenizer is only loaded after them
+        if !self.added_tokens.is_empty() {
+            let vocab_size = serde_json::from_str::<serde_json::Value>(&config)?
+                .get("vocab_size")
+                .and_then(serde_json::Value::as_u64)
+                .and_then(|vocab_size| usize::try_from(vocab_size).ok())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "The model config has no `vocab_size` to check the added tokens against."
+                    )
+                })?;
+            check_added_token_ids(&self.added_tokens, vocab_size)?;
+        }
         // Otherwise, the device mapper will print it
         if mapper.is_dummy()
             && (self.config.topology.is_none()
@@ -305,7 +371,6 @@ impl Loader for NormalLoader {
             self.inner
                 .get_config_repr(&config, self.config.use_flash_attn)?
         );
-
         let mut loading_isq = in_situ_quant.is_some() || self.config.from_uqff.is_some();
         if let Some(ref topology) = self.config.topology {
             loading_isq |= topology
@@ -350,7 +415,8 @@ impl Loader for NormalLoader {
                 self.config.from_uqff.is_some(),
                 device.clone(),
                 attention_mechanism,
-                matches!(self.config.organization, IsqOrganization::MoeExpertsOnly)
+                matches!(self.config.organization, IsqOrganization::MoeExpertsOnly),
+                self.tensor_name_map.as_ref()
             ),
             ModelKind::Adapter {
                 adapter: AdapterKind::XLora,
@@ -364,7 +430,8 @@ impl Loader for NormalLoader {
                 silent,
                 mapper,
                 loading_isq,
-                device.clone()
+                device.clone(),
+                self.tensor_name_map.as_ref()
             ),
             ModelKind::Adapter {
                 adapter: AdapterKind::Lora,
@@ -378,12 +445,13 @@ impl Loader for NormalLoader {
                 silent,
                 mapper,
                 loading_isq,
-                device.clone()
+                device.clone(),
+                self.tensor_name_map.as_ref()
             ),
             _ => unreachable!(),
         };
 
-        let tokenizer = get_tokenizer(paths.get_tokenizer_filename(), None)?;
+        let tokenizer = get_tokenizer(paths.get_tokenizer_filename(), None, &self.added_tokens)?;
         let gen_conf: Option<GenerationConfig> = paths
             .get_gen_conf_filename()
             .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
@@ -396,6 +464,7 @@ impl Loader for NormalLoader {
                 .clone(),
             &self.chat_template,
             None,
+            &tokenizer,
         );
 
         if let Some(calibration_file) = &self.config.calibration_file {
@@ -505,9 +574,13 @@ impl Loader for NormalLoader {
             )?;
         }
 
+        let is_recurrent = model.is_recurrent();
         let paged_attn_config = if matches!(self.kind, ModelKind::Adapter { .. }) {
             warn!("Adapter models do not currently support PagedAttention, running without");
             None
+        } else if is_recurrent && paged_attn_config.is_some() {
+            warn!("Recurrent models do not have a KV cache for PagedAttention, running without");
+            None
         } else {
             paged_attn_config
         };
@@ -527,6 +600,19 @@ impl Loader for NormalLoader {
             (None, None)
         };
 
+        if let Some(len) = effective_context_len(&config, self.inner.rope_scaling_types()) {
+            info!("RoPE scaling extends the context length to {len} tokens.");
+        }
+        match (model.cache(), self.kv_cache_backend) {
+            (_, KvCacheBackendType::Contiguous) => (),
+            (EitherCache::Normal(normal), backend) => {
+                info!("Using the {backend:?} KV cache backend.");
+                normal.lock().unwrap().set_backend(backend, model.device());
+            }
+            (EitherCache::Full(_), backend) => {
+                anyhow::bail!("The {backend:?} KV cache backend is not supported for this model.")
+            }
+        }
         let max_seq_len = model.max_seq_len();
         let tok_env = build_tok_env(tokenizer.clone());
         let num_hidden_layers = match model.cache() {
@@ -552,6 +638,7 @@ impl Loader for NormalLoader {
                 max_seq_len,
                 tok_env: Some(tok_env),
                 has_no_kv_cache: self.no_kv_cache,
+                is_recurrent,
                 num_hidden_layers,
                 eos_tok: eos,
                 kind: self.kind.clone(),
@@ -570,6 +657,7 @@ impl Loader for NormalLoader {
             generation_config: paths.get_gen_conf_filename().cloned(),
             config,
             imatrix: self.config.imatrix.clone(),
+            early_exit: None,
         })))
     }
 
@@ -724,8 +812,8 @@ impl Pipeline for NormalPipeline {
             }
             (None, None) => None,
         };
-        let logits = match self.model.is_xlora() {
-            false => self.model.forward(
+        let logits = match (self.model.is_xlora(), self.early_exit) {
+            (false, None) => self.model.forward(
                 &input_ids,
                 &seqlen_offsets,
                 seqlen_offsets_kernel,
@@ -734,7 +822,16 @@ impl Pipeline for NormalPipeline {
                 paged_attn_meta,
                 &flash_meta,
             )?,
-            true => self.model.xlora_forward(
+            (false, Some(num_layers)) => self.model.forward_early_exit(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+                paged_attn_meta,
+                &flash_meta,
+                num_layers,
+            )?,
+            (true, _) => self.model.xlora_forward(
                 &input_ids,
                 input_ids_full.as_ref().unwrap_or(&input_ids),
                 &seqlen_offsets,
@@ -768,6 +865,79 @@ impl Pipeline for NormalPipeline {
     fn category(&self) -> ModelCategory {
         ModelCategory::Text
     }
+    fn set_early_exit(&mut self, num_layers: Option<usize>) -> Result<(), candle_core::Error> {
+        if let Some(num_layers) = num_layers {
+            if self.model.is_xlora() {
+                candle_core::bail!("Early exit is not supported for X-LoRA models.");
+            }
+            let total = self.metadata.num_hidden_layers;
+            if num_layers == 0 || num_layers >= total {
+                candle_core::bail!(
+                    "Early exit must run between 1 and {} of the {total} layers, got {num_layers}.",
+                    total - 1
+                );
+            }
+        }
+        self.early_exit = num_layers;
+        Ok(())
+    }
+    fn forward_hidden(&mut self, tokens: Vec<u32>) -> Result<Tensor, candle_core::Error> {
+        let inputs = make_prompt_chunk(0, vec![tokens], &[0], &self.device(), None, false, None)
+            .map_err(candle_core::Error::msg)?;
+        // Swap in an empty cache, the running sequences may not copy theirs in for the next step
+        let saved = match self.model.cache() {
+            EitherCache::Full(full) => {
+                let mut cache = full.lock();
+                let saved = Either::Left(cache.clone());
+                cache.iter_mut().for_each(|layer| *layer = None);
+                saved
+            }
+            EitherCache::Normal(normal) => {
+                let mut cache = normal.lock().unwrap();
+                let saved = Either::Right(cache.clone());
+                cache.0.iter_mut().for_each(KvCache::reset);
+                saved
+            }
+        };
+        let hidden = self.model.forward_hidden(
+            &inputs.input,
+            &inputs.positions,
+            inputs.positions_kernel,
+            &inputs.flash_meta,
+        );
+        match (self.model.cache(), saved) {
+            (EitherCache::Full(full), Either::Left(saved)) => *full.lock() = saved,
+            (EitherCache::Normal(normal), Either::Right(saved)) => *normal.lock().unwrap() = saved,
+            _ => unreachable!("The cache kind does not change."),
+        }
+        hidden
+    }
+    fn save_safetensors(&mut self, path: &Path) -> Result<()> {
+        if self.model.is_xlora() {
+            anyhow::bail!("Saving X-LoRA models to safetensors is not supported.");
+        }
+        let mut config: serde_json::Value = serde_json::from_str(&self.config)?;
+        // The weights are saved in the activation dtype
+        config["torch_dtype"] = match self.metadata.activation_dtype {
+            DType::F16 => "float16",
+            DType::BF16 => "bfloat16",
+            _ => "float32",
+        }
+        .into();
+        self.model
+            .save_safetensors(
+                path,
+                UqffFullSer {
+                    tokenizer: &self.tokenizer,
+                    template_filename: &self.template_filename,
+                    generation_config: self.generation_config.as_ref(),
+                    config: serde_json::to_string_pretty(&config)?,
+                    processor_filename: &None,
+                    preprocessor_filename: &None,
+                },
+            )
+            .map_err(anyhow::Error::msg)
+    }
 }
 
 impl AnyMoePipelineMixin for NormalPipeline {
@@ -828,10 +998,12 @@ impl AnyMoePipelineMixin for NormalPipeline {
             let vb = from_mmaped_safetensors(
                 filenames,
                 vec![],
+                false,
                 Some(dtype),
                 dev,
                 silent,
                 None,
+                None,
                 move |key| {
                     if regex.is_match(&key) {
                         // Idx of the last char of the layer id, +1
@@ -879,10 +1051,12 @@ impl AnyMoePipelineMixin for NormalPipeline {
             let vb = from_mmaped_safetensors(
                 gate_filenames.clone(),
                 vec![],
+                false,
                 Some(dtype),
                 dev,
                 silent,
                 None,
+                None,
                 |_| true,
             )?;
             info!(
@@ -901,3 +1075,45 @@ impl AnyMoePipelineMixin for NormalPipeline {
         self.model.amoe_supported()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use crate::testing::{load_tiny_llama, write_tiny_llama, TempDir};
+
+    #[test]
+    fn test_save_safetensors_writes_the_loaded_weights() -> anyhow::Result<()> {
+        let dir = TempDir::new("save_safetensors_weights");
+        let saved = TempDir::new("save_safetensors_weights_saved");
+        write_tiny_llama(dir.path(), &[])?;
+        load_tiny_llama(dir.path())?
+            .blocking_lock()
+            .save_safetensors(&saved.path().join("model.safetensors"))?;
+
+        // Every weight is saved unchanged under its original name
+        let dev = Device::Cpu;
+        let original = candle_core::safetensors::load(dir.path().join("model.safetensors"), &dev)?;
+        let written = candle_core::safetensors::load(saved.path().join("model.safetensors"), &dev)?;
+        let mut names = written.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut expected = original.keys().collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(names, expected);
+        for (name, weight) in &original {
+            let diff = (weight - &written[name])?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert_eq!(diff, 0., "{name}");
+        }
+
+        // The configuration has the dtype of the saved weights
+        let config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(saved.path().join("config.json"))?)?;
+        assert_eq!(config["torch_dtype"], "float32");
+        assert!(saved.path().join("tokenizer.json").exists());
+        Ok(())
+    }
+}
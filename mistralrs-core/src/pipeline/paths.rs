@@ -12,13 +12,16 @@ use hf_hub::{
 };
 use regex_automata::meta::Regex;
 use serde_json::Value;
+use tokenizers::Tokenizer;
 use tracing::{info, warn};
 
 use crate::{
     api_dir_list, api_get_file,
     lora::LoraConfig,
     pipeline::{
-        chat_template::{ChatTemplate, ChatTemplateValue},
+        chat_template::{
+            has_chatml_special_tokens, ChatTemplate, ChatTemplateValue, CHATML_CHAT_TEMPLATE,
+        },
         isq::UQFF_RESIDUAL_SAFETENSORS,
     },
     utils::tokens::get_token,
@@ -30,6 +33,9 @@ use crate::{
 const SAFETENSOR_MATCH: &str = r"model-\d{5}-of-\d{5}.safetensors\b";
 const QUANT_SAFETENSOR_MATCH: &str = r"model.safetensors\b";
 const PICKLE_MATCH: &str = r"pytorch_model-\d{5}-of-\d{5}.((pth)|(pt)|(bin))\b";
+const SINGLE_PICKLE_MATCH: &str = r"^pytorch_model.((pth)|(pt)|(bin))$";
+/// Lists the shards of a sharded pickle checkpoint in its `weight_map`.
+const PICKLE_INDEX: &str = "pytorch_model.bin.index.json";
 
 pub(crate) struct XLoraPaths {
     pub adapter_configs: Option<Vec<((String, String), LoraConfig)>>,
@@ -144,14 +150,25 @@ pub fn get_xlora_paths(
         // Sort local paths for the adapter configs and safetensors files
         let mut adapters_configs = Vec::new();
         let mut adapters_safetensors = Vec::new();
+        let combined_adapters = &xlora_order.as_ref().unwrap().combined_adapters;
+        let combined_path = match combined_adapters {
+            Some(file) => Some(api_get_file!(api, file, model_id)),
+            None => None,
+        };
         if let Some(ref adapters) = xlora_order.as_ref().unwrap().adapters {
             for (i, name) in adapters.iter().enumerate() {
                 let paths = adapters_paths
                     .get(name)
                     .unwrap_or_else(|| panic!("Adapter {name} not found."));
+                // The tensors of every adapter are split out of the combined file when loading
+                if let Some(combined_path) = &combined_path {
+                    adapters_safetensors.push((name.clone(), combined_path.clone()));
+                }
                 for path in paths {
                     if path.extension().unwrap() == "safetensors" {
-                        adapters_safetensors.push((name.clone(), path.to_owned()));
+                        if combined_path.is_none() {
+                            adapters_safetensors.push((name.clone(), path.to_owned()));
+                        }
                     } else {
                         let conf = fs::read_to_string(path)?;
                         let lora_config: LoraConfig = serde_json::from_str(&conf)?;
@@ -217,9 +234,14 @@ pub fn get_xlora_paths(
                     let paths = adapters_paths
                         .get(&adapter.name)
                         .unwrap_or_else(|| panic!("Adapter {} not found.", adapter.name));
+                    if let Some(file) = &xlora_order.combined_adapters {
+                        safetensor = Some(api_get_file!(api, file, model_id));
+                    }
                     for path in paths {
                         if path.extension().unwrap() == "safetensors" {
-                            safetensor = Some(path.to_owned());
+                            if safetensor.is_none() {
+                                safetensor = Some(path.to_owned());
+                            }
                         } else {
                             let conf = fs::read_to_string(path)?;
                             let lora_config: LoraConfig = serde_json::from_str(&conf)?;
@@ -292,13 +314,16 @@ pub fn get_model_paths(
             let safetensor_match = Regex::new(SAFETENSOR_MATCH)?;
             let quant_safetensor_match = Regex::new(QUANT_SAFETENSOR_MATCH)?;
             let pickle_match = Regex::new(PICKLE_MATCH)?;
+            let single_pickle_match = Regex::new(SINGLE_PICKLE_MATCH)?;
 
             let mut filenames = vec![];
             let listing = api_dir_list!(api, model_id).filter(|x| {
                 safetensor_match.is_match(x)
                     || pickle_match.is_match(x)
+                    || single_pickle_match.is_match(x)
                     || quant_safetensor_match.is_match(x)
                     || x == UQFF_RESIDUAL_SAFETENSORS
+                    || x == PICKLE_INDEX
             });
             let safetensors = listing
                 .clone()
@@ -312,9 +337,14 @@ pub fn get_model_paths(
                 .clone()
                 .filter(|x| x == UQFF_RESIDUAL_SAFETENSORS)
                 .collect::<Vec<_>>();
+            let has_pickle_index = listing.clone().any(|x| x == PICKLE_INDEX);
             let files = if !safetensors.is_empty() {
                 // Always prefer safetensors
                 safetensors
+            } else if has_pickle_index {
+                // The index is authoritative for the shards, whatever they are named
+                let index = api_get_file!(api, PICKLE_INDEX, model_id);
+                index_shards(&fs::read_to_string(index)?)?
             } else if !pickles.is_empty() {
                 // Fall back to pickle
                 pickles
@@ -338,6 +368,25 @@ pub fn get_model_paths(
     }
 }
 
+/// The shard filenames listed in the `weight_map` of a sharded checkpoint index, in order.
+fn index_shards(index: &str) -> Result<Vec<String>> {
+    let index: Value = serde_json::from_str(index)?;
+    let Some(weight_map) = index.get("weight_map").and_then(Value::as_object) else {
+        anyhow::bail!("Expected a `weight_map` object in `{PICKLE_INDEX}`.");
+    };
+    let mut shards = weight_map
+        .values()
+        .map(|shard| {
+            shard.as_str().map(ToString::to_string).ok_or_else(|| {
+                anyhow::anyhow!("Expected the shards of `{PICKLE_INDEX}` to be strings.")
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    shards.sort();
+    shards.dedup();
+    Ok(shards)
+}
+
 /// Find and parse the appropriate [`ChatTemplate`], and ensure is has a valid [`ChatTemplate.chat_template`].
 /// If the provided `tokenizer_config.json` from [`ModelPaths.get_template_filename`] does not
 /// have a `chat_template`, use the provided one.
@@ -349,12 +398,16 @@ pub fn get_model_paths(
 ///
 /// After this, if the `chat_template_json` filename is specified (a json with one field: "chat_template"),
 ///  the chat template is overwritten with this chat template.
+///
+/// If there is still no chat template and the tokenizer has the ChatML special tokens, the ChatML template is used.
+/// Specifying a chat template overrides this.
 #[allow(clippy::borrowed_box)]
 pub(crate) fn get_chat_template(
     paths: &Box<dyn ModelPaths>,
     chat_template_json: &Option<String>,
     chat_template_fallback: &Option<String>,
     chat_template_ovrd: Option<String>,
+    tokenizer: &Tokenizer,
 ) -> ChatTemplate {
     // Get template content, this may be overridden.
     let template_content = if let Some(template_filename) = paths.get_template_filename() {
@@ -466,6 +519,13 @@ pub(crate) fn get_chat_template(
                         );
                     }
                 }
+                None if has_chatml_special_tokens(tokenizer) => {
+                    info!("No specified chat template, but the tokenizer has the ChatML special tokens. Using the ChatML chat template, specify a chat template to override this.");
+                    deser.insert(
+                        "chat_template".to_string(),
+                        Value::String(CHATML_CHAT_TEMPLATE.to_string()),
+                    );
+                }
                 None => {
                     info!("No specified chat template. No chat template will be used. Only prompts will be accepted, not messages.");
                     deser.insert("chat_template".to_string(), Value::Null);
@@ -535,4 +595,47 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn match_single_pickle() -> anyhow::Result<()> {
+        use regex_automata::meta::Regex;
+
+        use super::SINGLE_PICKLE_MATCH;
+        let single_pickle_match = Regex::new(SINGLE_PICKLE_MATCH)?;
+
+        for id in ["pytorch_model.bin", "pytorch_model.pt", "pytorch_model.pth"] {
+            assert!(single_pickle_match.is_match(id));
+        }
+        for id in [
+            "pytorch_model.bin.index.json",
+            "pytorch_model-00001-of-00002.bin",
+            "optimizer_pytorch_model.bin",
+        ] {
+            assert!(!single_pickle_match.is_match(id));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_shards() -> anyhow::Result<()> {
+        use super::index_shards;
+
+        let index = r#"{
+            "metadata": {"total_size": 16},
+            "weight_map": {
+                "lm_head.weight": "pytorch_model-00002-of-00002.bin",
+                "model.embed_tokens.weight": "pytorch_model-00001-of-00002.bin",
+                "model.norm.weight": "pytorch_model-00002-of-00002.bin"
+            }
+        }"#;
+        assert_eq!(
+            index_shards(index)?,
+            vec![
+                "pytorch_model-00001-of-00002.bin",
+                "pytorch_model-00002-of-00002.bin"
+            ]
+        );
+        assert!(index_shards(r#"{"metadata": {}}"#).is_err());
+        Ok(())
+    }
 }
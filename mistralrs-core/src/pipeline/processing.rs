@@ -31,11 +31,13 @@ pub enum MessagesAction {
 /// model.
 pub trait Processor {
     /// Get the tokens and the untokenized prompt. `add_special_tokens` should usually be true.
+    /// With `continue_final_message`, the final message is left open to be continued.
     fn process(
         &self,
         pipeline: &dyn Pipeline,
         messages: Vec<IndexMap<String, MessageContent>>,
         add_generation_prompt: bool,
+        continue_final_message: bool,
         add_special_tokens: bool,
         tools: Vec<Tool>,
     ) -> Result<(Vec<u32>, String)> {
@@ -43,6 +45,7 @@ pub trait Processor {
             pipeline,
             messages,
             add_generation_prompt,
+            continue_final_message,
             self.template_action(),
             tools,
         )?;
@@ -64,6 +67,7 @@ pub(crate) fn apply_chat_template(
     pipeline: &dyn Pipeline,
     messages: Vec<IndexMap<String, MessageContent>>,
     add_generation_prompt: bool,
+    continue_final_message: bool,
     action: MessagesAction,
     tools: Vec<Tool>,
 ) -> Result<String> {
@@ -137,6 +141,7 @@ pub(crate) fn apply_chat_template(
     apply_chat_template_to(
         messages,
         add_generation_prompt,
+        continue_final_message,
         template,
         bos_tok,
         eos_tok,
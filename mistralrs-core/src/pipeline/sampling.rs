@@ -19,20 +19,22 @@ pub(crate) async fn finish_or_add_toks_to_seq(
     eos_tok: Option<&[u32]>,
     use_prefix_cacher: bool,
 ) -> Result<()> {
-    let is_done = seq.is_done(logprobs.token, eos_tok, this.get_metadata().max_seq_len);
-    seq.add_token(
-        logprobs.clone(),
-        this.get_metadata()
-            .tok_env
-            .as_ref()
-            .ok_or(candle_core::Error::Msg(
-                "`finish_or_add_toks_to_seq` requires the pipeline to have a token trie"
-                    .to_string(),
-            ))?
-            .tok_trie()
-            .decode(&[logprobs.token]),
-        &is_done,
+    let completion_bytes = this
+        .get_metadata()
+        .tok_env
+        .as_ref()
+        .ok_or(candle_core::Error::Msg(
+            "`finish_or_add_toks_to_seq` requires the pipeline to have a token trie".to_string(),
+        ))?
+        .tok_trie()
+        .decode(&[logprobs.token]);
+    let is_done = seq.is_done(
+        logprobs.token,
+        &completion_bytes,
+        eos_tok,
+        this.get_metadata().max_seq_len,
     );
+    seq.add_token(logprobs.clone(), completion_bytes, &is_done);
     // Handle streaming requests
     if seq.get_mut_group().is_streaming {
         const STREAMING_RATE_LIMIT: usize = 3;
@@ -41,12 +43,46 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         let rate_limit_allowed = is_done.is_some() || token_index % STREAMING_RATE_LIMIT == 0;
 
         if rate_limit_allowed {
-            if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
+            if let Some(delta) =
+                crate::handle_seq_error_ok!(seq.get_delta(is_done.is_some()), seq.responder())
+            {
                 if seq.get_mut_group().is_chat {
+                    let content = match seq.take_assistant_prefix() {
+                        Some(prefix) => format!("{prefix}{delta}"),
+                        None => delta.clone(),
+                    };
+                    let (content, reasoning_content) = match seq.reasoning_parser() {
+                        Some(parser) => {
+                            let mut split = parser.push(&content);
+                            if is_done.is_some() {
+                                let rest = parser.finish();
+                                split.reasoning.push_str(&rest.reasoning);
+                                split.content.push_str(&rest.content);
+                            }
+                            let reasoning = Some(split.reasoning).filter(|r| !r.is_empty());
+                            (split.content, reasoning)
+                        }
+                        None => (content, None),
+                    };
+                    let (content, tool_calls) = match seq.tool_call_stream_parser() {
+                        Some(parser) => {
+                            let mut split = parser.push(&content);
+                            if is_done.is_some() {
+                                let rest = parser.finish();
+                                split.content.push_str(&rest.content);
+                                split.tool_calls.extend(rest.tool_calls);
+                            }
+                            let tool_calls = Some(split.tool_calls).filter(|c| !c.is_empty());
+                            (split.content, tool_calls)
+                        }
+                        None => (content, None),
+                    };
                     seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
                         delta: crate::Delta {
-                            content: delta.clone(),
+                            content,
+                            reasoning_content,
                             role: "assistant".to_string(),
+                            tool_calls,
                         },
                         index: seq.get_response_index(),
                         finish_reason: is_done.map(|x| x.to_string()),
@@ -83,9 +119,10 @@ pub(crate) async fn finish_or_add_toks_to_seq(
 
                 if let Some(reason) = is_done {
                     if use_prefix_cacher {
-                        prefix_cacher.add_sequence(seq);
+                        prefix_cacher.add_sequence(seq)?;
                         prefix_cacher.evict_to_cpu()?;
                     }
+                    seq.update_time_info();
                     seq.set_state(crate::sequence::SequenceState::Done(reason));
                     this.reset_non_granular_state();
                 }
@@ -142,29 +179,38 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 None
             };
 
-            let text = match reason {
+            let generated = match reason {
                 crate::sequence::StopReason::Length(_)
                 | crate::sequence::StopReason::ModelLength(_)
                 | crate::sequence::StopReason::Eos
                 | crate::sequence::StopReason::StopTok(_)
+                | crate::sequence::StopReason::BalancedBrackets { .. }
+                | crate::sequence::StopReason::OutputBytes { .. }
                 | crate::sequence::StopReason::Canceled => {
-                    String::from_utf8_lossy(seq.completion_bytes())
-                        .trim_start()
-                        .to_string()
+                    String::from_utf8_lossy(seq.complete_completion_bytes()).to_string()
                 }
                 crate::sequence::StopReason::StopString {
                     completion_bytes_pos,
                     ..
                 } => {
                     let txt = String::from_utf8_lossy(seq.completion_bytes());
-                    txt[..completion_bytes_pos].trim_start().to_string()
+                    txt[..completion_bytes_pos].to_string()
                 }
                 crate::sequence::StopReason::GeneratedImage => {
                     candle_core::bail!("Stop reason was `GeneratedImage`.")
                 }
             };
+            // The generated text continues the prefix, so its leading whitespace is kept
+            let text = match seq.assistant_prefix() {
+                Some(prefix) => format!("{prefix}{generated}"),
+                None => generated.trim_start().to_string(),
+            };
 
             if seq.get_mut_group().is_chat {
+                let (reasoning_content, text) = match seq.reasoning_parser() {
+                    Some(parser) => parser.markers().split(&text),
+                    None => (None, text),
+                };
                 let mut tool_calls = Vec::new();
                 let mut text_new = Some(text.clone());
                 if let Some(ref matcher) = seq.tools {
@@ -179,6 +225,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     index: seq.get_response_index(),
                     message: crate::ResponseMessage {
                         content: text_new,
+                        reasoning_content,
                         role: "assistant".to_string(),
                         tool_calls,
                     },
@@ -196,7 +243,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
             }
 
             if use_prefix_cacher {
-                prefix_cacher.add_sequence(seq);
+                prefix_cacher.add_sequence(seq)?;
                 prefix_cacher.evict_to_cpu()?;
             }
 
@@ -254,6 +301,14 @@ pub async fn sample_and_add_toks(
 
     let use_async_pool = seqs_len > 1;
 
+    for (logits_per_seq, seq) in std::iter::zip(&logits_seq, seqs.iter_mut()) {
+        if let Some(logits_dump) = seq.logits_dump() {
+            if let Err(e) = logits_dump.append(logits_per_seq) {
+                tracing::warn!("Failed to dump logits for sequence {}: {e}", seq.id());
+            }
+        }
+    }
+
     let sampling_futures: Vec<_> = std::iter::zip(logits_seq, seqs.iter_mut())
         .map(|(logits_per_seq, seq)| {
             let return_logprobs = seq.return_logprobs();
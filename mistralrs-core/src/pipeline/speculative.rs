@@ -10,7 +10,7 @@ use candle_core::{Device, IndexOp, Result, Tensor};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use tokenizers::Tokenizer;
-use tracing::warn;
+use tracing::{info, warn};
 
 use crate::{
     get_mut_arcmutex,
@@ -21,7 +21,7 @@ use crate::{
         AdapterInstruction,
     },
     prefix_cacher::PrefixCacheManager,
-    sequence::{Sequence, SequenceRecognizer},
+    sequence::{Sequence, SequenceRecognizer, SequenceState},
     DeviceMapMetadata, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource,
     TryIntoDType,
 };
@@ -149,6 +149,92 @@ impl Loader for SpeculativeLoader {
     }
 }
 
+/// A loader for a self-speculative pipeline, which drafts with an early exit of the target model.
+pub struct SelfSpeculativeLoader {
+    pub target: Box<dyn Loader>,
+    pub config: SelfSpeculativeConfig,
+}
+
+impl Loader for SelfSpeculativeLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let target = self.target.load_model_from_hf(
+            revision,
+            token_source,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_self_speculative(target, self.config)?,
+        )))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let target = self.target.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_self_speculative(target, self.config)?,
+        )))
+    }
+    fn get_id(&self) -> String {
+        format!(
+            "Self-speculative: tgt = `{}`, draft layers = `{}`, gamma = `{}`",
+            self.target.get_id(),
+            self.config.draft_layers,
+            self.config.gamma,
+        )
+    }
+    fn get_kind(&self) -> ModelKind {
+        ModelKind::Speculative {
+            target: Box::new(self.target.get_kind()),
+            draft: Box::new(self.target.get_kind()),
+        }
+    }
+}
+
 /// Speculative decoding pipeline: <https://arxiv.org/pdf/2211.17192>
 ///
 /// # Algorithm
@@ -160,12 +246,23 @@ impl Loader for SpeculativeLoader {
 /// - Else (q_i(x) > p_i(x)) accept that token with prob p_i(x)/q_i(x)
 ///     - If rejected, sample token from from p'_i(x) = norm(max(0, p(x) − q(x))) and do not take any more'
 ///
+/// With self-speculative decoding (<https://arxiv.org/abs/2404.16710>), q is an early exit of
+/// p: the first layers of the target model with its final norm and LM head. The draft shares
+/// the KV cache of those layers with the target model.
 pub struct SpeculativePipeline {
     target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-    draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    draft: Draft,
     gamma: usize,
     metadata: Arc<GeneralMetadata>,
     category: ModelCategory,
+    // Draft tokens proposed and accepted for the running sequence
+    drafted_toks: usize,
+    accepted_toks: usize,
+}
+
+enum Draft {
+    Model(Arc<tokio::sync::Mutex<dyn Pipeline>>),
+    EarlyExit { layers: usize },
 }
 
 #[derive(Copy, Clone)]
@@ -175,6 +272,35 @@ pub struct SpeculativeConfig {
     pub gamma: usize,
 }
 
+#[derive(Copy, Clone)]
+/// Metadata for a self-speculative pipeline
+pub struct SelfSpeculativeConfig {
+    /// Number of layers of the target model to run for the draft tokens
+    pub draft_layers: usize,
+    /// γ completions to run of the draft model
+    pub gamma: usize,
+}
+
+/// Drop the cached tokens after the first `len` in the first `num_layers` layers.
+fn truncate_cache_layers(cache: &EitherCache, num_layers: usize, len: usize) -> Result<()> {
+    match cache {
+        EitherCache::Full(full) => {
+            for layer in full.lock().iter_mut().take(num_layers) {
+                *layer = match layer.take() {
+                    Some((k, v)) if len > 0 => Some((k.narrow(2, 0, len)?, v.narrow(2, 0, len)?)),
+                    _ => None,
+                };
+            }
+        }
+        EitherCache::Normal(normal) => {
+            for cache in normal.lock().unwrap().0.iter_mut().take(num_layers) {
+                cache.set_len(len);
+            }
+        }
+    }
+    Ok(())
+}
+
 impl SpeculativePipeline {
     pub fn new(
         target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
@@ -219,10 +345,38 @@ impl SpeculativePipeline {
         // TODO: some checks or relaxation here?
         Ok(Self {
             target,
-            draft,
+            draft: Draft::Model(draft),
             gamma: config.gamma,
             metadata,
             category,
+            drafted_toks: 0,
+            accepted_toks: 0,
+        })
+    }
+
+    /// Draft the tokens with the first `draft_layers` layers of the target model.
+    pub fn new_self_speculative(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        config: SelfSpeculativeConfig,
+    ) -> Result<Self> {
+        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        if metadata.is_recurrent {
+            candle_core::bail!("Self-speculative decoding requires a KV cache, which recurrent models do not have.");
+        }
+        // Check that the model supports this early exit
+        get_mut_arcmutex!(target).set_early_exit(Some(config.draft_layers))?;
+        get_mut_arcmutex!(target).set_early_exit(None)?;
+        let category = get_mut_arcmutex!(target).category();
+        Ok(Self {
+            target,
+            draft: Draft::EarlyExit {
+                layers: config.draft_layers,
+            },
+            gamma: config.gamma,
+            metadata,
+            category,
+            drafted_toks: 0,
+            accepted_toks: 0,
         })
     }
 }
@@ -239,19 +393,26 @@ impl PreProcessingMixin for SpeculativePipeline {
 impl IsqPipelineMixin for SpeculativePipeline {
     fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
-        get_mut_arcmutex!(self.draft).re_isq_model(dtype)
+        match &self.draft {
+            Draft::Model(draft) => get_mut_arcmutex!(draft).re_isq_model(dtype),
+            Draft::EarlyExit { .. } => Ok(()),
+        }
     }
 }
 
 // TODO: correct handling of cloning in and out for normal cache
 impl CacheManagerMixin for SpeculativePipeline {
     fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        FullCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.draft), seqs, modify_draft_cache);
-        FullCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.target), seqs, false);
+        if let Draft::Model(draft) = &self.draft {
+            FullCacheManager.clone_in_cache(&*get_mut_arcmutex!(draft), seqs, modify_draft_cache);
+        }
+        get_mut_arcmutex!(self.target).clone_in_cache(seqs, false);
     }
     fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        FullCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.draft), seqs, modify_draft_cache);
-        FullCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.target), seqs, false);
+        if let Draft::Model(draft) = &self.draft {
+            FullCacheManager.clone_out_cache(&*get_mut_arcmutex!(draft), seqs, modify_draft_cache);
+        }
+        get_mut_arcmutex!(self.target).clone_out_cache(seqs, false);
     }
     fn set_none_cache(
         &self,
@@ -260,18 +421,15 @@ impl CacheManagerMixin for SpeculativePipeline {
         modify_draft_cache: bool,
         load_preallocated_cache: bool,
     ) {
-        FullCacheManager.set_none_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-            load_preallocated_cache,
-        );
-        FullCacheManager.set_none_cache(
-            &*get_mut_arcmutex!(self.target),
-            seqs,
-            false,
-            load_preallocated_cache,
-        );
+        if let Draft::Model(draft) = &self.draft {
+            FullCacheManager.set_none_cache(
+                &*get_mut_arcmutex!(draft),
+                seqs,
+                modify_draft_cache,
+                load_preallocated_cache,
+            );
+        }
+        get_mut_arcmutex!(self.target).set_none_cache(seqs, false, false, load_preallocated_cache);
         if reset_non_granular {
             self.reset_non_granular_state()
         }
@@ -285,7 +443,9 @@ impl AdapterActivationMixin for SpeculativePipeline {
     /// Returns the number of activated adapters.
     fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
         let mut res = 0;
-        res += get_mut_arcmutex!(self.draft).activate_adapters(adapters.clone())?;
+        if let Draft::Model(draft) = &self.draft {
+            res += get_mut_arcmutex!(draft).activate_adapters(adapters.clone())?;
+        }
         res += get_mut_arcmutex!(self.target).activate_adapters(adapters)?;
         Ok(res)
     }
@@ -299,16 +459,25 @@ impl MetadataMixin for SpeculativePipeline {
         get_mut_arcmutex!(self.target).tokenizer()
     }
     fn name(&self) -> String {
-        format!(
-            "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
-            get_mut_arcmutex!(self.target).name(),
-            get_mut_arcmutex!(self.draft).name(),
-            self.gamma,
-        )
+        match &self.draft {
+            Draft::Model(draft) => format!(
+                "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                get_mut_arcmutex!(draft).name(),
+                self.gamma,
+            ),
+            Draft::EarlyExit { layers } => format!(
+                "Self-speculative: tgt = `{}`, draft layers = `{layers}`, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                self.gamma,
+            ),
+        }
     }
     fn reset_non_granular_state(&self) {
         get_mut_arcmutex!(self.target).reset_non_granular_state();
-        get_mut_arcmutex!(self.draft).reset_non_granular_state();
+        if let Draft::Model(draft) = &self.draft {
+            get_mut_arcmutex!(draft).reset_non_granular_state();
+        }
     }
     fn get_metadata(&self) -> Arc<GeneralMetadata> {
         self.metadata.clone()
@@ -407,15 +576,30 @@ impl Pipeline for SpeculativePipeline {
                 assert_eq!(input_seqs.len(), 1);
 
                 let seq = &mut input_seqs[0];
+                if is_prompt {
+                    self.drafted_toks = 0;
+                    self.accepted_toks = 0;
+                }
+
+                let initial_cache_len = match get_mut_arcmutex!(self.target).cache() {
+                    EitherCache::Full(full) => full.lock()[0]
+                        .as_ref()
+                        .map(|(k, _)| k.dims()[2])
+                        .unwrap_or(0),
+                    EitherCache::Normal(normal) => normal.lock().unwrap().0[0].current_seq_len(),
+                };
 
                 // ======================= Run draft model gamma times producing tokens ============================
                 // ======================= Sample the `gamma` logits. ============================
+                let (draft, early_exit) = match &self.draft {
+                    Draft::Model(draft) => (draft.clone(), None),
+                    Draft::EarlyExit { layers } => (self.target.clone(), Some(*layers)),
+                };
                 let mut draft_samples = Vec::new();
                 for i in 0..self.gamma {
-                    let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
-                    let device = get_mut_arcmutex!(self.draft).device();
-                    let has_no_kv_cache =
-                        get_mut_arcmutex!(self.draft).get_metadata().has_no_kv_cache;
+                    let is_xlora = get_mut_arcmutex!(draft).get_metadata().is_xlora;
+                    let device = get_mut_arcmutex!(draft).device();
+                    let has_no_kv_cache = get_mut_arcmutex!(draft).get_metadata().has_no_kv_cache;
                     let inputs = self
                         .get_processor()
                         .inputs_processor()
@@ -435,8 +619,17 @@ impl Pipeline for SpeculativePipeline {
                         .nth(0)
                         .unwrap()
                         .unwrap();
-                    let logits =
-                        get_mut_arcmutex!(self.draft).forward_inputs(Box::new(inputs), false)?;
+                    let logits = {
+                        let mut draft = get_mut_arcmutex!(draft);
+                        if early_exit.is_some() {
+                            draft.set_early_exit(early_exit)?;
+                        }
+                        let logits = draft.forward_inputs(Box::new(inputs), false);
+                        if early_exit.is_some() {
+                            draft.set_early_exit(None)?;
+                        }
+                        logits?
+                    };
                     #[allow(irrefutable_let_patterns)]
                     let ForwardInputsResult::CausalGeneration { logits } = logits
                     else {
@@ -460,6 +653,16 @@ impl Pipeline for SpeculativePipeline {
                 }
                 seq.remove_tmp_tok(self.gamma);
 
+                // The early exit cached the draft tokens in the first layers, the target model
+                // recomputes them.
+                if let Some(layers) = early_exit {
+                    truncate_cache_layers(
+                        get_mut_arcmutex!(self.target).cache(),
+                        layers,
+                        initial_cache_len,
+                    )?;
+                }
+
                 // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
                 let mut draft_prefill_tokens = if is_prompt {
                     seq.get_toks().to_vec()
@@ -476,14 +679,6 @@ impl Pipeline for SpeculativePipeline {
 
                 // ======================= Run the model with all draft tokens. ============================
 
-                let initial_cache_len = match get_mut_arcmutex!(self.target).cache() {
-                    EitherCache::Full(full) => full.lock()[0]
-                        .as_ref()
-                        .map(|(k, _)| k.dims()[2])
-                        .unwrap_or(0),
-                    EitherCache::Normal(normal) => normal.lock().unwrap().0[0].current_seq_len(),
-                };
-
                 // ========= Run the model ============
                 let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
                 let device = get_mut_arcmutex!(self.target).device();
@@ -535,39 +730,45 @@ impl Pipeline for SpeculativePipeline {
                 .await?;
 
                 let mut accepted_tokens = Vec::new();
+                let mut n_draft_accepted = 0;
                 for (target_sample, draft_sample) in zip(samples, draft_samples) {
                     let tok = target_sample.sample.token;
                     accepted_tokens.push(target_sample.sample);
                     if draft_sample.sample.token != tok {
                         break;
                     }
+                    n_draft_accepted += 1;
                 }
+                self.drafted_toks += self.gamma;
+                self.accepted_toks += n_draft_accepted;
 
                 // ======================= Narrow caches to account for rejections ============================
                 let n_not_accepted = self.gamma - accepted_tokens.len();
-                match get_mut_arcmutex!(self.draft).cache() {
-                    EitherCache::Full(full) => {
-                        for (k, v) in full.lock().iter_mut().flatten() {
-                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                        }
-                    }
-                    EitherCache::Normal(normal) => {
-                        for cache in &mut *normal.lock().unwrap().0 {
-                            cache.set_len(cache.current_seq_len() - n_not_accepted);
-                        }
-                    }
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    match get_mut_arcmutex!(self.draft).cache() {
+                if let Draft::Model(draft) = &self.draft {
+                    match get_mut_arcmutex!(draft).cache() {
                         EitherCache::Full(full) => {
-                            for (k, v) in full.xlora_lock().iter_mut().flatten() {
+                            for (k, v) in full.lock().iter_mut().flatten() {
                                 *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
                                 *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
                             }
                         }
-                        EitherCache::Normal(_) => {
-                            unreachable!()
+                        EitherCache::Normal(normal) => {
+                            for cache in &mut *normal.lock().unwrap().0 {
+                                cache.set_len(cache.current_seq_len() - n_not_accepted);
+                            }
+                        }
+                    }
+                    if get_mut_arcmutex!(draft).get_metadata().is_xlora {
+                        match get_mut_arcmutex!(draft).cache() {
+                            EitherCache::Full(full) => {
+                                for (k, v) in full.xlora_lock().iter_mut().flatten() {
+                                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                                }
+                            }
+                            EitherCache::Normal(_) => {
+                                unreachable!()
+                            }
                         }
                     }
                 }
@@ -584,7 +785,7 @@ impl Pipeline for SpeculativePipeline {
                         }
                     }
                 }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
+                if self.metadata.is_xlora {
                     match get_mut_arcmutex!(self.target).cache() {
                         EitherCache::Full(full) => {
                             for (k, v) in full.xlora_lock().iter_mut().flatten() {
@@ -628,6 +829,17 @@ impl Pipeline for SpeculativePipeline {
                     }
                 }
 
+                if matches!(seq.getstate(), SequenceState::Done(_)) && self.drafted_toks > 0 {
+                    #[allow(clippy::cast_precision_loss)]
+                    let rate = self.accepted_toks as f64 / self.drafted_toks as f64;
+                    info!(
+                        "Speculative decoding accepted {} of {} draft tokens ({:.1}%).",
+                        self.accepted_toks,
+                        self.drafted_toks,
+                        rate * 100.
+                    );
+                }
+
                 // Trick to improve lower bounds. Sample last token in multinomial
                 /*
                 let sample = sample_sequence(
@@ -689,3 +901,49 @@ impl Pipeline for SpeculativePipeline {
 
 // TODO
 impl AnyMoePipelineMixin for SpeculativePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use candle_core::{Device, Result, Tensor};
+
+    use super::truncate_cache_layers;
+    use crate::pipeline::{Cache, EitherCache, KvCache, NormalCache};
+
+    #[test]
+    fn test_truncate_cache_layers() -> Result<()> {
+        let dev = Device::Cpu;
+        let kv = Tensor::randn(0f32, 1., (1, 2, 6, 4), &dev)?;
+
+        let full = EitherCache::Full(Cache::new(3, false));
+        for layer in full.full().lock().iter_mut() {
+            *layer = Some((kv.clone(), kv.clone()));
+        }
+        truncate_cache_layers(&full, 2, 4)?;
+        let lens = full
+            .full()
+            .lock()
+            .iter()
+            .map(|layer| layer.as_ref().map(|(k, _)| k.dims()[2]))
+            .collect::<Vec<_>>();
+        assert_eq!(lens, vec![Some(4), Some(4), Some(6)]);
+        truncate_cache_layers(&full, 1, 0)?;
+        assert!(full.full().lock()[0].is_none());
+
+        let mut caches = vec![KvCache::new(2, 64, 16); 3];
+        for cache in &mut caches {
+            cache.append(&kv, &kv)?;
+        }
+        let normal = EitherCache::Normal(Arc::new(Mutex::new(NormalCache(caches))));
+        truncate_cache_layers(&normal, 2, 4)?;
+        let lens = normal
+            .normal()
+            .0
+            .iter()
+            .map(|cache| cache.current_seq_len())
+            .collect::<Vec<_>>();
+        assert_eq!(lens, vec![4, 4, 6]);
+        Ok(())
+    }
+}
@@ -301,6 +301,7 @@ impl Loader for VisionLoader {
         let tokenizer = get_tokenizer(
             paths.get_tokenizer_filename(),
             Some(processor.get_special_tokens()),
+            &[],
         )?;
 
         let gen_conf: Option<GenerationConfig> = paths
@@ -315,6 +316,7 @@ impl Loader for VisionLoader {
                 .clone(),
             &self.chat_template,
             None,
+            &tokenizer,
         );
 
         if let Some(calibration_file) = &self.config.calibration_file {
@@ -462,6 +464,7 @@ impl Loader for VisionLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 has_no_kv_cache: false,
+                is_recurrent: false,
                 activation_dtype: dtype,
                 sliding_window,
                 cache_config,
@@ -718,10 +721,12 @@ impl AnyMoePipelineMixin for VisionPipeline {
             let vb = from_mmaped_safetensors(
                 filenames,
                 vec![],
+                false,
                 Some(dtype),
                 dev,
                 silent,
                 None,
+                None,
                 move |key| {
                     if regex.is_match(&key) {
                         // Idx of the last char of the layer id, +1
@@ -769,10 +774,12 @@ impl AnyMoePipelineMixin for VisionPipeline {
             let vb = from_mmaped_safetensors(
                 gate_filenames.clone(),
                 vec![],
+                false,
                 Some(dtype),
                 dev,
                 silent,
                 None,
+                None,
                 |_| true,
             )?;
             info!(
@@ -2,6 +2,8 @@ use std::sync::{Arc, Mutex};
 
 use candle_core::{Device, Result, Tensor};
 use radix_trie::{Trie, TrieCommon, TrieKey};
+use serde::Serialize;
+use tracing::debug;
 
 use crate::{get_mut_arcmutex, pipeline::LayerCaches, sequence::Sequence};
 
@@ -25,20 +27,73 @@ impl From<Vec<u32>> for Tokens {
 
 type EvictionCacheGroup = (Arc<Mutex<LayerCaches>>, Option<Arc<Mutex<LayerCaches>>>);
 
+/// The number of caches kept on the CPU for each cache kept on the device. The least recently used
+/// caches beyond these are dropped.
+const CPU_CACHES_PER_DEVICE_CACHE: usize = 1;
+
 pub struct PrefixCacheManager {
     caches: Trie<Tokens, Arc<Mutex<LayerCaches>>>,
     xlora_caches: Option<Trie<Tokens, Arc<Mutex<LayerCaches>>>>,
     device: Device,
     pub n_on_device: usize,
     no_prefix_cache: bool,
-    eviction_cache_ptrs: Vec<EvictionCacheGroup>,
+    // The cached tokens and caches, from the least to the most recently used
+    eviction_cache_ptrs: Vec<(Vec<u32>, EvictionCacheGroup)>,
+    stats: PrefixCacheStats,
 }
 
 #[derive(Clone)]
 pub struct MatchingCache {
     pub normal: LayerCaches,
     pub xlora: Option<LayerCaches>,
+    /// The tokens which are not covered by the cache and still need to be prefilled.
     pub toks: Vec<u32>,
+    /// The number of tokens covered by the cache.
+    pub prefix_len: usize,
+}
+
+/// Counters of the prefix cache lookups.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PrefixCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// The total number of prompt tokens which did not need to be prefilled.
+    pub reused_toks: usize,
+    /// The number of caches dropped to stay within the capacity.
+    pub dropped: usize,
+}
+
+/// The KV cache of a sequence as [`LayerCaches`], along with the number of tokens it covers.
+/// Sequences of models with a normal cache hold their cache in the preallocated form, which is
+/// copied out here so that the stored cache only holds the covered tokens and not the whole
+/// preallocated buffer.
+fn seq_layer_caches(seq: &mut Sequence) -> Result<Option<(LayerCaches, usize)>> {
+    let cache = if seq.cache().iter().any(|layer| layer.is_some()) {
+        seq.cache().clone()
+    } else {
+        let mut cache = Vec::new();
+        for layer in seq.normal_cache().iter() {
+            match layer {
+                Some(layer) => match (layer.k()?, layer.v()?) {
+                    (Some(k), Some(v)) => cache.push(Some((k.copy()?, v.copy()?))),
+                    _ => cache.push(None),
+                },
+                None => cache.push(None),
+            }
+        }
+        cache
+    };
+    let len = cache
+        .iter()
+        .flatten()
+        .next()
+        .map(|(k, _)| k.dim(2))
+        .transpose()?;
+    Ok(len.map(|len| (cache, len)))
+}
+
+fn is_on_cpu(cache: &LayerCaches) -> bool {
+    cache.iter().flatten().all(|(k, _)| k.device().is_cpu())
 }
 
 impl PrefixCacheManager {
@@ -50,28 +105,66 @@ impl PrefixCacheManager {
             n_on_device,
             no_prefix_cache,
             eviction_cache_ptrs: Vec::new(),
+            stats: PrefixCacheStats::default(),
         }
     }
 
     /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
     /// some caches will be evicted.
-    pub fn add_sequence(&mut self, seq: &mut Sequence) {
+    pub fn add_sequence(&mut self, seq: &mut Sequence) -> Result<()> {
         if self.no_prefix_cache {
-            return;
+            return Ok(());
         }
-        let cache = Arc::new(Mutex::new(seq.cache().clone()));
-        self.caches
-            .insert(seq.get_toks().to_vec().into(), cache.clone());
-        if seq.is_xlora() {
-            let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
-            self.xlora_caches
-                .as_mut()
-                .unwrap()
-                .insert(seq.get_toks().to_vec().into(), xlora_cache.clone());
-            self.eviction_cache_ptrs.push((cache, Some(xlora_cache)));
-        } else {
-            self.eviction_cache_ptrs.push((cache, None));
+        let Some((cache, len)) = seq_layer_caches(seq)? else {
+            return Ok(());
+        };
+        // The last sampled token has not been run through the model, so the cache covers fewer
+        // tokens than the sequence holds. Key the cache by the tokens it actually covers.
+        let Some(toks) = seq.get_toks().get(..len).map(|toks| toks.to_vec()) else {
+            return Ok(());
+        };
+        let xlora_cache = seq.is_xlora().then(|| seq.xlora_cache().clone());
+        self.add_cache(toks, cache, xlora_cache);
+        Ok(())
+    }
+
+    /// The most recently added cache of some tokens replaces an older one. Once there are more
+    /// than `n_on_device` caches on the device and as many on the CPU, the least recently used
+    /// ones are dropped.
+    fn add_cache(&mut self, toks: Vec<u32>, cache: LayerCaches, xlora_cache: Option<LayerCaches>) {
+        let cache = Arc::new(Mutex::new(cache));
+        if self
+            .caches
+            .insert(toks.clone().into(), cache.clone())
+            .is_some()
+        {
+            self.eviction_cache_ptrs
+                .retain(|(cached, _)| *cached != toks);
+        }
+        let xlora_cache = match (self.xlora_caches.as_mut(), xlora_cache) {
+            (Some(xlora_caches), Some(xlora_cache)) => {
+                let xlora_cache = Arc::new(Mutex::new(xlora_cache));
+                xlora_caches.insert(toks.clone().into(), xlora_cache.clone());
+                Some(xlora_cache)
+            }
+            _ => None,
+        };
+        self.eviction_cache_ptrs.push((toks, (cache, xlora_cache)));
+
+        let capacity = self.n_on_device * (1 + CPU_CACHES_PER_DEVICE_CACHE);
+        let n_dropped = self.eviction_cache_ptrs.len().saturating_sub(capacity);
+        for (toks, _) in self.eviction_cache_ptrs.drain(..n_dropped) {
+            let toks = Tokens(toks);
+            self.caches.remove(&toks);
+            if let Some(xlora_caches) = self.xlora_caches.as_mut() {
+                xlora_caches.remove(&toks);
+            }
         }
+        self.stats.dropped += n_dropped;
+    }
+
+    pub fn stats(&self) -> PrefixCacheStats {
+        self.stats
     }
 
     fn cache_to<'a>(
@@ -86,58 +179,29 @@ impl PrefixCacheManager {
         Ok(())
     }
 
-    /// Evict the caches to CPU. This will evict the first k seqs such that the number of sequences on device after the copy is
-    /// the maximum allowed. Returns the number of evicted sequences.
+    /// Evict the caches to CPU. This will evict all but the `n_on_device` most recently used caches.
+    /// Returns the number of evicted sequences.
     pub fn evict_to_cpu(&mut self) -> Result<usize> {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        let mut n_on_device = 0;
-        for (cache, _) in &self.eviction_cache_ptrs {
-            if get_mut_arcmutex!(cache.as_ref())[0].is_none() {
-                // TODO: add support for normal cache
-                continue;
-            }
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                n_on_device += 1;
-            }
-        }
+        let n_old = self
+            .eviction_cache_ptrs
+            .len()
+            .saturating_sub(self.n_on_device);
         let mut n_evicted = 0;
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if n_on_device - n_evicted == self.n_on_device {
-                break;
-            }
-            if get_mut_arcmutex!(cache.as_ref())[0].is_none() {
-                // TODO: add support for normal cache
+        for (_, (cache, xlora_cache)) in &self.eviction_cache_ptrs[..n_old] {
+            let mut cache = get_mut_arcmutex!(cache);
+            if is_on_cpu(&cache) {
                 continue;
             }
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
-                n_evicted += 1;
+            Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
+            if let Some(xlora_cache) = xlora_cache {
+                Self::cache_to(get_mut_arcmutex!(xlora_cache).iter_mut(), &Device::Cpu)?;
             }
+            n_evicted += 1;
         }
-        Ok(self.caches.len().saturating_sub(self.n_on_device))
+        Ok(n_evicted)
     }
 
     /// Evict all the caches to CPU.
@@ -145,60 +209,140 @@ impl PrefixCacheManager {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
+        for (_, (cache, xlora_cache)) in &self.eviction_cache_ptrs {
+            Self::cache_to(get_mut_arcmutex!(cache).iter_mut(), &Device::Cpu)?;
+            if let Some(xlora_cache) = xlora_cache {
+                Self::cache_to(get_mut_arcmutex!(xlora_cache).iter_mut(), &Device::Cpu)?;
             }
         }
         Ok(self.caches.len())
     }
 
-    /// Search for a matching cache given some toks
+    /// Search for the cache of the longest stored prefix of `toks`. At least one token is always
+    /// left to be prefilled, as the logits of the last token are needed to sample.
     pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
-        if self.no_prefix_cache || toks.is_empty() {
+        if self.no_prefix_cache || toks.len() < 2 {
+            return Ok(None);
+        }
+
+        let search = Tokens(toks[..toks.len() - 1].to_vec());
+        let Some((prefix, cache)) = self
+            .caches
+            .get_ancestor(&search)
+            .and_then(|ancestor| Some((ancestor.key()?.0.clone(), ancestor.value()?.clone())))
+        else {
+            self.stats.misses += 1;
+            return Ok(None);
+        };
+        // The trie is keyed by bytes, so check that the match ends on a token boundary
+        if prefix.is_empty() || !toks.starts_with(&prefix) {
+            self.stats.misses += 1;
             return Ok(None);
         }
 
-        let toks = Tokens(toks.to_vec());
-        if let Some(cache) = self.caches.get(&toks) {
-            Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
-            let cache = get_mut_arcmutex!(cache.as_ref()).clone();
-            let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
-                let mut xlora_cache = get_mut_arcmutex!(xlora_caches.get(&toks).unwrap().as_ref());
-                Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
-                Some(xlora_cache.clone())
-            } else {
-                None
+        Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
+        let cache = get_mut_arcmutex!(cache.as_ref()).clone();
+        let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
+            let Some(xlora_cache) = xlora_caches.get(&Tokens(prefix.clone())) else {
+                self.stats.misses += 1;
+                return Ok(None);
             };
-            let ancestor = &self
-                .caches
-                .get_ancestor(&toks)
-                .expect("No ancestor.")
-                .key()
-                .expect("Cannot get the key.")
-                .0;
-            // Know ancestor.len() < toks.len(), and toks[0..ancestor.len()] == toks
-            Ok(Some(MatchingCache {
-                normal: cache,
-                xlora: xlora_cache,
-                toks: toks.0[ancestor.len()..].to_vec(),
-            }))
+            let mut xlora_cache = get_mut_arcmutex!(xlora_cache.as_ref());
+            Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
+            Some(xlora_cache.clone())
         } else {
-            Ok(None)
+            None
+        };
+
+        // Mark the cache as the most recently used
+        if let Some(pos) = self
+            .eviction_cache_ptrs
+            .iter()
+            .position(|(cached, _)| *cached == prefix)
+        {
+            let used = self.eviction_cache_ptrs.remove(pos);
+            self.eviction_cache_ptrs.push(used);
         }
+        self.stats.hits += 1;
+        self.stats.reused_toks += prefix.len();
+        debug!(
+            "Prefix cache hit, reusing {} of {} prompt tokens.",
+            prefix.len(),
+            toks.len()
+        );
+        Ok(Some(MatchingCache {
+            normal: cache,
+            xlora: xlora_cache,
+            toks: toks[prefix.len()..].to_vec(),
+            prefix_len: prefix.len(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{PrefixCacheManager, PrefixCacheStats};
+
+    #[test]
+    fn test_warmed_prefix_hit() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let mut cacher = PrefixCacheManager::new(dev.clone(), 4, false, false);
+        let prefix = vec![1u32, 2, 3, 4];
+        let kv = Tensor::zeros((1, 2, prefix.len(), 8), DType::F32, &dev)?;
+        cacher.add_cache(prefix.clone(), vec![Some((kv.clone(), kv)); 2], None);
+
+        // A prompt extending the warmed prefix only needs the suffix prefilled
+        let matching = cacher
+            .search_for_matching_cache(&[1, 2, 3, 4, 5, 6])?
+            .expect("Expected a cache hit.");
+        assert_eq!(matching.prefix_len, prefix.len());
+        assert_eq!(matching.toks, vec![5, 6]);
+
+        // The prompt itself always leaves a token to run
+        assert!(cacher.search_for_matching_cache(&prefix)?.is_none());
+        // As does a diverging prompt
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2, 7, 4, 5])?
+            .is_none());
+
+        assert_eq!(
+            cacher.stats(),
+            PrefixCacheStats {
+                hits: 1,
+                misses: 2,
+                reused_toks: prefix.len(),
+                dropped: 0,
+            }
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_least_recently_used_dropped() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        // One cache on the device and one on the CPU
+        let mut cacher = PrefixCacheManager::new(dev.clone(), 1, false, false);
+        let kv = |len| Tensor::zeros((1, 2, len, 8), DType::F32, &dev);
+        cacher.add_cache(vec![1, 2], vec![Some((kv(2)?, kv(2)?))], None);
+        cacher.add_cache(vec![3, 4], vec![Some((kv(2)?, kv(2)?))], None);
+        assert!(cacher.search_for_matching_cache(&[1, 2, 5])?.is_some());
+
+        // The cache of [3, 4] is the least recently used
+        cacher.add_cache(vec![5, 6], vec![Some((kv(2)?, kv(2)?))], None);
+        assert!(cacher.search_for_matching_cache(&[3, 4, 7])?.is_none());
+        assert!(cacher.search_for_matching_cache(&[1, 2, 7])?.is_some());
+        assert!(cacher.search_for_matching_cache(&[5, 6, 7])?.is_some());
+        assert_eq!(cacher.eviction_cache_ptrs.len(), 2);
+        assert_eq!(
+            cacher.stats(),
+            PrefixCacheStats {
+                hits: 3,
+                misses: 1,
+                reused_toks: 6,
+                dropped: 1,
+            }
+        );
+        Ok(())
     }
 }
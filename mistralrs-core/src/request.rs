@@ -1,3 +1,4 @@
+use candle_core::Tensor;
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_quant::IsqType;
@@ -5,12 +6,13 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::{
+    prefix_cacher::PrefixCacheStats,
     response::Response,
-    sampler::SamplingParams,
+    sampler::{SamplingParams, SamplingUpdate},
     tools::{Tool, ToolChoice},
-    CustomLogitsProcessor, DiffusionGenerationParams,
+    ClassificationHead, CustomLogitsProcessor, DiffusionGenerationParams, ReasoningMarkers,
 };
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, path::PathBuf, sync::Arc, time::Instant};
 use tokio::sync::mpsc::Sender;
 
 pub type LlguidanceGrammar = llguidance::api::TopLevelGrammar;
@@ -22,6 +24,10 @@ pub enum Constraint {
     Lark(String),
     JsonSchema(serde_json::Value),
     Llguidance(LlguidanceGrammar),
+    /// A single JSON object, as with OpenAI's `response_format: {"type": "json_object"}`. When
+    /// the messages do not ask for JSON, a system instruction asking for it is added if the
+    /// chat template accepts it.
+    JsonObject,
     None,
 }
 
@@ -75,6 +81,13 @@ pub enum RequestMessage {
 ///     3) Apply temperature and softmax
 ///     4) Sample the next token (topk, topp, minp, etc)
 /// - `return_raw_logits`: Return raw logits.
+/// - `continue_final_message`: Leave the final chat message open so that the model continues it,
+///     instead of adding a generation prompt. Only applicable to chat messages.
+/// - `reasoning_markers`: Split the reasoning block delimited by these markers from the content
+///     of chat responses, into `reasoning_content`.
+/// - `assistant_prefix`: Prefill the start of the assistant response with this text, which is
+///     appended to the rendered prompt and included in the returned content. Only applicable to
+///     chat messages.
 pub struct NormalRequest {
     pub messages: RequestMessage,
     pub sampling_params: SamplingParams,
@@ -89,6 +102,9 @@ pub struct NormalRequest {
     pub tool_choice: Option<ToolChoice>,
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
     pub return_raw_logits: bool,
+    pub continue_final_message: bool,
+    pub reasoning_markers: Option<ReasoningMarkers>,
+    pub assistant_prefix: Option<String>,
 }
 
 impl NormalRequest {
@@ -114,6 +130,9 @@ impl NormalRequest {
             adapters: None,
             logits_processors: None,
             return_raw_logits: false,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: None,
         }
     }
 }
@@ -137,6 +156,32 @@ pub struct DetokenizationRequest {
     pub response: Sender<anyhow::Result<String>>,
 }
 
+#[derive(Clone)]
+/// Request to classify a prompt, given as text or as tokens.
+/// - The last-layer hidden state of the last prompt token is returned, or the logits of `head`
+///   applied to it if a head is provided.
+pub struct ClassificationRequest {
+    pub prompt: Either<Vec<u32>, String>,
+    pub head: Option<Arc<ClassificationHead>>,
+    pub response: Sender<anyhow::Result<Vec<f32>>>,
+}
+
+#[derive(Clone)]
+/// Request for the attention probabilities of a prompt, given as text or as tokens.
+/// - The probabilities of every layer are returned, of shape `(layers, heads, seq_len, seq_len)`.
+pub struct AttentionWeightsRequest {
+    pub prompt: Either<Vec<u32>, String>,
+    pub response: Sender<anyhow::Result<Tensor>>,
+}
+
+#[derive(Clone)]
+/// Request to save the weights of the model to the safetensors file `path`, along with its
+/// configuration and tokenizer.
+pub struct SaveSafetensorsRequest {
+    pub path: PathBuf,
+    pub response: Sender<anyhow::Result<()>>,
+}
+
 #[derive(Clone)]
 /// A request to the Engine, encapsulating the various parameters as well as
 /// the `mpsc` response `Sender` used to return the [`Response`].
@@ -146,6 +191,20 @@ pub enum Request {
     ActivateAdapters(Vec<String>),
     Tokenize(TokenizationRequest),
     Detokenize(DetokenizationRequest),
+    Classify(ClassificationRequest),
+    AttentionWeights(AttentionWeightsRequest),
+    SaveSafetensors(SaveSafetensorsRequest),
+    // Change the sampling parameters of the running request with this id for its following decode
+    // steps. Requests which are not running are not affected.
+    UpdateSampling(usize, SamplingUpdate),
+    // Sending a drain request makes the engine reject new requests and finish the in-flight ones.
+    // Those still running at the deadline are canceled, and then `run` returns.
+    Drain(Instant),
+    // Reply with the counters of the prefix cache lookups.
+    PrefixCacheStats(Sender<PrefixCacheStats>),
+    // Reply with the position of the request among the sequences waiting for the concurrent
+    // sequence limit, or `None` if it is not waiting.
+    QueuePosition(usize, Sender<Option<usize>>),
     // Sending a terminate request causes the `run` function to return to the thread created in `MistralRs::new`,
     // and then Engine will be dropped.
     Terminate,
@@ -179,6 +238,21 @@ impl Debug for Request {
             Request::Detokenize(req) => {
                 write!(f, "Tokenization Request {:?}", req.tokens)
             }
+            Request::Classify(req) => {
+                write!(f, "Classification Request {:?}", req.prompt)
+            }
+            Request::AttentionWeights(req) => {
+                write!(f, "Attention Weights Request {:?}", req.prompt)
+            }
+            Request::SaveSafetensors(req) => {
+                write!(f, "Save Safetensors Request {}", req.path.display())
+            }
+            Request::UpdateSampling(id, update) => {
+                write!(f, "Update Sampling Request {id} {update:?}")
+            }
+            Request::Drain(_) => write!(f, "Drain Request"),
+            Request::PrefixCacheStats(_) => write!(f, "Prefix Cache Stats Request"),
+            Request::QueuePosition(id, _) => write!(f, "Queue Position Request {id}"),
             Request::Terminate => write!(f, "Termination Request"),
         }
     }
@@ -8,7 +8,10 @@ use candle_core::Tensor;
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::{sampler::TopLogprob, tools::ToolCallResponse};
+use crate::{
+    sampler::TopLogprob,
+    tools::{ToolCallDelta, ToolCallResponse},
+};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -30,6 +33,9 @@ macro_rules! generate_repr {
 /// Chat completion response message.
 pub struct ResponseMessage {
     pub content: Option<String>,
+    /// The reasoning block, if the request asked for it to be split from the content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
     pub role: String,
     pub tool_calls: Vec<ToolCallResponse>,
 }
@@ -42,7 +48,12 @@ generate_repr!(ResponseMessage);
 /// Delta in content for streaming response.
 pub struct Delta {
     pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
     pub role: String,
+    /// The tool calls streamed in this chunk, if the request has tools.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
 generate_repr!(Delta);
@@ -154,6 +165,8 @@ pub struct ChatCompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Only present in the final chunk of the stream.
+    pub usage: Option<Usage>,
 }
 
 generate_repr!(ChatCompletionChunkResponse);
@@ -198,6 +211,8 @@ pub struct CompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Only present in the final chunk of the stream.
+    pub usage: Option<Usage>,
 }
 
 generate_repr!(CompletionChunkResponse);
@@ -6,7 +6,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use candle_core::{Device, Error, Result, Tensor, D};
+use candle_core::{DType, Device, Error, Result, Tensor, D};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
@@ -34,6 +34,7 @@ pub struct SamplingParams {
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
     pub min_p: Option<f64>,
+    pub top_a: Option<f64>,
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
@@ -42,19 +43,28 @@ pub struct SamplingParams {
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
     pub dry_params: Option<DrySamplingParams>,
+    pub length_bias: Option<LengthBiasParams>,
+    /// Stop once the brackets left open by the prompt, or opened by the completion, are closed.
+    /// Brackets in string and character literals are ignored.
+    pub stop_on_balanced_brackets: bool,
+    /// Stop once the decoded output reaches this many bytes. The output is cut at a character
+    /// boundary so that it is at most this long.
+    pub max_output_bytes: Option<usize>,
 }
 
 impl SamplingParams {
     /// This sets up the parameters so that there is:
-    /// - No temperature, topk, topp, minp
+    /// - No temperature, topk, topp, minp, topa
     /// - No penalties, stop tokens, or logit bias
-    /// - No maximum length
+    /// - No stopping on balanced brackets
+    /// - No maximum length or output bytes
     pub fn deterministic() -> Self {
         Self {
             temperature: None,
             top_k: None,
             top_p: None,
             min_p: None,
+            top_a: None,
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
@@ -63,6 +73,9 @@ impl SamplingParams {
             logits_bias: None,
             n_choices: 1,
             dry_params: None,
+            length_bias: None,
+            stop_on_balanced_brackets: false,
+            max_output_bytes: None,
         }
     }
 }
@@ -147,6 +160,53 @@ impl DrySamplingParamsInner {
     }
 }
 
+#[derive(Clone, Debug)]
+/// Bias the EOS logits toward finishing around `target_length` generated tokens. The bias is
+/// `strength * (generated - target_length) / target_length`: it discourages EOS before the target
+/// and encourages it after. A negative `strength` biases toward longer outputs instead.
+pub struct LengthBiasParams {
+    pub target_length: usize,
+    pub strength: f32,
+}
+
+/// Logits processor applying a [`LengthBiasParams`] schedule at the current decode position.
+pub(crate) struct EosLengthBias {
+    eos_toks: Vec<u32>,
+    prompt_len: usize,
+    params: LengthBiasParams,
+}
+
+impl EosLengthBias {
+    pub fn new(eos_toks: Vec<u32>, prompt_len: usize, params: LengthBiasParams) -> Result<Self> {
+        if params.target_length == 0 {
+            candle_core::bail!("`target_length` for the length bias must be at least 1.");
+        }
+        Ok(Self {
+            eos_toks,
+            prompt_len,
+            params,
+        })
+    }
+
+    fn bias(&self, generated: usize) -> f32 {
+        let target = self.params.target_length as f32;
+        self.params.strength * (generated as f32 - target) / target
+    }
+}
+
+impl CustomLogitsProcessor for EosLengthBias {
+    fn apply(&self, logits: &Tensor, context: &[u32]) -> Result<Tensor> {
+        let bias = self.bias(context.len().saturating_sub(self.prompt_len));
+        let mut biased: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+        for eos in &self.eos_toks {
+            if let Some(logit) = biased.get_mut(*eos as usize) {
+                *logit += bias;
+            }
+        }
+        Tensor::new(biased, logits.device())?.to_dtype(logits.dtype())
+    }
+}
+
 /// Customizable logits processor.
 ///
 /// # Example
@@ -177,6 +237,16 @@ impl<T: Fn(&Tensor, &[u32]) -> Result<Tensor> + Send + Sync> CustomLogitsProcess
     }
 }
 
+/// Sampling parameters to change for the remaining decode steps of a running request. Parameters
+/// which are `None` are left as they are.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SamplingUpdate {
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub min_p: Option<f64>,
+}
+
 /// Sampler for sampling.
 #[derive(Clone)]
 pub struct Sampler {
@@ -189,6 +259,7 @@ pub struct Sampler {
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    top_a: f64,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
 }
 
@@ -210,10 +281,65 @@ pub struct Logprobs {
     pub top_logprobs: Option<Vec<TopLogprob>>,
 }
 
+/// A candidate for the next token of a prompt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NextTokenCandidate {
+    pub token: u32,
+    pub prob: f32,
+}
+
+/// The `k` most likely next tokens by descending probability, given the raw logits of a prompt as
+/// returned in chunks by a `return_raw_logits` request. Only the logits of the last position are used.
+pub fn next_token_candidates(
+    logits_chunks: &[Tensor],
+    k: usize,
+) -> Result<Vec<NextTokenCandidate>> {
+    let Some(last_chunk) = logits_chunks.last() else {
+        candle_core::bail!("Expected at least one chunk of logits.");
+    };
+    let logits = last_chunk.reshape(((), last_chunk.dim(D::Minus1)?))?;
+    let logits = logits
+        .get(logits.dim(0)? - 1)?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut probs = logits.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+    let sum = probs.iter().sum::<f32>();
+    probs.iter_mut().for_each(|p| *p /= sum);
+
+    let mut indices = (0..probs.len()).collect::<Vec<_>>();
+    indices.sort_by(|a, b| probs[*b].total_cmp(&probs[*a]));
+    Ok(indices
+        .into_iter()
+        .take(k)
+        .map(|i| NextTokenCandidate {
+            token: i as u32,
+            prob: probs[i],
+        })
+        .collect())
+}
+
 fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
     logits.argmax(D::Minus1)
 }
 
+/// Top-a sampling keeps the tokens whose probability is at least `top_a * p_max^2`, so it filters
+/// more aggressively when the model is confident. The most likely token is always kept. `argsort_indices`
+/// must be sorted by descending probability.
+fn apply_top_a(probs: &mut [f32], argsort_indices: &[usize], top_a: f32) {
+    if top_a <= 0.0 {
+        return;
+    }
+    let max_p = probs[argsort_indices[0]];
+    let threshold = top_a * max_p * max_p;
+    for index in argsort_indices.iter().skip(1) {
+        if probs[*index] < threshold {
+            probs[*index] = 0.0;
+        }
+    }
+}
+
 impl Sampler {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -226,6 +352,7 @@ impl Sampler {
         top_k: i64,
         top_p: f64,
         min_p: f64,
+        top_a: f64,
         logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     ) -> anyhow::Result<Self> {
         let temperature = if temperature.map_or(true, |v| v < 1e-7) {
@@ -252,10 +379,29 @@ impl Sampler {
             top_k,
             top_p,
             min_p,
+            top_a,
             logits_processors,
         })
     }
 
+    /// A copy of this sampler with the parameters set in `update` changed.
+    pub fn with_update(&self, update: &SamplingUpdate) -> Self {
+        let mut sampler = self.clone();
+        if let Some(temperature) = update.temperature {
+            sampler.temperature = (temperature >= 1e-7).then_some(temperature);
+        }
+        if let Some(top_k) = update.top_k {
+            sampler.top_k = top_k as i64;
+        }
+        if let Some(top_p) = update.top_p {
+            sampler.top_p = top_p;
+        }
+        if let Some(min_p) = update.min_p {
+            sampler.min_p = min_p;
+        }
+        sampler
+    }
+
     fn get_top_logprobs(
         &self,
         probs: &[f32],
@@ -345,6 +491,7 @@ impl Sampler {
         top_k: i64,
         top_p: f32,
         min_p: f32,
+        top_a: f32,
     ) -> Result<Logprobs> {
         let mut probs: Vec<f32> = logits.to_vec1()?;
         let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
@@ -362,6 +509,9 @@ impl Sampler {
             }
         }
 
+        // TOP A
+        apply_top_a(&mut probs, &argsort_indices, top_a);
+
         // TOP P
 
         // top-p sampling (or "nucleus sampling") samples from the smallest set of
@@ -465,6 +615,7 @@ impl Sampler {
         top_k: i64,
         top_p: f32,
         min_p: f32,
+        top_a: f32,
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
     ) -> Result<Logprobs> {
@@ -482,6 +633,9 @@ impl Sampler {
             }
         }
 
+        // TOP A
+        apply_top_a(probs, &argsort_indices, top_a);
+
         if top_p <= 0.0 || top_p >= 1.0 {
             return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
         }
@@ -658,6 +812,7 @@ impl Sampler {
                     self.top_k,
                     self.top_p as f32,
                     self.min_p as f32,
+                    self.top_a as f32,
                 )?,
                 Some(temperature) => {
                     let logits = (&logits / temperature)?;
@@ -669,6 +824,7 @@ impl Sampler {
                         self.top_k,
                         self.top_p as f32,
                         self.min_p as f32,
+                        self.top_a as f32,
                     )?
                 }
             }
@@ -685,6 +841,7 @@ impl Sampler {
                         self.top_k,
                         self.top_p as f32,
                         self.min_p as f32,
+                        self.top_a as f32,
                         return_logprobs,
                         rng,
                     )?
@@ -706,7 +863,7 @@ mod tests {
         use std::sync::Mutex;
 
         let sampler =
-            Sampler::new(None, 10, None, None, None, None, 32, 0.1, 0.05, vec![]).unwrap();
+            Sampler::new(None, 10, None, None, None, None, 32, 0.1, 0.05, 0.0, vec![]).unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
@@ -717,6 +874,90 @@ mod tests {
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
 
+    #[test]
+    fn test_sampling_update() {
+        use super::{Sampler, SamplingUpdate};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sample_n = |sampler: &Sampler| {
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+            (0..20)
+                .map(|_| {
+                    let logits = Tensor::arange(0f32, 16f32, &Device::Cpu).unwrap();
+                    sampler
+                        .sample(logits, &[], false, rng.clone(), false)
+                        .unwrap()
+                        .token
+                })
+                .collect::<Vec<_>>()
+        };
+        // A low temperature always picks the most likely token
+        let sampler = Sampler::new(
+            Some(0.01),
+            0,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            0.0,
+            vec![],
+        )
+        .unwrap();
+        assert!(sample_n(&sampler).iter().all(|tok| *tok == 15));
+
+        // With the same seed, raising the temperature changes the tokens
+        let updated = sampler.with_update(&SamplingUpdate {
+            temperature: Some(100.0),
+            ..Default::default()
+        });
+        assert!(sample_n(&updated).iter().any(|tok| *tok != 15));
+
+        // A temperature of 0 switches to greedy sampling
+        let greedy = updated.with_update(&SamplingUpdate {
+            temperature: Some(0.0),
+            ..Default::default()
+        });
+        assert!(sample_n(&greedy).iter().all(|tok| *tok == 15));
+    }
+
+    #[test]
+    fn test_next_token_candidates() {
+        use super::next_token_candidates;
+        use candle_core::{Device, Tensor};
+
+        // Two prompt chunks, only the last position of the last chunk is used
+        let first = Tensor::zeros((2, 5), candle_core::DType::F32, &Device::Cpu).unwrap();
+        let last = Tensor::new(
+            &[[9f32, 9., 9., 9., 9.], [1., 3., 0., 2., -1.]],
+            &Device::Cpu,
+        )
+        .unwrap();
+        let candidates = next_token_candidates(&[first, last.clone()], 3).unwrap();
+        assert_eq!(
+            candidates.iter().map(|c| c.token).collect::<Vec<_>>(),
+            vec![1, 3, 0]
+        );
+        assert!(candidates.windows(2).all(|w| w[0].prob >= w[1].prob));
+        let expected = 1.
+            / [1f32, 3., 0., 2., -1.]
+                .map(|x| (x - 3.).exp())
+                .iter()
+                .sum::<f32>();
+        assert!((candidates[0].prob - expected).abs() < 1e-6);
+
+        // All of the candidates together sum to 1
+        let all = next_token_candidates(&[last], 10).unwrap();
+        assert_eq!(all.len(), 5);
+        assert!((all.iter().map(|c| c.prob).sum::<f32>() - 1.).abs() < 1e-5);
+    }
+
     #[test]
     fn test_gumbel_speculative() {
         use super::Sampler;
@@ -727,7 +968,7 @@ mod tests {
         use std::sync::Mutex;
 
         let sampler =
-            Sampler::new(None, 10, None, None, None, None, 32, 0.1, 0.05, vec![]).unwrap();
+            Sampler::new(None, 10, None, None, None, None, 32, 0.1, 0.05, 0.0, vec![]).unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
@@ -737,4 +978,92 @@ mod tests {
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_top_a() {
+        use super::apply_top_a;
+
+        let survivors = |top_a: f32| {
+            let mut probs = vec![0.15, 0.5, 0.05, 0.3];
+            apply_top_a(&mut probs, &[1, 3, 0, 2], top_a);
+            probs
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| **p > 0.0)
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+
+        // Threshold of 1 * 0.5^2 = 0.25
+        assert_eq!(survivors(1.0), vec![1, 3]);
+        // Threshold of 0.4 * 0.5^2 = 0.1
+        assert_eq!(survivors(0.4), vec![0, 1, 3]);
+        // Disabled
+        assert_eq!(survivors(0.0), vec![0, 1, 2, 3]);
+        // The most likely token is always kept
+        assert_eq!(survivors(100.0), vec![1]);
+    }
+
+    #[test]
+    fn test_length_bias_earlier_eos() {
+        use super::{EosLengthBias, LengthBiasParams, Sampler};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        const EOS: u32 = 0;
+        const MAX_LEN: usize = 64;
+        let prompt = vec![1u32, 2, 3];
+
+        // Mean number of generated tokens up to and including EOS, with uniform logits
+        let mean_length = |strength: f32| {
+            let processor = EosLengthBias::new(
+                vec![EOS],
+                prompt.len(),
+                LengthBiasParams {
+                    target_length: 4,
+                    strength,
+                },
+            )
+            .unwrap();
+            let sampler = Sampler::new(
+                Some(1.0),
+                0,
+                None,
+                None,
+                None,
+                None,
+                -1,
+                1.0,
+                0.0,
+                0.0,
+                vec![Arc::new(processor)],
+            )
+            .unwrap();
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0)));
+            let mut total = 0;
+            for _ in 0..200 {
+                let mut context = prompt.clone();
+                while context.len() - prompt.len() < MAX_LEN {
+                    let logits = Tensor::zeros(32, candle_core::DType::F32, &Device::Cpu).unwrap();
+                    let token = sampler
+                        .sample(logits, &context, false, rng.clone(), false)
+                        .unwrap()
+                        .token;
+                    context.push(token);
+                    if token == EOS {
+                        break;
+                    }
+                }
+                total += context.len() - prompt.len();
+            }
+            total as f32 / 200.
+        };
+
+        let (none, weak, strong) = (mean_length(0.0), mean_length(2.0), mean_length(8.0));
+        assert!(none > weak, "{none} {weak}");
+        assert!(weak > strong, "{weak} {strong}");
+    }
 }
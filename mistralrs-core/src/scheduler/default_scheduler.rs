@@ -176,6 +176,7 @@ pub struct DefaultScheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: DefaultSchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    cancel_all: bool,
 }
 
 impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
@@ -188,6 +189,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            cancel_all: false,
         }
     }
 
@@ -301,9 +303,16 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
 
 impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn schedule(&mut self) -> SchedulerOutput<'_> {
-        SchedulerOutput::DefaultScheduler {
-            output: self.schedule(),
+        let cancel_all = self.cancel_all;
+        let output = self.schedule();
+        if cancel_all {
+            output
+                .prompt
+                .iter()
+                .chain(output.completion.iter())
+                .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
         }
+        SchedulerOutput::DefaultScheduler { output }
     }
     fn waiting_len(&self) -> usize {
         self.waiting.len()
@@ -319,6 +328,9 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
             self.waiting.add(seq);
         }
     }
+    fn cancel_all(&mut self) {
+        self.cancel_all = true;
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         None
     }
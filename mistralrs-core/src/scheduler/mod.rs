@@ -50,6 +50,9 @@ pub trait Scheduler {
     fn waiting_len(&self) -> usize;
     fn running_len(&self) -> usize;
     fn add_seq(&mut self, seq: Sequence);
+    /// Cancel every sequence from now on as soon as it is scheduled, including the waiting ones.
+    /// Canceled sequences finish with the output generated so far and their KV cache is freed.
+    fn cancel_all(&mut self);
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
 
@@ -9,14 +9,15 @@ use crate::{
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
     pipeline::{DiffusionGenerationParams, KvCache},
     response::CompletionChoice,
-    tools::ToolCallingMatcher,
+    tools::{ToolCallStreamParser, ToolCallingMatcher},
+    utils::{brackets::BracketTracker, logits_dump::LogitsDump, reasoning::ReasoningParser},
     CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, ImageChoice,
     ImageGenerationResponse, ImageGenerationResponseFormat,
 };
 use candle_core::Tensor;
 use std::{
     fmt::Display,
-    sync::{Arc, RwLock},
+    sync::{Arc, RwLock, Weak},
     time::{SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{
@@ -24,6 +25,36 @@ use tokio::sync::{
     Mutex, MutexGuard,
 };
 
+/// Strip a trailing multi-byte sequence which was cut off before it was complete, for example
+/// when generation stops in the middle of a character split over several tokens.
+///
+/// Invalid bytes elsewhere are left as they are.
+pub(crate) fn trim_incomplete_utf8(bytes: &[u8]) -> &[u8] {
+    // A UTF-8 sequence is at most 4 bytes, so only the last lead byte can start an incomplete one.
+    let Some(lead) = bytes
+        .iter()
+        .rposition(|b| b & 0b1100_0000 != 0b1000_0000)
+        .filter(|i| bytes.len() - i < 4)
+    else {
+        return bytes;
+    };
+    match std::str::from_utf8(&bytes[lead..]) {
+        // `error_len` is `None` only if the input ends in an incomplete sequence.
+        Err(e) if e.error_len().is_none() => &bytes[..lead],
+        _ => bytes,
+    }
+}
+
+/// If adding the `new` bytes to the `completion` reaches `max_output_bytes`, the length to cut the
+/// output to. This is at most `max_output_bytes`, and does not end in the middle of a character.
+fn output_bytes_limit_pos(completion: &[u8], new: &[u8], max_output_bytes: usize) -> Option<usize> {
+    if completion.len() + new.len() < max_output_bytes {
+        return None;
+    }
+    let output = [completion, new].concat();
+    Some(trim_incomplete_utf8(&output[..max_output_bytes.min(output.len())]).len())
+}
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum StopReason {
     Eos,
@@ -34,6 +65,14 @@ pub enum StopReason {
         stop_string_idx: usize,
         completion_bytes_pos: usize,
     },
+    /// The brackets left open were all closed, the completion ends after the closing bracket.
+    BalancedBrackets {
+        completion_bytes_pos: usize,
+    },
+    /// The output reached `max_output_bytes`, the completion is cut at a character boundary.
+    OutputBytes {
+        completion_bytes_pos: usize,
+    },
     Canceled,
     GeneratedImage,
 }
@@ -42,8 +81,12 @@ impl Display for StopReason {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             StopReason::Eos => write!(f, "stop"),
-            StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
-            StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
+            StopReason::Length(_) | StopReason::ModelLength(_) | StopReason::OutputBytes { .. } => {
+                write!(f, "length")
+            }
+            StopReason::StopTok(_)
+            | StopReason::StopString { .. }
+            | StopReason::BalancedBrackets { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
             StopReason::GeneratedImage => write!(f, "generated-image"),
         }
@@ -155,7 +198,8 @@ pub struct Sequence {
     prompt_len: usize,
     max_len: Option<usize>,
     timestamp: u128,
-    sampler: Arc<Sampler>,
+    // Shared with the engine, which can swap the sampler while the sequence runs
+    sampler: Arc<RwLock<Arc<Sampler>>>,
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
     return_logprobs: bool,
@@ -179,6 +223,7 @@ pub struct Sequence {
 
     // Prefix caching
     prefill_prompt_toks: Option<Vec<u32>>,
+    prefix_cache_len: usize,
 
     // Adapter dynamic config
     adapters: Option<Vec<String>>,
@@ -220,6 +265,20 @@ pub struct Sequence {
 
     // Tool calls
     pub tools: Option<Arc<ToolCallingMatcher>>,
+    tool_call_stream: Option<ToolCallStreamParser>,
+
+    // Logits export
+    logits_dump: Option<LogitsDump>,
+
+    // Reasoning split
+    reasoning: Option<ReasoningParser>,
+
+    // Prefilled start of the response
+    assistant_prefix: Option<String>,
+
+    // Stop on balanced brackets
+    brackets: Option<BracketTracker>,
+    max_output_bytes: Option<usize>,
 }
 
 impl BlockEngineSequence for Sequence {
@@ -314,7 +373,7 @@ impl Sequence {
             },
             seq_preallocated_cache,
             responder,
-            sampler: sampler.into(),
+            sampler: Arc::new(RwLock::new(Arc::new(sampler))),
             stop_tokens,
             stop_strings,
             max_len,
@@ -327,6 +386,7 @@ impl Sequence {
             creation_time,
             recognizer,
             prefill_prompt_toks: None,
+            prefix_cache_len: 0,
             suffix,
             prefix,
             cumulative_logprob: 0.,
@@ -340,7 +400,13 @@ impl Sequence {
             adapters,
             input_images,
             custom_metadata,
+            tool_call_stream: tools.as_ref().and_then(|tools| tools.stream_parser()),
             tools,
+            logits_dump: None,
+            reasoning: None,
+            assistant_prefix: None,
+            brackets: None,
+            max_output_bytes: None,
             image_gen_response_format,
             sequence_stepping_type,
             diffusion_params,
@@ -369,19 +435,66 @@ impl Sequence {
         (self.scheduling_urgency as f64) + (self.len() as f64).log2()
     }
 
+    /// Start from the cache of a prompt prefix, `toks` are the remaining tokens to prefill.
     pub fn prefill(
         mut self,
         cache: LayerCaches,
         xlora_cache: Option<LayerCaches>,
         toks: Vec<u32>,
+        prefix_len: usize,
     ) -> Self {
         self.cache = cache;
         self.xlora_cache = xlora_cache;
         self.prefill_prompt_toks = Some(toks);
+        self.prefix_cache_len = prefix_len;
         self.set_state(SequenceState::RunningPrefillPrompt);
         self
     }
 
+    /// Like [`Sequence::prefill`], for models with a normal cache. The cache of every layer is
+    /// rebuilt from the empty `template`, which has the backend of the model.
+    pub fn prefill_normal(
+        mut self,
+        cache: LayerCaches,
+        toks: Vec<u32>,
+        prefix_len: usize,
+        template: &KvCache,
+    ) -> candle_core::Result<Self> {
+        for (layer, src) in self.normal_cache.iter_mut().zip(cache) {
+            *layer = match src {
+                Some((k, v)) => {
+                    let mut kv = template.clone();
+                    kv.append(&k, &v)?;
+                    Some(kv)
+                }
+                None => None,
+            };
+        }
+        self.prefill_prompt_toks = Some(toks);
+        self.prefix_cache_len = prefix_len;
+        self.set_state(SequenceState::RunningPrefillPrompt);
+        Ok(self)
+    }
+
+    /// The number of prompt tokens covered by a prefix cache which are not run again.
+    pub fn prefix_cache_len(&self) -> usize {
+        self.prefix_cache_len
+    }
+
+    /// Drop the prefix cache so that the whole prompt is run.
+    pub fn reset_prefix_cache(&mut self) {
+        if self.prefix_cache_len == 0 {
+            return;
+        }
+        self.prefill_prompt_toks = None;
+        self.prefix_cache_len = 0;
+        self.cache.iter_mut().for_each(|layer| *layer = None);
+        self.normal_cache.iter_mut().for_each(|layer| *layer = None);
+        if let Some(xlora_cache) = self.xlora_cache.as_mut() {
+            xlora_cache.iter_mut().for_each(|layer| *layer = None);
+        }
+    }
+
     /// This is the number of tokens. If the KV cache is Some, then it will use that.
     pub fn len(&self) -> usize {
         if let Some(toks) = &self.prefill_prompt_toks {
@@ -482,6 +595,11 @@ impl Sequence {
         &self.completion_bytes
     }
 
+    /// The completion bytes without a trailing incomplete multi-byte sequence.
+    pub fn complete_completion_bytes(&self) -> &[u8] {
+        trim_incomplete_utf8(&self.completion_bytes)
+    }
+
     pub fn preallocated_cache(&self) -> Option<&Tensor> {
         self.seq_preallocated_cache.as_ref()
     }
@@ -510,8 +628,58 @@ impl Sequence {
         self.xlora_cache.is_some()
     }
 
+    pub(crate) fn set_logits_dump(&mut self, logits_dump: LogitsDump) {
+        self.logits_dump = Some(logits_dump);
+    }
+
+    pub(crate) fn logits_dump(&mut self) -> Option<&mut LogitsDump> {
+        self.logits_dump.as_mut()
+    }
+
+    pub(crate) fn set_reasoning_parser(&mut self, reasoning: ReasoningParser) {
+        self.reasoning = Some(reasoning);
+    }
+
+    pub(crate) fn reasoning_parser(&mut self) -> Option<&mut ReasoningParser> {
+        self.reasoning.as_mut()
+    }
+
+    pub(crate) fn set_assistant_prefix(&mut self, prefix: String) {
+        self.assistant_prefix = Some(prefix);
+    }
+
+    /// The prefilled start of the response, which is returned before the generated content.
+    pub(crate) fn assistant_prefix(&self) -> Option<&str> {
+        self.assistant_prefix.as_deref()
+    }
+
+    /// Take the prefilled start of the response, to stream it with the first delta.
+    pub(crate) fn take_assistant_prefix(&mut self) -> Option<String> {
+        self.assistant_prefix.take()
+    }
+
+    pub(crate) fn tool_call_stream_parser(&mut self) -> Option<&mut ToolCallStreamParser> {
+        self.tool_call_stream.as_mut()
+    }
+
+    pub(crate) fn set_bracket_tracker(&mut self, brackets: BracketTracker) {
+        self.brackets = Some(brackets);
+    }
+
+    pub(crate) fn set_max_output_bytes(&mut self, max_output_bytes: usize) {
+        self.max_output_bytes = Some(max_output_bytes);
+    }
+
     pub fn sampler(&mut self) -> Arc<Sampler> {
-        self.sampler.clone()
+        self.sampler
+            .read()
+            .expect("Sampler lock was poisoned.")
+            .clone()
+    }
+
+    /// A handle to replace the sampler used for the following decode steps.
+    pub(crate) fn sampler_handle(&self) -> Weak<RwLock<Arc<Sampler>>> {
+        Arc::downgrade(&self.sampler)
     }
 
     /// Add a some prefill tokens. Only meant for internal speculative decoding usage.
@@ -556,6 +724,24 @@ impl Sequence {
             // And by not adding it here, we can avoid having to delete these tokens from the output.
             self.completion_bytes.extend_from_slice(&completion_bytes);
             self.last_completion_bytes_len = completion_bytes.len();
+            if let Some(brackets) = &mut self.brackets {
+                brackets.push(&completion_bytes);
+            }
+            if let Some(
+                StopReason::BalancedBrackets {
+                    completion_bytes_pos,
+                }
+                | StopReason::OutputBytes {
+                    completion_bytes_pos,
+                },
+            ) = is_done
+            {
+                // Drop what the token adds after the closing bracket or over the byte limit
+                self.last_completion_bytes_len = self
+                    .last_completion_bytes_len
+                    .saturating_sub(self.completion_bytes.len() - completion_bytes_pos);
+                self.completion_bytes.truncate(*completion_bytes_pos);
+            }
         }
         self.last_logprob = tok.logprob;
         self.last_is_done = *is_done;
@@ -567,6 +753,7 @@ impl Sequence {
         self.tokens.push(tok.token);
         self.logprobs.push(tok);
         self.prefill_prompt_toks = None;
+        self.prefix_cache_len = 0;
     }
 
     pub fn responder(&self) -> Sender<Response> {
@@ -588,9 +775,11 @@ impl Sequence {
         *self.state.read().unwrap()
     }
 
+    /// Whether adding `tok`, which decodes to `completion_bytes`, finishes the sequence.
     pub fn is_done(
         &self,
         tok: u32,
+        completion_bytes: &[u8],
         eos_tok: Option<&[u32]>,
         max_model_len: usize,
     ) -> Option<StopReason> {
@@ -607,6 +796,21 @@ impl Sequence {
             Some(StopReason::Canceled)
         } else if self.stop_tokens.contains(&tok) {
             Some(StopReason::StopTok(tok))
+        } else if let Some(completion_bytes_pos) = self
+            .brackets
+            .clone()
+            .and_then(|mut brackets| brackets.push(completion_bytes))
+        {
+            Some(StopReason::BalancedBrackets {
+                completion_bytes_pos,
+            })
+        } else if let Some(completion_bytes_pos) = self
+            .max_output_bytes
+            .and_then(|max| output_bytes_limit_pos(&self.completion_bytes, completion_bytes, max))
+        {
+            Some(StopReason::OutputBytes {
+                completion_bytes_pos,
+            })
         } else if self.max_len.is_some()
             && self.tokens.len().saturating_sub(self.prompt_len) == self.max_len.unwrap()
         {
@@ -646,14 +850,21 @@ impl Sequence {
         &self.stop_strings
     }
 
-    /// Returns the delta between the last two decoded sequences
+    /// Returns the delta between the last two decoded sequences. If this is the final delta,
+    /// a trailing incomplete multi-byte sequence is dropped instead of being held back.
     pub fn get_delta(
         &mut self,
+        is_final: bool,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let is_first = self.stream_idx == 0;
-        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..]);
+        let new_bytes = &self.completion_bytes[self.stream_idx..];
+        let new_decoded = if is_final {
+            String::from_utf8_lossy(trim_incomplete_utf8(new_bytes))
+        } else {
+            String::from_utf8_lossy(new_bytes)
+        };
         // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
-        if new_decoded.ends_with('�') {
+        if !is_final && new_decoded.ends_with('�') {
             return Ok(None);
         }
         self.stream_idx = self.completion_bytes.len();
@@ -675,7 +886,9 @@ impl Sequence {
         self.prompt_timestamp
     }
 
-    fn update_time_info(&self) {
+    /// Add the timing and token counts of this sequence to the group's usage. This is done once,
+    /// when the sequence is finished.
+    pub(crate) fn update_time_info(&self) {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time travel has occurred!")
@@ -688,8 +901,10 @@ impl Sequence {
 
         get_mut_group!(self).total_time += now - self.timestamp;
 
+        // Count the actual tokens rather than the KV cache length, which is not accurate if the
+        // prompt was prefilled from the prefix cache.
         get_mut_group!(self).total_prompt_toks += self.prompt_len;
-        get_mut_group!(self).total_toks += self.len();
+        get_mut_group!(self).total_toks += self.tokens.len();
     }
 
     pub fn add_image_choice_to_group(&self, choice: ImageChoice) {
@@ -909,6 +1124,10 @@ impl SequenceGroup {
 
             std::mem::swap(&mut swap_streaming_chunks, &mut self.chat_streaming_chunks);
 
+            let usage = swap_streaming_chunks
+                .iter()
+                .all(|x| x.finish_reason.is_some())
+                .then(|| self.get_usage());
             seq.responder()
                 .send(Response::Chunk(ChatCompletionChunkResponse {
                     id: seq.id.to_string(),
@@ -917,6 +1136,7 @@ impl SequenceGroup {
                     model: model.clone(),
                     system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                     object: "chat.completion.chunk".to_string(),
+                    usage,
                 }))
                 .await?;
         } else if self.completion_streaming_chunks.len() == self.n_choices && self.is_streaming {
@@ -927,6 +1147,10 @@ impl SequenceGroup {
                 &mut self.completion_streaming_chunks,
             );
 
+            let usage = swap_streaming_chunks
+                .iter()
+                .all(|x| x.finish_reason.is_some())
+                .then(|| self.get_usage());
             seq.responder()
                 .send(Response::CompletionChunk(CompletionChunkResponse {
                     id: seq.id.to_string(),
@@ -935,6 +1159,7 @@ impl SequenceGroup {
                     model: model.clone(),
                     system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                     object: "text_completion".to_string(),
+                    usage,
                 }))
                 .await?;
         }
@@ -952,3 +1177,183 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{Device, Tensor};
+    use tokio::sync::Mutex;
+
+    use super::{
+        output_bytes_limit_pos, trim_incomplete_utf8, SeqStepType, Sequence, SequenceGroup,
+        SequenceRecognizer,
+    };
+    use crate::sampler::{Logprobs, Sampler};
+
+    /// A waiting sequence of a one layer model, with no stop conditions.
+    fn sequence(tokens: Vec<u32>, group: Arc<Mutex<SequenceGroup>>) -> Sequence {
+        let (responder, _) = tokio::sync::mpsc::channel(1);
+        let sampler =
+            Sampler::new(None, 0, None, None, None, None, -1, 0.0, 0.0, 0.0, vec![]).unwrap();
+        Sequence::new_waiting(
+            tokens,
+            String::new(),
+            0,
+            0,
+            1,
+            responder,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+            None,
+            false,
+        )
+    }
+
+    #[test]
+    fn test_usage_counts_tokens() -> candle_core::Result<()> {
+        let group = Arc::new(Mutex::new(SequenceGroup::new(1, false, true, None)));
+        let mut seq = sequence(vec![1, 2, 3, 4, 5], group.clone());
+        for token in [6, 7, 8] {
+            let logprobs = Logprobs {
+                token,
+                logprob: 0.,
+                bytes: None,
+                top_logprobs: None,
+            };
+            seq.add_token(logprobs, vec![b'a'], &None);
+        }
+        // A sliding window cache holds fewer positions than the sequence has tokens
+        let kv = Tensor::zeros((1, 1, 4, 2), candle_core::DType::F32, &Device::Cpu)?;
+        seq.cache[0] = Some((kv.clone(), kv));
+        assert_ne!(seq.len(), 8);
+
+        seq.update_time_info();
+        let usage = group.blocking_lock().get_usage();
+        assert_eq!(usage.prompt_tokens, 5);
+        assert_eq!(usage.completion_tokens, 3);
+        assert_eq!(usage.total_tokens, 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sampler_handle_replaces_sampler() {
+        let group = Arc::new(Mutex::new(SequenceGroup::new(1, false, true, None)));
+        let mut seq = sequence(vec![1, 2, 3], group);
+        let handle = seq.sampler_handle();
+        let updated = Arc::new(
+            Sampler::new(
+                Some(5.0),
+                0,
+                None,
+                None,
+                None,
+                None,
+                -1,
+                1.0,
+                0.0,
+                0.0,
+                vec![],
+            )
+            .unwrap(),
+        );
+        *handle
+            .upgrade()
+            .expect("Expected a running sequence")
+            .write()
+            .unwrap() = updated.clone();
+        assert!(Arc::ptr_eq(&seq.sampler(), &updated));
+
+        // The handle does not keep a finished sequence's sampler alive
+        drop(seq);
+        assert!(handle.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_trim_incomplete_utf8() {
+        // "你好" is two 3 byte characters, stop after the first byte of the second one.
+        let full = "Hi 你好".as_bytes();
+        let cut = &full[..full.len() - 2];
+        assert_eq!(trim_incomplete_utf8(cut), "Hi 你".as_bytes());
+        assert_eq!(String::from_utf8_lossy(trim_incomplete_utf8(cut)), "Hi 你");
+
+        // Complete input is unchanged
+        assert_eq!(trim_incomplete_utf8(full), full);
+
+        // A 4 byte emoji cut after 3 bytes
+        let emoji = "ok 🦀".as_bytes();
+        assert_eq!(trim_incomplete_utf8(&emoji[..emoji.len() - 1]), b"ok ");
+
+        // Invalid bytes in the middle are kept, only the incomplete tail is removed
+        let mut bytes = b"a\xffb ".to_vec();
+        bytes.extend_from_slice(&cut[cut.len() - 1..]);
+        assert_eq!(trim_incomplete_utf8(&bytes), b"a\xffb ");
+        assert_eq!(
+            String::from_utf8_lossy(trim_incomplete_utf8(&bytes)),
+            "a\u{fffd}b "
+        );
+    }
+
+    #[test]
+    fn test_output_bytes_limit() {
+        // CJK characters are 3 bytes each, so few tokens reach the limit
+        let completion = "東京は".as_bytes();
+        assert_eq!(
+            output_bytes_limit_pos(completion, "日".as_bytes(), 16),
+            None
+        );
+        // The limit falls inside of the new character, which is dropped
+        let pos = output_bytes_limit_pos(completion, "日本".as_bytes(), 10).unwrap();
+        assert_eq!(pos, 9);
+        // Reaching the limit exactly keeps everything
+        assert_eq!(
+            output_bytes_limit_pos(completion, "日".as_bytes(), 12),
+            Some(12)
+        );
+        // A character split over tokens is not cut in half
+        assert_eq!(
+            output_bytes_limit_pos(completion, &"日".as_bytes()[..2], 11),
+            Some(9)
+        );
+    }
+
+    #[test]
+    fn test_output_bytes_limit_straddling_character() {
+        // The limit of 8 bytes falls on the second byte of the 3 byte "都"
+        let completion = "東京".as_bytes();
+        assert_eq!(
+            output_bytes_limit_pos(completion, "都".as_bytes(), 8),
+            Some(6)
+        );
+        // Also when the completion already ends in the first byte of it
+        let tokyo_to = "東京都".as_bytes();
+        assert_eq!(
+            output_bytes_limit_pos(&tokyo_to[..7], &tokyo_to[7..], 8),
+            Some(6)
+        );
+        // A mix of 1, 3 and 4 byte characters, with the limit inside the emoji
+        let completion = "a東🦀".as_bytes();
+        assert_eq!(
+            output_bytes_limit_pos(&completion[..5], &completion[5..], 6),
+            Some(4)
+        );
+        assert_eq!(String::from_utf8_lossy(&completion[..4]), "a東");
+    }
+}
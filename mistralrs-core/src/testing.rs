@@ -0,0 +1,326 @@
+//! A tiny Llama model for the tests of this crate and of the crates depending on it. The model is
+//! written to a directory so that it is loaded like any local model. This is only built for tests
+//! and with the `testing` feature.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use candle_core::{DType, Device, Tensor};
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use crate::{
+    DeviceMapMetadata, IsqOrganization, KvCacheBackendType, Loader, ModelDType,
+    NormalLoaderBuilder, NormalSpecificConfig, Pipeline, TokenSource,
+};
+
+pub const TINY_LLAMA_HIDDEN_SIZE: usize = 16;
+pub const TINY_LLAMA_LAYERS: usize = 2;
+const INTERMEDIATE_SIZE: usize = 32;
+const NUM_ATTENTION_HEADS: usize = 4;
+const NUM_KEY_VALUE_HEADS: usize = 2;
+
+/// The special tokens follow the 256 byte tokens.
+const SPECIAL_TOKENS: &[&str] = &["<|im_start|>", "<|im_end|>"];
+/// Multi-byte tokens after the special tokens, which the model can generate but the tokenizer
+/// has no merges for.
+const WORD_TOKENS: &[&str] = &["東", "京"];
+const VOCAB_SIZE: usize = 256 + SPECIAL_TOKENS.len() + WORD_TOKENS.len();
+
+const CHATML_TEMPLATE: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>' + '\\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}";
+
+/// A directory for the files of one test, which is removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("mistralrs_{name}_{}", std::process::id())))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The characters of the bytes in a byte-level BPE vocabulary, indexed by byte.
+fn byte_chars() -> Vec<char> {
+    let printable = |b: u32| (33..=126).contains(&b) || (161..=172).contains(&b) || b >= 174;
+    let mut unprintable = 0;
+    (0..256u32)
+        .map(|b| {
+            let c = if printable(b) {
+                b
+            } else {
+                unprintable += 1;
+                255 + unprintable
+            };
+            char::from_u32(c).expect("Byte characters are valid")
+        })
+        .collect()
+}
+
+/// The id of a special token, of a word token or of a single byte character.
+fn token_id(token: &str) -> usize {
+    if let Some(pos) = SPECIAL_TOKENS.iter().position(|t| *t == token) {
+        return 256 + pos;
+    }
+    if let Some(pos) = WORD_TOKENS.iter().position(|t| *t == token) {
+        return 256 + SPECIAL_TOKENS.len() + pos;
+    }
+    match token.as_bytes() {
+        [b] => usize::from(*b),
+        _ => panic!("`{token}` is not a special token, a word token or a single byte."),
+    }
+}
+
+fn tokenizer_json() -> serde_json::Value {
+    let byte_chars = byte_chars();
+    let words = WORD_TOKENS.iter().map(|word| {
+        let chars = word.bytes().map(|b| byte_chars[usize::from(b)]);
+        (chars.collect::<String>(), token_id(word))
+    });
+    let vocab = byte_chars
+        .iter()
+        .enumerate()
+        .map(|(id, c)| (c.to_string(), id))
+        .chain(words)
+        .collect::<HashMap<_, _>>();
+    let added_tokens = SPECIAL_TOKENS
+        .iter()
+        .map(|token| {
+            json!({
+                "id": token_id(token),
+                "content": token,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": {
+            "type": "ByteLevel",
+            "add_prefix_space": false,
+            "trim_offsets": true,
+            "use_regex": true,
+        },
+        "post_processor": null,
+        "decoder": {
+            "type": "ByteLevel",
+            "add_prefix_space": false,
+            "trim_offsets": true,
+            "use_regex": true,
+        },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "vocab": vocab,
+            "merges": [],
+        },
+    })
+}
+
+/// The byte-level tokenizer of the tiny model, without writing the model.
+pub fn tiny_llama_tokenizer() -> anyhow::Result<tokenizers::Tokenizer> {
+    tokenizers::Tokenizer::from_bytes(tokenizer_json().to_string()).map_err(anyhow::Error::msg)
+}
+
+/// Deterministic values in `[-scale, scale)`.
+fn pseudo_random(shape: &[usize], seed: u32, scale: f64) -> anyhow::Result<Tensor> {
+    let n = u32::try_from(shape.iter().product::<usize>())?;
+    let values = (0..n)
+        .map(|i| {
+            let x = (f64::from(i) * 12.9898 + f64::from(seed) * 78.233).sin() * 43758.5453;
+            (x - x.floor() - 0.5) * 2. * scale
+        })
+        .collect::<Vec<_>>();
+    Ok(Tensor::from_vec(values, shape, &Device::Cpu)?.to_dtype(DType::F32)?)
+}
+
+/// Write a tiny Llama model with a byte-level tokenizer and a ChatML template to `dir`.
+///
+/// The first hidden dimension is constant through the model and acts as the bias of the LM head.
+/// If `preferred` is empty, the other weights are deterministic pseudo-random values and the
+/// special tokens are never generated. Otherwise, the model ignores its input and ranks the
+/// `preferred` tokens first, in order, so that greedy generation repeats the first one allowed.
+pub fn write_tiny_llama(dir: &Path, preferred: &[&str]) -> anyhow::Result<()> {
+    let (h, i, v) = (TINY_LLAMA_HIDDEN_SIZE, INTERMEDIATE_SIZE, VOCAB_SIZE);
+    let kv = h / NUM_ATTENTION_HEADS * NUM_KEY_VALUE_HEADS;
+    let random = preferred.is_empty();
+    let mut seed = 0;
+    let mut weight = |shape: &[usize]| {
+        seed += 1;
+        if random {
+            pseudo_random(shape, seed, 0.3)
+        } else {
+            Ok(Tensor::zeros(shape, DType::F32, &Device::Cpu)?)
+        }
+    };
+    // Zero the first hidden dimension of the outputs added to the residual stream
+    let without_bias_output = |w: Tensor| -> anyhow::Result<Tensor> {
+        let rows = w.dim(0)?;
+        Ok(Tensor::cat(
+            &[
+                &w.narrow(0, 0, 1)?.zeros_like()?,
+                &w.narrow(0, 1, rows - 1)?,
+            ],
+            0,
+        )?)
+    };
+
+    let embed = weight(&[v, h])?;
+    let embed = Tensor::cat(
+        &[
+            &Tensor::ones((v, 1), DType::F32, &Device::Cpu)?,
+            &embed.narrow(1, 1, h - 1)?,
+        ],
+        1,
+    )?;
+    let bias = (0..v)
+        .map(
+            |token| match preferred.iter().position(|t| token_id(t) == token) {
+                Some(rank) => 50. * f64::from(u32::try_from(preferred.len() - rank).unwrap()),
+                None if token >= 256 => -100.,
+                None => 0.,
+            },
+        )
+        .collect::<Vec<_>>();
+    let lm_head = weight(&[v, h])?;
+    let lm_head = Tensor::cat(
+        &[
+            &Tensor::from_vec(bias, (v, 1), &Device::Cpu)?.to_dtype(DType::F32)?,
+            &lm_head.narrow(1, 1, h - 1)?,
+        ],
+        1,
+    )?;
+
+    let mut tensors = HashMap::from([
+        ("model.embed_tokens.weight".to_string(), embed),
+        (
+            "model.norm.weight".to_string(),
+            Tensor::ones(h, DType::F32, &Device::Cpu)?,
+        ),
+        ("lm_head.weight".to_string(), lm_head),
+    ]);
+    for layer in 0..TINY_LLAMA_LAYERS {
+        let p = format!("model.layers.{layer}");
+        tensors.extend([
+            (format!("{p}.self_attn.q_proj.weight"), weight(&[h, h])?),
+            (format!("{p}.self_attn.k_proj.weight"), weight(&[kv, h])?),
+            (format!("{p}.self_attn.v_proj.weight"), weight(&[kv, h])?),
+            (
+                format!("{p}.self_attn.o_proj.weight"),
+                without_bias_output(weight(&[h, h])?)?,
+            ),
+            (format!("{p}.mlp.gate_proj.weight"), weight(&[i, h])?),
+            (format!("{p}.mlp.up_proj.weight"), weight(&[i, h])?),
+            (
+                format!("{p}.mlp.down_proj.weight"),
+                without_bias_output(weight(&[h, i])?)?,
+            ),
+            (
+                format!("{p}.input_layernorm.weight"),
+                Tensor::ones(h, DType::F32, &Device::Cpu)?,
+            ),
+            (
+                format!("{p}.post_attention_layernorm.weight"),
+                Tensor::ones(h, DType::F32, &Device::Cpu)?,
+            ),
+        ]);
+    }
+
+    std::fs::create_dir_all(dir)?;
+    candle_core::safetensors::save(&tensors, dir.join("model.safetensors"))?;
+    let config = json!({
+        "architectures": ["LlamaForCausalLM"],
+        "hidden_size": h,
+        "intermediate_size": i,
+        "vocab_size": v,
+        "num_hidden_layers": TINY_LLAMA_LAYERS,
+        "num_attention_heads": NUM_ATTENTION_HEADS,
+        "num_key_value_heads": NUM_KEY_VALUE_HEADS,
+        "hidden_act": "silu",
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "max_position_embeddings": 1024,
+        "tie_word_embeddings": false,
+    });
+    std::fs::write(dir.join("config.json"), config.to_string())?;
+    std::fs::write(dir.join("tokenizer.json"), tokenizer_json().to_string())?;
+    let tokenizer_config = json!({
+        "chat_template": CHATML_TEMPLATE,
+        "eos_token": "<|im_end|>",
+    });
+    std::fs::write(
+        dir.join("tokenizer_config.json"),
+        tokenizer_config.to_string(),
+    )?;
+    Ok(())
+}
+
+/// Load a model written by [`write_tiny_llama`] on the CPU.
+pub fn load_tiny_llama(dir: &Path) -> anyhow::Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+    load_tiny_llama_with_kv_cache_backend(dir, KvCacheBackendType::default())
+}
+
+/// Like [`load_tiny_llama`], storing the KV cache in `backend`.
+pub fn load_tiny_llama_with_kv_cache_backend(
+    dir: &Path,
+    backend: KvCacheBackendType,
+) -> anyhow::Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+    load_tiny_llama_with(dir, |builder| builder.with_kv_cache_backend(backend))
+}
+
+/// Like [`load_tiny_llama`], with the options set by `configure` on the loader builder.
+pub fn load_tiny_llama_with(
+    dir: &Path,
+    configure: impl FnOnce(NormalLoaderBuilder) -> NormalLoaderBuilder,
+) -> anyhow::Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+    let builder = NormalLoaderBuilder::new(
+        NormalSpecificConfig {
+            use_flash_attn: false,
+            prompt_batchsize: None,
+            topology: None,
+            organization: IsqOrganization::Default,
+            write_uqff: None,
+            from_uqff: None,
+            imatrix: None,
+            calibration_file: None,
+        },
+        None,
+        None,
+        Some(dir.display().to_string()),
+    );
+    let loader = configure(builder).build(None)?;
+    loader.load_model_from_hf(
+        None,
+        TokenSource::None,
+        &ModelDType::F32,
+        &Device::Cpu,
+        true,
+        DeviceMapMetadata::dummy(),
+        None,
+        None,
+    )
+}
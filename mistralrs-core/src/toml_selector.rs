@@ -1,13 +1,13 @@
-use std::{fs::File, num::NonZeroUsize, path::PathBuf};
+use std::{collections::HashMap, fs::File, num::NonZeroUsize, path::PathBuf};
 
 use serde::Deserialize;
 
 use crate::{
     amoe::AnyMoeConfig, pipeline::IsqOrganization, AnyMoeLoader, GGMLLoaderBuilder,
     GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, ModelDType,
-    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
-    SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
-    GGUF_MULTI_FILE_DELIMITER,
+    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SelfSpeculativeConfig,
+    SelfSpeculativeLoader, SpeculativeConfig, SpeculativeLoader, Topology, VisionLoaderBuilder,
+    VisionLoaderType, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
 };
 
 fn default_one() -> usize {
@@ -56,6 +56,11 @@ pub enum TomlModelSelected {
         /// Generate and utilize an imatrix to enhance GGUF quantizations.
         /// Incompatible with `--imatrix/-i`
         calibration_file: Option<PathBuf>,
+
+        /// Extra special tokens to register in the tokenizer, mapped to their ids. The ids must be
+        /// below the vocab size of the model.
+        #[serde(default)]
+        added_tokens: HashMap<String, u32>,
     },
 
     /// Select an X-LoRA architecture
@@ -308,6 +313,15 @@ pub struct SpeculativeTomlModelSelected {
     draft_model: TomlModelSelected,
 }
 
+#[derive(Deserialize)]
+pub struct SelfSpeculativeTomlModelSelected {
+    /// Gamma value for the model
+    gamma: usize,
+
+    /// Number of layers of the model to draft with
+    draft_layers: usize,
+}
+
 #[derive(Deserialize)]
 pub struct AnyMoeTomlModelSelected {
     /// Config
@@ -341,6 +355,9 @@ pub struct TomlSelector {
     /// Speculative model selector
     speculative: Option<SpeculativeTomlModelSelected>,
 
+    /// Self-speculative decoding, drafting with the first layers of the model
+    self_speculative: Option<SelfSpeculativeTomlModelSelected>,
+
     /// AnyMoE config
     anymoe: Option<AnyMoeTomlModelSelected>,
 }
@@ -392,6 +409,7 @@ fn loader_from_selected(
             from_uqff,
             imatrix,
             calibration_file,
+            added_tokens,
         } => NormalLoaderBuilder::new(
             NormalSpecificConfig {
                 use_flash_attn,
@@ -407,6 +425,7 @@ fn loader_from_selected(
             args.tokenizer_json,
             Some(model_id),
         )
+        .with_added_tokens(added_tokens.into_iter().collect())
         .build(arch)?,
         TomlModelSelected::XLora {
             model_id,
@@ -683,6 +702,17 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
         } else {
             loader
         };
+        let loader = if let Some(self_speculative) = selector.self_speculative {
+            Box::new(SelfSpeculativeLoader {
+                target: loader,
+                config: SelfSpeculativeConfig {
+                    draft_layers: self_speculative.draft_layers,
+                    gamma: self_speculative.gamma,
+                },
+            })
+        } else {
+            loader
+        };
         let loader = if let Some(AnyMoeTomlModelSelected {
             config,
             dataset_json,
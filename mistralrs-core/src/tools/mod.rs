@@ -1,10 +1,12 @@
 mod request;
 mod response;
+mod stream;
 
 pub use request::*;
 pub use response::*;
 use serde_json::Value;
 use std::collections::HashMap;
+pub(crate) use stream::ToolCallStreamParser;
 use uuid::Uuid;
 
 pub struct ToolCallingMatcher {
@@ -30,6 +32,11 @@ impl ToolCallingMatcher {
         Ok(Self { tool_choice })
     }
 
+    /// A parser to stream the tool calls, unless tools are disallowed.
+    pub(crate) fn stream_parser(&self) -> Option<ToolCallStreamParser> {
+        (!matches!(self.tool_choice, ToolChoice::None)).then(ToolCallStreamParser::new)
+    }
+
     pub fn get_call(&self, message: &str) -> anyhow::Result<Vec<ToolCallResponse>> {
         if matches!(self.tool_choice, ToolChoice::None) {
             return Ok(Vec::new());
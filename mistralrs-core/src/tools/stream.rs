@@ -0,0 +1,433 @@
+//! Streaming tool calls as they are generated, as OpenAI style tool call deltas.
+
+use uuid::Uuid;
+
+use super::{CalledFunctionDelta, ToolCallDelta, ToolCallType};
+
+/// Keys of the tool call object which hold the arguments.
+const ARGUMENTS_KEYS: &[&str] = &["parameters", "arguments"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamState {
+    /// Only whitespace was generated so far.
+    Undecided,
+    Text,
+    /// The output is a JSON tool call object, or an array of them.
+    Json,
+    /// The tool calls ended, the text following them is content. Only whitespace followed them
+    /// so far, which is dropped.
+    Trailing,
+}
+
+/// The state of the tool call object being generated.
+#[derive(Debug, Default, Clone)]
+struct CallState {
+    key: Option<String>,
+    expect_key: bool,
+    awaiting_value: bool,
+    name: Option<String>,
+    name_sent: bool,
+    args_start: Option<usize>,
+    args_end: Option<usize>,
+    args_sent: usize,
+}
+
+impl CallState {
+    fn in_args(&self) -> bool {
+        self.args_start.is_some() && self.args_end.is_none()
+    }
+}
+
+/// An incremental split of a streamed output into content and tool call deltas.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct ToolCallStreamDelta {
+    pub content: String,
+    pub tool_calls: Vec<ToolCallDelta>,
+}
+
+/// Parses tool calls out of a streamed output as the deltas arrive. Output starting with `{` or `[`
+/// is read as a tool call in the format accepted by [`super::ToolCallingMatcher`]. The function name
+/// of each call is streamed complete, once the call also has the start of its `arguments` or
+/// `parameters` object. The arguments are then streamed as they are generated, such that the
+/// argument deltas of a call concatenate to its JSON arguments. JSON which turns out not to contain
+/// a tool call is returned as content once it ends, and text after the tool calls is content.
+#[derive(Debug, Clone)]
+pub(crate) struct ToolCallStreamParser {
+    state: StreamState,
+    buf: String,
+    pos: usize,
+    depth: usize,
+    // The depth of the tool call objects, 2 inside of an array
+    call_depth: usize,
+    in_string: bool,
+    escaped: bool,
+    str_start: usize,
+    closed: bool,
+    // The end of the JSON in the buffer, once it is closed
+    end: usize,
+    index: usize,
+    found_call: bool,
+    call: CallState,
+}
+
+impl ToolCallStreamParser {
+    pub fn new() -> Self {
+        Self {
+            state: StreamState::Undecided,
+            buf: String::new(),
+            pos: 0,
+            depth: 0,
+            call_depth: 1,
+            in_string: false,
+            escaped: false,
+            str_start: 0,
+            closed: false,
+            end: 0,
+            index: 0,
+            found_call: false,
+            call: CallState::default(),
+        }
+    }
+
+    pub fn push(&mut self, text: &str) -> ToolCallStreamDelta {
+        let mut delta = ToolCallStreamDelta::default();
+        match self.state {
+            StreamState::Text => delta.content.push_str(text),
+            StreamState::Undecided => {
+                self.buf.push_str(text);
+                match self.buf.trim_start().chars().next() {
+                    None => (),
+                    Some(first @ ('{' | '[')) => {
+                        self.state = StreamState::Json;
+                        self.call_depth = if first == '[' { 2 } else { 1 };
+                        self.scan(&mut delta);
+                    }
+                    Some(_) => {
+                        self.state = StreamState::Text;
+                        delta.content = std::mem::take(&mut self.buf);
+                    }
+                }
+            }
+            StreamState::Json => {
+                self.buf.push_str(text);
+                self.scan(&mut delta);
+            }
+            StreamState::Trailing => {
+                self.buf.push_str(text);
+                self.trailing(&mut delta);
+            }
+        }
+        delta
+    }
+
+    /// Flush the output held back at the end of the output.
+    pub fn finish(&mut self) -> ToolCallStreamDelta {
+        let mut delta = ToolCallStreamDelta::default();
+        match self.state {
+            StreamState::Undecided => delta.content = std::mem::take(&mut self.buf),
+            StreamState::Json if !self.found_call => {
+                delta.content = std::mem::take(&mut self.buf);
+            }
+            StreamState::Json | StreamState::Text | StreamState::Trailing => (),
+        }
+        delta
+    }
+
+    /// Return the text after the tool calls as content, once it is more than whitespace.
+    fn trailing(&mut self, delta: &mut ToolCallStreamDelta) {
+        let text = self.buf.trim_start();
+        if !text.is_empty() {
+            delta.content.push_str(text);
+            self.buf.clear();
+            self.state = StreamState::Text;
+        }
+    }
+
+    fn scan(&mut self, delta: &mut ToolCallStreamDelta) {
+        let out = &mut delta.tool_calls;
+        let chars = self.buf[self.pos..]
+            .char_indices()
+            .map(|(i, c)| (self.pos + i, c))
+            .collect::<Vec<_>>();
+        self.pos = self.buf.len();
+        for (i, c) in chars {
+            if self.closed {
+                break;
+            }
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if c == '\\' {
+                    self.escaped = true;
+                } else if c == '"' {
+                    self.in_string = false;
+                    if self.depth == self.call_depth {
+                        self.end_string(i);
+                    }
+                }
+                continue;
+            }
+
+            let at_call = self.depth == self.call_depth;
+            if at_call && self.call.awaiting_value && !c.is_whitespace() {
+                self.call.awaiting_value = false;
+                // The arguments of a tool call are an object
+                if c == '{'
+                    && self
+                        .call
+                        .key
+                        .as_deref()
+                        .is_some_and(|key| ARGUMENTS_KEYS.contains(&key))
+                {
+                    self.call.args_start = Some(i);
+                }
+            }
+            match c {
+                '"' => {
+                    self.in_string = true;
+                    self.str_start = i;
+                }
+                '{' | '[' => {
+                    self.depth += 1;
+                    if self.depth == self.call_depth && c == '{' {
+                        self.call = CallState {
+                            expect_key: true,
+                            ..Default::default()
+                        };
+                    }
+                }
+                '}' | ']' => {
+                    if at_call && self.call.in_args() {
+                        self.call.args_end = Some(i);
+                    } else if self.depth == self.call_depth + 1 && self.call.in_args() {
+                        self.call.args_end = Some(i + 1);
+                    }
+                    self.depth = self.depth.saturating_sub(1);
+                    if at_call {
+                        self.flush_call(i, out);
+                        if self.call.name_sent {
+                            self.index += 1;
+                        }
+                        self.call = CallState::default();
+                    }
+                    self.closed = self.depth == 0;
+                    if self.closed {
+                        self.end = i + c.len_utf8();
+                    }
+                }
+                ',' if at_call => {
+                    if self.call.in_args() {
+                        self.call.args_end = Some(i);
+                    }
+                    self.call.expect_key = true;
+                }
+                ':' if at_call => {
+                    self.call.expect_key = false;
+                    self.call.awaiting_value = true;
+                }
+                _ => (),
+            }
+        }
+        if self.depth >= self.call_depth {
+            self.flush_call(self.buf.len(), out);
+        }
+        if self.closed {
+            if self.found_call {
+                self.buf.drain(..self.end);
+                self.state = StreamState::Trailing;
+                self.trailing(delta);
+            } else {
+                delta.content = std::mem::take(&mut self.buf);
+                self.state = StreamState::Text;
+            }
+        }
+    }
+
+    /// A string directly in the tool call object, a key or a value, ended at `end`.
+    fn end_string(&mut self, end: usize) {
+        let Ok(s) = serde_json::from_str::<String>(&self.buf[self.str_start..=end]) else {
+            return;
+        };
+        if self.call.expect_key {
+            self.call.key = Some(s);
+        } else if self.call.key.as_deref() == Some("name") {
+            self.call.name = Some(s);
+        }
+    }
+
+    /// Emit the name of the current call once both it and the start of the arguments are known,
+    /// and then its arguments up to `upto`.
+    fn flush_call(&mut self, upto: usize, out: &mut Vec<ToolCallDelta>) {
+        if let (Some(name), false, Some(_)) =
+            (&self.call.name, self.call.name_sent, self.call.args_start)
+        {
+            out.push(ToolCallDelta {
+                index: self.index,
+                id: Some(format!("call-{}", Uuid::new_v4())),
+                tp: Some(ToolCallType::Function),
+                function: CalledFunctionDelta {
+                    name: Some(name.clone()),
+                    arguments: String::new(),
+                },
+            });
+            self.call.name_sent = true;
+            self.found_call = true;
+        }
+        let Some(start) = self.call.args_start.filter(|_| self.call.name_sent) else {
+            return;
+        };
+        let from = start.max(self.call.args_sent);
+        let to = self.call.args_end.unwrap_or(upto);
+        if to > from {
+            out.push(ToolCallDelta {
+                index: self.index,
+                id: None,
+                tp: None,
+                function: CalledFunctionDelta {
+                    name: None,
+                    arguments: self.buf[from..to].to_string(),
+                },
+            });
+            self.call.args_sent = to;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::{json, Value};
+
+    use super::{ToolCallStreamDelta, ToolCallStreamParser};
+
+    fn stream(tokens: &[&str]) -> Vec<ToolCallStreamDelta> {
+        let mut parser = ToolCallStreamParser::new();
+        let mut deltas = tokens
+            .iter()
+            .map(|token| parser.push(token))
+            .collect::<Vec<_>>();
+        deltas.push(parser.finish());
+        deltas
+    }
+
+    /// Reconstruct the calls from the deltas, checking that each name comes before its arguments.
+    fn reconstruct(deltas: &[ToolCallStreamDelta]) -> BTreeMap<usize, (String, String)> {
+        let mut calls = BTreeMap::<usize, (String, String)>::new();
+        for call in deltas.iter().flat_map(|d| &d.tool_calls) {
+            match &call.function.name {
+                Some(name) => {
+                    assert!(call.id.is_some());
+                    assert!(calls
+                        .insert(call.index, (name.clone(), call.function.arguments.clone()))
+                        .is_none());
+                }
+                None => calls
+                    .get_mut(&call.index)
+                    .expect("Arguments were streamed before the name.")
+                    .1
+                    .push_str(&call.function.arguments),
+            }
+        }
+        calls
+    }
+
+    #[test]
+    fn test_streamed_tool_call_deltas() {
+        let tokens = [
+            " {\"na",
+            "me\": \"get_",
+            "weather\", \"param",
+            "eters\": {\"city\": \"Par",
+            "is, \\\"FR\\\"\", \"days\"",
+            ": [1, 2]}",
+            "}",
+        ];
+        let deltas = stream(&tokens);
+        assert!(deltas.iter().all(|d| d.content.is_empty()));
+        // The arguments are streamed in several deltas
+        assert!(deltas.iter().filter(|d| !d.tool_calls.is_empty()).count() > 2);
+
+        let calls = reconstruct(&deltas);
+        assert_eq!(calls.len(), 1);
+        let (name, arguments) = &calls[&0];
+        assert_eq!(name, "get_weather");
+        assert_eq!(
+            serde_json::from_str::<Value>(arguments).unwrap(),
+            json!({"city": "Paris, \"FR\"", "days": [1, 2]})
+        );
+    }
+
+    #[test]
+    fn test_streamed_tool_call_array() {
+        // The name of the second call is generated after its arguments
+        let tokens = [
+            "[{\"name\": \"a\", \"arguments\": {\"x\": \"}\"}}, ",
+            "{\"arguments\": {\"y\": null}, ",
+            "\"name\": \"b\"}]",
+        ];
+        let calls = reconstruct(&stream(&tokens));
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[&0].0, "a");
+        assert_eq!(
+            serde_json::from_str::<Value>(&calls[&0].1).unwrap(),
+            json!({"x": "}"})
+        );
+        assert_eq!(calls[&1].0, "b");
+        assert_eq!(
+            serde_json::from_str::<Value>(&calls[&1].1).unwrap(),
+            json!({"y": null})
+        );
+    }
+
+    #[test]
+    fn test_streamed_text_without_tool_call() {
+        let content = |deltas: Vec<ToolCallStreamDelta>| {
+            assert!(deltas.iter().all(|d| d.tool_calls.is_empty()));
+            deltas.into_iter().map(|d| d.content).collect::<String>()
+        };
+        assert_eq!(content(stream(&[" ", "Hello", " there"])), " Hello there");
+        // JSON which is not a tool call is returned once it ends
+        assert_eq!(content(stream(&["{\"a\":", " 1}"])), "{\"a\": 1}");
+    }
+
+    #[test]
+    fn test_streamed_text_after_tool_call() {
+        let tokens = [
+            "{\"name\": \"f\", \"arguments\": {}}",
+            "\n\n",
+            "Calling f",
+            " now.",
+        ];
+        let deltas = stream(&tokens);
+        let calls = reconstruct(&deltas);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[&0], ("f".to_string(), "{}".to_string()));
+        // The whitespace separating the text from the call is dropped
+        let content = deltas
+            .iter()
+            .map(|d| d.content.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(content, ["", "", "Calling f", " now.", ""]);
+    }
+
+    #[test]
+    fn test_name_without_arguments_is_not_a_tool_call() {
+        let tokens = ["{\"name\": \"Alice\", ", "\"age\": 30}", " is a person."];
+        let deltas = stream(&tokens);
+        assert!(deltas.iter().all(|d| d.tool_calls.is_empty()));
+        // Nothing is sent before the JSON ends, as arguments could still follow the name
+        assert_eq!(deltas[0], ToolCallStreamDelta::default());
+        assert_eq!(
+            deltas
+                .iter()
+                .map(|d| d.content.as_str())
+                .collect::<String>(),
+            "{\"name\": \"Alice\", \"age\": 30} is a person."
+        );
+
+        // Arguments which are not an object do not make a tool call either
+        let deltas = stream(&["{\"name\": \"f\", \"arguments\": \"x\"}"]);
+        assert!(deltas.iter().all(|d| d.tool_calls.is_empty()));
+    }
+}
@@ -0,0 +1,204 @@
+//! Tracking the bracket depth of generated code, to stop once the brackets are balanced.
+
+/// The longest escape accepted in a character literal, such as `'\u{10FFFF}'`.
+const MAX_ESCAPE_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScanState {
+    Code,
+    /// Inside a `"` or `` ` `` string literal.
+    Str {
+        quote: u8,
+        escaped: bool,
+    },
+}
+
+enum CharLiteral {
+    Literal(usize),
+    NotLiteral,
+    NeedMore,
+}
+
+fn utf8_len(lead: u8) -> usize {
+    match lead {
+        0xF0.. => 4,
+        0xE0.. => 3,
+        0xC0.. => 2,
+        _ => 1,
+    }
+}
+
+/// Whether `bytes`, starting with a `'`, start a character literal and its length. A `'` which does
+/// not close after one character or an escape, for example a Rust lifetime, is not a literal.
+fn char_literal(bytes: &[u8]) -> CharLiteral {
+    match bytes.get(1) {
+        None => CharLiteral::NeedMore,
+        Some(b'\\') => {
+            // The escaped character itself may be a `'`
+            for (i, b) in bytes.iter().enumerate().skip(3).take(MAX_ESCAPE_LEN) {
+                match b {
+                    b'\'' => return CharLiteral::Literal(i + 1),
+                    b'\n' => return CharLiteral::NotLiteral,
+                    _ => (),
+                }
+            }
+            if bytes.len() < 3 + MAX_ESCAPE_LEN {
+                CharLiteral::NeedMore
+            } else {
+                CharLiteral::NotLiteral
+            }
+        }
+        Some(b'\'' | b'\n') => CharLiteral::NotLiteral,
+        Some(lead) => {
+            let len = utf8_len(*lead);
+            match bytes.get(1 + len) {
+                None => CharLiteral::NeedMore,
+                Some(b'\'') => CharLiteral::Literal(2 + len),
+                Some(_) => CharLiteral::NotLiteral,
+            }
+        }
+    }
+}
+
+fn opener(closer: u8) -> u8 {
+    match closer {
+        b')' => b'(',
+        b']' => b'[',
+        _ => b'{',
+    }
+}
+
+/// Tracks the brackets left open by the prompt and the generated text, ignoring those in string and
+/// character literals. Generation is balanced once a generated closing bracket closes the last open
+/// one. A closing bracket which does not match the innermost open one is ignored.
+///
+/// Single quotes only delimit character literals, so that Rust lifetimes are not mistaken for
+/// literals. Longer single quoted strings are scanned as code.
+#[derive(Debug, Clone)]
+pub(crate) struct BracketTracker {
+    open: Vec<u8>,
+    state: ScanState,
+    // The bytes after a `'` which may not be a complete character literal yet
+    pending: Vec<u8>,
+    consumed: usize,
+}
+
+impl BracketTracker {
+    /// Start from the brackets left open by the prompt.
+    pub fn new(prompt: &str) -> Self {
+        let mut this = Self {
+            open: Vec::new(),
+            state: ScanState::Code,
+            pending: Vec::new(),
+            consumed: 0,
+        };
+        this.push(prompt.as_bytes());
+        // A trailing `'` of the prompt cannot be resolved
+        this.pending.clear();
+        this.consumed = 0;
+        this
+    }
+
+    /// Scan the generated `bytes`. Returns the position in the generated text right after the
+    /// closing bracket which balanced the brackets, if any.
+    pub fn push(&mut self, bytes: &[u8]) -> Option<usize> {
+        self.pending.extend_from_slice(bytes);
+        let mut balanced = None;
+        let mut i = 0;
+        while i < self.pending.len() {
+            let b = self.pending[i];
+            match self.state {
+                ScanState::Str { quote, escaped } => {
+                    self.state = if escaped {
+                        ScanState::Str {
+                            quote,
+                            escaped: false,
+                        }
+                    } else if b == b'\\' {
+                        ScanState::Str {
+                            quote,
+                            escaped: true,
+                        }
+                    } else if b == quote {
+                        ScanState::Code
+                    } else {
+                        self.state
+                    };
+                }
+                ScanState::Code => match b {
+                    b'"' | b'`' => {
+                        self.state = ScanState::Str {
+                            quote: b,
+                            escaped: false,
+                        }
+                    }
+                    b'\'' => match char_literal(&self.pending[i..]) {
+                        CharLiteral::Literal(len) => {
+                            i += len;
+                            continue;
+                        }
+                        CharLiteral::NotLiteral => (),
+                        CharLiteral::NeedMore => break,
+                    },
+                    b'(' | b'[' | b'{' => self.open.push(b),
+                    b')' | b']' | b'}' => {
+                        if self.open.last() == Some(&opener(b)) {
+                            self.open.pop();
+                            if self.open.is_empty() && balanced.is_none() {
+                                balanced = Some(self.consumed + i + 1);
+                            }
+                        }
+                    }
+                    _ => (),
+                },
+            }
+            i += 1;
+        }
+        self.pending.drain(..i);
+        self.consumed += i;
+        balanced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BracketTracker;
+
+    fn balanced_at(prompt: &str, tokens: &[&str]) -> Option<usize> {
+        let mut tracker = BracketTracker::new(prompt);
+        tokens
+            .iter()
+            .find_map(|token| tracker.push(token.as_bytes()))
+    }
+
+    #[test]
+    fn test_prompt_brackets_closed() {
+        let completion = "    let s = \"}\";\n    if c == '}' { s }\n}\nfn other() {}";
+        let pos = balanced_at("fn main() {\n", &[completion]).unwrap();
+        assert_eq!(
+            &completion[..pos],
+            "    let s = \"}\";\n    if c == '}' { s }\n}"
+        );
+    }
+
+    #[test]
+    fn test_balanced_across_tokens() {
+        // Character literals and lifetimes split over token boundaries
+        let tokens = [
+            "    let c = ['\\",
+            "'', b'(' ",
+            "];\n    g(&'a",
+            ")",
+            "\n}",
+            " // done",
+        ];
+        let completion = tokens.concat();
+        let pos = balanced_at("fn f() {", &tokens).unwrap();
+        assert_eq!(
+            &completion[..pos],
+            "    let c = ['\\'', b'(' ];\n    g(&'a)\n}"
+        );
+
+        assert_eq!(balanced_at("", &["let x = 1;", " let y = \"(\";"]), None);
+    }
+}
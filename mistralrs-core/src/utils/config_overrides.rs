@@ -0,0 +1,255 @@
+//! Overrides applied to a model's `config.json` before the model is constructed.
+
+use anyhow::Result;
+use serde_json::{Map, Value};
+use tracing::info;
+
+/// The largest epsilon accepted by the overrides. Norm epsilons are typically 1e-5 or 1e-6, anything
+/// larger than this is almost certainly a mistake.
+const MAX_EPS: f64 = 1e-2;
+
+/// Keys used for the layer norm epsilon by the supported architectures.
+const LAYER_NORM_EPS_KEYS: &[&str] = &["layer_norm_eps", "layer_norm_epsilon", "norm_epsilon"];
+
+/// Overrides for values of the model config. This is an escape hatch for converted checkpoints
+/// which ship with a wrong value, for example an incorrect norm epsilon.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    rms_norm_eps: Option<f64>,
+    layer_norm_eps: Option<f64>,
+    bidirectional: Option<bool>,
+}
+
+fn validate_eps(name: &str, eps: f64) -> Result<f64> {
+    if !eps.is_finite() || eps <= 0. || eps > MAX_EPS {
+        anyhow::bail!("`{name}` override must be positive and at most {MAX_EPS}, got {eps}.");
+    }
+    Ok(eps)
+}
+
+fn set_eps(fields: &mut Map<String, Value>, key: &str, eps: f64) {
+    match fields.insert(key.to_string(), eps.into()) {
+        Some(old) => info!("Overriding `{key}` in the model config: {old} -> {eps}."),
+        None => info!("Setting `{key}` in the model config to {eps}."),
+    }
+}
+
+impl ConfigOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Override `rms_norm_eps`.
+    pub fn with_rms_norm_eps(mut self, eps: f64) -> Result<Self> {
+        self.rms_norm_eps = Some(validate_eps("rms_norm_eps", eps)?);
+        Ok(self)
+    }
+
+    /// Override `layer_norm_eps`, as well as the `layer_norm_epsilon` and `norm_epsilon` aliases
+    /// if the config uses them.
+    pub fn with_layer_norm_eps(mut self, eps: f64) -> Result<Self> {
+        self.layer_norm_eps = Some(validate_eps("layer_norm_eps", eps)?);
+        Ok(self)
+    }
+
+    /// Drop the causal mask so that every position attends to all others, for embedding
+    /// fine-tunes with bidirectional attention. This is only honored by Llama models and is not
+    /// suitable for generation.
+    pub fn with_bidirectional(mut self, bidirectional: bool) -> Self {
+        self.bidirectional = Some(bidirectional);
+        self
+    }
+
+    /// Apply the overrides to the JSON model config.
+    pub(crate) fn apply(&self, config: &str) -> Result<String> {
+        if self.rms_norm_eps.is_none()
+            && self.layer_norm_eps.is_none()
+            && self.bidirectional.is_none()
+        {
+            return Ok(config.to_string());
+        }
+        let mut config: Value = serde_json::from_str(config)?;
+        let Some(fields) = config.as_object_mut() else {
+            anyhow::bail!("Expected the model config to be a JSON object.");
+        };
+
+        if let Some(eps) = self.rms_norm_eps {
+            set_eps(fields, "rms_norm_eps", eps);
+        }
+        if let Some(eps) = self.layer_norm_eps {
+            let present = LAYER_NORM_EPS_KEYS
+                .iter()
+                .filter(|key| fields.contains_key(**key))
+                .copied()
+                .collect::<Vec<_>>();
+            if present.is_empty() {
+                set_eps(fields, LAYER_NORM_EPS_KEYS[0], eps);
+            }
+            for key in present {
+                set_eps(fields, key, eps);
+            }
+        }
+
+        if let Some(bidirectional) = self.bidirectional {
+            info!("Setting `bidirectional` in the model config to {bidirectional}.");
+            fields.insert("bidirectional".to_string(), bidirectional.into());
+        }
+
+        Ok(serde_json::to_string(&config)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::ConfigOverrides;
+    use crate::{
+        paged_attention::AttentionImplementation,
+        pipeline::{
+            text_models_inputs_processor::FlashParams, NormalLoadingMetadata, NormalModelLoader,
+        },
+        DeviceMapMetadata, LlamaLoader,
+    };
+
+    const LLAMA_CONFIG: &str = r#"{
+        "hidden_size": 8,
+        "intermediate_size": 16,
+        "vocab_size": 32,
+        "num_hidden_layers": 1,
+        "num_attention_heads": 2,
+        "num_key_value_heads": 2,
+        "hidden_act": "silu",
+        "rms_norm_eps": 0.5,
+        "rope_theta": 10000.0,
+        "max_position_embeddings": 64
+    }"#;
+
+    #[test]
+    fn test_invalid_eps() {
+        assert!(ConfigOverrides::new().with_rms_norm_eps(0.).is_err());
+        assert!(ConfigOverrides::new().with_rms_norm_eps(-1e-6).is_err());
+        assert!(ConfigOverrides::new().with_layer_norm_eps(1.).is_err());
+        assert!(ConfigOverrides::new()
+            .with_layer_norm_eps(f64::NAN)
+            .is_err());
+    }
+
+    /// The final hidden states of a Llama loaded from `config` whose embeddings are all `x` and
+    /// whose blocks output zeros, so that only the final norm is applied.
+    fn final_norm_output(config: &str, x: f32) -> anyhow::Result<Vec<f32>> {
+        let dev = Device::Cpu;
+        let (h, i, v) = (8, 16, 32);
+        let mut weights = HashMap::from([
+            (
+                "model.embed_tokens.weight".to_string(),
+                Tensor::full(x, (v, h), &dev)?,
+            ),
+            (
+                "model.norm.weight".to_string(),
+                Tensor::ones(h, DType::F32, &dev)?,
+            ),
+            (
+                "lm_head.weight".to_string(),
+                Tensor::zeros((v, h), DType::F32, &dev)?,
+            ),
+        ]);
+        for (name, shape) in [
+            ("self_attn.q_proj.weight", (h, h)),
+            ("self_attn.k_proj.weight", (h, h)),
+            ("self_attn.v_proj.weight", (h, h)),
+            ("self_attn.o_proj.weight", (h, h)),
+            ("mlp.gate_proj.weight", (i, h)),
+            ("mlp.up_proj.weight", (i, h)),
+            ("mlp.down_proj.weight", (h, i)),
+        ] {
+            weights.insert(
+                format!("model.layers.0.{name}"),
+                Tensor::zeros(shape, DType::F32, &dev)?,
+            );
+        }
+        for name in ["input_layernorm.weight", "post_attention_layernorm.weight"] {
+            weights.insert(
+                format!("model.layers.0.{name}"),
+                Tensor::ones(h, DType::F32, &dev)?,
+            );
+        }
+
+        let model = LlamaLoader.load(
+            config,
+            false,
+            VarBuilder::from_tensors(weights, DType::F32, &dev),
+            NormalLoadingMetadata {
+                mapper: DeviceMapMetadata::dummy().into_mapper(1, &dev, None)?,
+                loading_isq: false,
+                real_device: dev.clone(),
+            },
+            AttentionImplementation::Eager,
+        )?;
+        let flash_params = FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: Tensor::zeros(1, DType::U32, &dev)?,
+            cumulative_seqlens_k: Tensor::zeros(1, DType::U32, &dev)?,
+        };
+        Ok(model
+            .forward_hidden(
+                &Tensor::new(&[[1u32, 2]], &dev)?,
+                &[0],
+                Tensor::new(&[0i64], &dev)?,
+                &flash_params,
+            )?
+            .flatten_all()?
+            .to_vec1::<f32>()?)
+    }
+
+    #[test]
+    fn test_eps_override_reaches_norm() -> anyhow::Result<()> {
+        let x = 0.01f32;
+        // The norm of the final layer is x / sqrt(x^2 + eps)
+        let normed = |eps: f64| x as f64 / ((x as f64).powi(2) + eps).sqrt();
+
+        let out = final_norm_output(LLAMA_CONFIG, x)?;
+        assert!(out.iter().all(|y| (*y as f64 - normed(0.5)).abs() < 1e-4));
+
+        let eps = 1e-6;
+        let config = ConfigOverrides::new()
+            .with_rms_norm_eps(eps)?
+            .apply(LLAMA_CONFIG)?;
+        let out = final_norm_output(&config, x)?;
+        assert!(
+            out.iter().all(|y| (*y as f64 - normed(eps)).abs() < 1e-4),
+            "{out:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_norm_eps_aliases() -> anyhow::Result<()> {
+        let overrides = ConfigOverrides::new().with_layer_norm_eps(1e-5)?;
+        let config: serde_json::Value =
+            serde_json::from_str(&overrides.apply(r#"{"norm_epsilon": 0.1}"#)?)?;
+        assert_eq!(config["norm_epsilon"].as_f64(), Some(1e-5));
+        assert!(config.get("layer_norm_eps").is_none());
+
+        let config: serde_json::Value = serde_json::from_str(&overrides.apply("{}")?)?;
+        assert_eq!(config["layer_norm_eps"].as_f64(), Some(1e-5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bidirectional_override() -> anyhow::Result<()> {
+        let config = ConfigOverrides::new()
+            .with_bidirectional(true)
+            .apply(LLAMA_CONFIG)?;
+        let repr = format!("{:?}", LlamaLoader.get_config_repr(&config, false)?);
+        assert!(repr.contains("bidirectional: true"), "{repr}");
+
+        let repr = format!("{:?}", LlamaLoader.get_config_repr(LLAMA_CONFIG, false)?);
+        assert!(repr.contains("bidirectional: false"), "{repr}");
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+//! The context length of a model, extended by the `rope_scaling` of its `config.json`.
+
+use serde_json::Value;
+
+/// The effective context length of the model described by `config`, if its `rope_scaling` is one
+/// of the `rope_scaling_types` applied by the RoPE of the architecture. This is
+/// `original_max_position_embeddings * factor`, which the loader sizes the rotary tables to.
+/// Returns `None` if the context is not extended or the original length is not given, so that
+/// `max_position_embeddings` is used.
+pub(crate) fn effective_context_len(config: &str, rope_scaling_types: &[&str]) -> Option<usize> {
+    let config: Value = serde_json::from_str(config).ok()?;
+    let rope_scaling = config.get("rope_scaling")?;
+    let rope_type = rope_scaling
+        .get("rope_type")
+        .or_else(|| rope_scaling.get("type"))?
+        .as_str()?;
+    if !rope_scaling_types.contains(&rope_type) {
+        return None;
+    }
+    let factor = rope_scaling.get("factor")?.as_f64()?;
+    // `max_position_embeddings` may already be the extended length, so it is not a fallback
+    let original = rope_scaling
+        .get("original_max_position_embeddings")
+        .or_else(|| config.get("original_max_position_embeddings"))?
+        .as_u64()?;
+    if !factor.is_finite() || factor <= 0. {
+        return None;
+    }
+    Some((original as f64 * factor).round() as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::effective_context_len;
+
+    #[test]
+    fn test_effective_context_len() {
+        let linear = r#"{
+            "max_position_embeddings": 4096,
+            "rope_scaling": {"type": "linear", "factor": 4.0, "original_max_position_embeddings": 4096}
+        }"#;
+        assert_eq!(effective_context_len(linear, &["linear"]), Some(4096 * 4));
+        // Not extended if the architecture does not apply the scaling
+        assert_eq!(effective_context_len(linear, &[]), None);
+
+        let yarn = r#"{
+            "max_position_embeddings": 32768,
+            "original_max_position_embeddings": 8192,
+            "rope_scaling": {"rope_type": "yarn", "factor": 4.0}
+        }"#;
+        assert_eq!(effective_context_len(yarn, &["yarn"]), Some(8192 * 4));
+
+        // Without the original length, `max_position_embeddings` may already be extended
+        let extended = r#"{
+            "max_position_embeddings": 131072,
+            "rope_scaling": {"type": "yarn", "factor": 4.0}
+        }"#;
+        assert_eq!(effective_context_len(extended, &["yarn"]), None);
+        assert_eq!(
+            effective_context_len(r#"{"max_position_embeddings": 4096}"#, &["linear"]),
+            None
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Selecting the device to load a model on, with an optional fallback to the CPU.
+
+use std::{fmt::Display, str::FromStr};
+
+use candle_core::Device;
+use tracing::warn;
+
+/// An explicit device selection, parsed from `cpu`, `cuda`, `cuda:N`, `metal` or `metal:N`. The
+/// device index defaults to 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceSpec {
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl FromStr for DeviceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, ordinal) = match s.trim().split_once(':') {
+            Some((kind, ordinal)) => {
+                let ordinal = ordinal
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid device index `{ordinal}` in `{s}`."))?;
+                (kind, Some(ordinal))
+            }
+            None => (s.trim(), None),
+        };
+        match (kind.to_lowercase().as_str(), ordinal) {
+            ("cpu", None) => Ok(Self::Cpu),
+            ("cuda", ordinal) => Ok(Self::Cuda(ordinal.unwrap_or(0))),
+            ("metal", ordinal) => Ok(Self::Metal(ordinal.unwrap_or(0))),
+            _ => Err(format!(
+                "Invalid device `{s}`, expected `cpu`, `cuda`, `cuda:N`, `metal` or `metal:N`."
+            )),
+        }
+    }
+}
+
+impl Display for DeviceSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cpu => write!(f, "cpu"),
+            Self::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            Self::Metal(ordinal) => write!(f, "metal:{ordinal}"),
+        }
+    }
+}
+
+impl DeviceSpec {
+    /// Create the device. This fails if the device does not exist or if support for it was not
+    /// compiled in.
+    pub fn new_device(&self) -> candle_core::Result<Device> {
+        match self {
+            Self::Cpu => Ok(Device::Cpu),
+            Self::Cuda(ordinal) => Device::new_cuda(*ordinal),
+            Self::Metal(ordinal) => Device::new_metal(*ordinal),
+        }
+    }
+}
+
+fn load_with<T>(
+    spec: &DeviceSpec,
+    allow_cpu_fallback: bool,
+    new_device: impl Fn(&DeviceSpec) -> candle_core::Result<Device>,
+    mut load: impl FnMut(&Device) -> anyhow::Result<T>,
+) -> anyhow::Result<(T, Device)> {
+    let res = new_device(spec)
+        .map_err(anyhow::Error::from)
+        .and_then(|device| Ok((load(&device)?, device)));
+    match res {
+        Err(e) if allow_cpu_fallback && *spec != DeviceSpec::Cpu => {
+            warn!("Loading the model on {spec} failed, falling back to the CPU: {e}");
+            Ok((load(&Device::Cpu)?, Device::Cpu))
+        }
+        res => res,
+    }
+}
+
+/// Create the device selected by `spec` and load onto it. If the device is unavailable or loading
+/// fails, for example because it runs out of memory, and `allow_cpu_fallback` is set, this warns
+/// and loads onto the CPU instead. Returns the loaded value and the device it was loaded on.
+pub fn load_on_device<T>(
+    spec: &DeviceSpec,
+    allow_cpu_fallback: bool,
+    load: impl FnMut(&Device) -> anyhow::Result<T>,
+) -> anyhow::Result<(T, Device)> {
+    load_with(spec, allow_cpu_fallback, DeviceSpec::new_device, load)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::{load_on_device, load_with, DeviceSpec};
+
+    #[test]
+    fn test_parse_device_spec() {
+        assert_eq!("cuda:1".parse(), Ok(DeviceSpec::Cuda(1)));
+        assert_eq!("cuda".parse(), Ok(DeviceSpec::Cuda(0)));
+        assert_eq!("Metal:2".parse(), Ok(DeviceSpec::Metal(2)));
+        assert_eq!("cpu".parse(), Ok(DeviceSpec::Cpu));
+        assert_eq!(DeviceSpec::Cuda(1).to_string(), "cuda:1");
+
+        assert!("cuda:x".parse::<DeviceSpec>().is_err());
+        assert!("cpu:0".parse::<DeviceSpec>().is_err());
+        assert!("tpu".parse::<DeviceSpec>().is_err());
+    }
+
+    #[test]
+    fn test_cpu_fallback_on_load_failure() -> anyhow::Result<()> {
+        // Stand in for the GPU with the CPU device, the first load runs out of memory
+        let gpu = |_: &DeviceSpec| -> candle_core::Result<Device> { Ok(Device::Cpu) };
+        let mut attempts = 0;
+        let (loaded, device) = load_with(&DeviceSpec::Cuda(1), true, gpu, |_| {
+            attempts += 1;
+            if attempts == 1 {
+                anyhow::bail!("out of memory")
+            }
+            Ok("loaded")
+        })?;
+        assert_eq!(loaded, "loaded");
+        assert!(device.is_cpu());
+        assert_eq!(attempts, 2);
+
+        // Without the fallback the error is returned
+        let res = load_with(
+            &DeviceSpec::Cuda(1),
+            false,
+            gpu,
+            |_| -> anyhow::Result<()> { anyhow::bail!("out of memory") },
+        );
+        assert!(res.unwrap_err().to_string().contains("out of memory"));
+        Ok(())
+    }
+
+    #[cfg(not(any(feature = "cuda", feature = "metal")))]
+    #[test]
+    fn test_cpu_fallback_on_unavailable_device() -> anyhow::Result<()> {
+        // CUDA support is not compiled in, so creating the device fails
+        assert!(load_on_device(&DeviceSpec::Cuda(1), false, |_| Ok(())).is_err());
+        let ((), device) = load_on_device(&DeviceSpec::Cuda(1), true, |_| Ok(()))?;
+        assert!(device.is_cpu());
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+//! Streaming export of the logits of a generation to a `.npy` file for offline analysis.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, ErrorKind, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use candle_core::{DType, Result, Tensor};
+
+/// Size of the `.npy` preamble (magic, version, header length and header). This leaves ample
+/// space for the shape, which is rewritten in place as rows are appended.
+const NPY_PREAMBLE_LEN: usize = 128;
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Appended to the dump file names so that request ids reused by clients, engine reboots or
+/// several engines sharing a directory never write to the same file.
+static NEXT_DUMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes a `[steps, vocab]` array of f32 logits to a `.npy` file, one row per generation step.
+///
+/// Rows are written to disk as they are added instead of being buffered, and the header is kept
+/// up to date so that the file is a valid array after every step.
+pub(crate) struct LogitsDump {
+    path: PathBuf,
+    file: BufWriter<File>,
+    vocab_size: Option<usize>,
+    steps: usize,
+}
+
+impl LogitsDump {
+    /// Create a dump in `dir` named `<request id>_<choice index>_<n>.npy`, where `n` makes the
+    /// name unique. Existing files are never overwritten.
+    pub fn create_in(dir: &Path, request_id: usize, choice: usize) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        loop {
+            let n = NEXT_DUMP_ID.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("{request_id}_{choice}_{n}.npy"));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Self::new(path, file),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn new(path: PathBuf, file: File) -> Result<Self> {
+        let mut this = Self {
+            file: BufWriter::new(file),
+            path,
+            vocab_size: None,
+            steps: 0,
+        };
+        this.write_header()?;
+        Ok(this)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let dict = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+            self.steps,
+            self.vocab_size.unwrap_or(0)
+        );
+        // Magic (6), version (2) and header length (2) are followed by the header, which is
+        // padded with spaces and terminated by a newline.
+        let header_len = NPY_PREAMBLE_LEN - NPY_MAGIC.len() - 4;
+        if dict.len() + 1 > header_len {
+            candle_core::bail!(
+                "Logits dump header for `{}` is too long.",
+                self.path.display()
+            );
+        }
+        let mut header = dict.into_bytes();
+        header.resize(header_len - 1, b' ');
+        header.push(b'\n');
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(NPY_MAGIC)?;
+        self.file.write_all(&[1, 0])?;
+        self.file.write_all(&(header_len as u16).to_le_bytes())?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Append the logits of one step. The logits must have the same number of elements each step.
+    pub fn append(&mut self, logits: &Tensor) -> Result<()> {
+        let logits = logits
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+        match self.vocab_size {
+            None => self.vocab_size = Some(logits.len()),
+            Some(vocab_size) if vocab_size != logits.len() => {
+                candle_core::bail!(
+                    "Expected {vocab_size} logits for the logits dump, got {}.",
+                    logits.len()
+                )
+            }
+            Some(_) => (),
+        }
+        for x in logits {
+            self.file.write_all(&x.to_le_bytes())?;
+        }
+        self.steps += 1;
+        self.write_header()?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{LogitsDump, NPY_PREAMBLE_LEN};
+
+    #[test]
+    fn test_logits_dump_shape() -> candle_core::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("mistralrs_logits_dump_{}", std::process::id()));
+        let mut dump = LogitsDump::create_in(&dir, 0, 0)?;
+        // The same request and choice never share a file
+        let other = LogitsDump::create_in(&dir, 0, 0)?;
+        assert_ne!(dump.path, other.path);
+        drop(other);
+        let path = dump.path.clone();
+        for step in 0..3 {
+            let logits = Tensor::arange(step as f32, step as f32 + 5., &Device::Cpu)?;
+            dump.append(&logits.reshape((1, 1, 5))?)?;
+        }
+        assert!(dump
+            .append(&Tensor::zeros(4, candle_core::DType::F32, &Device::Cpu)?)
+            .is_err());
+        drop(dump);
+
+        let bytes = std::fs::read(&path)?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(bytes.len(), NPY_PREAMBLE_LEN + 3 * 5 * 4);
+        let header = String::from_utf8_lossy(&bytes[10..NPY_PREAMBLE_LEN]);
+        assert!(header.contains("'shape': (3, 5)"));
+        assert!(header.ends_with('\n'));
+
+        let last = f32::from_le_bytes(bytes[bytes.len() - 4..].try_into().unwrap());
+        assert_eq!(last, 6.);
+        Ok(())
+    }
+}
@@ -1,10 +1,17 @@
+pub(crate) mod brackets;
+pub(crate) mod config_overrides;
+pub(crate) mod context_len;
 pub(crate) mod debug;
+pub(crate) mod device;
 pub(crate) mod gguf_metadata;
 pub(crate) mod log;
+pub(crate) mod logits_dump;
 pub(crate) mod memory_usage;
 pub(crate) mod model_config;
 pub(crate) mod normal;
 pub(crate) mod progress;
+pub(crate) mod reasoning;
+pub(crate) mod tensor_name_map;
 pub(crate) mod tokenizer;
 pub(crate) mod tokens;
 pub(crate) mod unvarbuilder;
@@ -113,6 +120,7 @@ macro_rules! handle_pipeline_forward_error {
                             index: seq.get_response_index(),
                             message: ResponseMessage {
                                 content: Some(res),
+                                reasoning_content: None,
                                 role: "assistant".to_string(),
                                 tool_calls: Vec::new(),
                             },
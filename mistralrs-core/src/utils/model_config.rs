@@ -50,6 +50,7 @@ impl<'a> Adapter<'a> {
         let ordering = paths.get_ordering().as_ref().unwrap();
         let preload_adapters = load_preload_adapters(
             paths.get_lora_preload_adapter_info(),
+            ordering.combined_adapters.is_some(),
             candle_core::DType::F32,
             device,
             silent,
@@ -67,17 +68,13 @@ impl<'a> Adapter<'a> {
         // TODO: `from_mmaped_safetensors` has `xlora_paths` as the 2nd param (_valid but params need to be named better_)
         let vb = from_mmaped_safetensors(
             xlora_paths,
-            paths
-                .get_adapter_filenames()
-                .as_ref()
-                .unwrap()
-                .iter()
-                .map(|(_, x)| (*x).to_owned())
-                .collect::<Vec<_>>(),
+            paths.get_adapter_filenames().as_ref().unwrap().clone(),
+            ordering.combined_adapters.is_some(),
             Some(candle_core::DType::F32),
             device,
             silent,
             None,
+            None,
             |_| true,
         )?;
 
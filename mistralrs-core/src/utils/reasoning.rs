@@ -0,0 +1,306 @@
+//! Splitting the output of reasoning models into the reasoning block and the final content.
+
+use serde::{Deserialize, Serialize};
+
+fn default_start() -> String {
+    "<think>".to_string()
+}
+
+fn default_end() -> String {
+    "</think>".to_string()
+}
+
+/// The markers delimiting the reasoning block, `<think>` and `</think>` by default.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReasoningMarkers {
+    #[serde(default = "default_start")]
+    pub start: String,
+    #[serde(default = "default_end")]
+    pub end: String,
+}
+
+impl Default for ReasoningMarkers {
+    fn default() -> Self {
+        Self {
+            start: default_start(),
+            end: default_end(),
+        }
+    }
+}
+
+impl ReasoningMarkers {
+    /// Split a complete output into the reasoning and the content. Without a closing marker, the
+    /// text after the opening marker is treated as content.
+    pub(crate) fn split(&self, text: &str) -> (Option<String>, String) {
+        let Some(start) = text.find(&self.start) else {
+            return (None, text.to_string());
+        };
+        let before = &text[..start];
+        let after_start = &text[start + self.start.len()..];
+        match after_start.find(&self.end) {
+            Some(end) => {
+                let reasoning = after_start[..end].trim().to_string();
+                let after_end = after_start[end + self.end.len()..].trim_start();
+                (Some(reasoning), format!("{before}{after_end}"))
+            }
+            None => (None, format!("{before}{}", after_start.trim_start())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReasoningState {
+    BeforeReasoning,
+    Reasoning,
+    AfterReasoning,
+}
+
+/// An incremental split of a streamed output.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct ReasoningDelta {
+    pub reasoning: String,
+    pub content: String,
+}
+
+/// Splits a streamed output as the deltas arrive. Text which may be the start of a marker split
+/// over a token boundary is held back until it can be resolved, as is trailing whitespace of the
+/// reasoning, which [`ReasoningMarkers::split`] trims. The reasoning is streamed as it is
+/// generated, so unlike the split of the complete output, reasoning without a closing marker
+/// stays reasoning.
+#[derive(Debug, Clone)]
+pub(crate) struct ReasoningParser {
+    markers: ReasoningMarkers,
+    state: ReasoningState,
+    pending: String,
+    // Trailing whitespace of the reasoning, which is only emitted if more reasoning follows
+    reasoning_whitespace: String,
+    // Whether leading whitespace of the reasoning or content is still to be dropped
+    trim_start: bool,
+}
+
+impl ReasoningParser {
+    pub fn new(markers: ReasoningMarkers) -> Self {
+        Self {
+            markers,
+            state: ReasoningState::BeforeReasoning,
+            pending: String::new(),
+            reasoning_whitespace: String::new(),
+            trim_start: false,
+        }
+    }
+
+    pub fn markers(&self) -> &ReasoningMarkers {
+        &self.markers
+    }
+
+    fn emit(&mut self, delta: &mut ReasoningDelta, text: &str) {
+        let text = if self.trim_start {
+            text.trim_start()
+        } else {
+            text
+        };
+        if text.is_empty() {
+            return;
+        }
+        self.trim_start = false;
+        match self.state {
+            ReasoningState::Reasoning => {
+                let trimmed = text.trim_end();
+                if !trimmed.is_empty() {
+                    delta
+                        .reasoning
+                        .push_str(&std::mem::take(&mut self.reasoning_whitespace));
+                    delta.reasoning.push_str(trimmed);
+                }
+                self.reasoning_whitespace.push_str(&text[trimmed.len()..]);
+            }
+            ReasoningState::BeforeReasoning | ReasoningState::AfterReasoning => {
+                delta.content.push_str(text)
+            }
+        }
+    }
+
+    pub fn push(&mut self, text: &str) -> ReasoningDelta {
+        self.pending.push_str(text);
+        let mut delta = ReasoningDelta::default();
+        loop {
+            let marker = match self.state {
+                ReasoningState::BeforeReasoning => self.markers.start.clone(),
+                ReasoningState::Reasoning => self.markers.end.clone(),
+                ReasoningState::AfterReasoning => {
+                    let pending = std::mem::take(&mut self.pending);
+                    self.emit(&mut delta, &pending);
+                    return delta;
+                }
+            };
+            if let Some(pos) = self.pending.find(marker.as_str()) {
+                let rest = self.pending.split_off(pos + marker.len());
+                let mut before = std::mem::replace(&mut self.pending, rest);
+                before.truncate(pos);
+                self.emit(&mut delta, &before);
+                self.state = match self.state {
+                    ReasoningState::BeforeReasoning => ReasoningState::Reasoning,
+                    _ => {
+                        self.reasoning_whitespace.clear();
+                        ReasoningState::AfterReasoning
+                    }
+                };
+                // The text following either marker starts without whitespace
+                self.trim_start = true;
+                continue;
+            }
+            // Hold back the longest suffix which could begin the marker
+            let held = self
+                .pending
+                .char_indices()
+                .map(|(i, _)| i)
+                .find(|&i| marker.starts_with(&self.pending[i..]))
+                .unwrap_or(self.pending.len());
+            let held = self.pending.split_off(held);
+            let ready = std::mem::replace(&mut self.pending, held);
+            self.emit(&mut delta, &ready);
+            return delta;
+        }
+    }
+
+    /// Flush the text held back at the end of the output, without the trailing whitespace of
+    /// unterminated reasoning.
+    pub fn finish(&mut self) -> ReasoningDelta {
+        let mut delta = ReasoningDelta::default();
+        let rest = std::mem::take(&mut self.pending);
+        self.emit(&mut delta, &rest);
+        delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReasoningMarkers, ReasoningParser};
+
+    #[test]
+    fn test_split_reasoning() {
+        let markers = ReasoningMarkers::default();
+        let (reasoning, content) =
+            markers.split("<think>\nThe user greets me.\n</think>\n\nHello there!");
+        assert_eq!(reasoning.as_deref(), Some("The user greets me."));
+        assert_eq!(content, "Hello there!");
+
+        // A missing closing marker leaves everything as content
+        let (reasoning, content) = markers.split("<think>Hello there!");
+        assert_eq!(reasoning, None);
+        assert_eq!(content, "Hello there!");
+
+        let (reasoning, content) = markers.split("Hello there!");
+        assert_eq!(reasoning, None);
+        assert_eq!(content, "Hello there!");
+    }
+
+    #[test]
+    fn test_streamed_split_across_tokens() {
+        let mut parser = ReasoningParser::new(ReasoningMarkers::default());
+        let (mut reasoning, mut content) = (String::new(), String::new());
+        for token in [
+            "<th", "ink>", "Thinking", " hard</", "thi", "nk>", "An", "swer <", "3",
+        ] {
+            let delta = parser.push(token);
+            reasoning.push_str(&delta.reasoning);
+            content.push_str(&delta.content);
+        }
+        let delta = parser.finish();
+        reasoning.push_str(&delta.reasoning);
+        content.push_str(&delta.content);
+        assert_eq!(reasoning, "Thinking hard");
+        assert_eq!(content, "Answer <3");
+    }
+
+    /// The reasoning and content of an output streamed in chunks of `chunk` characters.
+    fn stream(text: &str, chunk: usize) -> (Option<String>, String) {
+        let mut parser = ReasoningParser::new(ReasoningMarkers::default());
+        let (mut reasoning, mut content) = (String::new(), String::new());
+        let chars = text.chars().collect::<Vec<_>>();
+        for token in chars.chunks(chunk) {
+            let delta = parser.push(&token.iter().collect::<String>());
+            reasoning.push_str(&delta.reasoning);
+            content.push_str(&delta.content);
+        }
+        let delta = parser.finish();
+        reasoning.push_str(&delta.reasoning);
+        content.push_str(&delta.content);
+        ((!reasoning.is_empty()).then_some(reasoning), content)
+    }
+
+    #[test]
+    fn test_streamed_split_matches_split() {
+        let markers = ReasoningMarkers::default();
+        for text in [
+            "<think>\nThe user greets me.\n</think>\n\nHello there!",
+            "<think> Several \n lines\n\nof reasoning \n</think>\n\nHello there!",
+            "Before <think> reasoning </think>   after",
+            "No reasoning at all",
+        ] {
+            for chunk in [1, 2, 3, 7, text.len()] {
+                assert_eq!(
+                    stream(text, chunk),
+                    markers.split(text),
+                    "{text:?} by {chunk}"
+                );
+            }
+        }
+    }
+
+    /// The reasoning and content deltas of an output streamed by `tokens`.
+    fn deltas(tokens: &[&str]) -> Vec<(String, String)> {
+        let mut parser = ReasoningParser::new(ReasoningMarkers::default());
+        let mut deltas = tokens
+            .iter()
+            .map(|token| parser.push(token))
+            .collect::<Vec<_>>();
+        deltas.push(parser.finish());
+        deltas
+            .into_iter()
+            .map(|delta| (delta.reasoning, delta.content))
+            .filter(|(reasoning, content)| !reasoning.is_empty() || !content.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn test_reasoning_is_streamed_incrementally() {
+        let deltas = deltas(&[
+            "<think>", "\nThe", " user", " greets", " me.\n", "</th", "ink>", "\nHello", "!",
+        ]);
+        let reasoning = |text: &str| (text.to_string(), String::new());
+        let content = |text: &str| (String::new(), text.to_string());
+        assert_eq!(
+            deltas,
+            vec![
+                reasoning("The"),
+                reasoning(" user"),
+                reasoning(" greets"),
+                // The trailing newline is held back, and dropped at the closing marker
+                reasoning(" me."),
+                content("Hello"),
+                content("!"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_streamed_unterminated_reasoning() {
+        // The streamed reasoning is not taken back when the closing marker never comes
+        let deltas = deltas(&["<think> Still", " thinking", " about it </thi"]);
+        assert_eq!(
+            deltas,
+            vec![
+                ("Still".to_string(), String::new()),
+                (" thinking".to_string(), String::new()),
+                (" about it".to_string(), String::new()),
+                (" </thi".to_string(), String::new()),
+            ]
+        );
+        // Whereas the complete output is content
+        assert_eq!(
+            ReasoningMarkers::default().split("<think> Still thinking about it </thi"),
+            (None, "Still thinking about it </thi".to_string())
+        );
+    }
+}
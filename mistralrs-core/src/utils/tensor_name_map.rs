@@ -0,0 +1,133 @@
+//! Remapping of checkpoint tensor names to the names expected by the model implementations.
+
+use candle_core::Result;
+use regex::Regex;
+
+#[derive(Debug, Clone)]
+enum NameRewrite {
+    Prefix { from: String, to: String },
+    Regex { regex: Regex, replacement: String },
+}
+
+/// An ordered list of rewrites applied to every tensor name in a checkpoint before it is
+/// made available to the `VarBuilder`. This allows loading checkpoints which are structurally
+/// identical to a supported architecture but use different tensor names.
+///
+/// Each rewrite is applied at most once per name, in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct TensorNameMap {
+    rewrites: Vec<NameRewrite>,
+}
+
+impl TensorNameMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the prefix `from` with `to` for names starting with `from`.
+    pub fn with_prefix(mut self, from: impl ToString, to: impl ToString) -> Self {
+        self.rewrites.push(NameRewrite::Prefix {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        self
+    }
+
+    /// Replace the first match of `pattern` with `replacement`. The replacement may reference
+    /// capture groups, for example `$1`.
+    pub fn with_regex(mut self, pattern: &str, replacement: impl ToString) -> Result<Self> {
+        let regex = Regex::new(pattern).map_err(candle_core::Error::msg)?;
+        self.rewrites.push(NameRewrite::Regex {
+            regex,
+            replacement: replacement.to_string(),
+        });
+        Ok(self)
+    }
+
+    /// Checkpoints saved from a `torch.compile`-d module, where every name has an `_orig_mod.` prefix.
+    pub fn torch_compile() -> Self {
+        Self::new().with_prefix("_orig_mod.", "")
+    }
+
+    /// Checkpoints which omit the leading `model.` before the decoder layers, embeddings and final norm.
+    pub fn missing_model_prefix() -> Self {
+        Self::new()
+            .with_regex(r"^(layers|embed_tokens|norm)\.", "model.$1.")
+            .expect("Static regex is valid")
+    }
+
+    /// GPT-2 style conversions which use `transformer.h.*`, `transformer.wte` and `transformer.ln_f`
+    /// in place of `model.layers.*`, `model.embed_tokens` and `model.norm`.
+    pub fn gpt2_style() -> Self {
+        Self::new()
+            .with_regex(r"^transformer\.h\.(\d+)\.", "model.layers.$1.")
+            .expect("Static regex is valid")
+            .with_prefix("transformer.wte.", "model.embed_tokens.")
+            .with_prefix("transformer.ln_f.", "model.norm.")
+    }
+
+    /// Append the rewrites of `other` after those of `self`.
+    pub fn chain(mut self, other: Self) -> Self {
+        self.rewrites.extend(other.rewrites);
+        self
+    }
+
+    /// Apply all rewrites to a tensor name.
+    pub fn map_name(&self, name: &str) -> String {
+        let mut name = name.to_string();
+        for rewrite in &self.rewrites {
+            match rewrite {
+                NameRewrite::Prefix { from, to } => {
+                    if let Some(rest) = name.strip_prefix(from.as_str()) {
+                        name = format!("{to}{rest}");
+                    }
+                }
+                NameRewrite::Regex { regex, replacement } => {
+                    name = regex.replace(&name, replacement.as_str()).into_owned();
+                }
+            }
+        }
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TensorNameMap;
+
+    #[test]
+    fn test_prefix_rewrite() {
+        let map = TensorNameMap::new().with_prefix("transformer.", "model.");
+        assert_eq!(
+            map.map_name("transformer.layers.0.self_attn.q_proj.weight"),
+            "model.layers.0.self_attn.q_proj.weight"
+        );
+        // Only the prefix is rewritten
+        assert_eq!(
+            map.map_name("lm_head.transformer.weight"),
+            "lm_head.transformer.weight"
+        );
+    }
+
+    #[test]
+    fn test_builtin_maps() {
+        let map = TensorNameMap::torch_compile().chain(TensorNameMap::gpt2_style());
+        assert_eq!(
+            map.map_name("_orig_mod.transformer.h.12.mlp.down_proj.weight"),
+            "model.layers.12.mlp.down_proj.weight"
+        );
+        assert_eq!(
+            map.map_name("transformer.wte.weight"),
+            "model.embed_tokens.weight"
+        );
+        assert_eq!(map.map_name("lm_head.weight"), "lm_head.weight");
+
+        let map = TensorNameMap::missing_model_prefix();
+        assert_eq!(
+            map.map_name("layers.3.input_layernorm.weight"),
+            "model.layers.3.input_layernorm.weight"
+        );
+        assert_eq!(map.map_name("norm.weight"), "model.norm.weight");
+        assert_eq!(map.map_name("model.norm.weight"), "model.norm.weight");
+    }
+}
@@ -11,14 +11,80 @@ struct AddedToken {
     content: String,
 }
 
+/// Register `special_tokens` in the `added_tokens` of the tokenizer JSON, with the given ids. A
+/// token which is already present with the same id is skipped, and any other collision of either the
+/// content or the id with the vocab or the added tokens is an error.
+fn add_special_tokens(tokenizer: &mut Value, special_tokens: &[(String, u32)]) -> Result<()> {
+    let added_tokens: Vec<AddedToken> =
+        serde_json::from_value(tokenizer["added_tokens"].clone()).unwrap();
+    let vocab: HashMap<String, usize> =
+        serde_json::from_value(tokenizer["model"]["vocab"].clone()).unwrap();
+    let mut ids = vocab
+        .iter()
+        .map(|(content, id)| (*id, content.clone()))
+        .chain(added_tokens.into_iter().map(|tok| (tok.id, tok.content)))
+        .collect::<HashMap<_, _>>();
+    for (content, id) in special_tokens {
+        let id = *id as usize;
+        if ids.get(&id) == Some(content) {
+            continue;
+        }
+        if let Some(existing) = ids.get(&id) {
+            anyhow::bail!(
+                "Added token `{content}` has id {id}, which is already used by `{existing}`."
+            );
+        }
+        if let Some((existing, _)) = ids.iter().find(|(_, c)| *c == content) {
+            anyhow::bail!(
+                "Added token `{content}` is already in the tokenizer with id {existing}."
+            );
+        }
+        tokenizer["added_tokens"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({
+                "id": id,
+                "content": content,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            }));
+        ids.insert(id, content.clone());
+    }
+    Ok(())
+}
+
+/// Check that the ids of `special_tokens` are below the `vocab_size` of the model, as the
+/// embeddings have no rows for higher ids.
+pub(crate) fn check_added_token_ids(
+    special_tokens: &[(String, u32)],
+    vocab_size: usize,
+) -> Result<()> {
+    for (content, id) in special_tokens {
+        if *id as usize >= vocab_size {
+            anyhow::bail!(
+                "Added token `{content}` has id {id}, which is outside of the vocab size {vocab_size} of the model."
+            );
+        }
+    }
+    Ok(())
+}
+
 /// May fix the tokenizer according to: https://gist.github.com/jneuff/682d47b786329f19291d166957b3274a
+///
+/// `special_tokens` are registered as special tokens with their given ids, so that they are
+/// encoded as a single token and skipped when decoding with `skip_special_tokens`.
 pub(crate) fn get_tokenizer<P: AsRef<Path> + Clone>(
     p: P,
     processor_added_tokens: Option<&[&str]>,
+    special_tokens: &[(String, u32)],
 ) -> Result<Tokenizer> {
     let mut tokenizer = {
         let raw = std::fs::read(p.clone()).map_err(anyhow::Error::msg)?;
         let mut tokenizer: Value = serde_json::from_slice(&raw).unwrap();
+        add_special_tokens(&mut tokenizer, special_tokens)?;
         let added_tokens: Vec<AddedToken> =
             serde_json::from_value(tokenizer["added_tokens"].clone()).unwrap();
         let vocab: HashMap<String, usize> =
@@ -46,3 +112,61 @@ pub(crate) fn get_tokenizer<P: AsRef<Path> + Clone>(
     }
     Ok(tokenizer)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_added_token_ids, get_tokenizer};
+
+    const TOKENIZER: &str = r#"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": {"hello": 0, "world": 1, "[UNK]": 2}, "unk_token": "[UNK]"}
+    }"#;
+
+    #[test]
+    fn test_added_special_tokens() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "mistralrs_added_tokens_{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, TOKENIZER)?;
+
+        let tokenizer = get_tokenizer(&path, None, &[("<|tool|>".to_string(), 3)]);
+        let collision = get_tokenizer(&path, None, &[("<|tool|>".to_string(), 1)]);
+        let duplicate = get_tokenizer(&path, None, &[("world".to_string(), 3)]);
+        std::fs::remove_file(&path)?;
+        let tokenizer = tokenizer?;
+        assert!(collision.is_err());
+        assert!(duplicate.is_err());
+
+        let ids = tokenizer
+            .encode("hello <|tool|> world", false)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        assert_eq!(ids, vec![0, 3, 1]);
+        assert_eq!(
+            tokenizer.decode(&ids, false).map_err(anyhow::Error::msg)?,
+            "hello <|tool|> world"
+        );
+        assert_eq!(
+            tokenizer.decode(&ids, true).map_err(anyhow::Error::msg)?,
+            "hello world"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_added_token_ids_within_vocab_size() {
+        let tokens = [("<|tool|>".to_string(), 3), ("<|end|>".to_string(), 4)];
+        assert!(check_added_token_ids(&tokens, 5).is_ok());
+        let err = check_added_token_ids(&tokens, 4).unwrap_err().to_string();
+        assert!(err.contains("`<|end|>` has id 4"), "{err}");
+    }
+}
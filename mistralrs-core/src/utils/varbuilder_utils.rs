@@ -18,6 +18,7 @@ use regex::Regex;
 
 use crate::lora::LoraConfig;
 use crate::utils::progress::IterWithProgress;
+use crate::utils::tensor_name_map::TensorNameMap;
 use derive_new::new;
 
 trait TensorLoaderBackend {
@@ -36,7 +37,18 @@ impl TensorLoaderBackend for SafetensorBackend {
             .collect::<Vec<_>>()
     }
     fn load_name(&self, name: &str, device: &Device, dtype: Option<DType>) -> Result<Tensor> {
-        let t = self.0.load(name, device)?;
+        let view = self.0.get(name)?;
+        let t = if view.dtype() == safetensors::Dtype::I8 {
+            // Candle has no int8 dtype, int8 weights such as SmoothQuant's are loaded as f32
+            let data = view
+                .data()
+                .iter()
+                .map(|&x| f32::from(x as i8))
+                .collect::<Vec<_>>();
+            Tensor::from_vec(data, view.shape(), device)?
+        } else {
+            self.0.load(name, device)?
+        };
         if let Some(dtype) = dtype {
             if t.dtype() == DType::I32 {
                 Ok(t)
@@ -78,16 +90,25 @@ impl TensorLoaderBackend for PickleBackend {
 /// Load tensors into a VarBuilder backed by a VarMap using MmapedSafetensors.
 /// Set `silent` to not show a progress bar.
 ///
+/// If `name_map` is specified, tensor names are rewritten with it before being inserted into the
+/// VarBuilder. The dummy regexes are matched against the rewritten names.
+///
+/// `xlora_paths` are the adapter names and files, in X-LoRA order. With `combined_adapters`, the
+/// files are the combined file of the ordering, see [`combined_adapter_prefix`].
+///
 /// # Predicate semantics:
 /// - If `regexes` is specified, this will be used in `make_dummy_predicate` based on `.any`
 /// - Otherwise, only include keys for which predicate evaluates to true.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn from_mmaped_safetensors<'a>(
     paths: Vec<PathBuf>,
-    xlora_paths: Vec<PathBuf>,
+    xlora_paths: Vec<(String, PathBuf)>,
+    combined_adapters: bool,
     dtype: Option<DType>,
     device: &Device,
     silent: bool,
     make_dummy_regexes: Option<Arc<Vec<Regex>>>,
+    name_map: Option<&TensorNameMap>,
     predicate: impl Fn(String) -> bool + Send + Sync + Clone + 'static,
 ) -> Result<VarBuilderArgs<'a, Box<dyn SimpleBackend>>> {
     #[allow(clippy::type_complexity)]
@@ -95,10 +116,11 @@ pub(crate) fn from_mmaped_safetensors<'a>(
 
     for path in paths {
         let device = device.clone();
+        let name_map = name_map.cloned();
         if let Some(regexes) = make_dummy_regexes.clone() {
             let predicate = predicate.clone();
             handles.push(thread::spawn(Box::new(move || {
-                let loader = Common::new();
+                let loader = Common::new(name_map);
                 loader.load_tensors_from_path(&path, &device, dtype, silent, predicate, |key| {
                     regexes.iter().any(|r| r.is_match(key))
                 })
@@ -106,17 +128,18 @@ pub(crate) fn from_mmaped_safetensors<'a>(
         } else {
             let predicate = predicate.clone();
             handles.push(thread::spawn(Box::new(move || {
-                let loader = Common::new();
+                let loader = Common::new(name_map);
                 loader.load_tensors_from_path(&path, &device, dtype, silent, predicate, |_| false)
             })));
         }
     }
-    for (i, path) in xlora_paths.into_iter().enumerate() {
+    for (i, (name, path)) in xlora_paths.into_iter().enumerate() {
+        let prefix = combined_adapter_prefix(&name, combined_adapters);
         let device = device.clone();
         if let Some(regexes) = make_dummy_regexes.clone() {
             let predicate = predicate.clone();
             handles.push(thread::spawn(Box::new(move || {
-                let loader = XLora::new(i + 1);
+                let loader = XLora::new(i + 1, prefix);
                 loader.load_tensors_from_path(&path, &device, dtype, silent, predicate, |key| {
                     regexes.iter().any(|r| r.is_match(key))
                 })
@@ -124,7 +147,7 @@ pub(crate) fn from_mmaped_safetensors<'a>(
         } else {
             let predicate = predicate.clone();
             handles.push(thread::spawn(Box::new(move || {
-                let loader = XLora::new(i + 1);
+                let loader = XLora::new(i + 1, prefix);
                 loader.load_tensors_from_path(&path, &device, dtype, silent, predicate, |_| false)
             })));
         }
@@ -146,8 +169,28 @@ pub(crate) fn from_mmaped_safetensors<'a>(
     ))
 }
 
+/// Adapters may be bundled in a combined file whose tensor names are prefixed by the adapter name,
+/// `<adapter name>.<tensor name>`, as set by [`Ordering::combined_adapters`]. For such a file, this
+/// is the prefix of the tensors of the adapter `name`.
+///
+/// [`Ordering::combined_adapters`]: crate::lora::Ordering::combined_adapters
+fn combined_adapter_prefix(name: &str, combined_adapters: bool) -> Option<String> {
+    combined_adapters.then(|| format!("{name}."))
+}
+
+/// Keep the tensors of the adapter with `prefix`, if any, with the prefix removed.
+fn strip_adapter_prefix(name: String, prefix: Option<&str>) -> Option<(String, String)> {
+    match prefix {
+        Some(prefix) => name
+            .strip_prefix(prefix)
+            .map(|stripped| (name.clone(), stripped.to_string())),
+        None => Some((name.clone(), name)),
+    }
+}
+
 pub(crate) fn load_preload_adapters<'a>(
     paths: &Option<HashMap<String, (PathBuf, LoraConfig)>>,
+    combined_adapters: bool,
     dtype: DType,
     device: &Device,
     silent: bool,
@@ -155,7 +198,7 @@ pub(crate) fn load_preload_adapters<'a>(
     if let Some(paths) = paths {
         let mut map = HashMap::new();
         for (name, (path, config)) in paths {
-            let loader = Common::new();
+            let loader = PreloadAdapter::new(combined_adapter_prefix(name, combined_adapters));
             let loaded_tensors = loader.load_tensors_from_path(
                 path,
                 device,
@@ -216,7 +259,7 @@ trait LoadTensors {
         let mut loaded_tensors = HashMap::new();
         if !iter.is_empty() {
             for (load_name, key_name) in iter.into_iter().with_progress(is_silent) {
-                if !make_dummy_predicate(&load_name) {
+                if !make_dummy_predicate(&key_name) {
                     // If making a dummy, don't add the tensor. `mistralrs_quant` handles this!
                     let tensor = tensors.load_name(&load_name, device, dtype)?;
 
@@ -241,13 +284,49 @@ trait LoadTensors {
 }
 
 #[derive(new)]
-struct Common {}
-impl LoadTensors for Common {}
+struct Common {
+    name_map: Option<TensorNameMap>,
+}
+
+impl LoadTensors for Common {
+    fn get_name_key_pairs(
+        &self,
+        tensors: impl Iterator<Item = String>,
+    ) -> impl Iterator<Item = (String, String)> {
+        tensors.map(|name| {
+            let mut new_name = name.replace("base_model.model.model", "model");
+            if let Some(name_map) = &self.name_map {
+                new_name = name_map.map_name(&new_name);
+            }
+
+            (name, new_name)
+        })
+    }
+}
+
+#[derive(new)]
+struct PreloadAdapter {
+    // Set for an adapter of a combined file
+    prefix: Option<String>,
+}
+
+impl LoadTensors for PreloadAdapter {
+    fn get_name_key_pairs(
+        &self,
+        tensors: impl Iterator<Item = String>,
+    ) -> impl Iterator<Item = (String, String)> {
+        tensors
+            .filter_map(|name| strip_adapter_prefix(name, self.prefix.as_deref()))
+            .map(|(name, key)| (name, key.replace("base_model.model.model", "model")))
+    }
+}
 
 #[derive(new)]
 struct XLora {
     // Matches the associated path instance for reference in `get_name_key_pairs()`
     adapter_index: usize,
+    // Set for an adapter of a combined file
+    prefix: Option<String>,
 }
 
 impl LoadTensors for XLora {
@@ -259,8 +338,9 @@ impl LoadTensors for XLora {
 
         tensors
             .filter(|name| !name.contains("internal_xlora_classifier"))
-            .map(|name| {
-                let mut new_name = name.replace("base_model.model.model", "model");
+            .filter_map(|name| strip_adapter_prefix(name, self.prefix.as_deref()))
+            .map(|(name, key)| {
+                let mut new_name = key.replace("base_model.model.model", "model");
                 // TODO: Add better context to describe intent / requirement:
                 let pos = new_name.find(".lora").expect(expectation);
                 new_name.insert_str(pos + 7, &format!(".{}", self.adapter_index));
@@ -269,3 +349,209 @@ impl LoadTensors for XLora {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{from_mmaped_safetensors, load_preload_adapters};
+    use crate::lora::LoraConfig;
+
+    const LORA_A: &str = "base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight";
+
+    #[test]
+    fn test_combined_adapters() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let path = std::env::temp_dir().join(format!(
+            "mistralrs_combined_adapters_{}.safetensors",
+            std::process::id()
+        ));
+        let tensors = HashMap::from([
+            (format!("math.{LORA_A}"), Tensor::full(1f32, (2, 4), &dev)?),
+            (format!("code.{LORA_A}"), Tensor::full(2f32, (2, 4), &dev)?),
+        ]);
+        candle_core::safetensors::save(&tensors, &path)?;
+
+        let config: LoraConfig =
+            serde_json::from_str(r#"{"lora_alpha": 16, "r": 2, "target_modules": ["q_proj"]}"#)
+                .unwrap();
+        let preload_config = config.clone();
+        let preload = HashMap::from([
+            ("math".to_string(), (path.clone(), config.clone())),
+            ("code".to_string(), (path.clone(), config)),
+        ]);
+        let adapters =
+            load_preload_adapters(&Some(preload), true, DType::F32, &dev, true)?.unwrap();
+
+        // X-LoRA indices follow the adapter order, not the file
+        let xlora = from_mmaped_safetensors(
+            vec![],
+            vec![
+                ("code".to_string(), path.clone()),
+                ("math".to_string(), path.clone()),
+            ],
+            true,
+            Some(DType::F32),
+            &dev,
+            true,
+            None,
+            None,
+            |_| true,
+        );
+        // Selecting a single adapter of the combined file still splits out its tensors
+        let single = load_preload_adapters(
+            &Some(HashMap::from([(
+                "code".to_string(),
+                (path.clone(), preload_config.clone()),
+            )])),
+            true,
+            DType::F32,
+            &dev,
+            true,
+        );
+        std::fs::remove_file(&path)?;
+        let xlora = xlora?;
+        let single = single?.unwrap();
+        let (vb, _) = &single["code"];
+        let weight = vb.get((2, 4), "model.layers.0.self_attn.q_proj.lora_A.weight")?;
+        assert_eq!(weight.flatten_all()?.to_vec1::<f32>()?, vec![2f32; 8]);
+
+        // Each adapter is registered with only its own tensors
+        let key = "model.layers.0.self_attn.q_proj.lora_A.weight";
+        for (name, value) in [("math", 1f32), ("code", 2f32)] {
+            let (vb, _) = &adapters[name];
+            let weight = vb.get((2, 4), key)?;
+            assert_eq!(weight.flatten_all()?.to_vec1::<f32>()?, vec![value; 8]);
+            assert!(!vb.contains_tensor(&format!("{name}.{key}")));
+        }
+
+        let key = |i: usize| format!("model.layers.0.self_attn.q_proj.lora_A.{i}.weight");
+        let first = xlora
+            .get((2, 4), &key(1))?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let second = xlora
+            .get((2, 4), &key(2))?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        assert_eq!(first, vec![2f32; 8]);
+        assert_eq!(second, vec![1f32; 8]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pickle_matches_safetensors() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        // The state dict of `torch.save`, with `lm_head.weight` a transposed view:
+        // {"model.embed_tokens.weight": torch.arange(6.).reshape(2, 3),
+        //  "lm_head.weight": torch.tensor([[0.5, -1., 2.5], [3., -4.5, 6.]]).t()}
+        let pickle = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/pytorch_model.bin");
+        let safetensors = std::env::temp_dir().join(format!(
+            "mistralrs_pickle_equivalent_{}.safetensors",
+            std::process::id()
+        ));
+        let tensors = HashMap::from([
+            (
+                "model.embed_tokens.weight".to_string(),
+                Tensor::arange(0f32, 6., &dev)?.reshape((2, 3))?,
+            ),
+            (
+                "lm_head.weight".to_string(),
+                Tensor::new(&[[0.5f32, 3.], [-1., -4.5], [2.5, 6.]], &dev)?,
+            ),
+        ]);
+        candle_core::safetensors::save(&tensors, &safetensors)?;
+
+        let load = |path| {
+            from_mmaped_safetensors(
+                vec![path],
+                vec![],
+                false,
+                Some(DType::F32),
+                &dev,
+                true,
+                None,
+                None,
+                |_| true,
+            )
+        };
+        let from_pickle = load(pickle)?;
+        let from_safetensors = load(safetensors.clone());
+        std::fs::remove_file(&safetensors)?;
+        let from_safetensors = from_safetensors?;
+
+        for (name, shape) in [
+            ("model.embed_tokens.weight", (2, 3)),
+            ("lm_head.weight", (3, 2)),
+        ] {
+            assert_eq!(
+                from_pickle.get(shape, name)?.to_vec2::<f32>()?,
+                from_safetensors.get(shape, name)?.to_vec2::<f32>()?,
+            );
+        }
+        Ok(())
+    }
+    #[test]
+    fn test_int8_safetensors() -> candle_core::Result<()> {
+        use mistralrs_quant::{QuantMethod, QuantMethodType, QuantizedConfig, SmoothQuantLinear};
+        use safetensors::{tensor::TensorView, Dtype};
+
+        let dev = Device::Cpu;
+        let path =
+            std::env::temp_dir().join(format!("mistralrs_int8_{}.safetensors", std::process::id()));
+        let weight = [1i8, -2, 3, 127, -127, 0];
+        let weight_bytes = weight.iter().map(|&x| x as u8).collect::<Vec<_>>();
+        let scale_bytes = [0.5f32, 0.25, 0.1]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+        let tensors = vec![
+            (
+                "proj.weight".to_string(),
+                TensorView::new(Dtype::I8, vec![3, 2], &weight_bytes).unwrap(),
+            ),
+            (
+                "proj.weight_scale".to_string(),
+                TensorView::new(Dtype::F32, vec![3], &scale_bytes).unwrap(),
+            ),
+        ];
+        safetensors::serialize_to_file(tensors, &None, &path).unwrap();
+
+        let vb = from_mmaped_safetensors(
+            vec![path.clone()],
+            vec![],
+            false,
+            Some(DType::F32),
+            &dev,
+            true,
+            None,
+            None,
+            |_| true,
+        );
+        std::fs::remove_file(&path)?;
+        let vb = vb?;
+        assert_eq!(
+            vb.get((3, 2), "proj.weight")?.to_vec2::<f32>()?,
+            vec![vec![1., -2.], vec![3., 127.], vec![-127., 0.]]
+        );
+
+        let config = QuantizedConfig {
+            quant_method: QuantMethodType::SmoothQuant,
+            ..Default::default()
+        };
+        let layer = SmoothQuantLinear::linear_b(2, 3, false, &config, vb.pp("proj"))?;
+        let x = Tensor::new(&[[2f32, -2.], [0., 1.]], &dev)?;
+        let out = layer.forward(&x)?.to_vec2::<f32>()?;
+        // The weight is dequantized to [[0.5, -1], [0.75, 31.75], [-12.7, 0]]
+        let expected = [[3f32, -62., -25.4], [-1., 31.75, 0.]];
+        for (row, expected) in out.iter().zip(expected) {
+            for (x, y) in row.iter().zip(expected) {
+                assert!((x - y).abs() < 1e-4, "{x} != {y}");
+            }
+        }
+        Ok(())
+    }
+}
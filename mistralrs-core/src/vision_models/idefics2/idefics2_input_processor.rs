@@ -63,6 +63,7 @@ impl Processor for Idefics2Processor {
         pipeline: &dyn Pipeline,
         messages: Vec<IndexMap<String, MessageContent>>,
         add_generation_prompt: bool,
+        continue_final_message: bool,
         add_special_tokens: bool,
         tools: Vec<Tool>,
     ) -> anyhow::Result<(Vec<u32>, String)> {
@@ -70,6 +71,7 @@ impl Processor for Idefics2Processor {
             pipeline,
             messages,
             add_generation_prompt,
+            continue_final_message,
             self.template_action(),
             tools,
         )?;
@@ -120,6 +120,7 @@ impl MLlamaTextSelfAttention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: None,
+                attention_softmax_f32: false,
             },
             rope,
             num_heads: cfg.num_attention_heads,
@@ -335,6 +336,7 @@ impl MLlamaTextCrossAttention {
                 softcap: None,
                 softmax_scale: 1.0 / (cfg.head_dim() as f32).sqrt(),
                 sliding_window: None,
+                attention_softmax_f32: false,
             },
         })
     }
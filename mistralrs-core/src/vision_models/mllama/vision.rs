@@ -169,6 +169,7 @@ impl MLlamaVisionAttention {
                 softcap: None,
                 softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                 sliding_window: None,
+                attention_softmax_f32: false,
             },
             num_heads: cfg.num_attention_heads,
             head_dim,
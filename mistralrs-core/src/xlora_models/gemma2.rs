@@ -232,6 +232,7 @@ impl Attention {
                 softcap: cfg.attn_logit_softcapping.map(|x| x as f32),
                 softmax_scale: 1.0 / (cfg.query_pre_attn_scalar as f32).sqrt(),
                 sliding_window,
+                attention_softmax_f32: cfg.attention_softmax_f32,
             },
         })
     }
@@ -417,6 +417,7 @@ impl ModelConfig::FromAdapterGGML for ModelWeights {
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                     sliding_window: None,
+                    attention_softmax_f32: false,
                 },
             })
         }
@@ -715,6 +716,7 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                     sliding_window: None,
+                    attention_softmax_f32: false,
                 },
             })
         }
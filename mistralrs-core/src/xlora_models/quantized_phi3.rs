@@ -342,6 +342,7 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
                     softcap: None,
                     softmax_scale: 1.0 / (head_dim as f32).sqrt(),
                     sliding_window: Some(context_window),
+                    attention_softmax_f32: false,
                 },
             })
         }
@@ -37,8 +37,13 @@ class ChatCompletionRequest:
     adapters: list[str] | None = None
     min_p: float | None = None
     min_p: float | None = None
+    top_a: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    target_length: int | None = None
+    length_bias_strength: float | None = None
+    stop_on_balanced_brackets: bool = False
+    max_output_bytes: int | None = None
 
 @dataclass
 class CompletionRequest:
@@ -65,8 +70,13 @@ class CompletionRequest:
     grammar_type: str | None = None
     adapters: list[str] | None = None
     min_p: float | None = None
+    top_a: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    target_length: int | None = None
+    length_bias_strength: float | None = None
+    stop_on_balanced_brackets: bool = False
+    max_output_bytes: int | None = None
 
 @dataclass
 class Architecture(Enum):
@@ -79,6 +89,11 @@ class Architecture(Enum):
     Gemma2 = "gemma2"
     Starcoder2 = "starcoder2"
     Phi3_5MoE = "phi3.5moe"
+    Cohere = "cohere"
+    Arctic = "arctic"
+    Granite = "granite"
+    Qwen2Moe = "qwen2moe"
+    Mamba = "mamba"
 
 @dataclass
 class VisionArchitecture(Enum):
@@ -129,6 +144,7 @@ class Which(Enum):
         organization: str | None = None
         write_uqff: str | None = None
         dtype: ModelDType = ModelDType.Auto
+        added_tokens: dict[str, int] | None = None
 
     @dataclass
     class XLora:
@@ -413,6 +429,18 @@ class ToolCallResponse:
     type: ToolCallType
     function: CalledFunction
 
+@dataclass
+class CalledFunctionDelta:
+    name: str | None
+    arguments: str
+
+@dataclass
+class ToolCallDelta:
+    index: int
+    id: str | None
+    type: ToolCallType | None
+    function: CalledFunctionDelta
+
 @dataclass
 class ResponseMessage:
     content: str
@@ -457,6 +485,7 @@ class ChatCompletionResponse:
 class Delta:
     content: str
     role: str
+    tool_calls: list[ToolCallDelta] | None
 
 @dataclass
 class ChunkChoice:
@@ -473,6 +502,7 @@ class ChatCompletionChunkResponse:
     model: str
     system_fingerprint: str
     object: str
+    usage: Usage | None
 
 @dataclass
 class CompletionChoice:
@@ -24,8 +24,8 @@ use mistralrs_core::{
     DetokenizationRequest, DeviceLayerMapMetadata, DeviceMapMetadata, DiffusionGenerationParams,
     DiffusionLoaderBuilder, DiffusionSpecificConfig, DrySamplingParams, GGMLLoaderBuilder,
     GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, ImageGenerationResponse,
-    ImageGenerationResponseFormat, LlguidanceGrammar, Loader, MemoryGpuConfig, MistralRs,
-    MistralRsBuilder, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig,
+    ImageGenerationResponseFormat, LengthBiasParams, LlguidanceGrammar, Loader, MemoryGpuConfig,
+    MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig,
     PagedAttentionConfig, Request as _Request, RequestMessage, Response, ResponseOk,
     SamplingParams, SchedulerConfig, SpeculativeConfig, SpeculativeLoader, StopTokens, TokenSource,
     TokenizationRequest, Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig,
@@ -94,6 +94,7 @@ fn parse_which(
             dtype: _,
             imatrix,
             calibration_file,
+            added_tokens,
         } => NormalLoaderBuilder::new(
             NormalSpecificConfig {
                 use_flash_attn,
@@ -110,6 +111,7 @@ fn parse_which(
             Some(model_id),
         )
         .with_no_kv_cache(no_kv_cache)
+        .with_added_tokens(added_tokens.unwrap_or_default().into_iter().collect())
         .build(arch.map(Into::into))?,
         Which::XLora {
             model_id,
@@ -882,7 +884,14 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    top_a: request.top_a,
                     dry_params,
+                    length_bias: request.target_length.map(|target_length| LengthBiasParams {
+                        target_length,
+                        strength: request.length_bias_strength.unwrap_or(1.0),
+                    }),
+                    stop_on_balanced_brackets: request.stop_on_balanced_brackets,
+                    max_output_bytes: request.max_output_bytes,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -894,6 +903,9 @@ impl Runner {
                 tools,
                 logits_processors: None,
                 return_raw_logits: false,
+                continue_final_message: false,
+                reasoning_markers: None,
+                assistant_prefix: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -988,7 +1000,14 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    top_a: request.top_a,
                     dry_params,
+                    length_bias: request.target_length.map(|target_length| LengthBiasParams {
+                        target_length,
+                        strength: request.length_bias_strength.unwrap_or(1.0),
+                    }),
+                    stop_on_balanced_brackets: request.stop_on_balanced_brackets,
+                    max_output_bytes: request.max_output_bytes,
                 },
                 response: tx,
                 return_logprobs: false,
@@ -1000,6 +1019,9 @@ impl Runner {
                 tools,
                 logits_processors: None,
                 return_raw_logits: false,
+                continue_final_message: false,
+                reasoning_markers: None,
+                assistant_prefix: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1057,6 +1079,9 @@ impl Runner {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: None,
         });
 
         let sender = self.runner.get_sender()?;
@@ -37,12 +37,17 @@ pub struct CompletionRequest {
     pub(crate) grammar_type: Option<String>,
     pub(crate) adapters: Option<Vec<String>>,
     pub(crate) min_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
     pub(crate) tool_schemas: Option<Vec<String>>,
     pub(crate) tool_choice: Option<ToolChoice>,
     pub(crate) dry_multiplier: Option<f32>,
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) target_length: Option<usize>,
+    pub(crate) length_bias_strength: Option<f32>,
+    pub(crate) stop_on_balanced_brackets: bool,
+    pub(crate) max_output_bytes: Option<usize>,
 }
 
 #[pymethods]
@@ -67,12 +72,17 @@ impl CompletionRequest {
         grammar_type = None,
         adapters = None,
         min_p=None,
+        top_a=None,
         tool_schemas=None,
         tool_choice=None,
         dry_multiplier=None,
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        target_length=None,
+        length_bias_strength=None,
+        stop_on_balanced_brackets=false,
+        max_output_bytes=None,
     ))]
     fn new(
         prompt: String,
@@ -93,12 +103,17 @@ impl CompletionRequest {
         grammar_type: Option<String>,
         adapters: Option<Vec<String>>,
         min_p: Option<f64>,
+        top_a: Option<f64>,
         tool_schemas: Option<Vec<String>>,
         tool_choice: Option<ToolChoice>,
         dry_multiplier: Option<f32>,
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        target_length: Option<usize>,
+        length_bias_strength: Option<f32>,
+        stop_on_balanced_brackets: bool,
+        max_output_bytes: Option<usize>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -119,12 +134,17 @@ impl CompletionRequest {
             grammar_type,
             adapters,
             min_p,
+            top_a,
             tool_schemas,
             tool_choice,
             dry_multiplier,
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            target_length,
+            length_bias_strength,
+            stop_on_balanced_brackets,
+            max_output_bytes,
         })
     }
 }
@@ -160,12 +180,17 @@ pub struct ChatCompletionRequest {
     pub(crate) grammar_type: Option<String>,
     pub(crate) adapters: Option<Vec<String>>,
     pub(crate) min_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
     pub(crate) tool_schemas: Option<Vec<String>>,
     pub(crate) tool_choice: Option<ToolChoice>,
     pub(crate) dry_multiplier: Option<f32>,
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) target_length: Option<usize>,
+    pub(crate) length_bias_strength: Option<f32>,
+    pub(crate) stop_on_balanced_brackets: bool,
+    pub(crate) max_output_bytes: Option<usize>,
 }
 
 #[pymethods]
@@ -190,12 +215,17 @@ impl ChatCompletionRequest {
         grammar_type = None,
         adapters = None,
         min_p=None,
+        top_a=None,
         tool_schemas=None,
         tool_choice=None,
         dry_multiplier=None,
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        target_length=None,
+        length_bias_strength=None,
+        stop_on_balanced_brackets=false,
+        max_output_bytes=None,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -216,12 +246,17 @@ impl ChatCompletionRequest {
         grammar_type: Option<String>,
         adapters: Option<Vec<String>>,
         min_p: Option<f64>,
+        top_a: Option<f64>,
         tool_schemas: Option<Vec<String>>,
         tool_choice: Option<ToolChoice>,
         dry_multiplier: Option<f32>,
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        target_length: Option<usize>,
+        length_bias_strength: Option<f32>,
+        stop_on_balanced_brackets: bool,
+        max_output_bytes: Option<usize>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -290,12 +325,17 @@ impl ChatCompletionRequest {
             grammar_type,
             adapters,
             min_p,
+            top_a,
             tool_choice,
             tool_schemas,
             dry_multiplier,
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            target_length,
+            length_bias_strength,
+            stop_on_balanced_brackets,
+            max_output_bytes,
         })
     }
 }
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 use either::Either;
 use mistralrs_core::{DiffusionLoaderType, ModelDType, NormalLoaderType, VisionLoaderType};
@@ -17,6 +17,11 @@ pub enum Architecture {
     Gemma2,
     Starcoder2,
     Phi3_5MoE,
+    Cohere,
+    Arctic,
+    Granite,
+    Qwen2Moe,
+    Mamba,
 }
 
 impl From<Architecture> for NormalLoaderType {
@@ -32,6 +37,11 @@ impl From<Architecture> for NormalLoaderType {
             Architecture::Gemma2 => Self::Gemma2,
             Architecture::Starcoder2 => Self::Starcoder2,
             Architecture::Phi3_5MoE => Self::Phi3_5MoE,
+            Architecture::Cohere => Self::Cohere,
+            Architecture::Arctic => Self::Arctic,
+            Architecture::Granite => Self::Granite,
+            Architecture::Qwen2Moe => Self::Qwen2Moe,
+            Architecture::Mamba => Self::Mamba,
         }
     }
 }
@@ -108,6 +118,7 @@ pub enum Which {
         dtype = ModelDType::Auto,
         imatrix = None,
         calibration_file = None,
+        added_tokens = None,
     ))]
     Plain {
         model_id: String,
@@ -120,6 +131,7 @@ pub enum Which {
         dtype: ModelDType,
         imatrix: Option<PathBuf>,
         calibration_file: Option<PathBuf>,
+        added_tokens: Option<HashMap<String, u32>>,
     },
 
     #[pyo3(constructor = (
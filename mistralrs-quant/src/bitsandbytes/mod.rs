@@ -204,6 +204,7 @@ impl QuantMethod for BnbLinear {
             | QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
+            | QuantMethodConfig::SmoothQuant { .. }
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::FP8 { .. } => unreachable!(),
             QuantMethodConfig::Bnb {
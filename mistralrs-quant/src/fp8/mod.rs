@@ -41,6 +41,7 @@ impl QuantMethod for FP8Linear {
             | QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
+            | QuantMethodConfig::SmoothQuant { .. }
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Bnb { .. } => unreachable!(),
             QuantMethodConfig::FP8 { lin, dtype } => {
@@ -38,6 +38,7 @@ impl QuantMethod for GgufMatMul {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
+            | QuantMethodConfig::SmoothQuant { .. }
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. } => unreachable!(),
         }
@@ -20,6 +20,7 @@ impl QuantMethod for GptqLayer {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
+            | QuantMethodConfig::SmoothQuant { .. }
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. } => {
                 unreachable!()
@@ -527,6 +527,7 @@ impl QuantMethod for HqqLayer {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Dummy
+            | QuantMethodConfig::SmoothQuant { .. }
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. } => {
                 unreachable!()
@@ -21,6 +21,7 @@ mod gguf;
 mod gptq;
 mod hqq;
 mod imatrix;
+mod smoothquant;
 mod unquantized;
 mod utils;
 
@@ -32,6 +33,7 @@ use gptq::gptq_linear;
 pub use gptq::GptqLayer;
 pub use hqq::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
 pub use imatrix::ImatrixLayerStats;
+pub use smoothquant::{fold_smooth_scales, SmoothQuantLinear};
 pub use unquantized::UnquantLinear;
 
 use candle_nn::{Linear, Module, VarBuilder};
@@ -46,6 +48,8 @@ pub enum QuantMethodType {
     #[default]
     #[serde(rename = "bitsandbytes")]
     Bitsandbytes,
+    #[serde(rename = "smoothquant")]
+    SmoothQuant,
 }
 
 impl Display for QuantMethodType {
@@ -53,6 +57,7 @@ impl Display for QuantMethodType {
         match self {
             Self::Gptq => write!(f, "GPTQ"),
             Self::Bitsandbytes => write!(f, "bnb"),
+            Self::SmoothQuant => write!(f, "SmoothQuant"),
             Self::Unreachable => write!(f, "unreachable",),
         }
     }
@@ -69,9 +74,23 @@ pub struct QuantizedConfig {
     pub bnb_4bit_quant_type: Option<String>,
 
     pub quant_method: QuantMethodType,
+
+    /// Set by the model loaders for the SmoothQuant layers whose smoothing scales are folded into the
+    /// preceding norm, so that they are not applied again.
+    #[serde(skip)]
+    pub smooth_scales_folded: bool,
 }
 
 impl QuantizedConfig {
+    /// The config for the layers reading the output of a norm, whose SmoothQuant smoothing scales
+    /// are folded into the norm with [`fold_smooth_scales`]. Other methods are unchanged.
+    pub fn with_smooth_scales_folded(config: &Option<Self>) -> Option<Self> {
+        config.clone().map(|config| Self {
+            smooth_scales_folded: matches!(config.quant_method, QuantMethodType::SmoothQuant),
+            ..config
+        })
+    }
+
     pub fn get_bits_name(&self, _vb: &VarBuilder) -> String {
         match self.bits {
             Some(bits) => format!("{bits} bits"),
@@ -124,6 +143,12 @@ pub enum QuantMethodConfig {
         params: BnbQuantParmas,
         quant_ty: BnbQuantType,
     },
+    SmoothQuant {
+        weight: Tensor,
+        weight_scale: Tensor,
+        smooth_scales: Option<Tensor>,
+        bias: Option<Tensor>,
+    },
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Hash, Eq)]
@@ -296,6 +321,9 @@ pub fn linear_no_bias(
             QuantMethodType::Bitsandbytes => {
                 Arc::new(BnbLinear::linear_b(in_dim, out_dim, false, vb)?) as Arc<_>
             }
+            QuantMethodType::SmoothQuant => Arc::new(SmoothQuantLinear::linear_b(
+                in_dim, out_dim, false, quant_conf, vb,
+            )?) as Arc<_>,
             QuantMethodType::Unreachable => unreachable!(),
         }
     } else {
@@ -325,6 +353,9 @@ pub fn linear(
             QuantMethodType::Bitsandbytes => {
                 Arc::new(BnbLinear::linear_b(in_dim, out_dim, false, vb)?) as Arc<_>
             }
+            QuantMethodType::SmoothQuant => Arc::new(SmoothQuantLinear::linear_b(
+                in_dim, out_dim, true, quant_conf, vb,
+            )?) as Arc<_>,
             QuantMethodType::Unreachable => unreachable!(),
         }
     } else {
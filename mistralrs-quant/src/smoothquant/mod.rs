@@ -0,0 +1,338 @@
+//! Int8 SmoothQuant (W8A8) checkpoints.
+//!
+//! SmoothQuant moves the quantization difficulty of the activation outliers into the weights with
+//! per input channel smoothing scales `s`: `x W^T = (x / s) (W * s)^T`. The checkpoint stores the
+//! smoothed weights quantized to int8 per output channel, with their `weight_scale`, and the
+//! `smooth_scales` which the input must be divided by. The activations are quantized to int8 per
+//! token at runtime.
+//!
+//! This is a compatibility loader, so that SmoothQuant checkpoints can be run, with no memory or
+//! speed benefit. The int8 weight is dequantized once at load, in the model dtype, so the weight
+//! takes the memory of the model dtype and the matmul runs in the model dtype on all devices. The
+//! activations are only quantized and dequantized again, to keep the accuracy of the W8A8 model,
+//! which adds work to each forward pass instead of saving any.
+
+use std::{
+    num::NonZeroUsize,
+    sync::{atomic::AtomicUsize, Arc},
+};
+
+use candle_core::{DType, Device, Result, Tensor, D};
+use candle_nn::VarBuilder;
+
+use crate::{
+    IsqType, QuantMethod, QuantMethodConfig, QuantMethodType, QuantizedConfig, QuantizedSerde,
+};
+
+/// The largest magnitude of a symmetric int8 value.
+const INT8_MAX: f32 = 127.;
+
+/// The largest relative difference between the smoothing scales of layers sharing a norm.
+const SHARED_SCALES_TOLERANCE: f64 = 1e-5;
+
+#[derive(Debug)]
+pub struct SmoothQuantLinear {
+    /// The weight dequantized with its per output channel scales, in the model dtype.
+    weight: Tensor,
+    /// Divides the input if the scales are not folded into the preceding norm, in f32.
+    smooth_scales: Option<Tensor>,
+    bias: Option<Tensor>,
+    dtype: DType,
+}
+
+impl SmoothQuantLinear {
+    pub fn linear_b(
+        in_dim: usize,
+        out_dim: usize,
+        bias: bool,
+        config: &QuantizedConfig,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let weight =
+            vb.get_with_hints_dtype((out_dim, in_dim), "weight", Default::default(), DType::F32)?;
+        // Exports store the scales as `(out_dim,)` or `(out_dim, 1)`
+        let weight_scale = vb
+            .get_unchecked_dtype("weight_scale", DType::F32)?
+            .flatten_all()?;
+        if weight_scale.dim(0)? != out_dim {
+            candle_core::bail!(
+                "Expected {out_dim} SmoothQuant weight scales, got {}.",
+                weight_scale.dim(0)?
+            );
+        }
+        let smooth_scales = if !config.smooth_scales_folded && vb.contains_tensor("smooth_scales") {
+            Some(vb.get_with_hints_dtype(
+                (in_dim,),
+                "smooth_scales",
+                Default::default(),
+                DType::F32,
+            )?)
+        } else {
+            None
+        };
+        let bias = if bias {
+            Some(vb.get((out_dim,), "bias")?)
+        } else {
+            None
+        };
+        Ok(Self {
+            weight: dequantize(&weight, &weight_scale, vb.dtype())?,
+            smooth_scales,
+            bias,
+            dtype: vb.dtype(),
+        })
+    }
+}
+
+/// Dequantize the int8 `weight` of shape `(out_dim, in_dim)` with the scales of its output channels.
+fn dequantize(weight: &Tensor, weight_scale: &Tensor, dtype: DType) -> Result<Tensor> {
+    weight
+        .to_dtype(DType::F32)?
+        .broadcast_mul(&weight_scale.to_dtype(DType::F32)?.unsqueeze(1)?)?
+        .to_dtype(dtype)
+}
+
+impl QuantMethod for SmoothQuantLinear {
+    fn new(method: QuantMethodConfig) -> candle_core::Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Gptq { .. }
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Dummy
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Bnb { .. } => unreachable!(),
+            QuantMethodConfig::SmoothQuant {
+                weight,
+                weight_scale,
+                smooth_scales,
+                bias,
+            } => {
+                let dtype = bias.as_ref().map_or(DType::F32, |b| b.dtype());
+                Ok(Self {
+                    weight: dequantize(&weight, &weight_scale.flatten_all()?, dtype)?,
+                    smooth_scales: smooth_scales.map(|s| s.to_dtype(DType::F32)).transpose()?,
+                    dtype,
+                    bias,
+                })
+            }
+        }
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut x = xs.to_dtype(DType::F32)?;
+        if let Some(smooth_scales) = &self.smooth_scales {
+            x = x.broadcast_div(smooth_scales)?;
+        }
+        // Quantize the activations per token, as in the exported model
+        let x_scale = (x.abs()?.max_keepdim(D::Minus1)? / f64::from(INT8_MAX))?
+            .clamp(f32::EPSILON, f32::MAX)?;
+        let xq = x
+            .broadcast_div(&x_scale)?
+            .round()?
+            .clamp(-INT8_MAX, INT8_MAX)?
+            .broadcast_mul(&x_scale)?;
+        let res = xq
+            .to_dtype(self.weight.dtype())?
+            .broadcast_matmul(&self.weight.t()?)?
+            .to_dtype(xs.dtype())?;
+        match &self.bias {
+            Some(bias) => res.broadcast_add(&bias.to_dtype(xs.dtype())?),
+            None => Ok(res),
+        }
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        None
+    }
+
+    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        candle_core::bail!("SmoothQuant quantization does not support adding weight delta.")
+    }
+
+    fn dtype_and_device(&self) -> (DType, Device) {
+        (self.dtype, self.weight.device().clone())
+    }
+
+    fn get_bias_mut(&mut self) -> Option<&mut Tensor> {
+        self.bias.as_mut()
+    }
+
+    fn apply_isq(
+        self: Arc<Self>,
+        _dtype: Option<IsqType>,
+        _device: Device,
+        _n_quantized: &AtomicUsize,
+        _imatrix_weight: Option<Vec<f32>>,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        candle_core::bail!("SmoothQuant layers are already quantized, ISQ cannot be applied.")
+    }
+
+    fn get_max_isq_cpu_threads(&self, _dtype: IsqType) -> Option<NonZeroUsize> {
+        None
+    }
+}
+
+impl QuantizedSerde for SmoothQuantLinear {
+    fn name(&self) -> &'static str {
+        "smoothquant-linear"
+    }
+}
+
+/// Fold the SmoothQuant smoothing scales of the `layers` reading the output of a norm into the
+/// norm weight, dividing it by the scales. The layers must share their scales and be loaded with
+/// [`QuantizedConfig::with_smooth_scales_folded`]. For other methods, the weight is unchanged.
+pub fn fold_smooth_scales(
+    norm_weight: &Tensor,
+    config: &Option<QuantizedConfig>,
+    layers: &[VarBuilder],
+) -> Result<Tensor> {
+    if !config
+        .as_ref()
+        .is_some_and(|c| matches!(c.quant_method, QuantMethodType::SmoothQuant))
+    {
+        return Ok(norm_weight.clone());
+    }
+    let dim = norm_weight.dim(0)?;
+    let mut shared: Option<Tensor> = None;
+    for vb in layers {
+        if !vb.contains_tensor("smooth_scales") {
+            continue;
+        }
+        let scales = vb
+            .get_with_hints_dtype((dim,), "smooth_scales", Default::default(), DType::F32)?
+            .to_device(norm_weight.device())?;
+        match &shared {
+            Some(shared) => {
+                let max_diff = (&scales - shared)?.abs()?.max(0)?.to_scalar::<f32>()?;
+                let max = shared.abs()?.max(0)?.to_scalar::<f32>()?;
+                if f64::from(max_diff) > SHARED_SCALES_TOLERANCE * f64::from(max) {
+                    candle_core::bail!(
+                        "The layers reading the output of a norm must share their SmoothQuant smoothing scales to fold them, they differ by up to {max_diff}."
+                    );
+                }
+            }
+            None => shared = Some(scales),
+        }
+    }
+    match shared {
+        Some(scales) => norm_weight
+            .to_dtype(DType::F32)?
+            .broadcast_div(&scales)?
+            .to_dtype(norm_weight.dtype()),
+        None => Ok(norm_weight.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Result, Tensor, D};
+    use candle_nn::VarBuilder;
+
+    use super::{fold_smooth_scales, SmoothQuantLinear};
+    use crate::{QuantMethod, QuantMethodConfig, QuantMethodType, QuantizedConfig};
+
+    fn max_abs(t: &Tensor) -> Result<f32> {
+        t.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    /// Random activations with a few outlier channels, and weights, of shapes `(tokens, in_dim)`
+    /// and `(out_dim, in_dim)`.
+    fn activations_and_weight(dev: &Device) -> Result<(Tensor, Tensor)> {
+        let (tokens, in_dim, out_dim) = (16, 64, 32);
+        let outliers = Tensor::from_vec(
+            (0..in_dim)
+                .map(|i| if i % 16 == 3 { 50f32 } else { 1. })
+                .collect::<Vec<_>>(),
+            (in_dim,),
+            dev,
+        )?;
+        let x = Tensor::randn(0f32, 1., (tokens, in_dim), dev)?.broadcast_mul(&outliers)?;
+        let w = Tensor::randn(0f32, 0.1, (out_dim, in_dim), dev)?;
+        Ok((x, w))
+    }
+
+    /// The reference SmoothQuant export of `w`: the smoothing scales for the activations `x`, with
+    /// alpha of 0.5, and the smoothed weight quantized per output channel.
+    fn smooth_then_quantize(x: &Tensor, w: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
+        let x_max = x.abs()?.max(0)?;
+        let w_max = w.abs()?.max(0)?;
+        let smooth_scales = (x_max.sqrt()? / w_max.sqrt()?)?;
+        let smoothed = w.broadcast_mul(&smooth_scales)?;
+        let weight_scale = (smoothed.abs()?.max_keepdim(D::Minus1)? / 127.)?;
+        let weight = smoothed.broadcast_div(&weight_scale)?.round()?;
+        Ok((weight, weight_scale.flatten_all()?, smooth_scales))
+    }
+
+    #[test]
+    fn test_smoothquant_matmul() -> Result<()> {
+        let dev = Device::Cpu;
+        let (x, w) = activations_and_weight(&dev)?;
+        let (weight, weight_scale, smooth_scales) = smooth_then_quantize(&x, &w)?;
+        let layer = SmoothQuantLinear::new(QuantMethodConfig::SmoothQuant {
+            weight,
+            weight_scale,
+            smooth_scales: Some(smooth_scales),
+            bias: None,
+        })?;
+
+        let expected = x.matmul(&w.t()?)?;
+        let err = max_abs(&(layer.forward(&x)? - &expected)?)?;
+        assert!(
+            err < 0.03 * max_abs(&expected)?,
+            "SmoothQuant error {err} is too large"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold_smooth_scales_into_norm() -> Result<()> {
+        let dev = Device::Cpu;
+        let (x, w) = activations_and_weight(&dev)?;
+        let (weight, weight_scale, smooth_scales) = smooth_then_quantize(&x, &w)?;
+        let in_dim = x.dim(1)?;
+        let out_dim = w.dim(0)?;
+        let mut tensors = HashMap::new();
+        for layer in ["q_proj", "k_proj"] {
+            tensors.insert(format!("{layer}.weight"), weight.clone());
+            tensors.insert(format!("{layer}.weight_scale"), weight_scale.clone());
+            tensors.insert(format!("{layer}.smooth_scales"), smooth_scales.clone());
+        }
+        let vb = VarBuilder::from_tensors(tensors, DType::F32, &dev);
+        let config = Some(QuantizedConfig {
+            quant_method: QuantMethodType::SmoothQuant,
+            ..Default::default()
+        });
+
+        let norm_weight = Tensor::ones((in_dim,), DType::F32, &dev)?;
+        let folded =
+            fold_smooth_scales(&norm_weight, &config, &[vb.pp("q_proj"), vb.pp("k_proj")])?;
+        let applied = SmoothQuantLinear::linear_b(
+            in_dim,
+            out_dim,
+            false,
+            config.as_ref().unwrap(),
+            vb.pp("q_proj"),
+        )?;
+        let folded_layer = SmoothQuantLinear::linear_b(
+            in_dim,
+            out_dim,
+            false,
+            QuantizedConfig::with_smooth_scales_folded(&config)
+                .as_ref()
+                .unwrap(),
+            vb.pp("q_proj"),
+        )?;
+
+        // Scaling the input by the folded norm weight is the same as applying the scales
+        let err =
+            max_abs(&(folded_layer.forward(&x.broadcast_mul(&folded)?)? - applied.forward(&x)?)?)?;
+        assert!(err < 1e-3, "{err}");
+        Ok(())
+    }
+}
@@ -35,6 +35,7 @@ impl QuantMethod for UnquantLinear {
             | QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
+            | QuantMethodConfig::SmoothQuant { .. }
             | QuantMethodConfig::FP8 { .. }
             | QuantMethodConfig::Bnb { .. } => unreachable!(),
             QuantMethodConfig::Unquantized(l) => Ok(Self {
@@ -12,7 +12,7 @@ use std::{
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::{
-    openai::{ChatCompletionRequest, Grammar, MessageInnerContent, StopTokens},
+    openai::{ChatCompletionRequest, Grammar, MessageInnerContent, ResponseFormat, StopTokens},
     util,
 };
 use anyhow::{Context as _, Result};
@@ -27,8 +27,9 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    ChatCompletionResponse, Constraint, DrySamplingParams, LengthBiasParams, MistralRs,
+    NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 
@@ -44,6 +45,7 @@ impl std::error::Error for ModelErrorMessage {}
 pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
+    include_usage: bool,
     state: Arc<MistralRs>,
 }
 
@@ -70,10 +72,13 @@ impl futures::Stream for Streamer {
                     MistralRs::maybe_log_error(self.state.clone(), &*e);
                     Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
                 }
-                Response::Chunk(response) => {
+                Response::Chunk(mut response) => {
                     if response.choices.iter().all(|x| x.finish_reason.is_some()) {
                         self.is_done = true;
                     }
+                    if !self.include_usage {
+                        response.usage = None;
+                    }
                     MistralRs::maybe_log_response(self.state.clone(), &response);
                     Poll::Ready(Some(Event::default().json_data(response)))
                 }
@@ -300,6 +305,18 @@ async fn parse_request(
         None
     };
 
+    let constraint = match (oairequest.response_format, oairequest.grammar) {
+        (Some(ResponseFormat::JsonObject), Some(_)) => {
+            anyhow::bail!("A `json_object` response format cannot be combined with a grammar.")
+        }
+        (Some(ResponseFormat::JsonObject), None) => Constraint::JsonObject,
+        (_, Some(Grammar::Regex(regex))) => Constraint::Regex(regex),
+        (_, Some(Grammar::Lark(lark))) => Constraint::Lark(lark),
+        (_, Some(Grammar::JsonSchema(schema))) => Constraint::JsonSchema(schema),
+        (_, Some(Grammar::Llguidance(llguidance))) => Constraint::Llguidance(llguidance),
+        (_, None) => Constraint::None,
+    };
+
     let is_streaming = oairequest.stream.unwrap_or(false);
     Ok((
         Request::Normal(NormalRequest {
@@ -310,6 +327,7 @@ async fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
+                top_a: oairequest.top_a,
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
@@ -318,23 +336,28 @@ async fn parse_request(
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                length_bias: oairequest
+                    .target_length
+                    .map(|target_length| LengthBiasParams {
+                        target_length,
+                        strength: oairequest.length_bias_strength.unwrap_or(1.0),
+                    }),
+                stop_on_balanced_brackets: oairequest.stop_on_balanced_brackets.unwrap_or(false),
+                max_output_bytes: oairequest.max_output_bytes,
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
             is_streaming,
             suffix: None,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                Some(Grammar::Lark(lark)) => Constraint::Lark(lark),
-                Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema),
-                Some(Grammar::Llguidance(llguidance)) => Constraint::Llguidance(llguidance),
-                None => Constraint::None,
-            },
+            constraint,
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
             return_raw_logits: false,
+            continue_final_message: oairequest.continue_final_message,
+            reasoning_markers: oairequest.reasoning_markers,
+            assistant_prefix: oairequest.assistant_prefix,
         }),
         is_streaming,
     ))
@@ -352,6 +375,10 @@ pub async fn chatcompletions(
     Json(oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
     let (tx, mut rx) = channel(10_000);
+    let include_usage = oairequest
+        .stream_options
+        .as_ref()
+        .is_some_and(|opts| opts.include_usage);
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
         Ok(x) => x,
         Err(e) => {
@@ -372,6 +399,7 @@ pub async fn chatcompletions(
         let streamer = Streamer {
             rx,
             is_done: false,
+            include_usage,
             state,
         };
 
@@ -19,8 +19,8 @@ use axum::{
     },
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    CompletionResponse, Constraint, DrySamplingParams, LengthBiasParams, MistralRs, NormalRequest,
+    Request, RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 use tracing::warn;
@@ -37,6 +37,7 @@ impl std::error::Error for ModelErrorMessage {}
 pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
+    include_usage: bool,
     state: Arc<MistralRs>,
 }
 
@@ -63,10 +64,13 @@ impl futures::Stream for Streamer {
                     MistralRs::maybe_log_error(self.state.clone(), &*e);
                     Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
                 }
-                Response::CompletionChunk(response) => {
+                Response::CompletionChunk(mut response) => {
                     if response.choices.iter().all(|x| x.finish_reason.is_some()) {
                         self.is_done = true;
                     }
+                    if !self.include_usage {
+                        response.usage = None;
+                    }
                     MistralRs::maybe_log_response(self.state.clone(), &response);
                     Poll::Ready(Some(Event::default().json_data(response)))
                 }
@@ -144,7 +148,7 @@ impl IntoResponse for CompletionResponder {
     }
 }
 
-fn parse_request(
+pub(crate) fn parse_request(
     oairequest: CompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
@@ -187,6 +191,7 @@ fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
+                top_a: oairequest.top_a,
                 top_n_logprobs: 1,
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
@@ -195,6 +200,14 @@ fn parse_request(
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                length_bias: oairequest
+                    .target_length
+                    .map(|target_length| LengthBiasParams {
+                        target_length,
+                        strength: oairequest.length_bias_strength.unwrap_or(1.0),
+                    }),
+                stop_on_balanced_brackets: oairequest.stop_on_balanced_brackets.unwrap_or(false),
+                max_output_bytes: oairequest.max_output_bytes,
             },
             response: tx,
             return_logprobs: false,
@@ -212,6 +225,9 @@ fn parse_request(
             tools: oairequest.tools,
             logits_processors: None,
             return_raw_logits: false,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: None,
         }),
         is_streaming,
     ))
@@ -236,6 +252,10 @@ pub async fn completions(
         );
     }
 
+    let include_usage = oairequest
+        .stream_options
+        .as_ref()
+        .is_some_and(|opts| opts.include_usage);
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
         Err(e) => {
@@ -256,6 +276,7 @@ pub async fn completions(
         let streamer = Streamer {
             rx,
             is_done: false,
+            include_usage,
             state,
         };
 
@@ -0,0 +1,414 @@
+//! Long-polling generation for clients which cannot consume server-sent events.
+//!
+//! `POST /generate` starts a completion and returns its id immediately, the generation continues
+//! in the background and `GET /generate/{id}` returns the text accumulated so far.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response as AxumResponse},
+};
+use mistralrs_core::{MistralRs, Request, Response, Usage};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::mpsc::{channel, Receiver};
+
+use crate::{completions::parse_request, openai::CompletionRequest};
+
+/// Finished generations which are never polled again are dropped after this long.
+const FINISHED_TTL: Duration = Duration::from_secs(10 * 60);
+
+static GENERATIONS: Lazy<GenerationRegistry> = Lazy::new(GenerationRegistry::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GenerationStatus {
+    Running,
+    Done,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PolledChoice {
+    pub index: usize,
+    pub text: String,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GenerationPoll {
+    pub id: usize,
+    pub status: GenerationStatus,
+    pub choices: Vec<PolledChoice>,
+    pub usage: Option<Usage>,
+    pub error: Option<String>,
+    /// While running, the position of the generation among those waiting for the concurrent
+    /// sequence limit.
+    pub queue_position: Option<usize>,
+}
+
+struct Generation {
+    poll: GenerationPoll,
+    finished_at: Option<Instant>,
+}
+
+/// The in-progress and finished generations, by request id.
+#[derive(Default, Clone)]
+pub struct GenerationRegistry(Arc<Mutex<HashMap<usize, Generation>>>);
+
+impl GenerationRegistry {
+    fn insert(&self, id: usize) {
+        let mut generations = self.0.lock().unwrap();
+        generations.retain(|_, generation| {
+            generation
+                .finished_at
+                .map_or(true, |finished_at| finished_at.elapsed() < FINISHED_TTL)
+        });
+        generations.insert(
+            id,
+            Generation {
+                poll: GenerationPoll {
+                    id,
+                    status: GenerationStatus::Running,
+                    choices: Vec::new(),
+                    usage: None,
+                    error: None,
+                    queue_position: None,
+                },
+                finished_at: None,
+            },
+        );
+    }
+
+    fn finish(generation: &mut Generation, status: GenerationStatus, error: Option<String>) {
+        generation.poll.status = status;
+        generation.poll.error = error;
+        generation.finished_at = Some(Instant::now());
+    }
+
+    /// Apply a response of the engine. Returns `false` once the generation is finished.
+    fn update(&self, id: usize, response: Response) -> bool {
+        let mut generations = self.0.lock().unwrap();
+        let Some(generation) = generations.get_mut(&id) else {
+            return false;
+        };
+        match response {
+            Response::CompletionChunk(chunk) => {
+                for choice in chunk.choices {
+                    let choices = &mut generation.poll.choices;
+                    let pos = match choices.iter().position(|c| c.index == choice.index) {
+                        Some(pos) => pos,
+                        None => {
+                            choices.push(PolledChoice {
+                                index: choice.index,
+                                text: String::new(),
+                                finish_reason: None,
+                            });
+                            choices.len() - 1
+                        }
+                    };
+                    let polled = &mut choices[pos];
+                    polled.text.push_str(&choice.text);
+                    polled.finish_reason = choice.finish_reason;
+                }
+                if chunk.usage.is_some() {
+                    generation.poll.usage = chunk.usage;
+                }
+                let done = !generation.poll.choices.is_empty()
+                    && generation
+                        .poll
+                        .choices
+                        .iter()
+                        .all(|c| c.finish_reason.is_some());
+                if done {
+                    Self::finish(generation, GenerationStatus::Done, None);
+                }
+                !done
+            }
+            Response::ModelError(msg, _) | Response::CompletionModelError(msg, _) => {
+                Self::finish(generation, GenerationStatus::Error, Some(msg));
+                false
+            }
+            Response::ValidationError(e) | Response::InternalError(e) => {
+                Self::finish(generation, GenerationStatus::Error, Some(e.to_string()));
+                false
+            }
+            Response::Done(_)
+            | Response::CompletionDone(_)
+            | Response::Chunk(_)
+            | Response::ImageGeneration(_)
+            | Response::Raw { .. } => {
+                Self::finish(
+                    generation,
+                    GenerationStatus::Error,
+                    Some("Received an unexpected response for a streamed generation.".to_string()),
+                );
+                false
+            }
+        }
+    }
+
+    /// The state of a generation. Once it is finished, it is removed by this call.
+    fn poll(&self, id: usize) -> Option<GenerationPoll> {
+        let mut generations = self.0.lock().unwrap();
+        let generation = generations.get(&id)?;
+        if generation.finished_at.is_some() {
+            generations.remove(&id).map(|generation| generation.poll)
+        } else {
+            Some(generation.poll.clone())
+        }
+    }
+
+    /// Drop a generation, whatever its state.
+    fn remove(&self, id: usize) {
+        self.0.lock().unwrap().remove(&id);
+    }
+
+    /// Consume the responses of a generation until it finishes.
+    async fn track(self, id: usize, mut rx: Receiver<Response>) {
+        while let Some(response) = rx.recv().await {
+            if !self.update(id, response) {
+                return;
+            }
+        }
+        let mut generations = self.0.lock().unwrap();
+        if let Some(generation) = generations.get_mut(&id) {
+            if generation.finished_at.is_none() {
+                Self::finish(
+                    generation,
+                    GenerationStatus::Error,
+                    Some("The generation stopped without finishing.".to_string()),
+                );
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GenerationStarted {
+    id: usize,
+}
+
+#[derive(Serialize)]
+struct GenerationError {
+    message: String,
+}
+
+fn error_response(code: StatusCode, message: String) -> AxumResponse {
+    let mut r = Json(GenerationError { message }).into_response();
+    *r.status_mut() = code;
+    r
+}
+
+pub async fn start_generation(
+    State(state): State<Arc<MistralRs>>,
+    Json(mut oairequest): Json<CompletionRequest>,
+) -> AxumResponse {
+    if oairequest.logprobs.is_some() {
+        return error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Completion requests do not support logprobs.".to_string(),
+        );
+    }
+    // The generation is always streamed internally so that partial output can be polled.
+    oairequest.stream = Some(true);
+
+    let (tx, rx) = channel(10_000);
+    let (request, _) = match parse_request(oairequest, state.clone(), tx) {
+        Ok(x) => x,
+        Err(e) => {
+            let e = anyhow::Error::msg(e.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    };
+    let Request::Normal(ref normal_request) = request else {
+        unreachable!()
+    };
+    let id = normal_request.id;
+
+    GENERATIONS.insert(id);
+    if let Err(e) = state.get_sender().unwrap().send(request).await {
+        GENERATIONS.remove(id);
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+    tokio::spawn(GENERATIONS.clone().track(id, rx));
+
+    Json(GenerationStarted { id }).into_response()
+}
+
+pub async fn poll_generation(
+    State(state): State<Arc<MistralRs>>,
+    Path(id): Path<usize>,
+) -> AxumResponse {
+    match GENERATIONS.poll(id) {
+        Some(mut poll) => {
+            if poll.status == GenerationStatus::Running {
+                poll.queue_position = state.queue_position(id).await.ok().flatten();
+            }
+            Json(poll).into_response()
+        }
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            format!("No generation with id `{id}`."),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request as HttpRequest, StatusCode},
+        Router,
+    };
+    use mistralrs_core::{
+        testing::{load_tiny_llama, write_tiny_llama, TempDir},
+        CompletionChunkChoice, CompletionChunkResponse, DefaultSchedulerMethod, MistralRsBuilder,
+        Response, SchedulerConfig,
+    };
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc::channel;
+    use tower::ServiceExt;
+
+    use super::{GenerationRegistry, GenerationStatus};
+
+    fn chunk(text: &str, finish_reason: Option<&str>) -> Response {
+        Response::CompletionChunk(CompletionChunkResponse {
+            id: "0".to_string(),
+            choices: vec![CompletionChunkChoice {
+                text: text.to_string(),
+                index: 0,
+                logprobs: None,
+                finish_reason: finish_reason.map(ToString::to_string),
+            }],
+            created: 0,
+            model: "test".to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "text_completion".to_string(),
+            usage: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_poll_generation_to_completion() {
+        let registry = GenerationRegistry::default();
+        let (tx, rx) = channel(16);
+        registry.insert(3);
+        let tracker = tokio::spawn(registry.clone().track(3, rx));
+
+        tx.send(chunk("Hello", None)).await.unwrap();
+        tx.send(chunk(",", None)).await.unwrap();
+        loop {
+            let poll = registry.poll(3).unwrap();
+            assert_eq!(poll.status, GenerationStatus::Running);
+            if poll.choices.first().is_some_and(|c| c.text == "Hello,") {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+
+        tx.send(chunk(" world", Some("stop"))).await.unwrap();
+        tracker.await.unwrap();
+        let poll = registry.poll(3).unwrap();
+        assert_eq!(poll.status, GenerationStatus::Done);
+        assert_eq!(poll.choices[0].text, "Hello, world");
+        assert_eq!(poll.choices[0].finish_reason.as_deref(), Some("stop"));
+
+        // The finished generation is removed once it has been returned
+        assert!(registry.poll(3).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_response_is_an_error() {
+        let registry = GenerationRegistry::default();
+        let (tx, rx) = channel(16);
+        registry.insert(4);
+        let tracker = tokio::spawn(registry.clone().track(4, rx));
+
+        tx.send(Response::Raw {
+            logits_chunks: vec![],
+            tokens: vec![],
+        })
+        .await
+        .unwrap();
+        tracker.await.unwrap();
+        let poll = registry.poll(4).unwrap();
+        assert_eq!(poll.status, GenerationStatus::Error);
+        assert!(poll.error.is_some());
+    }
+
+    #[test]
+    fn test_remove_running_generation() {
+        let registry = GenerationRegistry::default();
+        registry.insert(5);
+        assert_eq!(registry.poll(5).unwrap().status, GenerationStatus::Running);
+        registry.remove(5);
+        assert!(registry.poll(5).is_none());
+    }
+
+    /// The status code and JSON body of a request to the server.
+    async fn call(
+        router: &Router,
+        request: HttpRequest<Body>,
+    ) -> anyhow::Result<(StatusCode, Value)> {
+        let response = router.clone().oneshot(request).await?;
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        Ok((status, serde_json::from_slice(&body)?))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_start_and_poll_generation() -> anyhow::Result<()> {
+        let dir = TempDir::new("server_generate");
+        write_tiny_llama(dir.path(), &["a"])?;
+        let state = MistralRsBuilder::new(
+            load_tiny_llama(dir.path())?,
+            SchedulerConfig::DefaultScheduler {
+                method: DefaultSchedulerMethod::Fixed(NonZeroUsize::new(4).unwrap()),
+            },
+        )
+        .build();
+        let router = crate::get_router(state);
+
+        let (status, started) = call(
+            &router,
+            HttpRequest::post("/generate")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    json!({ "prompt": "Hello", "max_tokens": 8 }).to_string(),
+                ))?,
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK, "{started}");
+        let id = started["id"].as_u64().expect("Expected an id");
+
+        let poll = |id| HttpRequest::get(format!("/generate/{id}")).body(Body::empty());
+        let done = loop {
+            let (status, polled) = call(&router, poll(id)?).await?;
+            assert_eq!(status, StatusCode::OK, "{polled}");
+            match polled["status"].as_str() {
+                Some("running") => tokio::time::sleep(Duration::from_millis(10)).await,
+                Some("done") => break polled,
+                _ => anyhow::bail!("Unexpected poll {polled}"),
+            }
+        };
+        assert_eq!(done["choices"][0]["text"], "a".repeat(8));
+        assert_eq!(done["choices"][0]["finish_reason"], "length");
+
+        // The finished generation was removed when it was returned
+        let (status, _) = call(&router, poll(id)?).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
@@ -83,6 +83,9 @@ fn parse_request(
         tools: None,
         logits_processors: None,
         return_raw_logits: false,
+        continue_final_message: false,
+        reasoning_markers: None,
+        assistant_prefix: None,
     }))
 }
 
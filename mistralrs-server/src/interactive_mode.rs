@@ -87,6 +87,7 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        top_a: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
@@ -95,6 +96,9 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        length_bias: None,
+        stop_on_balanced_brackets: false,
+        max_output_bytes: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -177,6 +181,9 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: None,
         });
         sender.send(req).await.unwrap();
 
@@ -279,6 +286,7 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        top_a: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
@@ -287,6 +295,9 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        length_bias: None,
+        stop_on_balanced_brackets: false,
+        max_output_bytes: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -398,6 +409,9 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: None,
         });
         sender.send(req).await.unwrap();
 
@@ -521,6 +535,9 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>) {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: None,
         });
 
         let start = Instant::now();
@@ -8,22 +8,26 @@ use axum::{
 use candle_core::Device;
 use clap::Parser;
 use mistralrs_core::{
-    get_model_dtype, get_tgt_non_granular_index, initialize_logging, paged_attn_supported,
-    parse_isq_value, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata, IsqType,
-    Loader, LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected,
-    PagedAttentionConfig, Request, SchedulerConfig, TokenSource,
+    get_model_dtype, get_tgt_non_granular_index, initialize_logging, load_on_device,
+    paged_attn_supported, parse_isq_value, set_rope_table_cache, verify_flash_attention,
+    DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata, DeviceSpec, IsqType,
+    KvCacheBackendType, Loader, LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder,
+    ModelDType, ModelSelected, PagedAttentionConfig, PrefixCacheStats, PromptLimitPolicy, Request,
+    SchedulerConfig, TokenSource,
 };
 use openai::{
     ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, Message, ModelObjects,
-    StopTokens,
+    StopTokens, StreamOptions,
 };
 use serde::{Deserialize, Serialize};
-use std::{num::NonZeroUsize, sync::Arc};
+use std::{num::NonZeroUsize, sync::Arc, time::Duration};
 
 mod chat_completion;
 mod completions;
+mod generate;
 mod image_generation;
 mod interactive_mode;
+mod next_tokens;
 mod openai;
 mod util;
 
@@ -31,7 +35,9 @@ use crate::openai::ModelObject;
 use crate::{
     chat_completion::{__path_chatcompletions, chatcompletions},
     completions::completions,
+    generate::{poll_generation, start_generation},
     image_generation::image_generation,
+    next_tokens::next_tokens,
 };
 
 use interactive_mode::interactive_mode;
@@ -48,6 +54,10 @@ fn parse_token_source(s: &str) -> Result<TokenSource, String> {
     s.parse()
 }
 
+fn parse_prompt_limit_policy(s: &str) -> Result<PromptLimitPolicy, String> {
+    s.parse()
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -73,6 +83,42 @@ struct Args {
     #[clap(long, short, action)]
     truncate_sequence: bool,
 
+    /// Maximum number of prompt tokens admitted for a single request, independent of the model's
+    /// maximum length. Prompts over this limit are handled according to `max-prompt-tokens-policy`.
+    #[arg(long)]
+    max_prompt_tokens: Option<usize>,
+
+    /// What to do with prompts over `max-prompt-tokens`: `reject` or `truncate` (keeping the last tokens).
+    #[arg(long, default_value_t = PromptLimitPolicy::Reject, value_parser = parse_prompt_limit_policy)]
+    max_prompt_tokens_policy: PromptLimitPolicy,
+
+    /// Maximum number of sequences running at once. Further requests wait in a queue until a running sequence finishes.
+    /// Unlike `max-seqs`, this is checked before the scheduler, so it is never exceeded.
+    #[arg(long)]
+    max_concurrent_sequences: Option<NonZeroUsize>,
+
+    /// Model ID of a smaller plain model, loaded alongside the primary model, which serves the
+    /// requests whose admission would exceed `fallback-max-admission-tokens` on the primary model,
+    /// and the prompts longer than the maximum sequence length of the primary model.
+    #[arg(long, requires = "fallback_max_admission_tokens")]
+    fallback_model_id: Option<String>,
+
+    /// KV cache token budget of a request on the primary model: number of choices times the maximum
+    /// sequence length. Requests over it are routed to the fallback model.
+    #[arg(long, requires = "fallback_model_id")]
+    fallback_max_admission_tokens: Option<usize>,
+
+    /// Write the logits of every generation step to `<DIR>/<request id>_<choice index>_<n>.npy` for offline analysis,
+    /// where `n` makes the file name unique.
+    /// This is memory and disk heavy: each step writes the full vocabulary as f32.
+    #[arg(long)]
+    dump_logits: Option<String>,
+
+    /// On SIGTERM or Ctrl-C, the number of seconds in-flight requests are given to finish before
+    /// they are canceled. New requests are rejected in the meantime.
+    #[arg(long, default_value_t = 30)]
+    drain_timeout: u64,
+
     /// Model selector
     #[clap(subcommand)]
     model: ModelSelected,
@@ -100,10 +146,32 @@ struct Args {
     #[clap(long, short, action)]
     interactive_mode: bool,
 
-    /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on a LRU strategy.
+    /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on a LRU strategy,
+    /// and dropped beyond as many on the CPU.
     #[arg(long, default_value_t = 16)]
     prefix_cache_n: usize,
 
+    /// System prompt to run at startup so that its KV cache is in the prefix cache. It is rendered with the chat template,
+    /// so chat requests starting with the same system message reuse it. This may be specified multiple times.
+    #[arg(long = "warmup-prefix")]
+    warmup_prefixes: Vec<String>,
+
+    /// Device to load the model on, such as `cuda:1` to select the second GPU: `cpu`, `cuda`, `cuda:N`, `metal` or `metal:N`.
+    /// By default, the first GPU is used if available.
+    #[arg(long)]
+    device: Option<DeviceSpec>,
+
+    /// If the device selected with `--device` is unavailable or loading the model onto it fails, for example because it
+    /// runs out of memory, warn and load the model on the CPU instead.
+    #[arg(long)]
+    allow_cpu_fallback: bool,
+
+    /// After loading, check that flash attention agrees with the dense attention implementation for the model's head
+    /// layout and sliding window on random inputs, and exit if they differ. The maximum absolute difference is logged.
+    /// This has no effect unless built with flash attention.
+    #[arg(long)]
+    verify_attention: bool,
+
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
     /// ORD:NUM;... Where ORD is a unique device ordinal and NUM is the number of layers for that device.
@@ -147,8 +215,19 @@ struct Args {
     throughput_log: bool,
 
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
-    #[arg(long = "prompt-batchsize")]
+    /// The KV cache is accumulated across chunks, so the result matches an unchunked prefill.
+    #[arg(long = "prompt-batchsize", alias = "prefill-chunk-size")]
     prompt_batchsize: Option<usize>,
+
+    /// Share the rotary sin and cos tables between models with the same RoPE parameters, which
+    /// speeds up loading several models. The shared tables are dropped when a model is unloaded.
+    #[arg(long = "cache-rope-tables", default_value_t = false)]
+    cache_rope_tables: bool,
+
+    /// Where plain models store their KV cache: `contiguous` on the device, or `offloaded` to CPU
+    /// memory for contexts which do not fit on the device, at the cost of a copy per step.
+    #[arg(long = "kv-cache-backend", default_value = "contiguous")]
+    kv_cache_backend: KvCacheBackendType,
 }
 
 #[utoipa::path(
@@ -165,6 +244,7 @@ async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
             object: "model",
             created: state.get_creation_time(),
             owned_by: "local",
+            max_model_len: state.config().max_seq_len,
         }],
     })
 }
@@ -227,12 +307,28 @@ async fn re_isq(
     Ok(repr)
 }
 
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/prefix_cache_stats",
+    responses((status = 200, description = "The prefix cache hits, misses, reused prompt tokens and dropped caches."))
+)]
+async fn prefix_cache_stats(
+    State(state): State<Arc<MistralRs>>,
+) -> Result<Json<PrefixCacheStats>, String> {
+    state
+        .prefix_cache_stats()
+        .await
+        .map(Json)
+        .map_err(|e| e.to_string())
+}
+
 fn get_router(state: Arc<MistralRs>) -> Router {
     #[derive(OpenApi)]
     #[openapi(
         paths(models, health, chatcompletions),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, StopTokens, StreamOptions, Message)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -262,16 +358,52 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .route("/", get(health))
         .route("/activate_adapters", post(activate_adapters))
         .route("/re_isq", post(re_isq))
+        .route("/prefix_cache_stats", get(prefix_cache_stats))
         .route("/v1/images/generations", post(image_generation))
+        .route("/generate", post(start_generation))
+        .route("/generate/:id", get(poll_generation))
+        .route("/next_tokens", post(next_tokens))
         .layer(cors_layer)
         .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
         .with_state(state)
 }
 
+/// Wait for SIGTERM or Ctrl-C, then drain the engine. The server stops accepting connections once
+/// this returns, and waits for the open ones to finish.
+async fn shutdown_signal(mistralrs: Arc<MistralRs>, drain_timeout: Duration) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install the Ctrl-C handler.");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install the SIGTERM handler.")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+    info!(
+        "Shutting down, waiting up to {}s for in-flight requests.",
+        drain_timeout.as_secs()
+    );
+    if let Err(e) = mistralrs.drain(drain_timeout).await {
+        warn!("Failed to drain the engine: {e}");
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let mut args = Args::parse();
     initialize_logging();
+    set_rope_table_cache(args.cache_rope_tables);
 
     #[cfg(not(feature = "flash-attn"))]
     let use_flash_attn = false;
@@ -298,17 +430,9 @@ async fn main() -> Result<()> {
         .with_chat_template(args.chat_template)
         .with_use_flash_attn(use_flash_attn)
         .with_prompt_batchsize(prompt_batchsize)
+        .with_kv_cache_backend(args.kv_cache_backend)
         .build()?;
 
-    #[cfg(feature = "metal")]
-    let device = Device::new_metal(0)?;
-    #[cfg(not(feature = "metal"))]
-    let device = Device::cuda_if_available(0)?;
-
-    if let Some(seed) = args.seed {
-        device.set_seed(seed)?;
-    }
-
     info!(
         "avx: {}, neon: {}, simd128: {}, f16c: {}",
         candle_core::utils::with_avx(),
@@ -419,18 +543,84 @@ async fn main() -> Result<()> {
         (_, _, _, _, _, _) => None,
     };
 
-    let pipeline = loader.load_model_from_hf(
-        None,
-        args.token_source,
-        &dtype,
-        &device,
-        false,
-        mapper,
-        args.in_situ_quant,
-        cache_config,
-    )?;
+    let load = |device: &Device| {
+        if let Some(seed) = args.seed {
+            device.set_seed(seed)?;
+        }
+        loader.load_model_from_hf(
+            None,
+            args.token_source.clone(),
+            &dtype,
+            device,
+            false,
+            mapper.clone(),
+            args.in_situ_quant,
+            cache_config,
+        )
+    };
+    let (pipeline, device) = match args.device {
+        Some(spec) => load_on_device(&spec, args.allow_cpu_fallback, load)?,
+        None => {
+            #[cfg(feature = "metal")]
+            let device = Device::new_metal(0)?;
+            #[cfg(not(feature = "metal"))]
+            let device = Device::cuda_if_available(0)?;
+            (load(&device)?, device)
+        }
+    };
     info!("Model loaded.");
 
+    if args.verify_attention {
+        if use_flash_attn {
+            let max_diff = verify_flash_attention(&*pipeline.lock().await)?;
+            info!("Flash attention matches dense attention, the maximum absolute difference is {max_diff}.");
+        } else {
+            warn!("`--verify-attention` has no effect without flash attention.");
+        }
+    }
+
+    let fallback = if let (Some(model_id), Some(max_admission_tokens)) =
+        (args.fallback_model_id, args.fallback_max_admission_tokens)
+    {
+        let fallback_loader = LoaderBuilder::new(ModelSelected::Plain {
+            model_id,
+            tokenizer_json: None,
+            arch: None,
+            dtype: ModelDType::Auto,
+            topology: None,
+            organization: None,
+            write_uqff: None,
+            from_uqff: None,
+            imatrix: None,
+            calibration_file: None,
+            added_tokens: Vec::new(),
+        })
+        .with_use_flash_attn(use_flash_attn)
+        .build()?;
+        let fallback_pipeline = fallback_loader.load_model_from_hf(
+            None,
+            args.token_source,
+            &ModelDType::Auto,
+            &device,
+            false,
+            DeviceMapMetadata::dummy(),
+            args.in_situ_quant,
+            None,
+        )?;
+        info!("Fallback model loaded.");
+        let fallback = MistralRsBuilder::new(
+            fallback_pipeline,
+            SchedulerConfig::DefaultScheduler {
+                method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+            },
+        )
+        .with_truncate_sequence(args.truncate_sequence)
+        .build();
+        Some((fallback, max_admission_tokens))
+    } else {
+        None
+    };
+
     let scheduler_config = if cache_config.is_some() {
         // Handle case where we may have device mapping
         if let Some(ref cache_config) = pipeline.lock().await.get_metadata().cache_config {
@@ -453,7 +643,28 @@ async fn main() -> Result<()> {
         .with_opt_log(args.log)
         .with_truncate_sequence(args.truncate_sequence)
         .with_no_kv_cache(args.no_kv_cache)
-        .with_prefix_cache_n(args.prefix_cache_n);
+        .with_prefix_cache_n(args.prefix_cache_n)
+        .with_warmup_prefixes(args.warmup_prefixes);
+    let builder = if let Some(dump_logits) = args.dump_logits {
+        builder.with_logits_dump_dir(dump_logits)
+    } else {
+        builder
+    };
+    let builder = if let Some(max_prompt_tokens) = args.max_prompt_tokens {
+        builder.with_max_prompt_tokens(max_prompt_tokens, args.max_prompt_tokens_policy)
+    } else {
+        builder
+    };
+    let builder = if let Some(max_concurrent_sequences) = args.max_concurrent_sequences {
+        builder.with_max_concurrent_sequences(max_concurrent_sequences)
+    } else {
+        builder
+    };
+    let builder = if let Some((fallback, max_admission_tokens)) = fallback {
+        builder.with_fallback(fallback, max_admission_tokens)
+    } else {
+        builder
+    };
 
     if args.interactive_mode {
         interactive_mode(builder.build(), args.throughput_log).await;
@@ -469,7 +680,7 @@ async fn main() -> Result<()> {
 
     let port = args.port.expect("Interactive mode was not specified, so expected port to be specified. Perhaps you forgot `-i` or `--port`?");
 
-    let app = get_router(mistralrs);
+    let app = get_router(mistralrs.clone());
 
     let ip = if let Some(ref ip) = args.serve_ip {
         ip.to_string()
@@ -478,7 +689,12 @@ async fn main() -> Result<()> {
     };
     let listener = tokio::net::TcpListener::bind(format!("{ip}:{}", port)).await?;
     info!("Serving on http://{ip}:{}.", port);
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(
+            mistralrs,
+            Duration::from_secs(args.drain_timeout),
+        ))
+        .await?;
 
     Ok(())
 }
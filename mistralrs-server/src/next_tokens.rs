@@ -0,0 +1,147 @@
+//! Top-k next token predictions for a prompt, for autocomplete-style clients.
+//!
+//! `POST /next_tokens` runs a single forward pass over the prompt and returns the most likely next
+//! tokens with their probabilities. Nothing is generated.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response as AxumResponse},
+};
+use mistralrs_core::{
+    next_token_candidates, DetokenizationRequest, MistralRs, NormalRequest, Request,
+    RequestMessage, Response, SamplingParams,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::channel;
+use utoipa::ToSchema;
+
+fn default_k() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct NextTokensRequest {
+    #[schema(example = "The capital of France is")]
+    pub prompt: String,
+    /// The number of candidates to return.
+    #[serde(default = "default_k")]
+    #[schema(example = 5)]
+    pub k: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NextToken {
+    pub token: u32,
+    pub text: String,
+    pub prob: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NextTokensResponse {
+    pub tokens: Vec<NextToken>,
+}
+
+#[derive(Serialize)]
+struct NextTokensError {
+    message: String,
+}
+
+fn error_response(code: StatusCode, message: String) -> AxumResponse {
+    let mut r = Json(NextTokensError { message }).into_response();
+    *r.status_mut() = code;
+    r
+}
+
+async fn detokenize(state: &MistralRs, token: u32) -> anyhow::Result<String> {
+    let (tx, mut rx) = channel(1);
+    state
+        .get_sender()?
+        .send(Request::Detokenize(DetokenizationRequest {
+            tokens: vec![token],
+            skip_special_tokens: false,
+            response: tx,
+        }))
+        .await?;
+    rx.recv().await.context("Channel was erroneously closed!")?
+}
+
+async fn next_tokens_inner(
+    state: Arc<MistralRs>,
+    request: NextTokensRequest,
+) -> anyhow::Result<NextTokensResponse> {
+    let (tx, mut rx) = channel(1);
+    let mut normal_request = NormalRequest::new_simple(
+        RequestMessage::Completion {
+            text: request.prompt,
+            echo_prompt: false,
+            best_of: None,
+        },
+        SamplingParams {
+            max_len: Some(0),
+            ..SamplingParams::deterministic()
+        },
+        tx,
+        state.next_request_id(),
+        None,
+        None,
+    );
+    // The logits of the prompt are returned without sampling from them
+    normal_request.return_raw_logits = true;
+    state
+        .get_sender()?
+        .send(Request::Normal(normal_request))
+        .await?;
+
+    let logits_chunks = match rx
+        .recv()
+        .await
+        .context("No response received from the model.")?
+    {
+        Response::Raw { logits_chunks, .. } => logits_chunks,
+        Response::InternalError(e) | Response::ValidationError(e) => anyhow::bail!(e),
+        _ => anyhow::bail!("Got unexpected response type."),
+    };
+
+    let mut tokens = Vec::new();
+    for candidate in next_token_candidates(&logits_chunks, request.k)? {
+        tokens.push(NextToken {
+            token: candidate.token,
+            text: detokenize(&state, candidate.token).await?,
+            prob: candidate.prob,
+        });
+    }
+    Ok(NextTokensResponse { tokens })
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/next_tokens",
+    request_body = NextTokensRequest,
+    responses((status = 200, description = "The most likely next tokens of the prompt"))
+)]
+pub async fn next_tokens(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<NextTokensRequest>,
+) -> AxumResponse {
+    if request.k == 0 {
+        return error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "`k` must be at least 1.".to_string(),
+        );
+    }
+    let repr = serde_json::to_string(&request).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    match next_tokens_inner(state.clone(), request).await {
+        Ok(response) => Json(response).into_response(),
+        Err(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    }
+}
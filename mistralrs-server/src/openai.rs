@@ -1,5 +1,7 @@
 use either::Either;
-use mistralrs_core::{ImageGenerationResponseFormat, LlguidanceGrammar, Tool, ToolChoice};
+use mistralrs_core::{
+    ImageGenerationResponseFormat, LlguidanceGrammar, ReasoningMarkers, Tool, ToolChoice,
+};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, ops::Deref};
 use utoipa::ToSchema;
@@ -80,6 +82,22 @@ pub enum Grammar {
     Lark(String),
 }
 
+/// The format of a chat completion, as in OpenAI's `response_format`.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    /// Constrain the output to a single JSON object.
+    JsonObject,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct StreamOptions {
+    /// Include the usage in the final chunk of the stream.
+    #[serde(default = "default_false")]
+    pub include_usage: bool,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ChatCompletionRequest {
     #[schema(example = json!(vec![Message{content:"Why did the crab cross the road?".to_string(), role:"user".to_string(), name: None}]))]
@@ -114,10 +132,14 @@ pub struct ChatCompletionRequest {
     pub top_p: Option<f64>,
     #[schema(example = true)]
     pub stream: Option<bool>,
+    #[schema(example = json!(Option::None::<StreamOptions>))]
+    pub stream_options: Option<StreamOptions>,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
@@ -128,6 +150,8 @@ pub struct ChatCompletionRequest {
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
     #[schema(example = json!(Option::None::<f32>))]
     pub dry_multiplier: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -136,6 +160,24 @@ pub struct ChatCompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub target_length: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_bias_strength: Option<f32>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub stop_on_balanced_brackets: Option<bool>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub max_output_bytes: Option<usize>,
+    /// Continue the final (typically assistant) message instead of starting a new turn.
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub continue_final_message: bool,
+    /// Split the reasoning block delimited by these markers into `reasoning_content`.
+    #[schema(example = json!(Option::None::<ReasoningMarkers>))]
+    pub reasoning_markers: Option<ReasoningMarkers>,
+    /// Prefill the start of the assistant response, which is included in the returned content.
+    #[schema(example = json!(Option::None::<String>))]
+    pub assistant_prefix: Option<String>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -144,6 +186,8 @@ pub struct ModelObject {
     pub object: &'static str,
     pub created: u64,
     pub owned_by: &'static str,
+    /// The maximum number of prompt and generated tokens of a request.
+    pub max_model_len: usize,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -183,6 +227,8 @@ pub struct CompletionRequest {
     #[schema(example = json!(Option::None::<StopTokens>))]
     pub stop_seqs: Option<StopTokens>,
     pub stream: Option<bool>,
+    #[schema(example = json!(Option::None::<StreamOptions>))]
+    pub stream_options: Option<StreamOptions>,
     #[schema(example = 0.7)]
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
@@ -205,6 +251,8 @@ pub struct CompletionRequest {
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
     #[schema(example = json!(Option::None::<f32>))]
     pub dry_multiplier: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -213,6 +261,14 @@ pub struct CompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub target_length: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_bias_strength: Option<f32>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub stop_on_balanced_brackets: Option<bool>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub max_output_bytes: Option<usize>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
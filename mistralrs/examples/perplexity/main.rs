@@ -50,6 +50,9 @@ async fn process_chunk(runner: &MistralRs, chunk: Vec<u32>) -> anyhow::Result<(T
         tool_choice: None,
         logits_processors: None,
         return_raw_logits: true,
+        continue_final_message: false,
+        reasoning_markers: None,
+        assistant_prefix: None,
     });
 
     runner.get_sender()?.send(request).await?;
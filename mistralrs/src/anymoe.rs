@@ -3,7 +3,7 @@ use mistralrs_core::{
     Loader, MistralRsBuilder, NormalLoaderBuilder, NormalSpecificConfig, SchedulerConfig,
 };
 
-use crate::{best_device, Model, TextModelBuilder};
+use crate::{model::load_on_selected_device, Model, TextModelBuilder};
 
 pub struct AnyMoeModelBuilder {
     base: TextModelBuilder,
@@ -75,17 +75,25 @@ impl AnyMoeModelBuilder {
         });
 
         // Load, into a Pipeline
-        let pipeline = loader.load_model_from_hf(
-            self.base.hf_revision,
-            self.base.token_source,
-            &self.base.dtype,
-            &best_device(self.base.force_cpu)?,
-            !self.base.with_logging,
-            self.base
-                .device_mapping
-                .unwrap_or(DeviceMapMetadata::dummy()),
-            self.base.isq,
-            self.base.paged_attn_cfg,
+        let pipeline = load_on_selected_device(
+            self.base.device.as_ref(),
+            self.base.force_cpu,
+            self.base.allow_cpu_fallback,
+            |device| {
+                loader.load_model_from_hf(
+                    self.base.hf_revision.clone(),
+                    self.base.token_source.clone(),
+                    &self.base.dtype,
+                    device,
+                    !self.base.with_logging,
+                    self.base
+                        .device_mapping
+                        .clone()
+                        .unwrap_or(DeviceMapMetadata::dummy()),
+                    self.base.isq,
+                    self.base.paged_attn_cfg,
+                )
+            },
         )?;
 
         let scheduler_method = match self.base.paged_attn_cfg {
@@ -1,7 +1,7 @@
 use mistralrs_core::*;
 use std::num::NonZeroUsize;
 
-use crate::{best_device, Model};
+use crate::{model::load_on_selected_device, Model};
 
 /// Configure a text GGUF model with the various parameters for loading, running, and other inference behaviors.
 pub struct GgufModelBuilder {
@@ -18,6 +18,8 @@ pub struct GgufModelBuilder {
     // Model running
     pub(crate) prompt_batchsize: Option<NonZeroUsize>,
     pub(crate) force_cpu: bool,
+    pub(crate) device: Option<DeviceSpec>,
+    pub(crate) allow_cpu_fallback: bool,
     pub(crate) topology: Option<Topology>,
 
     // Other things
@@ -41,6 +43,8 @@ impl GgufModelBuilder {
             chat_template: None,
             tokenizer_json: None,
             force_cpu: false,
+            device: None,
+            allow_cpu_fallback: false,
             token_source: TokenSource::CacheToken,
             hf_revision: None,
             paged_attn_cfg: None,
@@ -90,6 +94,19 @@ impl GgufModelBuilder {
         self
     }
 
+    /// Load on an explicitly selected device, such as `cuda:1`, instead of the first GPU.
+    pub fn with_device(mut self, device: DeviceSpec) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// If the device selected with [`Self::with_device`] is unavailable or loading onto it fails,
+    /// for example because it runs out of memory, warn and load on the CPU instead.
+    pub fn with_cpu_fallback(mut self) -> Self {
+        self.allow_cpu_fallback = true;
+        self
+    }
+
     /// Source of the Hugging Face token.
     pub fn with_token_source(mut self, token_source: TokenSource) -> Self {
         self.token_source = token_source;
@@ -171,15 +188,24 @@ impl GgufModelBuilder {
         .build();
 
         // Load, into a Pipeline
-        let pipeline = loader.load_model_from_hf(
-            self.hf_revision,
-            self.token_source,
-            &ModelDType::Auto,
-            &best_device(self.force_cpu)?,
-            !self.with_logging,
-            self.device_mapping.unwrap_or(DeviceMapMetadata::dummy()),
-            None,
-            self.paged_attn_cfg,
+        let pipeline = load_on_selected_device(
+            self.device.as_ref(),
+            self.force_cpu,
+            self.allow_cpu_fallback,
+            |device| {
+                loader.load_model_from_hf(
+                    self.hf_revision.clone(),
+                    self.token_source.clone(),
+                    &ModelDType::Auto,
+                    device,
+                    !self.with_logging,
+                    self.device_mapping
+                        .clone()
+                        .unwrap_or(DeviceMapMetadata::dummy()),
+                    None,
+                    self.paged_attn_cfg,
+                )
+            },
         )?;
 
         let scheduler_method = match self.paged_attn_cfg {
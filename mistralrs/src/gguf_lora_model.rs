@@ -1,6 +1,6 @@
 use mistralrs_core::*;
 
-use crate::{best_device, GgufModelBuilder, Model};
+use crate::{model::load_on_selected_device, GgufModelBuilder, Model};
 
 /// Wrapper of [`GgufModelBuilder`] for LoRA models.
 pub struct GgufLoraModelBuilder {
@@ -43,15 +43,22 @@ impl GgufLoraModelBuilder {
         .build();
 
         // Load, into a Pipeline
-        let pipeline = loader.load_model_from_hf(
-            self.gguf_model.hf_revision,
-            self.gguf_model.token_source,
-            &ModelDType::Auto,
-            &best_device(self.gguf_model.force_cpu)?,
-            !self.gguf_model.with_logging,
-            DeviceMapMetadata::dummy(),
-            None,
-            self.gguf_model.paged_attn_cfg,
+        let pipeline = load_on_selected_device(
+            self.gguf_model.device.as_ref(),
+            self.gguf_model.force_cpu,
+            self.gguf_model.allow_cpu_fallback,
+            |device| {
+                loader.load_model_from_hf(
+                    self.gguf_model.hf_revision.clone(),
+                    self.gguf_model.token_source.clone(),
+                    &ModelDType::Auto,
+                    device,
+                    !self.gguf_model.with_logging,
+                    DeviceMapMetadata::dummy(),
+                    None,
+                    self.gguf_model.paged_attn_cfg,
+                )
+            },
         )?;
 
         let scheduler_method = match self.gguf_model.paged_attn_cfg {
@@ -59,6 +59,7 @@ mod lora_model;
 mod messages;
 mod model;
 mod text_model;
+mod thread_safe_pipeline;
 mod vision_model;
 mod xlora_model;
 
@@ -75,6 +76,7 @@ pub mod v0_4_api {
     };
     pub use super::model::{best_device, Model};
     pub use super::text_model::{PagedAttentionMetaBuilder, TextModelBuilder};
+    pub use super::thread_safe_pipeline::{GenerationStream, ThreadSafePipeline};
     pub use super::vision_model::VisionModelBuilder;
     pub use super::xlora_model::XLoraModelBuilder;
 }
@@ -16,6 +16,7 @@ pub trait RequestLike {
     fn take_constraint(&mut self) -> Constraint;
     fn take_tools(&mut self) -> Option<(Vec<Tool>, ToolChoice)>;
     fn take_sampling_params(&mut self) -> SamplingParams;
+    fn take_assistant_prefix(&mut self) -> Option<String>;
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -105,6 +106,9 @@ impl RequestLike for TextMessages {
     fn take_sampling_params(&mut self) -> SamplingParams {
         SamplingParams::deterministic()
     }
+    fn take_assistant_prefix(&mut self) -> Option<String> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -303,6 +307,9 @@ impl RequestLike for VisionMessages {
     fn take_sampling_params(&mut self) -> SamplingParams {
         SamplingParams::deterministic()
     }
+    fn take_assistant_prefix(&mut self) -> Option<String> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -314,6 +321,7 @@ impl RequestLike for VisionMessages {
 /// - Logprobs
 /// - Tools
 /// - Sampling
+/// - The prefilled start of the response
 pub struct RequestBuilder {
     messages: Vec<IndexMap<String, MessageContent>>,
     images: Vec<DynamicImage>,
@@ -324,6 +332,7 @@ pub struct RequestBuilder {
     tools: Vec<Tool>,
     tool_choice: ToolChoice,
     sampling_params: SamplingParams,
+    assistant_prefix: Option<String>,
 }
 
 impl Default for RequestBuilder {
@@ -344,6 +353,7 @@ impl From<TextMessages> for RequestBuilder {
             tools: Vec::new(),
             tool_choice: ToolChoice::Auto,
             sampling_params: SamplingParams::deterministic(),
+            assistant_prefix: None,
         }
     }
 }
@@ -360,6 +370,7 @@ impl From<VisionMessages> for RequestBuilder {
             tools: Vec::new(),
             tool_choice: ToolChoice::Auto,
             sampling_params: SamplingParams::deterministic(),
+            assistant_prefix: None,
         }
     }
 }
@@ -376,6 +387,7 @@ impl RequestBuilder {
             tools: Vec::new(),
             tool_choice: ToolChoice::Auto,
             sampling_params: SamplingParams::deterministic(),
+            assistant_prefix: None,
         }
     }
 
@@ -521,6 +533,11 @@ impl RequestBuilder {
         self
     }
 
+    pub fn set_sampler_topa(mut self, topa: f64) -> Self {
+        self.sampling_params.top_a = Some(topa);
+        self
+    }
+
     pub fn set_sampler_topn_logprobs(mut self, top_n_logprobs: usize) -> Self {
         self.sampling_params.top_n_logprobs = top_n_logprobs;
         self
@@ -560,6 +577,34 @@ impl RequestBuilder {
         self.sampling_params.dry_params = Some(dry_params);
         self
     }
+
+    /// Bias the EOS token toward finishing around `target_length` generated tokens.
+    pub fn set_sampler_length_bias(mut self, target_length: usize, strength: f32) -> Self {
+        self.sampling_params.length_bias = Some(LengthBiasParams {
+            target_length,
+            strength,
+        });
+        self
+    }
+
+    /// Stop once the brackets left open by the prompt, or opened by the completion, are closed.
+    pub fn set_sampler_stop_on_balanced_brackets(mut self, stop: bool) -> Self {
+        self.sampling_params.stop_on_balanced_brackets = stop;
+        self
+    }
+
+    /// Stop once the decoded output reaches `max_output_bytes` bytes, cut at a character boundary.
+    pub fn set_sampler_max_output_bytes(mut self, max_output_bytes: usize) -> Self {
+        self.sampling_params.max_output_bytes = Some(max_output_bytes);
+        self
+    }
+
+    /// Prefill the start of the response with `prefix`, which the model continues. The prefix is
+    /// included in the returned content.
+    pub fn set_assistant_prefix(mut self, prefix: impl ToString) -> Self {
+        self.assistant_prefix = Some(prefix.to_string());
+        self
+    }
 }
 
 impl RequestLike for RequestBuilder {
@@ -631,4 +676,8 @@ impl RequestLike for RequestBuilder {
         std::mem::swap(&mut other, &mut self.sampling_params);
         other
     }
+
+    fn take_assistant_prefix(&mut self) -> Option<String> {
+        self.assistant_prefix.take()
+    }
 }
@@ -2,7 +2,7 @@ use anyhow::Context;
 use candle_core::{Device, Result, Tensor};
 use either::Either;
 use mistralrs_core::*;
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 use tokio::sync::mpsc::channel;
 
 use crate::{RequestLike, TextMessages};
@@ -22,6 +22,20 @@ pub fn best_device(force_cpu: bool) -> Result<Device> {
     }
 }
 
+/// Load onto the device selected by the builder, or the [`best_device`] if there is none. The CPU
+/// fallback only applies to an explicitly selected device.
+pub(crate) fn load_on_selected_device<T>(
+    device: Option<&DeviceSpec>,
+    force_cpu: bool,
+    allow_cpu_fallback: bool,
+    mut load: impl FnMut(&Device) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    match device {
+        Some(spec) if !force_cpu => Ok(load_on_device(spec, allow_cpu_fallback, load)?.0),
+        _ => load(&best_device(force_cpu)?),
+    }
+}
+
 /// The object used to interact with the model. This can be used with many varietes of models, \
 /// and as such may be created with one of:
 /// - [`TextModelBuilder`]
@@ -78,6 +92,9 @@ impl Model {
             tool_choice,
             logits_processors: request.take_logits_processors(),
             return_raw_logits: false,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: request.take_assistant_prefix(),
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -122,6 +139,9 @@ impl Model {
             tool_choice,
             logits_processors: request.take_logits_processors(),
             return_raw_logits: true,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: request.take_assistant_prefix(),
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -167,6 +187,9 @@ impl Model {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -195,6 +218,16 @@ impl Model {
         Ok(self.runner.get_sender()?.send(request).await?)
     }
 
+    /// Change the sampling parameters of a running request from its next decode step on. The
+    /// request id of a streamed generation is given by [`crate::GenerationStream::request_id`].
+    pub async fn update_sampling(
+        &self,
+        request_id: usize,
+        update: SamplingUpdate,
+    ) -> anyhow::Result<()> {
+        Ok(self.runner.update_sampling(request_id, update).await?)
+    }
+
     /// Reapply ISQ to the model. This will be done on whatever device the model is already on.
     pub async fn re_isq_model(&self, isq_type: IsqType) -> anyhow::Result<()> {
         let request = Request::ReIsq(isq_type);
@@ -241,6 +274,51 @@ impl Model {
         rx.recv().await.context("Channel was erroneously closed!")?
     }
 
+    /// Classify a prompt with the last-layer hidden state of its last token. If `head` is
+    /// provided, its logits are returned, otherwise the hidden state itself.
+    pub async fn classify(
+        &self,
+        prompt: impl ToString,
+        head: Option<Arc<ClassificationHead>>,
+    ) -> anyhow::Result<Vec<f32>> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::Classify(ClassificationRequest {
+            prompt: Either::Right(prompt.to_string()),
+            head,
+            response: tx,
+        });
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// The attention probabilities of every layer for a prompt, of shape `(layers, heads,
+    /// seq_len, seq_len)`. Attention is computed densely for this request.
+    pub async fn attention_weights(&self, prompt: impl ToString) -> anyhow::Result<Tensor> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::AttentionWeights(AttentionWeightsRequest {
+            prompt: Either::Right(prompt.to_string()),
+            response: tx,
+        });
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Save the weights of the model to the safetensors file `path`, with the `config.json`,
+    /// tokenizer and generation config alongside, so that the directory can be loaded as a model.
+    /// The model must not be quantized.
+    pub async fn save_safetensors(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::SaveSafetensors(SaveSafetensorsRequest {
+            path: path.as_ref().to_path_buf(),
+            response: tx,
+        });
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
     /// Retrieve some information about this model.
     pub fn config(&self) -> &MistralRsConfig {
         self.runner.config()
@@ -250,3 +328,432 @@ impl Model {
         &self.runner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use either::Either;
+    use futures::StreamExt;
+
+    use mistralrs_core::testing::{
+        write_tiny_llama, TempDir, TINY_LLAMA_HIDDEN_SIZE, TINY_LLAMA_LAYERS,
+    };
+
+    use super::Model;
+    use crate::{
+        ChatCompletionResponse, ClassificationHead, CompletionChoice, Constraint, ModelDType,
+        NormalRequest, Request, RequestBuilder, RequestMessage, Response, SamplingParams,
+        SelfSpeculativeConfig, TextMessageRole, TextMessages, TextModelBuilder, ThreadSafePipeline,
+        Usage,
+    };
+
+    /// A small instruct model with a real tokenizer, for the tests which download it.
+    const SMOLLM2: &str = "HuggingFaceTB/SmolLM2-135M-Instruct";
+
+    async fn smollm2() -> anyhow::Result<Model> {
+        TextModelBuilder::new(SMOLLM2).build().await
+    }
+
+    /// Write and load a tiny model, see [`write_tiny_llama`] for `preferred`.
+    async fn tiny_model(dir: &TempDir, preferred: &[&str]) -> anyhow::Result<Model> {
+        write_tiny_llama(dir.path(), preferred)?;
+        TextModelBuilder::new(dir.path().display().to_string())
+            .with_dtype(ModelDType::F32)
+            .with_force_cpu()
+            .build()
+            .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_usage_matches_tokenizer_smollm2() -> anyhow::Result<()> {
+        let usage = usage_matches_tokenizer(smollm2().await?).await?;
+        assert!(usage.completion_tokens > 0 && usage.completion_tokens <= 8);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_usage_matches_tokenizer() -> anyhow::Result<()> {
+        let dir = TempDir::new("usage_matches_tokenizer");
+        let usage = usage_matches_tokenizer(tiny_model(&dir, &[]).await?).await?;
+        // The tiny model never generates the end of sequence token
+        assert_eq!(usage.completion_tokens, 8);
+        Ok(())
+    }
+
+    /// Check the usage of a chat request with up to 8 tokens, streamed and not, returning the
+    /// usage of the response which is not streamed.
+    async fn usage_matches_tokenizer(model: Model) -> anyhow::Result<Usage> {
+        let prompt = "Write a haiku about the sea.";
+
+        let prompt_toks = model
+            .tokenize(
+                Either::Left(TextMessages::new().add_message(TextMessageRole::User, prompt)),
+                None,
+                true,
+                true,
+            )
+            .await?;
+        let request = || {
+            RequestBuilder::new()
+                .add_message(TextMessageRole::User, prompt)
+                .set_sampler_max_len(8)
+        };
+
+        let response = model.send_chat_request(request()).await?;
+        let usage = response.usage.clone();
+        assert_eq!(usage.prompt_tokens, prompt_toks.len());
+        assert_eq!(
+            usage.total_tokens,
+            usage.prompt_tokens + usage.completion_tokens
+        );
+
+        // In a stream, only the final chunk has the usage.
+        let pipeline = ThreadSafePipeline::new(model);
+        let mut stream = pipeline.generate(request()).await?;
+        let mut chunks = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            chunks.push(chunk?);
+        }
+        let (last, rest) = chunks.split_last().expect("Expected at least one chunk");
+        assert!(rest.iter().all(|c| c.usage.is_none()));
+        let streamed = last
+            .usage
+            .as_ref()
+            .expect("Expected usage in the final chunk");
+        assert_eq!(streamed.prompt_tokens, prompt_toks.len());
+        assert_eq!(
+            streamed.total_tokens,
+            streamed.prompt_tokens + streamed.completion_tokens
+        );
+        Ok(usage)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_json_object_response_smollm2() -> anyhow::Result<()> {
+        json_object_response(smollm2().await?).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_json_object_response() -> anyhow::Result<()> {
+        let dir = TempDir::new("json_object_response");
+        // Unconstrained, the model only generates `}`, so a valid object comes from the constraint
+        json_object_response(tiny_model(&dir, &["}", "<|im_end|>", "{"]).await?).await
+    }
+
+    async fn json_object_response(model: Model) -> anyhow::Result<()> {
+        let request = RequestBuilder::new()
+            .add_message(TextMessageRole::User, "List three colors.")
+            .set_constraint(Constraint::JsonObject)
+            .set_sampler_max_len(256);
+
+        let response = model.send_chat_request(request).await?;
+        let content = response.choices[0]
+            .message
+            .content
+            .as_ref()
+            .expect("Expected content");
+        let value: serde_json::Value = serde_json::from_str(content)?;
+        assert!(value.is_object(), "{content}");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_assistant_prefix_smollm2() -> anyhow::Result<()> {
+        assistant_prefix(smollm2().await?).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_assistant_prefix() -> anyhow::Result<()> {
+        let dir = TempDir::new("assistant_prefix");
+        assistant_prefix(tiny_model(&dir, &[]).await?).await
+    }
+
+    async fn assistant_prefix(model: Model) -> anyhow::Result<()> {
+        let prompt = "Name a color.";
+        let prefix = "Sure, the color is";
+        let request = || {
+            RequestBuilder::new()
+                .add_message(TextMessageRole::User, prompt)
+                .set_sampler_max_len(8)
+        };
+
+        let plain = model.send_chat_request(request()).await?;
+        let prefixed = model
+            .send_chat_request(request().set_assistant_prefix(prefix))
+            .await?;
+        let content = prefixed.choices[0]
+            .message
+            .content
+            .as_ref()
+            .expect("Expected content");
+        assert!(content.starts_with(prefix), "{content}");
+        assert!(content.len() > prefix.len(), "{content}");
+        // The prefix is part of the prompt, it is not generated
+        assert!(prefixed.usage.prompt_tokens > plain.usage.prompt_tokens);
+        assert!(prefixed.usage.completion_tokens <= 8);
+
+        // In a stream, the prefix is sent with the first chunk
+        let pipeline = ThreadSafePipeline::new(model);
+        let mut stream = pipeline
+            .generate(request().set_assistant_prefix(prefix))
+            .await?;
+        let mut streamed = String::new();
+        while let Some(chunk) = stream.next().await {
+            streamed.push_str(&chunk?.choices[0].delta.content);
+        }
+        assert!(streamed.starts_with(prefix), "{streamed}");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_stop_on_balanced_brackets_smollm2() -> anyhow::Result<()> {
+        let choice =
+            balanced_brackets_completion(smollm2().await?, "fn add(a: i32, b: i32) -> i32 {")
+                .await?;
+        // The generation stops at the brace closing the one opened by the prompt
+        assert_eq!(choice.finish_reason, "stop", "{}", choice.text);
+        assert!(choice.text.ends_with('}'), "{}", choice.text);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stop_on_balanced_brackets() -> anyhow::Result<()> {
+        let dir = TempDir::new("stop_on_balanced_brackets");
+        let choice = balanced_brackets_completion(
+            tiny_model(&dir, &["}"]).await?,
+            "fn max(a: i32, b: i32) -> i32 { if a > b {",
+        )
+        .await?;
+        // The generation stops at the brace closing the first one opened by the prompt
+        assert_eq!(choice.finish_reason, "stop", "{}", choice.text);
+        assert_eq!(choice.text, "}}");
+        Ok(())
+    }
+
+    /// Complete `prompt` greedily, stopping once its brackets are balanced.
+    async fn balanced_brackets_completion(
+        model: Model,
+        prompt: &str,
+    ) -> anyhow::Result<CompletionChoice> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let request = NormalRequest::new_simple(
+            RequestMessage::Completion {
+                text: prompt.to_string(),
+                echo_prompt: false,
+                best_of: None,
+            },
+            SamplingParams {
+                max_len: Some(128),
+                stop_on_balanced_brackets: true,
+                ..SamplingParams::deterministic()
+            },
+            tx,
+            0,
+            None,
+            None,
+        );
+        model
+            .inner()
+            .get_sender()?
+            .send(Request::Normal(request))
+            .await?;
+
+        let Some(Response::CompletionDone(response)) = rx.recv().await else {
+            anyhow::bail!("Expected a completion response.");
+        };
+        Ok(response
+            .choices
+            .into_iter()
+            .next()
+            .expect("Expected a choice"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_max_output_bytes_smollm2() -> anyhow::Result<()> {
+        // The multi-byte characters reach the byte limit well before the token limit
+        let response = max_output_bytes_response(smollm2().await?).await?;
+        let choice = &response.choices[0];
+        let content = choice.message.content.as_ref().expect("Expected content");
+        assert_eq!(choice.finish_reason, "length", "{content}");
+        assert!(content.len() <= 32, "{content}");
+        assert!(response.usage.completion_tokens < 256);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_output_bytes() -> anyhow::Result<()> {
+        let dir = TempDir::new("max_output_bytes");
+        // The model generates a 3 byte character per token. The 11th token straddles the limit
+        // and is dropped, so that the output does not end in half a character.
+        let response = max_output_bytes_response(tiny_model(&dir, &["東"]).await?).await?;
+        let choice = &response.choices[0];
+        let content = choice.message.content.as_ref().expect("Expected content");
+        assert_eq!(choice.finish_reason, "length", "{content}");
+        assert_eq!(content, &"東".repeat(10));
+        assert_eq!(response.usage.completion_tokens, 11);
+        Ok(())
+    }
+
+    /// Ask for Chinese text with at most 256 tokens and 32 bytes of output.
+    async fn max_output_bytes_response(model: Model) -> anyhow::Result<ChatCompletionResponse> {
+        let request = RequestBuilder::new()
+            .add_message(
+                TextMessageRole::User,
+                "请用中文介绍一下东京的历史。只用中文回答。",
+            )
+            .set_sampler_max_len(256)
+            .set_sampler_max_output_bytes(32);
+        model.send_chat_request(request).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_classify_smollm2() -> anyhow::Result<()> {
+        let dir = TempDir::new("classify_smollm2");
+        // SmolLM2-135M has a hidden size of 576
+        classify(&smollm2().await?, &dir, 576).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_classify() -> anyhow::Result<()> {
+        let dir = TempDir::new("classify");
+        classify(&tiny_model(&dir, &[]).await?, &dir, TINY_LLAMA_HIDDEN_SIZE).await
+    }
+
+    /// Check the hidden state of a prompt, and a two label head written to `dir` applied to it.
+    async fn classify(model: &Model, dir: &TempDir, hidden_size: usize) -> anyhow::Result<()> {
+        let dev = candle_core::Device::Cpu;
+        let hidden = model.classify("This movie was wonderful.", None).await?;
+        assert_eq!(hidden.len(), hidden_size);
+
+        let path = dir.path().join("head.safetensors");
+        let weight = candle_core::Tensor::arange(0u32, 2 * hidden_size as u32, &dev)?
+            .to_dtype(candle_core::DType::F32)?
+            .reshape((2, hidden_size))?;
+        candle_core::safetensors::save(
+            &std::collections::HashMap::from([("score.weight".to_string(), weight.clone())]),
+            &path,
+        )?;
+        let head = std::sync::Arc::new(ClassificationHead::load(&path, &dev)?);
+
+        // The head is applied to the hidden state
+        let logits = model
+            .classify("This movie was wonderful.", Some(head.clone()))
+            .await?;
+        assert_eq!(logits.len(), head.num_labels());
+        let expected = weight
+            .matmul(&candle_core::Tensor::new(hidden, &dev)?.unsqueeze(1)?)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        for (x, y) in logits.iter().zip(expected) {
+            assert!((x - y).abs() < 1e-3 * y.abs().max(1.), "{logits:?}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_self_speculative_matches_greedy_smollm2() -> anyhow::Result<()> {
+        self_speculative_matches_greedy(|| TextModelBuilder::new(SMOLLM2), 8).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_self_speculative_matches_greedy() -> anyhow::Result<()> {
+        let dir = TempDir::new("self_speculative_matches_greedy");
+        write_tiny_llama(dir.path(), &[])?;
+        let builder = || {
+            TextModelBuilder::new(dir.path().display().to_string())
+                .with_dtype(ModelDType::F32)
+                .with_force_cpu()
+        };
+        self_speculative_matches_greedy(builder, TINY_LLAMA_LAYERS - 1).await
+    }
+
+    /// Compare greedy decoding with self-speculative decoding drafting with `draft_layers`.
+    async fn self_speculative_matches_greedy(
+        builder: impl Fn() -> TextModelBuilder,
+        draft_layers: usize,
+    ) -> anyhow::Result<()> {
+        let request = || {
+            RequestBuilder::new()
+                .add_message(TextMessageRole::User, "Count from one to ten.")
+                .set_deterministic_sampler()
+                .set_sampler_max_len(32)
+        };
+        let model = builder().build().await?;
+        let greedy = model.send_chat_request(request()).await?;
+        drop(model);
+
+        let model = builder()
+            .with_self_speculative(SelfSpeculativeConfig {
+                draft_layers,
+                gamma: 4,
+            })
+            .build()
+            .await?;
+        let speculative = model.send_chat_request(request()).await?;
+        assert_eq!(
+            speculative.choices[0].message.content,
+            greedy.choices[0].message.content
+        );
+        assert_eq!(
+            speculative.usage.completion_tokens,
+            greedy.usage.completion_tokens
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_save_safetensors_round_trip_smollm2() -> anyhow::Result<()> {
+        let saved = TempDir::new("save_safetensors_saved_smollm2");
+        save_safetensors_round_trip(smollm2().await?, &saved, TextModelBuilder::new).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_save_safetensors_round_trip() -> anyhow::Result<()> {
+        let dir = TempDir::new("save_safetensors_source");
+        let saved = TempDir::new("save_safetensors_saved");
+        save_safetensors_round_trip(tiny_model(&dir, &[]).await?, &saved, |path| {
+            TextModelBuilder::new(path)
+                .with_dtype(ModelDType::F32)
+                .with_force_cpu()
+        })
+        .await
+    }
+
+    /// Save `model` to `saved` and check that the model loaded back with `reload` generates the
+    /// same text.
+    async fn save_safetensors_round_trip(
+        model: Model,
+        saved: &TempDir,
+        reload: impl FnOnce(String) -> TextModelBuilder,
+    ) -> anyhow::Result<()> {
+        let request = || {
+            RequestBuilder::new()
+                .add_message(TextMessageRole::User, "Count from one to ten.")
+                .set_deterministic_sampler()
+                .set_sampler_max_len(32)
+        };
+        let original = model.send_chat_request(request()).await?;
+        model
+            .save_safetensors(saved.path().join("model.safetensors"))
+            .await?;
+        drop(model);
+
+        let reloaded = reload(saved.path().display().to_string())
+            .build()
+            .await?
+            .send_chat_request(request())
+            .await?;
+        assert_eq!(
+            reloaded.choices[0].message.content,
+            original.choices[0].message.content
+        );
+        Ok(())
+    }
+}
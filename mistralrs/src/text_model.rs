@@ -1,7 +1,7 @@
 use mistralrs_core::*;
 use std::{num::NonZeroUsize, path::PathBuf};
 
-use crate::{best_device, Model};
+use crate::{model::load_on_selected_device, Model};
 
 /// Configure a text model with the various parameters for loading, running, and other inference behaviors.
 pub struct TextModelBuilder {
@@ -25,6 +25,8 @@ pub struct TextModelBuilder {
     pub(crate) loader_type: Option<NormalLoaderType>,
     pub(crate) dtype: ModelDType,
     pub(crate) force_cpu: bool,
+    pub(crate) device: Option<DeviceSpec>,
+    pub(crate) allow_cpu_fallback: bool,
     pub(crate) isq: Option<IsqType>,
 
     // Other things
@@ -33,6 +35,8 @@ pub struct TextModelBuilder {
     pub(crate) no_kv_cache: bool,
     pub(crate) with_logging: bool,
     pub(crate) prefix_cache_n: Option<usize>,
+    pub(crate) self_speculative: Option<SelfSpeculativeConfig>,
+    pub(crate) kv_cache_backend: KvCacheBackendType,
 }
 
 /// Builder for PagedAttention metadata.
@@ -88,6 +92,8 @@ impl TextModelBuilder {
             loader_type: None,
             dtype: ModelDType::Auto,
             force_cpu: false,
+            device: None,
+            allow_cpu_fallback: false,
             token_source: TokenSource::CacheToken,
             hf_revision: None,
             isq: None,
@@ -99,9 +105,18 @@ impl TextModelBuilder {
             device_mapping: None,
             imatrix: None,
             calibration_file: None,
+            self_speculative: None,
+            kv_cache_backend: KvCacheBackendType::default(),
         }
     }
 
+    /// Store the KV cache in `kv_cache_backend`, for example offloaded to CPU memory for contexts
+    /// which do not fit on the device.
+    pub fn with_kv_cache_backend(mut self, kv_cache_backend: KvCacheBackendType) -> Self {
+        self.kv_cache_backend = kv_cache_backend;
+        self
+    }
+
     /// Set the prompt batchsize to use for inference.
     pub fn with_prompt_batchsize(mut self, prompt_batchsize: NonZeroUsize) -> Self {
         self.prompt_batchsize = Some(prompt_batchsize);
@@ -151,6 +166,19 @@ impl TextModelBuilder {
         self
     }
 
+    /// Load on an explicitly selected device, such as `cuda:1`, instead of the first GPU.
+    pub fn with_device(mut self, device: DeviceSpec) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// If the device selected with [`Self::with_device`] is unavailable or loading onto it fails,
+    /// for example because it runs out of memory, warn and load on the CPU instead.
+    pub fn with_cpu_fallback(mut self) -> Self {
+        self.allow_cpu_fallback = true;
+        self
+    }
+
     /// Source of the Hugging Face token.
     pub fn with_token_source(mut self, token_source: TokenSource) -> Self {
         self.token_source = token_source;
@@ -215,6 +243,14 @@ impl TextModelBuilder {
         self
     }
 
+    /// Use self-speculative decoding, drafting `gamma` tokens with the first `draft_layers` layers
+    /// of the model. Speculative decoding runs one sequence at a time, without the prefix cacher
+    /// or PagedAttention.
+    pub fn with_self_speculative(mut self, config: SelfSpeculativeConfig) -> Self {
+        self.self_speculative = Some(config);
+        self
+    }
+
     /// Enable logging.
     pub fn with_logging(mut self) -> Self {
         self.with_logging = true;
@@ -270,21 +306,46 @@ impl TextModelBuilder {
             Some(self.model_id),
         )
         .with_no_kv_cache(self.no_kv_cache)
+        .with_kv_cache_backend(self.kv_cache_backend)
         .build(self.loader_type)?;
+        let (loader, max_num_seqs, prefix_cache_n) = match self.self_speculative {
+            Some(config) => (
+                Box::new(SelfSpeculativeLoader {
+                    target: loader,
+                    config,
+                }) as Box<dyn Loader>,
+                1,
+                None,
+            ),
+            None => (loader, self.max_num_seqs, self.prefix_cache_n),
+        };
+        // Speculative decoding does not support PagedAttention
+        let paged_attn_cfg = self
+            .paged_attn_cfg
+            .filter(|_| self.self_speculative.is_none());
 
         // Load, into a Pipeline
-        let pipeline = loader.load_model_from_hf(
-            self.hf_revision,
-            self.token_source,
-            &self.dtype,
-            &best_device(self.force_cpu)?,
-            !self.with_logging,
-            self.device_mapping.unwrap_or(DeviceMapMetadata::dummy()),
-            self.isq,
-            self.paged_attn_cfg,
+        let pipeline = load_on_selected_device(
+            self.device.as_ref(),
+            self.force_cpu,
+            self.allow_cpu_fallback,
+            |device| {
+                loader.load_model_from_hf(
+                    self.hf_revision.clone(),
+                    self.token_source.clone(),
+                    &self.dtype,
+                    device,
+                    !self.with_logging,
+                    self.device_mapping
+                        .clone()
+                        .unwrap_or(DeviceMapMetadata::dummy()),
+                    self.isq,
+                    paged_attn_cfg,
+                )
+            },
         )?;
 
-        let scheduler_method = match self.paged_attn_cfg {
+        let scheduler_method = match paged_attn_cfg {
             Some(_) => {
                 let config = pipeline
                     .lock()
@@ -296,21 +357,21 @@ impl TextModelBuilder {
                     .clone();
 
                 SchedulerConfig::PagedAttentionMeta {
-                    max_num_seqs: self.max_num_seqs,
+                    max_num_seqs,
                     config,
                 }
             }
             None => SchedulerConfig::DefaultScheduler {
-                method: DefaultSchedulerMethod::Fixed(self.max_num_seqs.try_into()?),
+                method: DefaultSchedulerMethod::Fixed(max_num_seqs.try_into()?),
             },
         };
 
         let mut runner = MistralRsBuilder::new(pipeline, scheduler_method)
             .with_no_kv_cache(self.no_kv_cache)
             .with_gemm_full_precision_f16(true)
-            .with_no_prefix_cache(self.prefix_cache_n.is_none());
+            .with_no_prefix_cache(prefix_cache_n.is_none());
 
-        if let Some(n) = self.prefix_cache_n {
+        if let Some(n) = prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
 
@@ -0,0 +1,337 @@
+use std::{
+    ops::Deref,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use mistralrs_core::*;
+use tokio::sync::mpsc::{channel, Receiver};
+
+use crate::{Model, RequestLike};
+
+/// A cloneable handle to a loaded model which may be shared between many threads and async tasks.
+///
+/// # Threading model
+/// The model, its KV cache and the sampler state are owned by a single engine thread which is
+/// started when the model is loaded. Every handle only holds a channel to that thread, so
+/// submitting a request never touches the model directly. The engine interleaves the scheduled
+/// sequences and runs their forward passes one at a time, which means concurrent requests are
+/// batched by the scheduler rather than racing on the same cache.
+///
+/// Cloning a handle is cheap and all clones refer to the same loaded model. The model is dropped
+/// when the last handle is dropped.
+///
+/// All of the [`Model`] methods are available through [`Deref`].
+#[derive(Clone)]
+pub struct ThreadSafePipeline {
+    model: Arc<Model>,
+}
+
+impl ThreadSafePipeline {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model: Arc::new(model),
+        }
+    }
+
+    /// Generate with the model, returning a stream of the response chunks.
+    ///
+    /// The stream ends once every choice has a finish reason or an error is returned.
+    pub async fn generate<R: RequestLike>(
+        &self,
+        mut request: R,
+    ) -> anyhow::Result<GenerationStream> {
+        let (tx, rx) = channel(10_000);
+
+        let (tools, tool_choice) = if let Some((a, b)) = request.take_tools() {
+            (Some(a), Some(b))
+        } else {
+            (None, None)
+        };
+        let request_id = self.model.inner().next_request_id();
+        let request = Request::Normal(NormalRequest {
+            messages: request.take_messages(),
+            sampling_params: request.take_sampling_params(),
+            response: tx,
+            return_logprobs: request.return_logprobs(),
+            is_streaming: true,
+            id: request_id,
+            constraint: request.take_constraint(),
+            suffix: None,
+            adapters: request.take_adapters(),
+            tools,
+            tool_choice,
+            logits_processors: request.take_logits_processors(),
+            return_raw_logits: false,
+            continue_final_message: false,
+            reasoning_markers: None,
+            assistant_prefix: request.take_assistant_prefix(),
+        });
+
+        self.model.inner().get_sender()?.send(request).await?;
+
+        Ok(GenerationStream {
+            rx,
+            request_id,
+            is_done: false,
+        })
+    }
+}
+
+impl From<Model> for ThreadSafePipeline {
+    fn from(model: Model) -> Self {
+        Self::new(model)
+    }
+}
+
+impl Deref for ThreadSafePipeline {
+    type Target = Model;
+
+    fn deref(&self) -> &Self::Target {
+        &self.model
+    }
+}
+
+/// The stream of chunks returned by [`ThreadSafePipeline::generate`].
+pub struct GenerationStream {
+    rx: Receiver<Response>,
+    request_id: usize,
+    is_done: bool,
+}
+
+impl GenerationStream {
+    /// The id of the request, to change its sampling with [`Model::update_sampling`].
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+}
+
+impl futures::Stream for GenerationStream {
+    type Item = anyhow::Result<ChatCompletionChunkResponse>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_done {
+            return Poll::Ready(None);
+        }
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(Response::Chunk(chunk))) => {
+                if chunk.choices.iter().all(|x| x.finish_reason.is_some()) {
+                    self.is_done = true;
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Response::ModelError(msg, _))) => {
+                self.is_done = true;
+                Poll::Ready(Some(Err(anyhow::anyhow!(msg))))
+            }
+            Poll::Ready(Some(Response::ValidationError(e)))
+            | Poll::Ready(Some(Response::InternalError(e))) => {
+                self.is_done = true;
+                Poll::Ready(Some(Err(anyhow::anyhow!(e.to_string()))))
+            }
+            Poll::Ready(Some(_)) => {
+                self.is_done = true;
+                Poll::Ready(Some(Err(anyhow::anyhow!("Got unexpected response type."))))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use mistralrs_core::{
+        testing::{write_tiny_llama, TempDir},
+        ChatCompletionChunkResponse, ChunkChoice, Delta, ModelDType, Response, SamplingUpdate,
+    };
+    use tokio::sync::mpsc::channel;
+
+    use super::{GenerationStream, ThreadSafePipeline};
+    use crate::{RequestBuilder, TextMessageRole, TextModelBuilder};
+
+    const SMOLLM2: &str = "HuggingFaceTB/SmolLM2-135M-Instruct";
+
+    async fn smollm2_pipeline() -> anyhow::Result<ThreadSafePipeline> {
+        let model = TextModelBuilder::new(SMOLLM2).build().await?;
+        Ok(ThreadSafePipeline::new(model))
+    }
+
+    async fn tiny_pipeline(dir: &TempDir) -> anyhow::Result<ThreadSafePipeline> {
+        write_tiny_llama(dir.path(), &[])?;
+        let model = TextModelBuilder::new(dir.path().display().to_string())
+            .with_dtype(ModelDType::F32)
+            .with_force_cpu()
+            .build()
+            .await?;
+        Ok(ThreadSafePipeline::new(model))
+    }
+
+    async fn collect_tokens(
+        mut stream: GenerationStream,
+        on_chunk: impl Fn(usize) -> Option<SamplingUpdate>,
+        pipeline: &ThreadSafePipeline,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut deltas = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            deltas.push(chunk.choices[0].delta.content.clone());
+            if let Some(update) = on_chunk(deltas.len()) {
+                pipeline
+                    .update_sampling(stream.request_id(), update)
+                    .await?;
+            }
+        }
+        Ok(deltas)
+    }
+
+    fn chunk(content: &str, finish_reason: Option<&str>) -> Response {
+        Response::Chunk(ChatCompletionChunkResponse {
+            id: "0".to_string(),
+            choices: vec![ChunkChoice {
+                finish_reason: finish_reason.map(ToString::to_string),
+                index: 0,
+                delta: Delta {
+                    content: content.to_string(),
+                    reasoning_content: None,
+                    role: "assistant".to_string(),
+                    tool_calls: None,
+                },
+                logprobs: None,
+            }],
+            created: 0,
+            model: "model".to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "chat.completion.chunk".to_string(),
+            usage: None,
+        })
+    }
+
+    /// The items of a stream fed with `responses`, the channel is kept open.
+    async fn stream_items(responses: Vec<Response>) -> Vec<anyhow::Result<String>> {
+        let (tx, rx) = channel(responses.len());
+        for response in responses {
+            tx.send(response).await.unwrap();
+        }
+        let stream = GenerationStream {
+            rx,
+            request_id: 0,
+            is_done: false,
+        };
+        stream
+            .map(|chunk| chunk.map(|c| c.choices[0].delta.content.clone()))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_stream_ends_once_finished() {
+        // Without ending at the finish reason, the open channel would keep the stream pending
+        let items = stream_items(vec![
+            chunk("a", None),
+            chunk("b", Some("stop")),
+            chunk("c", None),
+        ])
+        .await;
+        let items = items
+            .into_iter()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(items, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_ends_at_error() {
+        let items = stream_items(vec![
+            chunk("a", None),
+            Response::ValidationError("Invalid request.".into()),
+            chunk("b", None),
+        ])
+        .await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), "a");
+        let err = items[1].as_ref().unwrap_err().to_string();
+        assert!(err.contains("Invalid request."), "{err}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_concurrent_generations_smollm2() -> anyhow::Result<()> {
+        concurrent_generations(smollm2_pipeline().await?).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_generations() -> anyhow::Result<()> {
+        let dir = TempDir::new("concurrent_generations");
+        concurrent_generations(tiny_pipeline(&dir).await?).await
+    }
+
+    async fn concurrent_generations(pipeline: ThreadSafePipeline) -> anyhow::Result<()> {
+        let mut handles = Vec::new();
+        for i in 0..64 {
+            let pipeline = pipeline.clone();
+            handles.push(tokio::spawn(async move {
+                let request = RequestBuilder::new()
+                    .add_message(TextMessageRole::User, format!("Count to {i}."))
+                    .set_sampler_max_len(16);
+                let mut stream = pipeline.generate(request).await?;
+                let mut n_chunks = 0;
+                let mut finished = false;
+                while let Some(chunk) = stream.next().await {
+                    let chunk = chunk?;
+                    finished |= chunk.choices.iter().all(|c| c.finish_reason.is_some());
+                    n_chunks += 1;
+                }
+                anyhow::ensure!(finished, "Stream ended without a finish reason");
+                anyhow::Ok(n_chunks)
+            }));
+        }
+
+        for handle in handles {
+            assert!(handle.await?? > 0);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "downloads a model"]
+    async fn test_update_sampling_mid_stream_smollm2() -> anyhow::Result<()> {
+        update_sampling_mid_stream(smollm2_pipeline().await?).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_sampling_mid_stream() -> anyhow::Result<()> {
+        let dir = TempDir::new("update_sampling_mid_stream");
+        update_sampling_mid_stream(tiny_pipeline(&dir).await?).await
+    }
+
+    async fn update_sampling_mid_stream(pipeline: ThreadSafePipeline) -> anyhow::Result<()> {
+        let request = || {
+            RequestBuilder::new()
+                .add_message(TextMessageRole::User, "Write a story about a dragon.")
+                .set_sampler_temperature(0.0)
+                .set_sampler_max_len(48)
+        };
+
+        let greedy =
+            collect_tokens(pipeline.generate(request()).await?, |_| None, &pipeline).await?;
+        // Raising the temperature after a few tokens changes the following tokens
+        let updated = collect_tokens(
+            pipeline.generate(request()).await?,
+            |n| {
+                (n == 4).then(|| SamplingUpdate {
+                    temperature: Some(5.0),
+                    ..Default::default()
+                })
+            },
+            &pipeline,
+        )
+        .await?;
+        assert_eq!(greedy[..4], updated[..4]);
+        assert_ne!(greedy, updated);
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use mistralrs_core::*;
 use std::{num::NonZeroUsize, path::PathBuf};
 
-use crate::{best_device, Model};
+use crate::{model::load_on_selected_device, Model};
 
 /// Configure a vision model with the various parameters for loading, running, and other inference behaviors.
 pub struct VisionModelBuilder {
@@ -24,6 +24,8 @@ pub struct VisionModelBuilder {
     pub(crate) loader_type: VisionLoaderType,
     pub(crate) dtype: ModelDType,
     pub(crate) force_cpu: bool,
+    pub(crate) device: Option<DeviceSpec>,
+    pub(crate) allow_cpu_fallback: bool,
     pub(crate) isq: Option<IsqType>,
 
     // Other things
@@ -49,6 +51,8 @@ impl VisionModelBuilder {
             loader_type,
             dtype: ModelDType::Auto,
             force_cpu: false,
+            device: None,
+            allow_cpu_fallback: false,
             token_source: TokenSource::CacheToken,
             hf_revision: None,
             isq: None,
@@ -95,6 +99,19 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Load on an explicitly selected device, such as `cuda:1`, instead of the first GPU.
+    pub fn with_device(mut self, device: DeviceSpec) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// If the device selected with [`Self::with_device`] is unavailable or loading onto it fails,
+    /// for example because it runs out of memory, warn and load on the CPU instead.
+    pub fn with_cpu_fallback(mut self) -> Self {
+        self.allow_cpu_fallback = true;
+        self
+    }
+
     /// Source of the Hugging Face token.
     pub fn with_token_source(mut self, token_source: TokenSource) -> Self {
         self.token_source = token_source;
@@ -188,15 +205,24 @@ impl VisionModelBuilder {
         .build(self.loader_type);
 
         // Load, into a Pipeline
-        let pipeline = loader.load_model_from_hf(
-            self.hf_revision,
-            self.token_source,
-            &self.dtype,
-            &best_device(self.force_cpu)?,
-            !self.with_logging,
-            self.device_mapping.unwrap_or(DeviceMapMetadata::dummy()),
-            self.isq,
-            None,
+        let pipeline = load_on_selected_device(
+            self.device.as_ref(),
+            self.force_cpu,
+            self.allow_cpu_fallback,
+            |device| {
+                loader.load_model_from_hf(
+                    self.hf_revision.clone(),
+                    self.token_source.clone(),
+                    &self.dtype,
+                    device,
+                    !self.with_logging,
+                    self.device_mapping
+                        .clone()
+                        .unwrap_or(DeviceMapMetadata::dummy()),
+                    self.isq,
+                    None,
+                )
+            },
         )?;
 
         let scheduler_method = SchedulerConfig::DefaultScheduler {
@@ -1,6 +1,6 @@
 use mistralrs_core::*;
 
-use crate::{best_device, Model, TextModelBuilder};
+use crate::{model::load_on_selected_device, Model, TextModelBuilder};
 
 /// Wrapper of [`TextModelBuilder`] for X-LoRA models.
 pub struct XLoraModelBuilder {
@@ -61,15 +61,22 @@ impl XLoraModelBuilder {
         .build(self.text_model.loader_type)?;
 
         // Load, into a Pipeline
-        let pipeline = loader.load_model_from_hf(
-            self.text_model.hf_revision,
-            self.text_model.token_source,
-            &self.text_model.dtype,
-            &best_device(self.text_model.force_cpu)?,
-            !self.text_model.with_logging,
-            DeviceMapMetadata::dummy(),
-            self.text_model.isq,
-            self.text_model.paged_attn_cfg,
+        let pipeline = load_on_selected_device(
+            self.text_model.device.as_ref(),
+            self.text_model.force_cpu,
+            self.text_model.allow_cpu_fallback,
+            |device| {
+                loader.load_model_from_hf(
+                    self.text_model.hf_revision.clone(),
+                    self.text_model.token_source.clone(),
+                    &self.text_model.dtype,
+                    device,
+                    !self.text_model.with_logging,
+                    DeviceMapMetadata::dummy(),
+                    self.text_model.isq,
+                    self.text_model.paged_attn_cfg,
+                )
+            },
         )?;
 
         let scheduler_method = match self.text_model.paged_attn_cfg {